@@ -0,0 +1,39 @@
+fn main() {
+    // The built-in configs are parsed here rather than on every run, and the program decodes the
+    // MessagePack written out instead. A YAML syntax mistake fails the build, but the script can't
+    // use the crate's types, so whether they decode is left to the `builtin_matches_yaml` test
+    #[cfg(feature = "std")]
+    {
+        println!("cargo:rerun-if-changed=src/config.yaml");
+        println!("cargo:rerun-if-changed=src/config");
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        for (source, encoded) in [
+            ("src/config.yaml", "config.msgpack"),
+            ("src/config/intel.yaml", "intel.msgpack"),
+            ("src/config/amd.yaml", "amd.msgpack"),
+        ] {
+            let text = std::fs::read_to_string(source).expect("Unable to read the config");
+            let config: serde_yaml::Value =
+                serde_yaml::from_str(&text).unwrap_or_else(|e| panic!("Invalid {}: {}", source, e));
+            std::fs::write(
+                format!("{}/{}", out_dir, encoded),
+                rmp_serde::to_vec(&config).expect("Unable to encode the config"),
+            )
+            .expect("Unable to write the encoded config");
+        }
+    }
+    // Only the C interface is exported, so the header is generated from its module alone
+    #[cfg(feature = "ffi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        cbindgen::Builder::new()
+            .with_src(format!("{}/src/ffi.rs", crate_dir))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("CPUINFO_H")
+            .with_no_includes()
+            .generate()
+            .expect("Unable to generate the C header")
+            .write_to_file(format!("{}/include/cpuinfo.h", crate_dir));
+    }
+}
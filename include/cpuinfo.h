@@ -0,0 +1,22 @@
+#ifndef CPUINFO_H
+#define CPUINFO_H
+
+/**
+ * Collect the facts of cpu 0 with the built-in config as a JSON list of
+ * `{"name": ..., "value": ...}` objects
+ *
+ * Returns NULL when the facts could not be collected, or cpu 0 could not be run on. The string
+ * is owned by the caller and must be released with `cpuinfo_free`.
+ */
+char *cpuinfo_collect_facts_json(void);
+
+/**
+ * Release a string returned by this library, NULL is ignored
+ *
+ * # Safety
+ *
+ * `string` must be NULL or a pointer returned by a `cpuinfo_` function that has not been freed.
+ */
+void cpuinfo_free(char *string);
+
+#endif /* CPUINFO_H */
@@ -0,0 +1,462 @@
+//! Facts from the ACPI tables describing processors, NUMA and caches
+//!
+//! Firmware describes the processors it brought up in the MADT, their NUMA proximity domains in
+//! the SRAT and the cache hierarchy in the PPTT. The kernel trusts these over CPUID when building
+//! its topology, so checking one against the other is useful when bringing up a hypervisor or
+//! new firmware. The tables are read from `/sys/firmware/acpi/tables`, which needs root. Tables
+//! the firmware does not provide are left out.
+//!
+//! Facts are named by table, e.g. `MADT/Local APIC/2/enabled`,
+//! `SRAT/processor/2/proximity_domain` and `PPTT/2/L2 Unified/size`.
+
+use super::facts::{self, Fact, FactValue};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const HEADER_LEN: usize = 36;
+
+fn u8_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset).map(|byte| u32::from(*byte))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u32::from(u16::from_le_bytes(bytes.try_into().ok()?)))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from(u32_at(data, offset)?) | u64::from(u32_at(data, offset + 4)?) << 32)
+}
+
+/// The `(type, body)` of each entry after the first `start` bytes of a table
+fn entries(table: &[u8], start: usize) -> Option<Vec<(u32, &[u8])>> {
+    let length = (u32_at(table, 4)? as usize).min(table.len());
+    let mut ret = Vec::new();
+    let mut offset = start;
+    while offset + 2 <= length {
+        let entry_len = u8_at(table, offset + 1)? as usize;
+        if entry_len < 2 {
+            return None;
+        }
+        ret.push((
+            u8_at(table, offset)?,
+            table.get(offset..offset + entry_len)?,
+        ));
+        offset += entry_len;
+    }
+    Some(ret)
+}
+
+/// A processor entry of the MADT
+pub struct LocalApic {
+    pub uid: u32,
+    pub apic_id: u32,
+    pub x2apic: bool,
+    pub enabled: bool,
+    /// A disabled processor that can be brought online later
+    pub online_capable: bool,
+}
+
+fn parse_madt(table: &[u8]) -> Option<Vec<LocalApic>> {
+    let mut ret = Vec::new();
+    for (kind, entry) in entries(table, HEADER_LEN + 8)? {
+        let (uid, apic_id, flags, x2apic) = match kind {
+            0 => (u8_at(entry, 2)?, u8_at(entry, 3)?, u32_at(entry, 4)?, false),
+            9 => (
+                u32_at(entry, 12)?,
+                u32_at(entry, 4)?,
+                u32_at(entry, 8)?,
+                true,
+            ),
+            _ => continue,
+        };
+        ret.push(LocalApic {
+            uid,
+            apic_id,
+            x2apic,
+            enabled: flags & 1 != 0,
+            online_capable: flags & 2 != 0,
+        });
+    }
+    Some(ret)
+}
+
+/// The NUMA proximity domains of the SRAT
+#[derive(Default)]
+pub struct Srat {
+    /// APIC ids of enabled processors and their proximity domain
+    pub processors: Vec<(u32, u32)>,
+    /// Bytes of enabled memory in each proximity domain
+    pub memory: BTreeMap<u32, u64>,
+}
+
+fn parse_srat(table: &[u8]) -> Option<Srat> {
+    let mut srat = Srat::default();
+    // The header is followed by 12 reserved bytes
+    for (kind, entry) in entries(table, HEADER_LEN + 12)? {
+        match kind {
+            0 if u32_at(entry, 4)? & 1 != 0 => {
+                let domain = u8_at(entry, 2)?
+                    | u8_at(entry, 9)? << 8
+                    | u8_at(entry, 10)? << 16
+                    | u8_at(entry, 11)? << 24;
+                srat.processors.push((u8_at(entry, 3)?, domain));
+            }
+            1 if u32_at(entry, 28)? & 1 != 0 => {
+                *srat.memory.entry(u32_at(entry, 2)?).or_default() += u64_at(entry, 16)?;
+            }
+            2 if u32_at(entry, 12)? & 1 != 0 => {
+                srat.processors.push((u32_at(entry, 8)?, u32_at(entry, 4)?));
+            }
+            _ => (),
+        }
+    }
+    Some(srat)
+}
+
+/// A cache of the PPTT, properties the firmware marked invalid are `None`
+pub struct Cache {
+    pub level: u32,
+    /// "Data", "Instruction" or "Unified" as in sysfs
+    pub kind: &'static str,
+    pub size: Option<u32>,
+    pub sets: Option<u32>,
+    pub ways: Option<u32>,
+    pub line_size: Option<u32>,
+}
+
+/// A processor, a leaf of the PPTT hierarchy
+pub struct PpttProcessor {
+    pub acpi_id: u32,
+    /// The ACPI id of the physical package containing the processor
+    pub package: Option<u32>,
+    pub caches: Vec<Cache>,
+}
+
+fn parse_cache(table: &[u8], offset: usize, level: u32) -> Option<(Cache, u32)> {
+    let entry = table.get(offset..)?;
+    if u8_at(entry, 0)? != 1 {
+        return None;
+    }
+    let flags = u32_at(entry, 4)?;
+    let valid = |bit: u32, value: u32| (flags & (1 << bit) != 0).then_some(value);
+    let kind = match (u8_at(entry, 21)? >> 2) & 3 {
+        0 => "Data",
+        1 => "Instruction",
+        _ => "Unified",
+    };
+    let cache = Cache {
+        level,
+        kind: if flags & (1 << 4) != 0 {
+            kind
+        } else {
+            "Unified"
+        },
+        size: valid(0, u32_at(entry, 12)?),
+        sets: valid(1, u32_at(entry, 16)?),
+        ways: valid(2, u8_at(entry, 20)?),
+        line_size: valid(6, u16_at(entry, 22)?),
+    };
+    Some((cache, u32_at(entry, 8)?))
+}
+
+fn parse_pptt(table: &[u8]) -> Option<Vec<PpttProcessor>> {
+    // Processor hierarchy nodes by offset, as flags, parent offset, ACPI id and private resources
+    let mut nodes: BTreeMap<usize, (u32, usize, u32, Vec<usize>)> = BTreeMap::new();
+    let mut offset = HEADER_LEN;
+    for (kind, entry) in entries(table, HEADER_LEN)? {
+        if kind == 0 {
+            let resources = (0..u32_at(entry, 16)? as usize)
+                .map(|index| u32_at(entry, 20 + 4 * index).map(|resource| resource as usize))
+                .collect::<Option<Vec<_>>>()?;
+            nodes.insert(
+                offset,
+                (
+                    u32_at(entry, 4)?,
+                    u32_at(entry, 8)? as usize,
+                    u32_at(entry, 12)?,
+                    resources,
+                ),
+            );
+        }
+        offset += entry.len();
+    }
+
+    let mut ret = Vec::new();
+    for (offset, (flags, _, acpi_id, _)) in &nodes {
+        // Leaf nodes with a valid ACPI id are the processors
+        if flags & 0b1010 != 0b1010 {
+            continue;
+        }
+        let mut processor = PpttProcessor {
+            acpi_id: *acpi_id,
+            package: None,
+            caches: Vec::new(),
+        };
+        let mut node = nodes.get(offset);
+        let mut visited = 0;
+        while let Some((flags, parent, id, resources)) = node {
+            // Each chain of caches counts levels from 1, the first cache found for a level and
+            // type wins as with the kernel's lookup
+            for resource in resources {
+                let (mut next, mut level) = (*resource, 1);
+                while let Some((cache, next_level)) = parse_cache(table, next, level) {
+                    if !processor
+                        .caches
+                        .iter()
+                        .any(|known| known.level == cache.level && known.kind == cache.kind)
+                    {
+                        processor.caches.push(cache);
+                    }
+                    if next_level == 0 || level > 8 {
+                        break;
+                    }
+                    next = next_level as usize;
+                    level += 1;
+                }
+            }
+            if flags & 1 != 0 && processor.package.is_none() {
+                processor.package = Some(*id);
+            }
+            visited += 1;
+            node = if *parent == 0 || visited > nodes.len() {
+                None
+            } else {
+                nodes.get(parent)
+            };
+        }
+        processor
+            .caches
+            .sort_by_key(|cache| (cache.level, cache.kind));
+        ret.push(processor);
+    }
+    Some(ret)
+}
+
+pub struct AcpiTables {
+    pub madt: Option<Vec<LocalApic>>,
+    pub srat: Option<Srat>,
+    pub pptt: Option<Vec<PpttProcessor>>,
+}
+
+impl AcpiTables {
+    pub fn read() -> crate::Result<Self> {
+        Self::from_dir("/sys/firmware/acpi/tables")
+    }
+
+    /// Read the tables from files named by signature, as in `/sys/firmware/acpi/tables`
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> crate::Result<Self> {
+        let dir = dir.as_ref();
+        let load = |signature: &str| match fs::read(dir.join(signature)) {
+            Ok(table) => Ok(Some(table)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let invalid = |signature: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("ACPI table {} is malformed", signature),
+            )
+        };
+        Ok(Self {
+            madt: load("APIC")?
+                .map(|table| parse_madt(&table).ok_or_else(|| invalid("APIC")))
+                .transpose()?,
+            srat: load("SRAT")?
+                .map(|table| parse_srat(&table).ok_or_else(|| invalid("SRAT")))
+                .transpose()?,
+            pptt: load("PPTT")?
+                .map(|table| parse_pptt(&table).ok_or_else(|| invalid("PPTT")))
+                .transpose()?,
+        })
+    }
+}
+
+impl facts::Facter<Fact> for AcpiTables {
+    fn collect_facts(&self) -> Vec<Fact> {
+        let mut ret = Vec::new();
+        let mut push = |name: String, value: FactValue| ret.push(Fact::new(name, value));
+
+        if let Some(madt) = &self.madt {
+            for apic in madt {
+                let path = format!(
+                    "MADT/{}/{}",
+                    if apic.x2apic {
+                        "Local x2APIC"
+                    } else {
+                        "Local APIC"
+                    },
+                    apic.apic_id
+                );
+                push(format!("{}/uid", path), apic.uid.into());
+                push(format!("{}/enabled", path), apic.enabled.into());
+                push(
+                    format!("{}/online_capable", path),
+                    apic.online_capable.into(),
+                );
+            }
+            let enabled = madt.iter().filter(|apic| apic.enabled).count() as u32;
+            push("MADT/enabled_processors".to_string(), enabled.into());
+            push(
+                "MADT/disabled_processors".to_string(),
+                (madt.len() as u32 - enabled).into(),
+            );
+        }
+
+        if let Some(srat) = &self.srat {
+            for (apic_id, domain) in &srat.processors {
+                push(
+                    format!("SRAT/processor/{}/proximity_domain", apic_id),
+                    (*domain).into(),
+                );
+            }
+            for (domain, bytes) in &srat.memory {
+                let mib = u32::try_from(bytes >> 20).unwrap_or(u32::MAX);
+                push(format!("SRAT/memory/{}/size_mb", domain), mib.into());
+            }
+        }
+
+        if let Some(pptt) = &self.pptt {
+            for processor in pptt {
+                if let Some(package) = processor.package {
+                    push(
+                        format!("PPTT/{}/package", processor.acpi_id),
+                        package.into(),
+                    );
+                }
+                for cache in &processor.caches {
+                    let path =
+                        format!("PPTT/{}/L{} {}", processor.acpi_id, cache.level, cache.kind);
+                    for (name, value) in [
+                        ("size", cache.size),
+                        ("sets", cache.sets),
+                        ("ways", cache.ways),
+                        ("line_size", cache.line_size),
+                    ] {
+                        if let Some(value) = value {
+                            push(format!("{}/{}", path, name), value.into());
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::Facter;
+
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut table = signature.to_vec();
+        table.extend(((HEADER_LEN + body.len()) as u32).to_le_bytes());
+        table.resize(HEADER_LEN, 0);
+        table.extend(body);
+        table
+    }
+
+    fn fact_names(tables: &AcpiTables) -> Vec<(String, FactValue)> {
+        let facts: Vec<Fact> = tables.collect_facts();
+        facts
+            .into_iter()
+            .map(|fact| (fact.get_name().to_string(), fact.value))
+            .collect()
+    }
+
+    #[test]
+    fn madt_and_srat() {
+        let mut madt = vec![0; 8];
+        madt.extend([0, 8, 0, 0, 1, 0, 0, 0]);
+        madt.extend([0, 8, 1, 2, 2, 0, 0, 0]);
+        let mut srat = vec![0; 12];
+        srat.extend([0, 16, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut memory = vec![1, 40, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        memory.extend((1u64 << 30).to_le_bytes());
+        memory.extend([0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        srat.extend(memory);
+
+        let tables = AcpiTables {
+            madt: parse_madt(&table(b"APIC", &madt)),
+            srat: parse_srat(&table(b"SRAT", &srat)),
+            pptt: None,
+        };
+        let facts = fact_names(&tables);
+        let get = |name: &str| {
+            facts
+                .iter()
+                .find(|(fact, _)| fact == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(get("MADT/Local APIC/0/enabled"), Some(true.into()));
+        assert_eq!(get("MADT/Local APIC/2/enabled"), Some(false.into()));
+        assert_eq!(get("MADT/Local APIC/2/online_capable"), Some(true.into()));
+        assert_eq!(get("MADT/disabled_processors"), Some(1u32.into()));
+        assert_eq!(get("SRAT/processor/0/proximity_domain"), Some(1u32.into()));
+        assert_eq!(get("SRAT/memory/1/size_mb"), Some(1024u32.into()));
+    }
+
+    #[test]
+    fn pptt_caches() {
+        let node = |flags: u32, parent: u32, id: u32, resources: &[u32]| {
+            let mut entry = vec![0, (20 + 4 * resources.len()) as u8, 0, 0];
+            for value in [flags, parent, id, resources.len() as u32] {
+                entry.extend(value.to_le_bytes());
+            }
+            for resource in resources {
+                entry.extend(resource.to_le_bytes());
+            }
+            entry
+        };
+        let cache = |next: u32, size: u32, attributes: u8| {
+            let mut entry = vec![1, 24, 0, 0];
+            // Size, associativity, type and line size are valid
+            entry.extend((0b1010101u32).to_le_bytes());
+            entry.extend(next.to_le_bytes());
+            entry.extend(size.to_le_bytes());
+            entry.extend(0u32.to_le_bytes());
+            entry.extend([8, attributes]);
+            entry.extend(64u16.to_le_bytes());
+            entry
+        };
+        // Offsets from the start of the table
+        let l2 = HEADER_LEN as u32;
+        let l1d = l2 + 24;
+        let package = l1d + 24;
+        let mut body = cache(0, 1 << 20, 2 << 2);
+        body.extend(cache(l2, 48 << 10, 0));
+        body.extend(node(1, 0, 7, &[]));
+        body.extend(node(0b1010, package, 3, &[l1d]));
+
+        let tables = AcpiTables {
+            madt: None,
+            srat: None,
+            pptt: parse_pptt(&table(b"PPTT", &body)),
+        };
+        let facts = fact_names(&tables);
+        let names: Vec<&str> = facts.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "PPTT/3/package",
+                "PPTT/3/L1 Data/size",
+                "PPTT/3/L1 Data/ways",
+                "PPTT/3/L1 Data/line_size",
+                "PPTT/3/L2 Unified/size",
+                "PPTT/3/L2 Unified/ways",
+                "PPTT/3/L2 Unified/line_size",
+            ]
+        );
+        assert_eq!(facts[0].1, FactValue::from(7u32));
+        assert_eq!(facts[4].1, FactValue::from(1u32 << 20));
+    }
+}
@@ -0,0 +1,249 @@
+//! Identification registers of aarch64 cpus
+//!
+//! Arm has no CPUID instruction. MIDR_EL1 identifies the implementer and part of a cpu, and the
+//! ID_AA64* registers enumerate its features in 4 bit fields. Linux publishes MIDR_EL1 and
+//! REVIDR_EL1 of each cpu in sysfs, and emulates reads of the ID_AA64* registers from user space,
+//! returning the features that are safe to use on every cpu of the system. KVM gives the values
+//! its guests see through `KVM_GET_ONE_REG` on a vcpu. The fields are described by the
+//! `id_registers` list of the config, with the same bit fields as MSRs.
+
+use super::bitfield::{self, Facter};
+use super::facts::{self, Fact};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::{fmt, fs, io};
+
+/// Wraps a general description of an ID register
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct IdRegisterDesc {
+    pub name: String,
+    pub fields: Vec<bitfield::Field>,
+}
+
+pub struct IdRegisterValue<'a> {
+    pub desc: &'a IdRegisterDesc,
+    pub value: u64,
+}
+
+impl<'a> IdRegisterValue<'a> {
+    /// The facts of every field, whether their conditions hold or not
+    fn decode(&self) -> Vec<Fact> {
+        self.desc
+            .fields
+            .iter()
+            .map(|field| {
+                let mut fact =
+                    bitfield::BoundField::from_register_and_field(self.value.into(), field)
+                        .collect_fact();
+                fact.add_path(&self.desc.name);
+                fact
+            })
+            .collect()
+    }
+}
+
+impl<'a> facts::Facter<Fact> for IdRegisterValue<'a> {
+    fn collect_facts(&self) -> Vec<Fact> {
+        let fields: Vec<&bitfield::Field> = self.desc.fields.iter().collect();
+        let mut facts = self.decode();
+        bitfield::retain_valid(&fields, &mut facts);
+        facts
+    }
+}
+
+impl<'a> fmt::Display for IdRegisterValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} = {:#018x}", self.desc.name, self.value)?;
+        let facts = self.decode();
+        for field in &self.desc.fields {
+            bitfield::write_field(self.value.into(), field, &facts, f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The ID register values of one cpu, by register name
+pub struct IdRegisters {
+    values: Vec<(String, u64)>,
+}
+
+impl IdRegisters {
+    /// Read the registers of the cpu numbered `cpu` by the kernel
+    ///
+    /// MIDR_EL1 and REVIDR_EL1 come from sysfs, the ID_AA64* registers are read on the current
+    /// cpu, where Linux reports the same sanitised values whichever cpu that is.
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    pub fn read(cpu: usize) -> crate::Result<Self> {
+        let mut registers = Self::from_sysfs_dir(format!(
+            "/sys/devices/system/cpu/cpu{}/regs/identification",
+            cpu
+        ))?;
+        registers.values.extend(
+            emulated::read()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value)),
+        );
+        Ok(registers)
+    }
+
+    /// ID registers only exist on aarch64
+    #[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+    pub fn read(_cpu: usize) -> crate::Result<Self> {
+        Err(crate::Error::Unsupported(
+            "ID registers can only be read on aarch64 Linux",
+        ))
+    }
+
+    /// Read the registers KVM presents to its guests, from a scratch vcpu of the preferred target
+    ///
+    /// Registers this KVM does not present are left out.
+    #[cfg(all(target_arch = "aarch64", target_os = "linux", feature = "kvm"))]
+    pub fn from_kvm(kvm: &kvm_ioctls::Kvm) -> crate::Result<Self> {
+        use kvm_bindings::{
+            kvm_vcpu_init, KVM_REG_ARM64, KVM_REG_ARM64_SYSREG, KVM_REG_ARM64_SYSREG_CRM_SHIFT,
+            KVM_REG_ARM64_SYSREG_CRN_SHIFT, KVM_REG_ARM64_SYSREG_OP0_SHIFT,
+            KVM_REG_ARM64_SYSREG_OP1_SHIFT, KVM_REG_ARM64_SYSREG_OP2_SHIFT, KVM_REG_SIZE_U64,
+        };
+        let os_error = |e: kvm_ioctls::Error| io::Error::from_raw_os_error(e.errno());
+
+        let vm = kvm.create_vm().map_err(os_error)?;
+        let vcpu = vm.create_vcpu(0).map_err(os_error)?;
+        let mut kvi = kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi).map_err(os_error)?;
+        vcpu.vcpu_init(&kvi).map_err(os_error)?;
+
+        let mut values = Vec::new();
+        for (name, [op0, op1, crn, crm, op2]) in SYSREGS {
+            let id = KVM_REG_ARM64
+                | KVM_REG_SIZE_U64
+                | u64::from(KVM_REG_ARM64_SYSREG)
+                | u64::from(op0) << KVM_REG_ARM64_SYSREG_OP0_SHIFT
+                | u64::from(op1) << KVM_REG_ARM64_SYSREG_OP1_SHIFT
+                | u64::from(crn) << KVM_REG_ARM64_SYSREG_CRN_SHIFT
+                | u64::from(crm) << KVM_REG_ARM64_SYSREG_CRM_SHIFT
+                | u64::from(op2) << KVM_REG_ARM64_SYSREG_OP2_SHIFT;
+            let mut data = [0u8; 8];
+            if vcpu.get_one_reg(id, &mut data).is_ok() {
+                values.push((name.to_string(), u64::from_ne_bytes(data)));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    /// Read the hex register files of a directory laid out like
+    /// `/sys/devices/system/cpu/cpu<N>/regs/identification`
+    pub fn from_sysfs_dir<P: AsRef<Path>>(dir: P) -> crate::Result<Self> {
+        let mut values = Vec::new();
+        for name in ["midr_el1", "revidr_el1"] {
+            let text = fs::read_to_string(dir.as_ref().join(name))?;
+            let text = text.trim();
+            let value = u64::from_str_radix(text.trim_start_matches("0x"), 16).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unable to parse {} value '{}'", name, text),
+                )
+            })?;
+            values.push((name.to_uppercase(), value));
+        }
+        Ok(Self { values })
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.values
+            .iter()
+            .find(|(register, _)| register == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// The values of the described registers that were read
+    pub fn bind<'a>(&self, descs: &'a [IdRegisterDesc]) -> Vec<IdRegisterValue<'a>> {
+        descs
+            .iter()
+            .filter_map(|desc| {
+                Some(IdRegisterValue {
+                    desc,
+                    value: self.get(&desc.name)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The `op0, op1, CRn, CRm, op2` encoding of each register KVM is asked for
+#[cfg(all(target_arch = "aarch64", target_os = "linux", feature = "kvm"))]
+const SYSREGS: [(&str, [u8; 5]); 12] = [
+    ("MIDR_EL1", [3, 0, 0, 0, 0]),
+    ("REVIDR_EL1", [3, 0, 0, 0, 6]),
+    ("ID_AA64PFR0_EL1", [3, 0, 0, 4, 0]),
+    ("ID_AA64PFR1_EL1", [3, 0, 0, 4, 1]),
+    ("ID_AA64ZFR0_EL1", [3, 0, 0, 4, 4]),
+    ("ID_AA64DFR0_EL1", [3, 0, 0, 5, 0]),
+    ("ID_AA64ISAR0_EL1", [3, 0, 0, 6, 0]),
+    ("ID_AA64ISAR1_EL1", [3, 0, 0, 6, 1]),
+    ("ID_AA64ISAR2_EL1", [3, 0, 0, 6, 2]),
+    ("ID_AA64MMFR0_EL1", [3, 0, 0, 7, 0]),
+    ("ID_AA64MMFR1_EL1", [3, 0, 0, 7, 1]),
+    ("ID_AA64MMFR2_EL1", [3, 0, 0, 7, 2]),
+];
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+mod emulated {
+    /// Read a register by its generic `S<op0>_<op1>_C<n>_C<m>_<op2>` name, which assemblers
+    /// accept even for registers newer than they know
+    macro_rules! read_sysreg {
+        ($encoding:literal) => {{
+            let value: u64;
+            // SAFETY: Linux traps and emulates reads of the ID registers from EL0
+            unsafe { core::arch::asm!(concat!("mrs {}, ", $encoding), out(reg) value) };
+            value
+        }};
+    }
+
+    pub fn read() -> Vec<(&'static str, u64)> {
+        vec![
+            ("ID_AA64PFR0_EL1", read_sysreg!("S3_0_C0_C4_0")),
+            ("ID_AA64PFR1_EL1", read_sysreg!("S3_0_C0_C4_1")),
+            ("ID_AA64ZFR0_EL1", read_sysreg!("S3_0_C0_C4_4")),
+            ("ID_AA64DFR0_EL1", read_sysreg!("S3_0_C0_C5_0")),
+            ("ID_AA64ISAR0_EL1", read_sysreg!("S3_0_C0_C6_0")),
+            ("ID_AA64ISAR1_EL1", read_sysreg!("S3_0_C0_C6_1")),
+            ("ID_AA64ISAR2_EL1", read_sysreg!("S3_0_C0_C6_2")),
+            ("ID_AA64MMFR0_EL1", read_sysreg!("S3_0_C0_C7_0")),
+            ("ID_AA64MMFR1_EL1", read_sysreg!("S3_0_C0_C7_1")),
+            ("ID_AA64MMFR2_EL1", read_sysreg!("S3_0_C0_C7_2")),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::{FactValue, Facter as _};
+
+    #[test]
+    fn midr_facts() {
+        let dir = std::env::temp_dir().join(format!("cpuinfo-idregs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // A Neoverse N1 r3p1
+        fs::write(dir.join("midr_el1"), "0x00000000413fd0c1\n").unwrap();
+        fs::write(dir.join("revidr_el1"), "0x0000000000000000\n").unwrap();
+        let registers = IdRegisters::from_sysfs_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let registers = registers.unwrap();
+
+        let descs: Vec<IdRegisterDesc> = serde_yaml::from_str(
+            "- name: MIDR_EL1\n  fields:\n  \
+             - {type: Int, name: Implementer, bounds: {start: 24, end: 32}}\n  \
+             - {type: Int, name: PartNum, bounds: {start: 4, end: 16}}\n\
+             - name: ID_AA64ISAR0_EL1\n  fields: []\n",
+        )
+        .unwrap();
+        let values = registers.bind(&descs);
+        assert_eq!(values.len(), 1);
+        let facts: Vec<Fact> = values[0].collect_facts();
+        assert_eq!(facts[0].get_name(), "MIDR_EL1/Implementer");
+        assert_eq!(facts[0].value, FactValue::from(0x41u32));
+        assert_eq!(facts[1].value, FactValue::from(0xd0cu32));
+    }
+}
@@ -1,12 +1,17 @@
 //! Provide a means to specify a bit field when working with CPU ID and feature registers
 //!
 
-use super::facts::GenericFact;
+use super::facts::{Fact, FactValue};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+use core::ops;
 use enum_dispatch::enum_dispatch;
 use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
-use std::fmt;
-use std::ops;
 
 pub type Register = u128;
 
@@ -20,16 +25,176 @@ pub trait Bindable {
     fn name(&self) -> &String;
 }
 
+/// When a field holds a meaningful value, judged by another field of the same leaf or register
+/// or by a fact collected alongside it
+///
+/// ```yaml
+/// - {type: Flag, name: AVX512VL, bit: 31, valid_if: AVX512F}
+/// - {type: Flag, name: AVX512F, bit: 16, valid_if: {fact: cpuid/Model/ecx/OSXSAVE}}
+/// - {type: Int, name: "Cache Type", bounds: {start: 0, end: 5}, valid_if: {field: "Cache Type", values: [1, 2, 3]}}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum Condition {
+    /// The flag of that name is set, or the field is not zero. The name can be given along with
+    /// its register, e.g. `ebx/AVX512F`
+    Field(String),
+    /// The field holds one of `values`, naming the field itself leaves out its reserved encodings
+    Values { field: String, values: Vec<u64> },
+    /// The fact of that full name is set or not zero, e.g. `cpuid/Model/ecx/OSXSAVE`
+    Fact { fact: String },
+}
+
+impl Condition {
+    fn holds(value: &FactValue, values: Option<&[u64]>) -> bool {
+        match (values, value) {
+            (Some(values), value) => value.as_u64().is_some_and(|value| values.contains(&value)),
+            (None, FactValue::Bool(set)) => *set,
+            (None, FactValue::U64(value)) => *value != 0,
+            (None, FactValue::String(text)) => !text.is_empty(),
+        }
+    }
+
+    /// Whether the condition holds for the fields decoded as `facts`, a field that is missing
+    /// does not. `None` for a condition on a fact, which is decided once every fact is collected
+    pub fn check_fields(&self, facts: &[Fact]) -> Option<bool> {
+        let (field, values) = match self {
+            Self::Field(field) => (field, None),
+            Self::Values { field, values } => (field, Some(values.as_slice())),
+            Self::Fact { .. } => return None,
+        };
+        Some(find_field(facts, field).is_some_and(|fact| Self::holds(&fact.value, values)))
+    }
+
+    /// Whether the condition holds for the collected `facts`, a fact that is missing does not.
+    /// `None` for a condition on a field, which is decided as the leaf is decoded
+    pub fn check_fact(&self, facts: &[Fact]) -> Option<bool> {
+        match self {
+            Self::Fact { fact: name } => Some(
+                facts
+                    .iter()
+                    .find(|fact| fact.name == *name)
+                    .is_some_and(|fact| Self::holds(&fact.value, None)),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// The fact of the field `name` among the facts of one leaf or register, the name can be given
+/// along with its register, e.g. `ebx/AVX512F`
+pub fn find_field<'a>(facts: &'a [Fact], name: &str) -> Option<&'a Fact> {
+    let suffix = format!("/{}", name);
+    facts
+        .iter()
+        .find(|fact| fact.name == name || fact.name.ends_with(&suffix))
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Field(field) => write!(f, "{} is set", field),
+            Self::Values { field, values } => write!(f, "{} is one of {:?}", field, values),
+            Self::Fact { fact } => write!(f, "fact {} is set", fact),
+        }
+    }
+}
+
+/// Leave out the facts of `fields` whose condition does not hold among the other fields, and of
+/// reserved ranges that are clear. `facts` holds the fact of each field in the same order
+pub fn retain_valid(fields: &[&Field], facts: &mut Vec<Fact>) {
+    let valid: Vec<bool> = fields
+        .iter()
+        .zip(facts.iter())
+        .map(|(field, fact)| match field {
+            Field::Reserved(_) => fact.value != FactValue::U64(0),
+            field => is_valid(field, facts),
+        })
+        .collect();
+    let mut valid = valid.into_iter();
+    facts.retain(|_| valid.next().unwrap_or(true));
+}
+
+/// Whether the condition of `field`, if any, holds among the fields decoded as `facts`
+pub fn is_valid(field: &Field, facts: &[Fact]) -> bool {
+    field
+        .valid_if()
+        .and_then(|condition| condition.check_fields(facts))
+        .unwrap_or(true)
+}
+
+/// What a field or register means and where it is specified, so the answer to "what is this
+/// bit?" travels with the output
+///
+/// ```yaml
+/// - {type: Flag, name: AVX, bit: 28, description: "AVX instructions", reference: "SDM Vol.2A Table 3-10"}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Docs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The document and section, e.g. `SDM Vol.2A Table 3-8`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+impl Docs {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_none() && self.reference.is_none()
+    }
+
+    /// Write the description and reference as lines indented by `indent`
+    pub fn write_lines(&self, indent: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(description) = &self.description {
+            writeln!(f, "{}{}", indent, description)?;
+        }
+        if let Some(reference) = &self.reference {
+            writeln!(f, "{}see {}", indent, reference)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the line of `field` as read from `reg_val`, marked when its condition does not hold
+/// among the fields decoded as `facts`. The alternate form (`{:#}`) adds its description below
+pub fn write_field(
+    reg_val: Register,
+    field: &Field,
+    facts: &[Fact],
+    f: &mut fmt::Formatter<'_>,
+) -> fmt::Result {
+    write!(
+        f,
+        "  {}",
+        BoundField::from_register_and_field(reg_val, field)
+    )?;
+    if !is_valid(field, facts) {
+        write!(f, " (not valid)")?;
+    }
+    writeln!(f)?;
+    if f.alternate() {
+        field.docs().write_lines("      ", f)?;
+    }
+    Ok(())
+}
+
 #[enum_dispatch()]
-pub trait Facter<T: From<u32> + From<bool>> {
-    fn collect_fact(&self) -> GenericFact<T>;
+pub trait Facter {
+    fn collect_fact(&self) -> Fact;
 }
 
 ///Wraps a bit flag, usually representing if a feature is present or not
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Flag {
     pub name: String,
     pub bit: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_if: Option<Condition>,
+    #[serde(flatten)]
+    pub docs: Docs,
 }
 
 impl Bindable for Flag {
@@ -43,24 +208,212 @@ impl Bindable for Flag {
     }
 }
 
-///Wraps an integer value from a bit field
+///Wraps an integer value from a bit field of up to 64 bits, such as a physical address in an MSR
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Int {
     pub name: String,
     pub bounds: ops::Range<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_if: Option<Condition>,
+    #[serde(flatten)]
+    pub docs: Docs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<NumberFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Radix {
+    Hex,
+    Dec,
+    Bin,
+}
+
+/// How a number field is shown, given as just the radix (`format: hex`) or along with the digits
+/// to pad to (`format: {radix: bin, width: 4}`)
+///
+/// Hex and binary numbers are shown with their `0x` and `0b` prefix. Facts keep the number unless
+/// `text` is set, which collects the shown string instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum NumberFormat {
+    Radix(Radix),
+    Padded {
+        radix: Radix,
+        #[serde(default)]
+        width: usize,
+        #[serde(default)]
+        text: bool,
+    },
+}
+
+impl NumberFormat {
+    pub fn render(&self, value: u64) -> String {
+        let (radix, width) = match *self {
+            Self::Radix(radix) => (radix, 0),
+            Self::Padded { radix, width, .. } => (radix, width),
+        };
+        match radix {
+            Radix::Hex => format!("0x{:0width$x}", value, width = width),
+            Radix::Dec => format!("{:0width$}", value, width = width),
+            Radix::Bin => format!("0b{:0width$b}", value, width = width),
+        }
+    }
+
+    /// Whether facts hold the rendered string rather than the number
+    pub fn text(&self) -> bool {
+        matches!(self, Self::Padded { text: true, .. })
+    }
+}
+
+/// The bits of `reg_val` within `bounds`, shifted down
+fn bits_value(reg_val: Register, bounds: &ops::Range<u8>) -> Register {
+    let shift = bounds.start;
+    let mut mask = 0u128;
+
+    for _bit in bounds.clone() {
+        mask <<= 1;
+        mask |= 1;
+    }
+    (reg_val >> shift) & mask
 }
 
 impl Bindable for Int {
-    type Rep = u32;
+    type Rep = u64;
     fn value(&self, reg_val: Register) -> Option<Self::Rep> {
-        let shift = self.bounds.start;
-        let mut mask = 0u128;
+        bits_value(reg_val, &self.bounds).try_into().ok()
+    }
+    fn name(&self) -> &String {
+        &self.name
+    }
+}
 
-        for _bit in self.bounds.clone() {
-            mask <<= 1;
-            mask |= 1;
+///Wraps a range of bits documented as reserved, which are expected to read as zero
+///
+///Any bit found set is reported as the fact `<name>/reserved_violation` holding the set bits in
+///place, the fact is left out while the range is clear.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Reserved {
+    pub name: String,
+    pub bounds: ops::Range<u8>,
+    #[serde(flatten)]
+    pub docs: Docs,
+}
+
+impl Bindable for Reserved {
+    type Rep = u64;
+    fn value(&self, reg_val: Register) -> Option<Self::Rep> {
+        (bits_value(reg_val, &self.bounds) << self.bounds.start)
+            .try_into()
+            .ok()
+    }
+    fn name(&self) -> &String {
+        &self.name
+    }
+}
+
+/// Merge `from` into `into` by name, an entry of `from` replaces the one of the same name where it
+/// is and the others are added after them
+pub fn merge_by_name<T>(into: &mut Vec<T>, from: Vec<T>, name: impl Fn(&T) -> &str) {
+    for entry in from {
+        match into
+            .iter_mut()
+            .find(|existing| name(existing) == name(&entry))
+        {
+            Some(existing) => *existing = entry,
+            None => into.push(entry),
         }
-        ((reg_val >> shift) & mask).try_into().ok()
+    }
+}
+
+/// A map key given as a number, or as a string holding one in decimal or in hex with a leading
+/// `0x`, as TOML and JSON only have string keys
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum NumberKey {
+    Number(u64),
+    Text(String),
+}
+
+impl NumberKey {
+    pub(crate) fn parse<K: TryFrom<u64>>(self) -> Result<K, String> {
+        let number = match self {
+            Self::Number(number) => number,
+            Self::Text(text) => {
+                let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => text.parse(),
+                };
+                parsed.map_err(|_| format!("invalid number key {:?}", text))?
+            }
+        };
+        K::try_from(number).map_err(|_| format!("number key {:#x} out of range", number))
+    }
+}
+
+/// Deserialize a map keyed by numbers such as leaves or enum values, taking the keys as numbers
+/// or as strings holding them, e.g. `0x80000008` in YAML or `"0x80000008"` in TOML and JSON
+pub fn deserialize_number_keys<'de, D, K, V>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    K: TryFrom<u64> + Ord,
+    V: Deserialize<'de>,
+{
+    struct NumberKeyed<K, V>(core::marker::PhantomData<(K, V)>);
+
+    impl<'de, K, V> serde::de::Visitor<'de> for NumberKeyed<K, V>
+    where
+        K: TryFrom<u64> + Ord,
+        V: Deserialize<'de>,
+    {
+        type Value = BTreeMap<K, V>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map keyed by numbers")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut ret = BTreeMap::new();
+            while let Some((key, value)) = map.next_entry::<NumberKey, V>()? {
+                ret.insert(key.parse().map_err(serde::de::Error::custom)?, value);
+            }
+            Ok(ret)
+        }
+    }
+
+    deserializer.deserialize_map(NumberKeyed(core::marker::PhantomData))
+}
+
+///Wraps a multi-bit field whose values stand for the names in `variants`, such as a cache or
+///core type. Values without a name read as `Unknown(<value>)`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Enum {
+    pub name: String,
+    pub bounds: ops::Range<u8>,
+    #[serde(deserialize_with = "deserialize_number_keys")]
+    pub variants: BTreeMap<u64, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_if: Option<Condition>,
+    #[serde(flatten)]
+    pub docs: Docs,
+}
+
+impl Bindable for Enum {
+    type Rep = String;
+    fn value(&self, reg_val: Register) -> Option<Self::Rep> {
+        let raw: u64 = bits_value(reg_val, &self.bounds).try_into().ok()?;
+        Some(match self.variants.get(&raw) {
+            Some(label) => label.clone(),
+            None => format!("Unknown({})", raw),
+        })
     }
     fn name(&self) -> &String {
         &self.name
@@ -70,8 +423,15 @@ impl Bindable for Int {
 /// Wraps an X86Model representation
 /// These can have a number of weird conditions and are always going to be a part of a bit field
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct X86Model {
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_if: Option<Condition>,
+    #[serde(flatten)]
+    pub docs: Docs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<NumberFormat>,
 }
 
 const MODEL_START_BIT: u8 = 4;
@@ -99,8 +459,15 @@ impl Bindable for X86Model {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct X86Family {
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_if: Option<Condition>,
+    #[serde(flatten)]
+    pub docs: Docs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<NumberFormat>,
 }
 
 const EXTENDED_FAMILY_START_BIT: u8 = 20;
@@ -138,7 +505,7 @@ impl<'a, T: Bindable> Bound<'a, T> {
 }
 
 impl<'a> fmt::Display for Bound<'a, Flag> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
             f,
             "{} = {:>10}",
@@ -152,64 +519,192 @@ impl<'a> fmt::Display for Bound<'a, Flag> {
     }
 }
 
-impl<'a> fmt::Display for Bound<'a, Int> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+impl<'a> fmt::Display for Bound<'a, Enum> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
             f,
-            "{} = {:>10x}",
+            "{} = {:>10}",
             self.bits.name,
-            self.bits.value(self.reg_val).unwrap_or(0)
+            self.bits.value(self.reg_val).unwrap_or_default()
         )
     }
 }
 
-impl<'a, B, R, T: From<u32> + From<bool>> Facter<T> for Bound<'a, B>
+impl<'a> fmt::Display for Bound<'a, Reserved> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let value = self.bits.value(self.reg_val).unwrap_or(0);
+        write!(f, "{} = {:>10x}", self.bits.name, value)?;
+        if value != 0 {
+            write!(f, " <- reserved bits set")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Bound<'a, Int> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let value = self.bits.value(self.reg_val).unwrap_or(0);
+        match &self.bits.format {
+            Some(format) => write!(f, "{} = {:>10}", self.bits.name, format.render(value)),
+            None => write!(f, "{} = {:>10x}", self.bits.name, value),
+        }
+    }
+}
+
+impl<'a, B, R> Facter for Bound<'a, B>
 where
-    R: Default + Into<T>,
+    R: Default + Into<FactValue>,
     B: Bindable<Rep = R>,
 {
-    fn collect_fact(&self) -> GenericFact<T> {
-        GenericFact::new(
+    fn collect_fact(&self) -> Fact {
+        Fact::new(
             self.bits.name().clone(),
             self.bits.value(self.reg_val).unwrap_or_default().into(),
         )
     }
 }
 
+impl<'a, B, R> Bound<'a, B>
+where
+    R: Default + Into<u64> + Into<FactValue>,
+    B: Bindable<Rep = R>,
+{
+    /// The fact of a number field, holding the string shown if its `format` says so
+    fn collect_formatted(&self, format: Option<&NumberFormat>) -> Fact {
+        match format {
+            Some(format) if format.text() => Fact::new(
+                self.bits.name().clone(),
+                format
+                    .render(self.bits.value(self.reg_val).unwrap_or_default().into())
+                    .into(),
+            ),
+            _ => self.collect_fact(),
+        }
+    }
+}
+
 impl<'a> fmt::Display for Bound<'a, X86Model> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(
-            f,
-            "{} = {:>10}",
-            self.bits.name,
-            self.bits.value(self.reg_val).unwrap_or(0)
-        )
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let value = self.bits.value(self.reg_val).unwrap_or(0);
+        match &self.bits.format {
+            Some(format) => write!(
+                f,
+                "{} = {:>10}",
+                self.bits.name,
+                format.render(value.into())
+            ),
+            None => write!(f, "{} = {:>10}", self.bits.name, value),
+        }
     }
 }
 
 impl<'a> fmt::Display for Bound<'a, X86Family> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(
-            f,
-            "{} = {:>10}",
-            self.bits.name,
-            self.bits.value(self.reg_val).unwrap_or(0)
-        )
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let value = self.bits.value(self.reg_val).unwrap_or(0);
+        match &self.bits.format {
+            Some(format) => write!(
+                f,
+                "{} = {:>10}",
+                self.bits.name,
+                format.render(value.into())
+            ),
+            None => write!(f, "{} = {:>10}", self.bits.name, value),
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type")]
 pub enum Field {
     Int(Int),
     Flag(Flag),
+    Enum(Enum),
+    Reserved(Reserved),
     X86Model(X86Model),
     X86Family(X86Family),
 }
 
+impl Field {
+    pub fn name(&self) -> &String {
+        match self {
+            Self::Int(bits) => bits.name(),
+            Self::Flag(bits) => bits.name(),
+            Self::Enum(bits) => bits.name(),
+            Self::Reserved(bits) => bits.name(),
+            Self::X86Model(bits) => bits.name(),
+            Self::X86Family(bits) => bits.name(),
+        }
+    }
+
+    /// The name of the fact the field is collected as
+    pub fn fact_name(&self) -> String {
+        match self {
+            Self::Reserved(bits) => format!("{}/reserved_violation", bits.name),
+            field => field.name().clone(),
+        }
+    }
+
+    /// When the field holds a meaningful value, always when `None`
+    pub fn valid_if(&self) -> Option<&Condition> {
+        match self {
+            Self::Int(bits) => bits.valid_if.as_ref(),
+            Self::Flag(bits) => bits.valid_if.as_ref(),
+            Self::Enum(bits) => bits.valid_if.as_ref(),
+            Self::Reserved(_) => None,
+            Self::X86Model(bits) => bits.valid_if.as_ref(),
+            Self::X86Family(bits) => bits.valid_if.as_ref(),
+        }
+    }
+
+    pub fn docs(&self) -> &Docs {
+        match self {
+            Self::Int(bits) => &bits.docs,
+            Self::Flag(bits) => &bits.docs,
+            Self::Enum(bits) => &bits.docs,
+            Self::Reserved(bits) => &bits.docs,
+            Self::X86Model(bits) => &bits.docs,
+            Self::X86Family(bits) => &bits.docs,
+        }
+    }
+
+    /// The name of the field type as used in the config
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "Int",
+            Self::Flag(_) => "Flag",
+            Self::Enum(_) => "Enum",
+            Self::Reserved(_) => "Reserved",
+            Self::X86Model(_) => "X86Model",
+            Self::X86Family(_) => "X86Family",
+        }
+    }
+
+    /// The bit ranges of the register the field is read from
+    #[allow(clippy::single_range_in_vec_init)]
+    pub fn bits(&self) -> Vec<ops::Range<u8>> {
+        match self {
+            Self::Int(bits) => vec![bits.bounds.clone()],
+            Self::Flag(bits) => vec![bits.bit..bits.bit + 1],
+            Self::Enum(bits) => vec![bits.bounds.clone()],
+            Self::Reserved(bits) => vec![bits.bounds.clone()],
+            Self::X86Model(_) => vec![
+                MODEL_START_BIT..MODEL_START_BIT + 4,
+                EXTENDED_MODEL_START_BIT..EXTENDED_MODEL_START_BIT + 4,
+            ],
+            Self::X86Family(_) => vec![
+                FAMILY_START_BIT..FAMILY_START_BIT + 4,
+                EXTENDED_FAMILY_START_BIT..EXTENDED_FAMILY_START_BIT + 8,
+            ],
+        }
+    }
+}
+
 pub enum BoundField<'a> {
     Int(Bound<'a, Int>),
     Flag(Bound<'a, Flag>),
+    Enum(Bound<'a, Enum>),
+    Reserved(Bound<'a, Reserved>),
     X86Model(Bound<'a, X86Model>),
     X86Family(Bound<'a, X86Family>),
 }
@@ -219,6 +714,8 @@ impl<'a> BoundField<'a> {
         match field {
             Field::Int(bits) => Self::Int(Bound { reg_val, bits }),
             Field::Flag(bits) => Self::Flag(Bound { reg_val, bits }),
+            Field::Enum(bits) => Self::Enum(Bound { reg_val, bits }),
+            Field::Reserved(bits) => Self::Reserved(Bound { reg_val, bits }),
             Field::X86Model(bits) => Self::X86Model(Bound { reg_val, bits }),
             Field::X86Family(bits) => Self::X86Family(Bound { reg_val, bits }),
         }
@@ -226,23 +723,31 @@ impl<'a> BoundField<'a> {
 }
 
 impl<'a> fmt::Display for BoundField<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
             Self::Int(bound) => bound.fmt(f),
             Self::Flag(bound) => bound.fmt(f),
+            Self::Enum(bound) => bound.fmt(f),
+            Self::Reserved(bound) => bound.fmt(f),
             Self::X86Model(bound) => bound.fmt(f),
             Self::X86Family(bound) => bound.fmt(f),
         }
     }
 }
 
-impl<'a, T: From<bool> + From<u32>> Facter<T> for BoundField<'a> {
-    fn collect_fact(&self) -> GenericFact<T> {
+impl<'a> Facter for BoundField<'a> {
+    fn collect_fact(&self) -> Fact {
         match self {
-            Self::Int(bound) => bound.collect_fact(),
+            Self::Int(bound) => bound.collect_formatted(bound.bits.format.as_ref()),
             Self::Flag(bound) => bound.collect_fact(),
-            Self::X86Model(bound) => bound.collect_fact(),
-            Self::X86Family(bound) => bound.collect_fact(),
+            Self::Enum(bound) => bound.collect_fact(),
+            Self::Reserved(bound) => {
+                let mut fact = bound.collect_fact();
+                fact.name.push_str("/reserved_violation");
+                fact
+            }
+            Self::X86Model(bound) => bound.collect_formatted(bound.bits.format.as_ref()),
+            Self::X86Family(bound) => bound.collect_formatted(bound.bits.format.as_ref()),
         }
     }
 }
@@ -255,6 +760,9 @@ mod test {
     fn x86_model_test() {
         let field_definition = super::X86Model {
             name: "model".to_string(),
+            valid_if: None,
+            docs: Default::default(),
+            format: None,
         };
         let regular_model: super::Register = 0x0AF50341;
         assert_eq!(field_definition.value(regular_model).unwrap(), 0x4);
@@ -267,6 +775,9 @@ mod test {
     fn x86_family_test() {
         let field_definition = super::X86Family {
             name: "model".to_string(),
+            valid_if: None,
+            docs: Default::default(),
+            format: None,
         };
         let regular_model: super::Register = 0x0AE50341;
         assert_eq!(field_definition.value(regular_model).unwrap(), 0x3);
@@ -276,4 +787,178 @@ mod test {
             0xAE + 0xF
         );
     }
+    #[test]
+    fn conditions() {
+        use super::Condition;
+        use crate::facts::Fact;
+        let facts = [
+            Fact::new("ebx/AVX512F".to_string(), false.into()),
+            Fact::new("eax/Cache Type".to_string(), 4u32.into()),
+            Fact::new("cpuid/Model/ecx/OSXSAVE".to_string(), true.into()),
+        ];
+        let field = Condition::Field("AVX512F".to_string());
+        assert_eq!(field.check_fields(&facts), Some(false));
+        assert_eq!(field.check_fact(&facts), None);
+        let values = Condition::Values {
+            field: "eax/Cache Type".to_string(),
+            values: vec![1, 2, 3],
+        };
+        assert_eq!(values.check_fields(&facts), Some(false));
+        assert_eq!(
+            Condition::Field("Missing".to_string()).check_fields(&facts),
+            Some(false)
+        );
+        let fact = Condition::Fact {
+            fact: "cpuid/Model/ecx/OSXSAVE".to_string(),
+        };
+        assert_eq!(fact.check_fields(&facts), None);
+        assert_eq!(fact.check_fact(&facts), Some(true));
+    }
+    #[test]
+    fn enum_labels() {
+        use super::Facter;
+        use alloc::collections::BTreeMap;
+        let field = super::Field::Enum(super::Enum {
+            name: "Core Type".to_string(),
+            bounds: 24..32,
+            variants: BTreeMap::from([(0x20, "Atom".to_string()), (0x40, "Core".to_string())]),
+            valid_if: None,
+            docs: Default::default(),
+        });
+        let bound = |reg: super::Register| super::BoundField::from_register_and_field(reg, &field);
+        assert_eq!(
+            bound(0x40000001).collect_fact().value,
+            crate::facts::FactValue::from("Core")
+        );
+        assert_eq!(format!("{}", bound(0x20000000)), "Core Type =       Atom");
+        assert_eq!(
+            bound(0x10000000).collect_fact().value,
+            crate::facts::FactValue::from("Unknown(16)")
+        );
+        assert_eq!(field.bits().first(), Some(&(24..32)));
+    }
+    #[test]
+    fn reserved_violations() {
+        use super::{retain_valid, BoundField, Facter, Field, Flag, Reserved};
+        let fields = [
+            Field::Flag(Flag {
+                name: "fpu".to_string(),
+                bit: 0,
+                valid_if: None,
+                docs: Default::default(),
+            }),
+            Field::Reserved(Reserved {
+                name: "reserved".to_string(),
+                bounds: 28..32,
+                docs: Default::default(),
+            }),
+        ];
+        let fields: Vec<&Field> = fields.iter().collect();
+        let facts = |reg: super::Register| {
+            let mut facts = fields
+                .iter()
+                .map(|field| BoundField::from_register_and_field(reg, field).collect_fact())
+                .collect();
+            retain_valid(&fields, &mut facts);
+            facts
+        };
+        assert_eq!(facts(0x0fff_ffff).len(), 1);
+        let violation = facts(0x3000_0001);
+        assert_eq!(violation[1].name, "reserved/reserved_violation");
+        assert_eq!(
+            violation[1].value,
+            crate::facts::FactValue::from(0x3000_0000u32)
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                BoundField::from_register_and_field(0x1000_0000, fields[1])
+            ),
+            "reserved =   10000000 <- reserved bits set"
+        );
+    }
+    #[test]
+    fn number_formats() {
+        use super::{BoundField, Facter, Field, Int, NumberFormat, Radix};
+        let int = |format| {
+            Field::Int(Int {
+                name: "size".to_string(),
+                bounds: 0..8,
+                valid_if: None,
+                docs: Default::default(),
+                format,
+            })
+        };
+        let show = |field: &Field| format!("{}", BoundField::from_register_and_field(0x1a, field));
+        assert_eq!(show(&int(None)), "size =         1a");
+        assert_eq!(
+            show(&int(Some(NumberFormat::Radix(Radix::Dec)))),
+            "size =         26"
+        );
+        let padded = int(Some(NumberFormat::Padded {
+            radix: Radix::Bin,
+            width: 8,
+            text: true,
+        }));
+        assert_eq!(show(&padded), "size = 0b00011010");
+        assert_eq!(
+            BoundField::from_register_and_field(0x1a, &padded)
+                .collect_fact()
+                .value,
+            crate::facts::FactValue::from("0b00011010")
+        );
+        assert_eq!(
+            NumberFormat::Padded {
+                radix: Radix::Hex,
+                width: 4,
+                text: false
+            }
+            .render(0x1a),
+            "0x001a"
+        );
+    }
+    #[test]
+    fn wide_ints() {
+        use super::{BoundField, Facter, Field, Int};
+        let field = Field::Int(Int {
+            name: "PhysBase".to_string(),
+            bounds: 12..52,
+            valid_if: None,
+            docs: Default::default(),
+            format: None,
+        });
+        let bound = BoundField::from_register_and_field(0x8001_2345_6789_a006, &field);
+        assert_eq!(
+            bound.collect_fact().value,
+            crate::facts::FactValue::from(0x12_3456_789au64)
+        );
+        assert_eq!(format!("{}", bound), "PhysBase = 123456789a");
+    }
+    #[test]
+    fn field_docs() {
+        use super::{write_field, Docs, Field, Flag};
+        use core::fmt;
+        let field = Field::Flag(Flag {
+            name: "AVX".to_string(),
+            bit: 28,
+            valid_if: None,
+            docs: Docs {
+                description: Some("Advanced Vector Extensions".to_string()),
+                reference: Some("SDM Vol.2A Table 3-10".to_string()),
+            },
+        });
+        struct Line<'a>(&'a Field);
+        impl fmt::Display for Line<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write_field(1 << 28, self.0, &[], f)
+            }
+        }
+        assert_eq!(format!("{}", Line(&field)), "  AVX =       true\n");
+        assert_eq!(
+            format!("{:#}", Line(&field)),
+            "  AVX =       true\n      Advanced Vector Extensions\n      see SDM Vol.2A Table 3-10\n"
+        );
+        assert!(!field.docs().is_empty());
+        assert!(Docs::default().is_empty());
+    }
 }
@@ -0,0 +1,141 @@
+//! Facts kept in a file along with the state of the machine they were collected in
+//!
+//! Collecting every cpu and reading MSRs takes too long to do for each caller, and the facts
+//! only change when the cpu does: after a microcode update, which may be loaded late, or after a
+//! reboot. A [`CachedFacts`] file records the microcode revision and the kernel's boot id next to
+//! the facts, so a reader can tell whether they still hold by comparing them with
+//! [`MachineState::current`].
+
+use super::facts::{Fact, FactsFile};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+/// What has to stay the same for collected facts to still be current
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MachineState {
+    /// The microcode revision of the first cpu, as the kernel reports it
+    #[serde(default)]
+    pub microcode: Option<String>,
+    /// The random id the kernel picks on each boot
+    #[serde(default)]
+    pub boot_id: Option<String>,
+    /// A hash of the options and config the facts were collected with, see `with_options`
+    #[serde(default)]
+    pub options: Option<String>,
+}
+
+impl MachineState {
+    /// The state of the running machine, parts the platform does not report are left out
+    #[cfg(target_os = "linux")]
+    pub fn current() -> Self {
+        let read = |path: &str| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|text| text.trim().to_string())
+        };
+        // sysfs only has the revision where the microcode loader is built in, VMs usually lack it
+        let microcode = read("/sys/devices/system/cpu/cpu0/microcode/version").or_else(|| {
+            let cpuinfo = super::proc_cpuinfo::ProcCpuinfo::read().ok()?;
+            cpuinfo.processor(0)?.get("microcode").cloned()
+        });
+        Self {
+            microcode,
+            boot_id: read("/proc/sys/kernel/random/boot_id"),
+            options: None,
+        }
+    }
+
+    /// Neither is reported outside Linux, so facts are never invalidated there
+    #[cfg(not(target_os = "linux"))]
+    pub fn current() -> Self {
+        Self::default()
+    }
+
+    /// The state with a hash of `options` added, such as the source options and config the facts
+    /// are collected with, so facts collected with others are not taken as current
+    pub fn with_options<T: Hash + ?Sized>(mut self, options: &T) -> Self {
+        let mut hasher = DefaultHasher::new();
+        options.hash(&mut hasher);
+        self.options = Some(format!("{:016x}", hasher.finish()));
+        self
+    }
+}
+
+/// Facts and the state they were collected in, as written by `cpuinfo daemon`
+///
+/// The file is a facts file with a `state` key added, so it can be read anywhere facts files are.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CachedFacts {
+    pub state: MachineState,
+    #[serde(flatten)]
+    pub file: FactsFile,
+}
+
+impl CachedFacts {
+    pub fn new(state: MachineState, facts: Vec<Fact>) -> Self {
+        Self {
+            state,
+            file: FactsFile::new(facts),
+        }
+    }
+
+    /// Read the cache at `path`, `None` when there is none or it can't be parsed
+    pub fn read<P: AsRef<Path>>(path: P) -> Option<Self> {
+        serde_yaml::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+
+    /// Write the cache to `path`, through a temporary file so readers never see part of it
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> crate::Result<()> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, serde_yaml::to_string(self)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Whether the facts were collected in `state`
+    pub fn is_current(&self, state: &MachineState) -> bool {
+        self.state == *state
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cached_facts() {
+        let state = MachineState {
+            microcode: Some("0x2b".to_string()),
+            boot_id: Some("08ef4139-f741-4218-957e-982ef533b001".to_string()),
+            options: None,
+        }
+        .with_options("--all-cpus");
+        let cached = CachedFacts::new(
+            state.clone(),
+            vec![Fact::new("cpuid/version/family".to_string(), 6u32.into())],
+        );
+        let text = serde_yaml::to_string(&cached).unwrap();
+        assert_eq!(serde_yaml::from_str::<CachedFacts>(&text).unwrap(), cached);
+        // The state is extra to the facts file layout
+        assert_eq!(FactsFile::parse(&text).unwrap(), cached.file);
+
+        assert!(cached.is_current(&state));
+        let updated = MachineState {
+            microcode: Some("0x2c".to_string()),
+            ..state.clone()
+        };
+        assert!(!cached.is_current(&updated));
+        let rebooted = MachineState {
+            boot_id: None,
+            ..state.clone()
+        };
+        assert!(!cached.is_current(&rebooted));
+        assert!(cached.is_current(&state.clone().with_options("--all-cpus")));
+        assert!(!cached.is_current(&state.with_options("--cpu 1")));
+    }
+}
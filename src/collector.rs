@@ -0,0 +1,509 @@
+//! Collect the facts of a cpu from a cpuid source and an MSR store
+//!
+//! This is the pipeline behind `cpuinfo facts`, for programs that embed the collection. Leaves
+//! are decoded under `cpuid/`, MSRs under `msr/` and the TSC frequency a hypervisor advertises
+//! as `hypervisor/tsc_frequency_hz`.
+//!
+//! ```no_run
+//! let config = cpuinfo::config::Definition::builtin()?;
+//! let facts = cpuinfo::Collector::new()
+//!     .with_config(&config)
+//!     .with_prefix("cpu0")
+//!     .collect()?;
+//! # Ok::<(), cpuinfo::Error>(())
+//! ```
+
+use super::config::Definition;
+use super::facts::{Fact, FactSet, Facter};
+use super::layout::DisplayLeaf;
+use super::msr::{EmptyMSR, MsrStore};
+use super::{hypervisor_tsc_frequency, CpuidType};
+
+pub struct Collector<'a> {
+    cpuid: CpuidType,
+    msr_store: Box<dyn MsrStore>,
+    config: Option<&'a Definition>,
+    prefixes: Vec<String>,
+    msr_raw: bool,
+    msr_cpu: Option<usize>,
+}
+
+impl<'a> Default for Collector<'a> {
+    fn default() -> Self {
+        Self {
+            cpuid: CpuidType::func(),
+            msr_store: Box::new(EmptyMSR {}),
+            config: None,
+            prefixes: Vec::new(),
+            msr_raw: false,
+            msr_cpu: None,
+        }
+    }
+}
+
+impl<'a> Collector<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Decode the leaves of `source` instead of the running cpu's
+    pub fn with_cpuid<S: Into<CpuidType>>(mut self, source: S) -> Self {
+        self.cpuid = source.into();
+        self
+    }
+
+    /// Read the configured MSRs from `store`, no MSRs are collected otherwise
+    pub fn with_msr<M: MsrStore + 'static>(mut self, store: M) -> Self {
+        self.msr_store = Box::new(store);
+        self
+    }
+
+    /// Decode with `config` instead of the definition built into the crate
+    pub fn with_config(mut self, config: &'a Definition) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Also give the whole value of each MSR as `msr/<name>/raw`, to check its fields against
+    pub fn with_msr_raw(mut self) -> Self {
+        self.msr_raw = true;
+        self
+    }
+
+    /// Record that the MSRs were read on `cpu` as `msr/<name>/cpu`
+    pub fn with_msr_cpu(mut self, cpu: usize) -> Self {
+        self.msr_cpu = Some(cpu);
+        self
+    }
+
+    /// Put every fact under `prefix`, e.g. a host or `cpu3`, prefixes added later go below
+    pub fn with_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    pub fn collect(&self) -> crate::Result<Vec<Fact>> {
+        let builtin;
+        let config = match self.config {
+            Some(config) => config,
+            None => {
+                builtin = Definition::builtin()?;
+                &builtin
+            }
+        };
+
+        let bound: Vec<_> = config
+            .cpuids_for(&self.cpuid)
+            .into_iter()
+            .filter_map(|(leaf, desc)| desc.bind_leaf(leaf, &self.cpuid))
+            .collect();
+        let mut ret: Vec<Fact> = bound
+            .iter()
+            .flat_map(|bound| bound.get_facts().into_iter())
+            .map(|mut fact| {
+                fact.add_path("cpuid");
+                fact
+            })
+            .collect();
+
+        if let Some(frequency) = hypervisor_tsc_frequency(&self.cpuid) {
+            let mut fact = Fact::new("tsc_frequency_hz".to_string(), frequency.into());
+            fact.add_path("hypervisor");
+            ret.push(fact);
+        }
+
+        if !self.msr_store.is_empty() {
+            for msr in config.msrs_with(&self.cpuid, &ret) {
+                if let Ok(value) = self.msr_store.get_value(msr) {
+                    let mut facts = value.collect_facts();
+                    let mut read = Vec::new();
+                    if self.msr_raw {
+                        read.push(Fact::new("raw".to_string(), value.value.into()));
+                    }
+                    if let Some(cpu) = self.msr_cpu {
+                        read.push(Fact::new("cpu".to_string(), (cpu as u64).into()));
+                    }
+                    for mut fact in read {
+                        fact.add_path(&msr.name);
+                        facts.push(fact);
+                    }
+                    for fact in &mut facts {
+                        fact.add_path("msr");
+                    }
+                    ret.append(&mut facts);
+                }
+            }
+        }
+
+        // Fields that depend on a fact rather than a field of their own leaf or MSR are only
+        // decided once every fact is in. Leaving one out can in turn invalidate fields whose
+        // condition is on it, such as the AVX-512 flags on AVX512F, so this repeats until no
+        // more are left out
+        let mut conditioned = Vec::new();
+        for bound in &bound {
+            for location in bound.desc.field_locations() {
+                if let Some(condition) = location.field.valid_if() {
+                    let in_leaf = location
+                        .path
+                        .rfind(&format!("{}/", location.register))
+                        .map_or("", |at| &location.path[..at]);
+                    conditioned.push((
+                        format!("cpuid/{}/{}", bound.desc.name(), location.path),
+                        format!("cpuid/{}/{}", bound.desc.name(), in_leaf),
+                        condition,
+                    ));
+                }
+            }
+        }
+        for msr in &config.msrs {
+            for field in &msr.fields {
+                if let Some(condition) = field.valid_if() {
+                    conditioned.push((
+                        format!("msr/{}/{}", msr.name, field.fact_name()),
+                        format!("msr/{}/", msr.name),
+                        condition,
+                    ));
+                }
+            }
+        }
+        loop {
+            let invalid: Vec<String> = conditioned
+                .iter()
+                .filter(|(name, _, _)| ret.iter().any(|fact| fact.name == *name))
+                .filter(|(_, scope, condition)| {
+                    let holds = condition.check_fact(&ret).unwrap_or_else(|| {
+                        let scoped: Vec<Fact> = ret
+                            .iter()
+                            .filter(|fact| fact.name.starts_with(scope.as_str()))
+                            .cloned()
+                            .collect();
+                        condition.check_fields(&scoped).unwrap_or(true)
+                    });
+                    !holds
+                })
+                .map(|(name, _, _)| name.clone())
+                .collect();
+            if invalid.is_empty() {
+                break;
+            }
+            ret.retain(|fact| !invalid.contains(&fact.name));
+        }
+        config.canonicalize(&mut ret);
+
+        for fact in &mut ret {
+            for prefix in self.prefixes.iter().rev() {
+                fact.add_path(prefix);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The collected facts as a set, ready for diffing
+    pub fn collect_set(&self) -> crate::Result<FactSet> {
+        Ok(self.collect()?.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::FactValue;
+    use crate::snapshot::{FileCpuidDB, SnapshotBuilder};
+
+    #[test]
+    fn collect_snapshot() {
+        let snapshot = SnapshotBuilder::new().vendor(0, "GenuineIntel").build();
+        let facts = Collector::new()
+            .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+            .with_msr(snapshot)
+            .with_prefix("host")
+            .with_prefix("cpu0")
+            .collect_set()
+            .unwrap();
+        let vendor = facts.get("host/cpu0/cpuid/Start/type").unwrap();
+        assert_eq!(vendor.value, FactValue::from("GenuineIntel"));
+        assert!(facts
+            .facts()
+            .iter()
+            .all(|fact| fact.name.starts_with("host/cpu0/cpuid/")));
+    }
+
+    #[test]
+    fn fact_conditions() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids:\n  \
+               1:\n    \
+                 name: Model\n    \
+                 data_type:\n      \
+                   type: BitField\n      \
+                   eax: []\n      \
+                   ebx: []\n      \
+                   ecx: [{type: Flag, name: OSXSAVE, bit: 27}]\n      \
+                   edx: []\n  \
+               7:\n    \
+                 name: Features\n    \
+                 data_type:\n      \
+                   type: BitField\n      \
+                   eax: []\n      \
+                   ebx:\n      \
+                     - {type: Flag, name: AVX512F, bit: 16, valid_if: {fact: cpuid/Model/ecx/OSXSAVE}}\n      \
+                     - {type: Flag, name: AVX512DQ, bit: 17, valid_if: AVX512F}\n      \
+                     - {type: Flag, name: AVX512VL, bit: 31, valid_if: ebx/AVX512F}\n      \
+                   ecx: []\n      \
+                   edx: []\n\
+             msrs: []\n",
+        )
+        .unwrap();
+        let collect = |osxsave: u32| {
+            let db = SnapshotBuilder::new()
+                .leaf(0, [7, 0, 0, 0])
+                .leaf(1, [0, 0, osxsave << 27, 0])
+                .leaf(7, [0, 1 << 16 | 1 << 17 | 1 << 31, 0, 0])
+                .db();
+            Collector::new()
+                .with_cpuid(db)
+                .with_config(&config)
+                .collect_set()
+                .unwrap()
+        };
+        let flags = ["AVX512F", "AVX512DQ", "AVX512VL"];
+        let enabled = collect(1);
+        for flag in flags {
+            assert!(enabled
+                .get(&format!("cpuid/Features/ebx/{}", flag))
+                .is_some());
+        }
+        // The flags conditioned on AVX512F go along with it
+        let disabled = collect(0);
+        for flag in flags {
+            assert!(disabled
+                .get(&format!("cpuid/Features/ebx/{}", flag))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn msr_arrays() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids: {}\n\
+             msrs:\n\
+             - {name: Single, address: 0x10, fields: [{type: Flag, name: On, bit: 0}]}\n\
+             - name: mc_banks\n  \
+               address: 0x400\n  \
+               count: 4\n  \
+               stride: 4\n  \
+               registers:\n  \
+               - {name: status, offset: 1, fields: [{type: Flag, name: VAL, bit: 63}]}\n",
+        )
+        .unwrap();
+        assert_eq!(config.msrs.len(), 5);
+        assert_eq!(config.msrs[4].name, "mc_banks/3/status");
+        assert_eq!(config.msrs[4].address, 0x40d);
+
+        // Bank 1 is not implemented
+        let snapshot = SnapshotBuilder::new()
+            .msr(0x10, 1)
+            .msr(0x401, 0)
+            .msr(0x409, 1 << 63)
+            .msr(0x40d, 0)
+            .build();
+        let facts = Collector::new()
+            .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+            .with_msr(snapshot)
+            .with_config(&config)
+            .collect_set()
+            .unwrap();
+        let flag = |name| facts.get(name).map(|fact| fact.value.clone());
+        assert_eq!(flag("msr/Single/On"), Some(true.into()));
+        assert_eq!(flag("msr/mc_banks/0/status/VAL"), Some(false.into()));
+        assert_eq!(flag("msr/mc_banks/1/status/VAL"), None);
+        assert_eq!(flag("msr/mc_banks/2/status/VAL"), Some(true.into()));
+    }
+
+    #[test]
+    fn msr_vendors() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids: {}\n\
+             msrs:\n\
+             - {name: Intel, address: 0x10, applies_to: {vendor: GenuineIntel}, fields: [{type: Flag, name: On, bit: 0}]}\n\
+             - {name: Zen, address: 0x11, applies_to: {vendor: AuthenticAMD, families: [0x17, 0x19]}, fields: [{type: Flag, name: On, bit: 0}]}\n",
+        )
+        .unwrap();
+        let collect = |family_signature| {
+            let snapshot = SnapshotBuilder::new()
+                .vendor(1, "AuthenticAMD")
+                .leaf(1, [family_signature, 0, 0, 0])
+                .msr(0x10, 1)
+                .msr(0x11, 1)
+                .build();
+            Collector::new()
+                .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+                .with_msr(snapshot)
+                .with_config(&config)
+                .collect_set()
+                .unwrap()
+        };
+        // Family 0xf with extended family 0xa, Zen 3
+        let zen3 = collect(0x00a00f00);
+        assert!(zen3.get("msr/Intel/On").is_none());
+        assert!(zen3.get("msr/Zen/On").is_some());
+        // Family 0xf with extended family 0x6, Bulldozer
+        assert!(collect(0x00600f00).get("msr/Zen/On").is_none());
+    }
+
+    #[test]
+    fn msr_requirements() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids:\n  \
+               1:\n    \
+                 name: Model\n    \
+                 data_type:\n      \
+                   type: BitField\n      \
+                   eax: []\n      \
+                   ebx: []\n      \
+                   ecx: [{type: Flag, name: VMX, bit: 5}]\n      \
+                   edx: []\n\
+             msrs:\n\
+             - {name: VMX, address: 0x480, requires: cpuid/Model/ecx/VMX, fields: [{type: Flag, name: On, bit: 0}]}\n\
+             - {name: Perf, address: 0x345, requires: {leaf: 1, register: ecx, bit: 15}, fields: [{type: Flag, name: On, bit: 0}]}\n",
+        )
+        .unwrap();
+        let collect = |ecx: u32| {
+            let snapshot = SnapshotBuilder::new()
+                .leaf(0, [1, 0, 0, 0])
+                .leaf(1, [0, 0, ecx, 0])
+                .msr(0x480, 1)
+                .msr(0x345, 1)
+                .build();
+            Collector::new()
+                .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+                .with_msr(snapshot)
+                .with_config(&config)
+                .collect_set()
+                .unwrap()
+        };
+        let vmx = collect(1 << 5);
+        assert!(vmx.get("msr/VMX/On").is_some());
+        assert!(vmx.get("msr/Perf/On").is_none());
+        let pdcm = collect(1 << 15);
+        assert!(pdcm.get("msr/VMX/On").is_none());
+        assert!(pdcm.get("msr/Perf/On").is_some());
+    }
+
+    #[test]
+    fn hyperv_msrs() {
+        let config = Definition::builtin().unwrap();
+        let collect = |hypervisor: bool| {
+            let mut builder = SnapshotBuilder::new()
+                .leaf(0, [1, 0, 0, 0])
+                .leaf(1, [0, 0, (hypervisor as u32) << 31, 0]);
+            if hypervisor {
+                // "Microsoft Hv", with the VP index and frequency privileges but not hypercalls
+                builder = builder
+                    .leaf(0x40000000, [0x40000006, 0x7263694d, 0x666f736f, 0x76482074])
+                    .leaf(0x40000003, [1 << 6 | 1 << 11, 0, 0, 0]);
+            }
+            let snapshot = builder
+                .msr(0x40000000, 1)
+                .msr(0x40000002, 3)
+                .msr(0x40000022, 2_000_000_000)
+                .build();
+            Collector::new()
+                .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+                .with_msr(snapshot)
+                .with_config(&config)
+                .collect_set()
+                .unwrap()
+        };
+        let guest = collect(true);
+        assert!(guest
+            .get("msr/HV_X64_MSR_GUEST_OS_ID/Build Number")
+            .is_none());
+        assert_eq!(
+            guest.get("msr/HV_X64_MSR_VP_INDEX/VP Index").unwrap().value,
+            3u64.into()
+        );
+        assert_eq!(
+            guest
+                .get("msr/HV_X64_MSR_TSC_FREQUENCY/Frequency")
+                .unwrap()
+                .value,
+            2_000_000_000u64.into()
+        );
+        assert!(collect(false)
+            .get("msr/HV_X64_MSR_VP_INDEX/VP Index")
+            .is_none());
+    }
+
+    #[test]
+    fn msr_raw_and_cpu() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids: {}\n\
+             msrs:\n\
+             - {name: Perf, address: 0x345, fields: [{type: Flag, name: On, bit: 0}]}\n",
+        )
+        .unwrap();
+        let snapshot = SnapshotBuilder::new().msr(0x345, 0x4f).build();
+        let collector = || {
+            Collector::new()
+                .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+                .with_msr(snapshot.clone())
+                .with_config(&config)
+        };
+        let plain = collector().collect_set().unwrap();
+        assert!(plain.get("msr/Perf/raw").is_none());
+        let facts = collector()
+            .with_msr_raw()
+            .with_msr_cpu(3)
+            .collect_set()
+            .unwrap();
+        assert_eq!(facts.get("msr/Perf/raw").unwrap().value, 0x4fu64.into());
+        assert_eq!(facts.get("msr/Perf/cpu").unwrap().value, 3u64.into());
+    }
+
+    #[test]
+    fn aliases() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids: {}\n\
+             msrs:\n\
+             - {name: Perf, address: 0x345, fields: [{type: Flag, name: On, bit: 0}]}\n\
+             aliases: {msr/Performance: msr/Perf, msr/Perf/On: msr/Perf/Enabled}\n",
+        )
+        .unwrap();
+        let snapshot = SnapshotBuilder::new().msr(0x345, 0x1).build();
+        let facts = Collector::new()
+            .with_cpuid(FileCpuidDB::new(snapshot.clone()))
+            .with_msr(snapshot)
+            .with_config(&config)
+            .collect_set()
+            .unwrap();
+        assert_eq!(facts.get("msr/Perf/Enabled").unwrap().value, true.into());
+
+        // Facts stored under the old names, below a prefix, are renamed the same way, through
+        // every alias in turn
+        let mut stored = vec![
+            Fact::new("cpu0/msr/Performance/On".to_string(), true.into()),
+            Fact::new("msr/Performance/On".to_string(), true.into()),
+            // Only matched at the start, not anywhere along the path
+            Fact::new("host/msr/Performance/On".to_string(), true.into()),
+        ];
+        config.canonicalize(&mut stored);
+        let names: Vec<&str> = stored.iter().map(|fact| fact.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "cpu0/msr/Perf/Enabled",
+                "msr/Perf/Enabled",
+                "host/msr/Performance/On"
+            ]
+        );
+
+        // Aliases renaming in a cycle stop rather than loop
+        let cycle: Definition =
+            serde_yaml::from_str("cpuids: {}\nmsrs: []\naliases: {msr/A: msr/B, msr/B: msr/A}\n")
+                .unwrap();
+        let mut facts = vec![Fact::new("msr/A/x".to_string(), true.into())];
+        cycle.canonicalize(&mut facts);
+        assert_eq!(facts[0].name, "msr/B/x");
+    }
+}
@@ -0,0 +1,1101 @@
+//! The description of what to decode and how facts are treated
+//!
+//! A [`Definition`] lists the CPUID leaves, MSRs and ID registers to decode, along with the
+//! tables mapping facts to QEMU features, node-feature-discovery labels and diff severities.
+//! The one built into the crate is `src/config.yaml`, with the leaves only Intel or AMD cpus
+//! implement in `src/config/` as the `vendor_cpuids` of their vendor strings, decoded only from
+//! cpus of that vendor. Further definitions can be merged over it with [`Definition::union`].
+
+use super::arm;
+use super::bitfield::{deserialize_number_keys, merge_by_name, Docs};
+use super::facts::Fact;
+use super::layout::{DisplayLeaf, LeafDesc};
+use super::migrate;
+use super::msr::{MSRDesc, Requirement, WritableMSR};
+use super::sub_leaf::SubLeaves;
+use super::{cpu_vendor, hypervisor_bases, CpuidDB, CpuidFunction};
+use core::fmt;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// How much a difference in a fact matters when diffing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warn,
+    Info,
+    Ignore,
+}
+
+/// Gives the facts matching `path`, a glob over fact paths, a severity
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SeverityRule {
+    pub path: String,
+    pub severity: Severity,
+}
+
+/// Lets the numbers of the facts matching `path`, a glob over fact paths, change by up to
+/// `absolute` or by up to `percent` of their former value without counting as changed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ToleranceRule {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub absolute: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+}
+
+impl ToleranceRule {
+    /// True when a change from `from` to `to` is within either tolerance
+    pub fn within(&self, from: u64, to: u64) -> bool {
+        let change = from.abs_diff(to);
+        self.absolute.is_some_and(|absolute| change <= absolute)
+            || self
+                .percent
+                .is_some_and(|percent| change as f64 <= from as f64 * percent / 100.0)
+    }
+}
+
+/// What to do when a config merged into another describes an entry it already has differently,
+/// an entry being a leaf, an MSR, an ID register, a sub-leaf strategy or a key of the maps
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// Fail with `Error::ConfigConflict` naming the entries
+    Error,
+    /// Keep the entry of the config merged into
+    First,
+    /// Take the entry of the config merged in
+    #[default]
+    Last,
+    /// Merge the fields of leaves, MSRs and ID registers by name, a field of the config merged in
+    /// replacing the one of the same name. Other entries are taken as with `Last`
+    Fields,
+}
+
+/// Merge `from` into `into`, resolving keys in both by `strategy`. `merge` merges the fields
+/// of an entry into another
+fn merge_map<K: Ord, V>(
+    into: &mut BTreeMap<K, V>,
+    from: BTreeMap<K, V>,
+    strategy: MergeStrategy,
+    merge: impl Fn(&mut V, V),
+) {
+    for (key, value) in from {
+        match into.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+            Entry::Occupied(mut entry) => match strategy {
+                MergeStrategy::First => {}
+                MergeStrategy::Fields => merge(entry.get_mut(), value),
+                MergeStrategy::Error | MergeStrategy::Last => {
+                    entry.insert(value);
+                }
+            },
+        }
+    }
+}
+
+/// As `merge_map` for lists whose entries are the same when `same` says so
+fn merge_list<T>(
+    into: &mut Vec<T>,
+    from: Vec<T>,
+    strategy: MergeStrategy,
+    same: impl Fn(&T, &T) -> bool,
+    merge: impl Fn(&mut T, T),
+) {
+    for value in from {
+        match into.iter_mut().find(|existing| same(existing, &value)) {
+            None => into.push(value),
+            Some(existing) => match strategy {
+                MergeStrategy::First => {}
+                MergeStrategy::Fields => merge(existing, value),
+                MergeStrategy::Error | MergeStrategy::Last => *existing = value,
+            },
+        }
+    }
+}
+
+/// The length of the `cpu<N>/` prefix `name` starts with, 0 when it has none
+fn cpu_prefix_len(name: &str) -> usize {
+    name.strip_prefix("cpu")
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(number, _)| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+        .map_or(0, |(number, _)| "cpu".len() + number.len() + 1)
+}
+
+/// Parse `text` as the format its file's `extension` names, YAML unless it is `json` or `toml`
+fn parse<T: serde::de::DeserializeOwned>(extension: Option<&str>, text: &str) -> crate::Result<T> {
+    match extension {
+        Some("json") => serde_json::from_str(text).map_err(crate::Error::ConfigJson),
+        #[cfg(feature = "toml")]
+        Some("toml") => Ok(toml::from_str(text)?),
+        #[cfg(not(feature = "toml"))]
+        Some("toml") => Err(crate::Error::Unsupported(
+            "TOML configs need the toml feature",
+        )),
+        _ => Ok(serde_yaml::from_str(text)?),
+    }
+}
+
+/// Whether two entries differ, going by what they serialize to
+fn differ<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+}
+
+/// The keys of `from` whose entries differ from those of `into`, prefixed by `section`
+fn map_conflicts<K: Ord + fmt::Display, V: Serialize>(
+    section: &str,
+    into: &BTreeMap<K, V>,
+    from: &BTreeMap<K, V>,
+) -> Vec<String> {
+    from.iter()
+        .filter(|(key, value)| {
+            into.get(key)
+                .is_some_and(|existing| differ(existing, *value))
+        })
+        .map(|(key, _)| format!("{} {}", section, key))
+        .collect()
+}
+
+/// A leaf number as conflicts name it
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Leaf(u32);
+
+impl fmt::Display for Leaf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+fn by_leaf<V>(map: &BTreeMap<u32, V>) -> BTreeMap<Leaf, &V> {
+    map.iter()
+        .map(|(leaf, value)| (Leaf(*leaf), value))
+        .collect()
+}
+
+fn merge_msr(existing: &mut MSRDesc, mut msr: MSRDesc) {
+    let mut fields = core::mem::take(&mut existing.fields);
+    merge_by_name(&mut fields, msr.fields, |field| field.name());
+    let mut derived = core::mem::take(&mut existing.derived);
+    merge_by_name(&mut derived, msr.derived, |derived| &derived.name);
+    msr.fields = fields;
+    msr.derived = derived;
+    *existing = msr;
+}
+
+fn same_msr(a: &MSRDesc, b: &MSRDesc) -> bool {
+    a.address == b.address && a.applies_to == b.applies_to
+}
+
+/// The leaves and MSRs of every cpu, `src/config.yaml` as encoded by the build script
+const SHARED_CONFIG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/config.msgpack"));
+
+/// The leaves only Intel or AMD cpus implement, `src/config/` as encoded by the build script
+const VENDOR_CONFIGS: &[&[u8]] = &[
+    include_bytes!(concat!(env!("OUT_DIR"), "/intel.msgpack")),
+    include_bytes!(concat!(env!("OUT_DIR"), "/amd.msgpack")),
+];
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Definition {
+    /// The version of the config format the config was written for, see `migrate`. Configs
+    /// without one are taken as the first version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Other config files merged in before this one, relative to its directory, e.g.
+    /// `include: [intel_leaves.yaml, amd_msrs.yaml]`. Read by [`Definition::from_file`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_number_keys")]
+    pub cpuids: BTreeMap<u32, LeafDesc>,
+    /// The MSRs to read, arrays of MSRs are expanded into their registers as they are read
+    #[serde(default, deserialize_with = "super::msr::deserialize_msrs")]
+    #[cfg_attr(feature = "schema", schemars(schema_with = "super::msr::msrs_schema"))]
+    pub msrs: Vec<MSRDesc>,
+    /// The bits of MSRs `msr write` may change, nothing is writable without an entry
+    #[serde(default)]
+    pub msr_writes: Vec<WritableMSR>,
+    /// Maps flag fact paths to QEMU cpu feature names
+    #[serde(default)]
+    pub qemu_features: BTreeMap<String, String>,
+    /// Maps fact paths to node-feature-discovery label names
+    #[serde(default)]
+    pub nfd_labels: BTreeMap<String, String>,
+    /// Maps flag fact paths to the short names `facts -o flags` lists, as in the flags of
+    /// /proc/cpuinfo
+    #[serde(default)]
+    pub flag_names: BTreeMap<String, String>,
+    /// How much differences in matching facts matter to diff
+    #[serde(default)]
+    pub severities: Vec<SeverityRule>,
+    /// How much numeric facts may change before diff counts them as changed
+    #[serde(default)]
+    pub tolerances: Vec<ToleranceRule>,
+    /// Old fact paths mapped to the paths they were renamed to, so facts stored under the old
+    /// name still compare with those collected under the new one
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// KVM capabilities to check with `--use-kvm`, as names mapped to KVM_CAP_* numbers
+    #[serde(default)]
+    pub kvm_caps: BTreeMap<String, u32>,
+    /// Leaves only decoded from cpus with the given vendor string of leaf 0, e.g. `GenuineIntel`
+    #[serde(default, deserialize_with = "deserialize_leaves_by_name")]
+    pub vendor_cpuids: BTreeMap<String, BTreeMap<u32, LeafDesc>>,
+    /// Leaves only decoded under the hypervisor reporting the given signature
+    #[serde(default, deserialize_with = "deserialize_leaves_by_name")]
+    pub hypervisor_cpuids: BTreeMap<String, BTreeMap<u32, LeafDesc>>,
+    /// Identification registers decoded on aarch64
+    #[serde(default)]
+    pub id_registers: Vec<arm::IdRegisterDesc>,
+    /// How the sub-leaves of leaves are enumerated, overriding the strategies built into
+    /// `sub_leaf`
+    #[serde(default, deserialize_with = "deserialize_number_keys")]
+    pub sub_leaves: BTreeMap<u32, SubLeaves>,
+    /// Named subsets of the leaves and MSRs, one of which `select_profile` keeps
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// The migrations applied to configs of older versions as they were read, for the caller to
+    /// warn about
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+/// A subset of the leaves and MSRs of the config, such as the few a CI gate compares
+///
+/// ```yaml
+/// profiles:
+///   minimal: {cpuids: [0x1, 0x7], msrs: []}
+/// ```
+///
+/// Each list left out keeps every entry of its kind.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Profile {
+    /// The leaves to keep, of `cpuids`, `vendor_cpuids` and `hypervisor_cpuids` alike
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuids: Option<Vec<u32>>,
+    /// The addresses of the MSRs to keep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msrs: Option<Vec<u32>>,
+    /// The names of the ID registers to keep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_registers: Option<Vec<String>>,
+}
+
+/// Deserialize the leaves of each vendor or hypervisor, taking their numbers as keys of any format
+fn deserialize_leaves_by_name<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, BTreeMap<u32, LeafDesc>>, D::Error> {
+    #[derive(Deserialize)]
+    struct Leaves(#[serde(deserialize_with = "deserialize_number_keys")] BTreeMap<u32, LeafDesc>);
+
+    let by_name: BTreeMap<String, Leaves> = Deserialize::deserialize(deserializer)?;
+    Ok(by_name
+        .into_iter()
+        .map(|(name, Leaves(leaves))| (name, leaves))
+        .collect())
+}
+
+impl Definition {
+    /// The definition built into the crate, with the leaves and MSRs of every vendor
+    ///
+    /// `src/config.yaml` and the vendor definitions in `src/config/` are parsed by the build
+    /// script and embedded as MessagePack, several times quicker to decode than the YAML.
+    pub fn builtin() -> crate::Result<Self> {
+        let mut definition = Self::decode(SHARED_CONFIG)?;
+        for encoded in VENDOR_CONFIGS {
+            definition.union(Self::decode(encoded)?);
+        }
+        Ok(definition)
+    }
+
+    /// Read a definition from `path`, as JSON or TOML when its name ends in `.json` or `.toml`
+    /// and as YAML otherwise
+    ///
+    /// The files it includes are read first, each merged over the one before, and the file's own
+    /// definitions merged over them. A file may not include itself, directly or not.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let files = Self::read_with_includes(path.as_ref(), &mut BTreeSet::new())?;
+        // The file itself comes last, the merged definition is of its version
+        let mut merged = Self {
+            version: files.last().and_then(|(_, definition)| definition.version),
+            ..Self::default()
+        };
+        for (_, definition) in files {
+            merged.union(definition);
+        }
+        Ok(merged)
+    }
+
+    /// Read `path` and the files it includes, in the order they are to be merged: the includes
+    /// of each file before it, in the order listed
+    ///
+    /// Each file is read once, those in `loaded`, such as a file two others include or a config
+    /// directory's pieces, are left out and the rest are added to it. The definitions are given
+    /// with their paths and without their includes, to be merged by whatever strategy the caller
+    /// picks.
+    pub fn read_with_includes(
+        path: &Path,
+        loaded: &mut BTreeSet<PathBuf>,
+    ) -> crate::Result<Vec<(PathBuf, Self)>> {
+        let mut files = Vec::new();
+        Self::read_within(path, &mut Vec::new(), loaded, &mut files)?;
+        Ok(files)
+    }
+
+    /// Read `path` and its includes into `files`, `including` holds the files it is included from
+    fn read_within(
+        path: &Path,
+        including: &mut Vec<PathBuf>,
+        loaded: &mut BTreeSet<PathBuf>,
+        files: &mut Vec<(PathBuf, Self)>,
+    ) -> crate::Result<()> {
+        let canonical = std::fs::canonicalize(path)?;
+        if including.contains(&canonical) {
+            return Err(crate::Error::ConfigCycle(path.display().to_string()));
+        }
+        if loaded.contains(&canonical) {
+            return Ok(());
+        }
+        let mut definition = Self::parse_file(path)?;
+        let includes = std::mem::take(&mut definition.include);
+
+        including.push(canonical);
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for include in includes {
+            let include = dir.join(include);
+            Self::read_within(&include, including, loaded, files).map_err(|e| match e {
+                cycle @ crate::Error::ConfigCycle(_) => cycle,
+                e => crate::Error::ConfigInclude(include.display().to_string(), Box::new(e)),
+            })?;
+        }
+        loaded.extend(including.pop());
+        files.push((path.to_path_buf(), definition));
+        Ok(())
+    }
+
+    /// Parse the config at `path`, migrating it when it was written for an older version
+    fn parse_file(path: &Path) -> crate::Result<Self> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(default)]
+            version: Option<u32>,
+        }
+
+        let text = std::fs::read_to_string(path)?;
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let version = parse::<Versioned>(extension, &text)?
+            .version
+            .unwrap_or(migrate::UNVERSIONED);
+        if version > migrate::CURRENT_VERSION {
+            return Err(crate::Error::ConfigVersion(version));
+        }
+        if version == migrate::CURRENT_VERSION {
+            return parse(extension, &text);
+        }
+
+        let mut document: serde_yaml::Value = parse(extension, &text)?;
+        let applied = migrate::migrate(&mut document, version);
+        let mut definition: Self = serde_yaml::from_value(document)?;
+        definition.warnings = applied
+            .into_iter()
+            .map(|migration| {
+                format!(
+                    "{} is a version {} config, {}",
+                    path.display(),
+                    version,
+                    migration
+                )
+            })
+            .collect();
+        Ok(definition)
+    }
+
+    fn decode(encoded: &[u8]) -> crate::Result<Self> {
+        Ok(rmp_serde::from_slice(encoded)?)
+    }
+
+    pub fn union(&mut self, b: Definition) {
+        self.union_with(b, MergeStrategy::Last)
+            .expect("Only the error strategy fails");
+    }
+
+    /// The entries `b` describes differently from this definition, e.g. `cpuids 0x7` or
+    /// `msrs 0x10a`, which `union_with` resolves by its strategy
+    pub fn conflicts(&self, b: &Definition) -> Vec<String> {
+        let mut conflicts = map_conflicts("cpuids", &by_leaf(&self.cpuids), &by_leaf(&b.cpuids));
+        for (kind, ours, theirs) in [
+            ("vendor_cpuids", &self.vendor_cpuids, &b.vendor_cpuids),
+            (
+                "hypervisor_cpuids",
+                &self.hypervisor_cpuids,
+                &b.hypervisor_cpuids,
+            ),
+        ] {
+            for (name, leaves) in theirs {
+                if let Some(existing) = ours.get(name) {
+                    conflicts.extend(map_conflicts(
+                        &format!("{} {}", kind, name),
+                        &by_leaf(existing),
+                        &by_leaf(leaves),
+                    ));
+                }
+            }
+        }
+        conflicts.extend(b.msrs.iter().filter_map(|msr| {
+            self.msrs
+                .iter()
+                .find(|existing| same_msr(existing, msr))
+                .filter(|existing| differ(*existing, msr))
+                .map(|_| format!("msrs {:#x}", msr.address))
+        }));
+        conflicts.extend(b.id_registers.iter().filter_map(|register| {
+            self.id_registers
+                .iter()
+                .find(|existing| existing.name == register.name)
+                .filter(|existing| differ(*existing, register))
+                .map(|_| format!("id_registers {}", register.name))
+        }));
+        conflicts.extend(map_conflicts(
+            "sub_leaves",
+            &by_leaf(&self.sub_leaves),
+            &by_leaf(&b.sub_leaves),
+        ));
+        conflicts.extend(map_conflicts(
+            "qemu_features",
+            &self.qemu_features,
+            &b.qemu_features,
+        ));
+        conflicts.extend(map_conflicts("nfd_labels", &self.nfd_labels, &b.nfd_labels));
+        conflicts.extend(map_conflicts("flag_names", &self.flag_names, &b.flag_names));
+        conflicts.extend(map_conflicts("aliases", &self.aliases, &b.aliases));
+        conflicts.extend(map_conflicts("kvm_caps", &self.kvm_caps, &b.kvm_caps));
+        conflicts.extend(map_conflicts("profiles", &self.profiles, &b.profiles));
+        conflicts
+    }
+
+    /// Merge `b` into this definition, resolving the entries both describe by `strategy`
+    ///
+    /// Gives the entries `b` described differently, see `conflicts`, so they can be reported.
+    /// Nothing is merged when `strategy` is `MergeStrategy::Error` and there are any. Severity
+    /// and tolerance rules and writable MSRs are always added after the existing ones.
+    pub fn union_with(
+        &mut self,
+        b: Definition,
+        strategy: MergeStrategy,
+    ) -> crate::Result<Vec<String>> {
+        let conflicts = self.conflicts(&b);
+        if strategy == MergeStrategy::Error && !conflicts.is_empty() {
+            return Err(crate::Error::ConfigConflict(conflicts));
+        }
+        let Definition {
+            version: _,
+            include: _,
+            cpuids,
+            msrs,
+            mut msr_writes,
+            qemu_features,
+            nfd_labels,
+            flag_names,
+            mut severities,
+            mut tolerances,
+            aliases,
+            kvm_caps,
+            vendor_cpuids,
+            hypervisor_cpuids,
+            id_registers,
+            sub_leaves,
+            profiles,
+            mut warnings,
+        } = b;
+        let replace = |existing: &mut String, value| *existing = value;
+        merge_map(&mut self.cpuids, cpuids, strategy, LeafDesc::merge_fields);
+        merge_list(&mut self.msrs, msrs, strategy, same_msr, merge_msr);
+        self.msr_writes.append(&mut msr_writes);
+        merge_map(&mut self.qemu_features, qemu_features, strategy, replace);
+        merge_map(&mut self.nfd_labels, nfd_labels, strategy, replace);
+        merge_map(&mut self.flag_names, flag_names, strategy, replace);
+        self.severities.append(&mut severities);
+        self.tolerances.append(&mut tolerances);
+        self.warnings.append(&mut warnings);
+        merge_map(&mut self.aliases, aliases, strategy, replace);
+        merge_map(&mut self.kvm_caps, kvm_caps, strategy, |existing, value| {
+            *existing = value
+        });
+        merge_list(
+            &mut self.id_registers,
+            id_registers,
+            strategy,
+            |a, b| a.name == b.name,
+            |existing, register| {
+                merge_by_name(&mut existing.fields, register.fields, |field| field.name())
+            },
+        );
+        merge_map(
+            &mut self.sub_leaves,
+            sub_leaves,
+            strategy,
+            |existing, value| *existing = value,
+        );
+        merge_map(&mut self.profiles, profiles, strategy, |existing, value| {
+            *existing = value
+        });
+        for (vendor, leaves) in vendor_cpuids {
+            merge_map(
+                self.vendor_cpuids.entry(vendor).or_default(),
+                leaves,
+                strategy,
+                LeafDesc::merge_fields,
+            );
+        }
+        for (signature, leaves) in hypervisor_cpuids {
+            merge_map(
+                self.hypervisor_cpuids.entry(signature).or_default(),
+                leaves,
+                strategy,
+                LeafDesc::merge_fields,
+            );
+        }
+        Ok(conflicts)
+    }
+
+    /// Keep only the leaves, MSRs and ID registers the profile `name` selects
+    pub fn select_profile(&mut self, name: &str) -> crate::Result<()> {
+        let profile = self
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| crate::Error::ConfigProfile(name.to_string()))?;
+        if let Some(leaves) = &profile.cpuids {
+            self.cpuids.retain(|leaf, _| leaves.contains(leaf));
+            for named_leaves in self
+                .vendor_cpuids
+                .values_mut()
+                .chain(self.hypervisor_cpuids.values_mut())
+            {
+                named_leaves.retain(|leaf, _| leaves.contains(leaf));
+            }
+        }
+        if let Some(addresses) = &profile.msrs {
+            self.msrs.retain(|msr| addresses.contains(&msr.address));
+        }
+        if let Some(names) = &profile.id_registers {
+            self.id_registers
+                .retain(|register| names.contains(&register.name));
+        }
+        Ok(())
+    }
+
+    /// Rename the facts under an old path of `aliases` to the new one
+    ///
+    /// An alias renames the fact at its path and every fact below it, the path starting the
+    /// fact's name or following a `cpu<N>/` prefix. Aliases are applied until none matches, so
+    /// an old path renamed twice reaches the latest name, and stop at a name already seen should
+    /// they rename in a cycle.
+    pub fn canonicalize(&self, facts: &mut [Fact]) {
+        if self.aliases.is_empty() {
+            return;
+        }
+        for fact in facts {
+            let prefix_len = cpu_prefix_len(&fact.name);
+            let (prefix, path) = fact.name.split_at(prefix_len);
+            let mut path = path.to_string();
+            let mut seen = vec![path.clone()];
+            // The longest old path matching is the most specific alias
+            while let Some((old, new)) = self
+                .aliases
+                .iter()
+                .filter(|(old, _)| {
+                    path == **old
+                        || path
+                            .strip_prefix(old.as_str())
+                            .is_some_and(|rest| rest.starts_with('/'))
+                })
+                .max_by_key(|(old, _)| old.len())
+            {
+                let renamed = format!("{}{}", new, &path[old.len()..]);
+                if seen.contains(&renamed) {
+                    break;
+                }
+                seen.push(renamed.clone());
+                path = renamed;
+            }
+            fact.name = format!("{}{}", prefix, path);
+        }
+    }
+
+    /// The documented fields and MSRs by the path of their facts, e.g. `cpuid/Model/ecx/AVX`
+    /// or `msr/IA32_ARCH_CAPABILITIES` for the MSR itself
+    pub fn docs(&self) -> BTreeMap<String, &Docs> {
+        let leaves = self.cpuids.values().chain(
+            self.vendor_cpuids
+                .values()
+                .chain(self.hypervisor_cpuids.values())
+                .flat_map(|leaves| leaves.values()),
+        );
+        let cpuid_docs = leaves.flat_map(|desc| {
+            desc.field_locations().into_iter().map(move |location| {
+                (
+                    format!("cpuid/{}/{}", desc.name(), location.path),
+                    location.field.docs(),
+                )
+            })
+        });
+        let msr_docs = self.msrs.iter().flat_map(|msr| {
+            let fields = msr.fields.iter().map(move |field| {
+                (
+                    format!("msr/{}/{}", msr.name, field.fact_name()),
+                    field.docs(),
+                )
+            });
+            std::iter::once((format!("msr/{}", msr.name), &msr.docs)).chain(fields)
+        });
+        let id_register_docs = self.id_registers.iter().flat_map(|register| {
+            register.fields.iter().map(move |field| {
+                (
+                    format!("idreg/{}/{}", register.name, field.fact_name()),
+                    field.docs(),
+                )
+            })
+        });
+        cpuid_docs
+            .chain(msr_docs)
+            .chain(id_register_docs)
+            .filter(|(_, docs)| !docs.is_empty())
+            .collect()
+    }
+
+    /// The docs of the fields and MSRs `facts` were collected from
+    pub fn docs_for(&self, facts: &[&Fact]) -> BTreeMap<String, Docs> {
+        // Facts may be under a prefix such as `cpu0/`, and an MSR's docs cover all its fields
+        let names: Vec<String> = facts
+            .iter()
+            .map(|fact| format!("/{}/", fact.name))
+            .collect();
+        self.docs()
+            .into_iter()
+            .filter(|(path, _)| {
+                let path = format!("/{}/", path);
+                names.iter().any(|name| name.contains(&path))
+            })
+            .map(|(path, docs)| (path, docs.clone()))
+            .collect()
+    }
+
+    /// The MSRs implemented on the cpu `cpuid` describes, see `MSRDesc::applies_to` and
+    /// `MSRDesc::requires`
+    pub fn msrs_for<DB: CpuidDB + ?Sized>(&self, cpuid: &DB) -> Vec<&MSRDesc> {
+        let needs_facts = self
+            .msrs
+            .iter()
+            .any(|msr| matches!(msr.requires, Some(Requirement::Fact(_))));
+        let facts = if needs_facts {
+            self.cpuid_facts(cpuid)
+        } else {
+            Vec::new()
+        };
+        self.msrs_with(cpuid, &facts)
+    }
+
+    /// Like `msrs_for`, with the cpuid facts already collected from `cpuid`
+    pub fn msrs_with<DB: CpuidDB + ?Sized>(&self, cpuid: &DB, facts: &[Fact]) -> Vec<&MSRDesc> {
+        self.msrs
+            .iter()
+            .filter(|msr| msr.applies_to(cpuid) && msr.is_enumerated(cpuid, facts))
+            .collect()
+    }
+
+    /// The facts of the leaves decoded from `cpuid`, under `cpuid/`
+    pub fn cpuid_facts<DB: CpuidDB + ?Sized>(&self, cpuid: &DB) -> Vec<Fact> {
+        self.cpuids_for(cpuid)
+            .into_iter()
+            .filter_map(|(leaf, desc)| desc.bind_leaf(leaf, cpuid))
+            .flat_map(|bound| bound.get_facts())
+            .map(|mut fact| {
+                fact.add_path("cpuid");
+                fact
+            })
+            .collect()
+    }
+
+    /// The leaves the cpu `cpuid` describes implements, those of `cpuids` and the `vendor_cpuids`
+    /// of its vendor, leaving out the leaves of hypervisors
+    pub fn cpu_leaves<DB: CpuidDB + ?Sized>(&self, cpuid: &DB) -> BTreeMap<u32, &LeafDesc> {
+        let vendor_leaves = cpu_vendor(cpuid)
+            .and_then(|vendor| self.vendor_cpuids.get(&vendor))
+            .into_iter()
+            .flatten();
+        self.cpuids
+            .iter()
+            .chain(vendor_leaves)
+            .map(|(leaf, desc)| (*leaf, desc))
+            .collect()
+    }
+
+    /// The leaves to decode from `cpuid`, including those of its vendor and of the hypervisor it
+    /// reports
+    pub fn cpuids_for<DB: CpuidDB + ?Sized>(&self, cpuid: &DB) -> BTreeMap<u32, &LeafDesc> {
+        let mut leaves = self.cpu_leaves(cpuid);
+        // The leaves are described relative to 0x40000000, Xen may present them a block higher
+        for (base, signature) in hypervisor_bases(cpuid) {
+            if let Some(hypervisor_leaves) = self.hypervisor_cpuids.get(&signature) {
+                let shift = base - CpuidFunction::Hypervisor.start_eax();
+                leaves.extend(
+                    hypervisor_leaves
+                        .iter()
+                        .map(|(leaf, desc)| (leaf + shift, desc)),
+                );
+            }
+        }
+        leaves
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    /// The gate for the built-in configs: the build script only checks their YAML syntax, whether
+    /// they decode into a definition is checked here
+    #[test]
+    fn builtin_matches_yaml() {
+        let mut yaml: Definition = serde_yaml::from_str(include_str!("config.yaml")).unwrap();
+        for vendor in [
+            include_str!("config/intel.yaml"),
+            include_str!("config/amd.yaml"),
+        ] {
+            yaml.union(serde_yaml::from_str(vendor).unwrap());
+        }
+        assert_eq!(
+            serde_yaml::to_string(&Definition::builtin().unwrap()).unwrap(),
+            serde_yaml::to_string(&yaml).unwrap()
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_matches_yaml() {
+        let yaml: Definition = serde_yaml::from_str(
+            "cpuids:\n\
+             \x20 0x80000001:\n\
+             \x20   name: Extended\n\
+             \x20   data_type:\n\
+             \x20     type: BitField\n\
+             \x20     eax: []\n\
+             \x20     ebx: []\n\
+             \x20     ecx:\n\
+             \x20       - {type: Flag, name: LAHF, bit: 0}\n\
+             \x20       - {type: Enum, name: Kind, bounds: {start: 1, end: 3}, variants: {0: none, 2: two}}\n\
+             \x20     edx: []\n\
+             msrs:\n\
+             \x20 - {name: Test, address: 0x10, fields: [{type: Flag, name: On, bit: 0}]}\n\
+             sub_leaves:\n\
+             \x20 0x80000026: {type: UntilZero, register: ecx, bounds: {start: 8, end: 16}}\n",
+        )
+        .unwrap();
+        let toml: Definition = toml::from_str(
+            r#"
+            msrs = [{ name = "Test", address = 0x10, fields = [{ type = "Flag", name = "On", bit = 0 }] }]
+
+            [cpuids."0x80000001"]
+            name = "Extended"
+            data_type = { type = "BitField", eax = [], ebx = [], edx = [], ecx = [
+                { type = "Flag", name = "LAHF", bit = 0 },
+                { type = "Enum", name = "Kind", bounds = { start = 1, end = 3 }, variants = { "0" = "none", "0x2" = "two" } },
+            ] }
+
+            [sub_leaves]
+            "0x80000026" = { type = "UntilZero", register = "ecx", bounds = { start = 8, end = 16 } }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_yaml::to_string(&toml).unwrap(),
+            serde_yaml::to_string(&yaml).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_keys() {
+        let json: Definition = serde_json::from_str(
+            r#"{
+                "cpuids": {
+                    "0x80000001": {
+                        "name": "Extended",
+                        "data_type": {
+                            "type": "BitField", "eax": [], "ebx": [], "edx": [],
+                            "ecx": [{"type": "Enum", "name": "Kind", "bounds": {"start": 1, "end": 3},
+                                     "variants": {"0": "none", "0x2": "two"}}]
+                        }
+                    }
+                },
+                "msrs": [],
+                "hypervisor_cpuids": {"KVMKVMKVM": {"1073741825": {"name": "Features", "data_type": {"type": "Start"}}}}
+            }"#,
+        )
+        .unwrap();
+        assert!(json.cpuids.contains_key(&0x80000001));
+        assert!(json.hypervisor_cpuids["KVMKVMKVM"].contains_key(&0x40000001));
+
+        let invalid = r#"{"cpuids": {"leaf1": {"name": "Model", "data_type": {"type": "Start"}}}, "msrs": []}"#;
+        assert!(serde_json::from_str::<Definition>(invalid).is_err());
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("cpuinfo-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("parts")).unwrap();
+        std::fs::write(
+            dir.join("main.yaml"),
+            "include: [parts/intel.yaml, parts/kvm.json]\nkvm_caps: {irqchip: 0}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("parts/intel.yaml"),
+            "include: [kvm.json]\nkvm_caps: {irqchip: 1, hlt: 2}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("parts/kvm.json"), r#"{"kvm_caps": {"hlt": 3}}"#).unwrap();
+        let merged = Definition::from_file(dir.join("main.yaml"));
+        let mut loaded = BTreeSet::new();
+        let files = Definition::read_with_includes(&dir.join("main.yaml"), &mut loaded);
+        let again = Definition::read_with_includes(&dir.join("parts/intel.yaml"), &mut loaded);
+
+        std::fs::write(
+            dir.join("parts/kvm.json"),
+            r#"{"include": ["../main.yaml"]}"#,
+        )
+        .unwrap();
+        let cycle = Definition::from_file(dir.join("main.yaml"));
+        std::fs::write(dir.join("main.yaml"), "include: [parts/missing.yaml]\n").unwrap();
+        let missing = Definition::from_file(dir.join("main.yaml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Later includes win over earlier ones, and the including file over all of them. kvm.json,
+        // included twice, is merged once where intel.yaml first includes it, so intel.yaml wins
+        let merged = merged.unwrap();
+        assert!(merged.include.is_empty());
+        assert_eq!(merged.kvm_caps["irqchip"], 0);
+        assert_eq!(merged.kvm_caps["hlt"], 2);
+        let files: Vec<PathBuf> = files.unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            files,
+            [
+                dir.join("parts/kvm.json"),
+                dir.join("parts/intel.yaml"),
+                dir.join("main.yaml")
+            ]
+        );
+        assert!(again.unwrap().is_empty());
+        assert!(matches!(cycle, Err(crate::Error::ConfigCycle(_))));
+        assert!(matches!(missing, Err(crate::Error::ConfigInclude(..))));
+    }
+
+    #[test]
+    fn union_with() {
+        let base = || {
+            serde_yaml::from_str::<Definition>(
+                "cpuids:
+  7:
+    name: Flags
+    data_type:
+      type: BitField
+      eax: []
+      ecx: []
+      edx: []
+      ebx:
+        - {type: Flag, name: FSGSBASE, bit: 0}
+msrs:
+  - {name: Capabilities, address: 0x10a, fields: [{type: Flag, name: RDCL_NO, bit: 0}]}
+kvm_caps: {irqchip: 0}
+",
+            )
+            .unwrap()
+        };
+        let later = || {
+            serde_yaml::from_str::<Definition>(
+                "cpuids:
+  7:
+    name: Structured Flags
+    data_type:
+      type: BitField
+      eax: []
+      ecx: []
+      edx: []
+      ebx:
+        - {type: Flag, name: SGX, bit: 2}
+msrs:
+  - {name: Capabilities, address: 0x10a, fields: [{type: Flag, name: IBRS_ALL, bit: 1}]}
+  - {name: Spec Ctrl, address: 0x48, fields: []}
+kvm_caps: {irqchip: 0, hlt: 1}
+",
+            )
+            .unwrap()
+        };
+        let paths = |def: &Definition| {
+            def.cpuids[&7]
+                .field_locations()
+                .into_iter()
+                .map(|location| location.path)
+                .collect::<Vec<_>>()
+        };
+
+        let mut def = base();
+        let conflicts = def.union_with(later(), MergeStrategy::Error);
+        assert!(matches!(
+            conflicts,
+            Err(crate::Error::ConfigConflict(entries)) if entries == ["cpuids 0x7", "msrs 0x10a"]
+        ));
+        assert_eq!(def.msrs.len(), 1);
+
+        let mut def = base();
+        let overridden = def.union_with(later(), MergeStrategy::First).unwrap();
+        assert_eq!(overridden, ["cpuids 0x7", "msrs 0x10a"]);
+        assert_eq!(def.cpuids[&7].name(), "Flags");
+        assert_eq!(def.msrs.len(), 2);
+        assert_eq!(def.msrs[0].fields[0].name(), "RDCL_NO");
+        assert_eq!(def.kvm_caps["hlt"], 1);
+
+        let mut def = base();
+        def.union_with(later(), MergeStrategy::Last).unwrap();
+        assert_eq!(paths(&def), ["ebx/SGX"]);
+        assert_eq!(def.msrs[0].fields[0].name(), "IBRS_ALL");
+
+        let mut def = base();
+        def.union_with(later(), MergeStrategy::Fields).unwrap();
+        assert_eq!(def.cpuids[&7].name(), "Structured Flags");
+        assert_eq!(paths(&def), ["ebx/FSGSBASE", "ebx/SGX"]);
+        assert_eq!(def.msrs.len(), 2);
+        assert_eq!(def.msrs[0].fields.len(), 2);
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema() {
+        let schema = serde_json::to_value(schemars::schema_for!(Definition)).unwrap();
+        // Arrays of MSRs are expanded as they are read, the schema has to take them as written
+        assert_eq!(
+            schema["properties"]["msrs"]["items"]["$ref"],
+            "#/definitions/ConfigEntry"
+        );
+        for name in ["LeafDesc", "Field", "MSRDesc", "MSRArrayDesc", "Expr"] {
+            assert!(schema["definitions"].get(name).is_some(), "{}", name);
+        }
+        assert_eq!(schema["definitions"]["Expr"]["type"], "string");
+    }
+
+    #[test]
+    fn migrate_older_versions() {
+        let dir = std::env::temp_dir().join(format!("cpuinfo-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("old.yaml"),
+            "cpuids:\n  7: {name: Flags, data_type: {type: SubLeafBitField, leaves: []}}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("main.json"),
+            r#"{"version": 2, "include": ["old.yaml"]}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("newer.yaml"), "version: 3\n").unwrap();
+        std::fs::write(dir.join("unchanged.yaml"), "kvm_caps: {irqchip: 0}\n").unwrap();
+        let old = Definition::from_file(dir.join("old.yaml"));
+        let unchanged = Definition::from_file(dir.join("unchanged.yaml"));
+        let including = Definition::from_file(dir.join("main.json"));
+        let newer = Definition::from_file(dir.join("newer.yaml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let old = old.unwrap();
+        assert_eq!(old.version, Some(migrate::CURRENT_VERSION));
+        assert!(matches!(
+            old.cpuids[&7].data_type(),
+            crate::layout::LeafType::SubLeafBitField(_)
+        ));
+        assert_eq!(old.warnings.len(), 1);
+        // Warnings about included configs reach the including one
+        assert_eq!(including.unwrap().warnings, old.warnings);
+        assert!(matches!(newer, Err(crate::Error::ConfigVersion(3))));
+        // Unversioned configs the migrations leave as they are are not warned about
+        assert!(unchanged.unwrap().warnings.is_empty());
+        // The old leaf type name is still read where configs are parsed directly
+        let direct: Definition = serde_yaml::from_str(
+            "cpuids:\n  7: {name: Flags, data_type: {type: SubLeafBitField, leaves: []}}\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            direct.cpuids[&7].data_type(),
+            crate::layout::LeafType::SubLeafBitField(_)
+        ));
+    }
+
+    #[test]
+    fn select_profile() {
+        let mut minimal = Definition::builtin().unwrap();
+        minimal.select_profile("minimal").unwrap();
+        assert!(minimal.cpuids.contains_key(&0x7));
+        assert!(!minimal.cpuids.contains_key(&0xb));
+        assert!(minimal.msrs.is_empty());
+        assert!(minimal
+            .hypervisor_cpuids
+            .values()
+            .all(|leaves| leaves.is_empty()));
+
+        let mut migration = Definition::builtin().unwrap();
+        migration.select_profile("migration").unwrap();
+        assert!(migration.vendor_cpuids["AuthenticAMD"].contains_key(&0x80000008));
+        assert!(!migration.vendor_cpuids["GenuineIntel"].contains_key(&0x4));
+        let addresses: Vec<u32> = migration.msrs.iter().map(|msr| msr.address).collect();
+        assert_eq!(addresses, [0xcf, 0x10a]);
+
+        let mut full = Definition::builtin().unwrap();
+        let leaves = full.cpuids.len();
+        full.select_profile("full").unwrap();
+        assert_eq!(full.cpuids.len(), leaves);
+
+        assert!(matches!(
+            full.select_profile("missing"),
+            Err(crate::Error::ConfigProfile(_))
+        ));
+    }
+
+    #[test]
+    fn vendor_leaves() {
+        let config = Definition::builtin().unwrap();
+        // The vendor of the source decoded picks the leaves, not that of the running cpu
+        let leaves = |vendor| {
+            let db = SnapshotBuilder::new().vendor(0x1f, vendor).db();
+            config.cpuids_for(&db).into_keys().collect::<Vec<u32>>()
+        };
+        let intel = leaves("GenuineIntel");
+        assert!(intel.contains(&0x4));
+        assert!(!intel.contains(&0x8000001d));
+        assert!(config
+            .msrs_for(&SnapshotBuilder::new().vendor(1, "GenuineIntel").db())
+            .iter()
+            .any(|msr| msr.address == 0x123));
+
+        let amd = leaves("HygonGenuine");
+        assert!(!amd.contains(&0x4));
+        assert!(amd.contains(&0x8000001d));
+        assert!(!config
+            .msrs_for(&SnapshotBuilder::new().vendor(1, "HygonGenuine").db())
+            .iter()
+            .any(|msr| msr.address == 0x123));
+
+        let other = leaves("CentaurHauls");
+        assert!(other.contains(&0x1));
+        assert!(!other.contains(&0x4));
+        assert!(!other.contains(&0x8000001d));
+    }
+}
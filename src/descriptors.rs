@@ -0,0 +1,172 @@
+//! The cache and TLB descriptors of leaf 0x2
+//!
+//! Before leaf 0x4 existed, Intel cpus described their caches and TLBs with one byte codes
+//! packed into the registers of leaf 0x2. The low byte of EAX is the number of times the leaf
+//! has to be read, which is 1 on every cpu made, and a register with bit 31 set holds no
+//! descriptors. Newer cpus report 0xFF, telling to read leaf 0x4 instead.
+
+use super::CpuidResult;
+use alloc::vec::Vec;
+
+/// The descriptors of Intel's SDM, table "Encoding of CPUID Leaf 2 Descriptors"
+const DESCRIPTORS: &[(u8, &str)] = &[
+    (0x01, "Instruction TLB: 4 KByte pages, 4-way set associative, 32 entries"),
+    (0x02, "Instruction TLB: 4 MByte pages, fully associative, 2 entries"),
+    (0x03, "Data TLB: 4 KByte pages, 4-way set associative, 64 entries"),
+    (0x04, "Data TLB: 4 MByte pages, 4-way set associative, 8 entries"),
+    (0x05, "Data TLB1: 4 MByte pages, 4-way set associative, 32 entries"),
+    (0x06, "1st-level instruction cache: 8 KBytes, 4-way set associative, 32 byte line size"),
+    (0x08, "1st-level instruction cache: 16 KBytes, 4-way set associative, 32 byte line size"),
+    (0x09, "1st-level instruction cache: 32 KBytes, 4-way set associative, 64 byte line size"),
+    (0x0a, "1st-level data cache: 8 KBytes, 2-way set associative, 32 byte line size"),
+    (0x0b, "Instruction TLB: 4 MByte pages, 4-way set associative, 4 entries"),
+    (0x0c, "1st-level data cache: 16 KBytes, 4-way set associative, 32 byte line size"),
+    (0x0d, "1st-level data cache: 16 KBytes, 4-way set associative, 64 byte line size"),
+    (0x0e, "1st-level data cache: 24 KBytes, 6-way set associative, 64 byte line size"),
+    (0x1d, "2nd-level cache: 128 KBytes, 2-way set associative, 64 byte line size"),
+    (0x21, "2nd-level cache: 256 KBytes, 8-way set associative, 64 byte line size"),
+    (0x22, "3rd-level cache: 512 KBytes, 4-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x23, "3rd-level cache: 1 MBytes, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x24, "2nd-level cache: 1 MBytes, 16-way set associative, 64 byte line size"),
+    (0x25, "3rd-level cache: 2 MBytes, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x29, "3rd-level cache: 4 MBytes, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x2c, "1st-level data cache: 32 KBytes, 8-way set associative, 64 byte line size"),
+    (0x30, "1st-level instruction cache: 32 KBytes, 8-way set associative, 64 byte line size"),
+    (0x40, "No 2nd-level cache or, if the processor has a valid 2nd-level cache, no 3rd-level cache"),
+    (0x41, "2nd-level cache: 128 KBytes, 4-way set associative, 32 byte line size"),
+    (0x42, "2nd-level cache: 256 KBytes, 4-way set associative, 32 byte line size"),
+    (0x43, "2nd-level cache: 512 KBytes, 4-way set associative, 32 byte line size"),
+    (0x44, "2nd-level cache: 1 MByte, 4-way set associative, 32 byte line size"),
+    (0x45, "2nd-level cache: 2 MByte, 4-way set associative, 32 byte line size"),
+    (0x46, "3rd-level cache: 4 MByte, 4-way set associative, 64 byte line size"),
+    (0x47, "3rd-level cache: 8 MByte, 8-way set associative, 64 byte line size"),
+    (0x48, "2nd-level cache: 3 MByte, 12-way set associative, 64 byte line size"),
+    (0x49, "3rd-level cache: 4 MByte, 16-way set associative, 64 byte line size on Xeon MP family 0FH model 06H, otherwise 2nd-level cache: 4 MByte, 16-way set associative, 64 byte line size"),
+    (0x4a, "3rd-level cache: 6 MByte, 12-way set associative, 64 byte line size"),
+    (0x4b, "3rd-level cache: 8 MByte, 16-way set associative, 64 byte line size"),
+    (0x4c, "3rd-level cache: 12 MByte, 12-way set associative, 64 byte line size"),
+    (0x4d, "3rd-level cache: 16 MByte, 16-way set associative, 64 byte line size"),
+    (0x4e, "2nd-level cache: 6 MByte, 24-way set associative, 64 byte line size"),
+    (0x4f, "Instruction TLB: 4 KByte pages, 32 entries"),
+    (0x50, "Instruction TLB: 4 KByte and 2 MByte or 4 MByte pages, 64 entries"),
+    (0x51, "Instruction TLB: 4 KByte and 2 MByte or 4 MByte pages, 128 entries"),
+    (0x52, "Instruction TLB: 4 KByte and 2 MByte or 4 MByte pages, 256 entries"),
+    (0x55, "Instruction TLB: 2 MByte or 4 MByte pages, fully associative, 7 entries"),
+    (0x56, "Data TLB0: 4 MByte pages, 4-way set associative, 16 entries"),
+    (0x57, "Data TLB0: 4 KByte pages, 4-way associative, 16 entries"),
+    (0x59, "Data TLB0: 4 KByte pages, fully associative, 16 entries"),
+    (0x5a, "Data TLB0: 2 MByte or 4 MByte pages, 4-way set associative, 32 entries"),
+    (0x5b, "Data TLB: 4 KByte and 4 MByte pages, 64 entries"),
+    (0x5c, "Data TLB: 4 KByte and 4 MByte pages, 128 entries"),
+    (0x5d, "Data TLB: 4 KByte and 4 MByte pages, 256 entries"),
+    (0x60, "1st-level data cache: 16 KByte, 8-way set associative, 64 byte line size"),
+    (0x61, "Instruction TLB: 4 KByte pages, fully associative, 48 entries"),
+    (0x63, "Data TLB: 2 MByte or 4 MByte pages, 4-way set associative, 32 entries and a separate array with 1 GByte pages, 4-way set associative, 4 entries"),
+    (0x64, "Data TLB: 4 KByte pages, 4-way set associative, 512 entries"),
+    (0x66, "1st-level data cache: 8 KByte, 4-way set associative, 64 byte line size"),
+    (0x67, "1st-level data cache: 16 KByte, 4-way set associative, 64 byte line size"),
+    (0x68, "1st-level data cache: 32 KByte, 4-way set associative, 64 byte line size"),
+    (0x6a, "uTLB: 4 KByte pages, 8-way set associative, 64 entries"),
+    (0x6b, "DTLB: 4 KByte pages, 8-way set associative, 256 entries"),
+    (0x6c, "DTLB: 2M/4M pages, 8-way set associative, 128 entries"),
+    (0x6d, "DTLB: 1 GByte pages, fully associative, 16 entries"),
+    (0x70, "Trace cache: 12 K-uop, 8-way set associative"),
+    (0x71, "Trace cache: 16 K-uop, 8-way set associative"),
+    (0x72, "Trace cache: 32 K-uop, 8-way set associative"),
+    (0x76, "Instruction TLB: 2M/4M pages, fully associative, 8 entries"),
+    (0x78, "2nd-level cache: 1 MByte, 4-way set associative, 64 byte line size"),
+    (0x79, "2nd-level cache: 128 KByte, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x7a, "2nd-level cache: 256 KByte, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x7b, "2nd-level cache: 512 KByte, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x7c, "2nd-level cache: 1 MByte, 8-way set associative, 64 byte line size, 2 lines per sector"),
+    (0x7d, "2nd-level cache: 2 MByte, 8-way set associative, 64 byte line size"),
+    (0x7f, "2nd-level cache: 512 KByte, 2-way set associative, 64 byte line size"),
+    (0x80, "2nd-level cache: 512 KByte, 8-way set associative, 64 byte line size"),
+    (0x82, "2nd-level cache: 256 KByte, 8-way set associative, 32 byte line size"),
+    (0x83, "2nd-level cache: 512 KByte, 8-way set associative, 32 byte line size"),
+    (0x84, "2nd-level cache: 1 MByte, 8-way set associative, 32 byte line size"),
+    (0x85, "2nd-level cache: 2 MByte, 8-way set associative, 32 byte line size"),
+    (0x86, "2nd-level cache: 512 KByte, 4-way set associative, 64 byte line size"),
+    (0x87, "2nd-level cache: 1 MByte, 8-way set associative, 64 byte line size"),
+    (0xa0, "DTLB: 4k pages, fully associative, 32 entries"),
+    (0xb0, "Instruction TLB: 4 KByte pages, 4-way set associative, 128 entries"),
+    (0xb1, "Instruction TLB: 2M pages, 4-way, 8 entries or 4M pages, 4-way, 4 entries"),
+    (0xb2, "Instruction TLB: 4 KByte pages, 4-way set associative, 64 entries"),
+    (0xb3, "Data TLB: 4 KByte pages, 4-way set associative, 128 entries"),
+    (0xb4, "Data TLB1: 4 KByte pages, 4-way associative, 256 entries"),
+    (0xb5, "Instruction TLB: 4 KByte pages, 8-way set associative, 64 entries"),
+    (0xb6, "Instruction TLB: 4 KByte pages, 8-way set associative, 128 entries"),
+    (0xba, "Data TLB1: 4 KByte pages, 4-way associative, 64 entries"),
+    (0xc0, "Data TLB: 4 KByte and 4 MByte pages, 4-way associative, 8 entries"),
+    (0xc1, "Shared 2nd-Level TLB: 4 KByte/2 MByte pages, 8-way associative, 1024 entries"),
+    (0xc2, "DTLB: 4 KByte/2 MByte pages, 4-way associative, 16 entries"),
+    (0xc3, "Shared 2nd-Level TLB: 4 KByte/2 MByte pages, 6-way associative, 1536 entries, and 1 GByte pages, 4-way associative, 16 entries"),
+    (0xc4, "DTLB: 2M/4M Byte pages, 4-way associative, 32 entries"),
+    (0xca, "Shared 2nd-Level TLB: 4 KByte pages, 4-way associative, 512 entries"),
+    (0xd0, "3rd-level cache: 512 KByte, 4-way set associative, 64 byte line size"),
+    (0xd1, "3rd-level cache: 1 MByte, 4-way set associative, 64 byte line size"),
+    (0xd2, "3rd-level cache: 2 MByte, 4-way set associative, 64 byte line size"),
+    (0xd6, "3rd-level cache: 1 MByte, 8-way set associative, 64 byte line size"),
+    (0xd7, "3rd-level cache: 2 MByte, 8-way set associative, 64 byte line size"),
+    (0xd8, "3rd-level cache: 4 MByte, 8-way set associative, 64 byte line size"),
+    (0xdc, "3rd-level cache: 1.5 MByte, 12-way set associative, 64 byte line size"),
+    (0xdd, "3rd-level cache: 3 MByte, 12-way set associative, 64 byte line size"),
+    (0xde, "3rd-level cache: 6 MByte, 12-way set associative, 64 byte line size"),
+    (0xe2, "3rd-level cache: 2 MByte, 16-way set associative, 64 byte line size"),
+    (0xe3, "3rd-level cache: 4 MByte, 16-way set associative, 64 byte line size"),
+    (0xe4, "3rd-level cache: 8 MByte, 16-way set associative, 64 byte line size"),
+    (0xea, "3rd-level cache: 12 MByte, 24-way set associative, 64 byte line size"),
+    (0xeb, "3rd-level cache: 18 MByte, 24-way set associative, 64 byte line size"),
+    (0xec, "3rd-level cache: 24 MByte, 24-way set associative, 64 byte line size"),
+    (0xf0, "64-Byte prefetching"),
+    (0xf1, "128-Byte prefetching"),
+    (0xfe, "No TLB descriptors, TLBs are described by leaf 0x18"),
+    (0xff, "No cache descriptors, caches are described by leaf 0x4"),
+];
+
+/// What the descriptor `byte` stands for, `None` for descriptors the table does not know
+pub fn describe(byte: u8) -> Option<&'static str> {
+    DESCRIPTORS
+        .binary_search_by_key(&byte, |(descriptor, _)| *descriptor)
+        .ok()
+        .map(|index| DESCRIPTORS[index].1)
+}
+
+/// The descriptor bytes held by a result of leaf 0x2, in register order without the null
+/// descriptor
+pub fn descriptors(result: &CpuidResult) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for (index, reg) in [result.eax, result.ebx, result.ecx, result.edx]
+        .iter()
+        .enumerate()
+    {
+        if reg & (1 << 31) != 0 {
+            continue;
+        }
+        // The low byte of EAX is the read count rather than a descriptor
+        let bytes = reg.to_le_bytes();
+        let bytes = if index == 0 { &bytes[1..] } else { &bytes[..] };
+        ret.extend(bytes.iter().filter(|byte| **byte != 0));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_descriptors() {
+        assert!(DESCRIPTORS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        // A Core 2 Duo, with EDX marked invalid
+        let result = CpuidResult {
+            eax: 0x05b0b101,
+            ebx: 0x005657f0,
+            ecx: 0x00000000,
+            edx: 0x802c4830,
+        };
+        assert_eq!(descriptors(&result), [0xb1, 0xb0, 0x05, 0xf0, 0x57, 0x56]);
+        assert_eq!(describe(0xf0), Some("64-Byte prefetching"));
+        assert_eq!(describe(0x2d), None);
+    }
+}
@@ -0,0 +1,196 @@
+//! A serializable copy of the CPUID leaves of any source
+//!
+//! `CpuidResult` comes from `core::arch` and can not be serialized, so the leaves are kept as
+//! `CpuidRegisters` by their `LeafAddr`. A dump serializes as a list in the same layout as the
+//! `cpuid` list of a snapshot:
+//!
+//! ```yaml
+//! - {leaf: 0, sub_leaf: 0, eax: 32, ebx: 1970169159, ecx: 1818588270, edx: 1231384169}
+//! ```
+//!
+//! Only the leaves and sub-leaves present in the dump are answered when it is used as a source.
+
+use super::sub_leaf::SubLeaves;
+use super::{hypervisor_ranges, CpuidDB, CpuidFunction, CpuidResult, LeafAddr};
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// The registers of a CPUID result
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuidRegisters {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl From<CpuidResult> for CpuidRegisters {
+    fn from(result: CpuidResult) -> Self {
+        Self {
+            eax: result.eax,
+            ebx: result.ebx,
+            ecx: result.ecx,
+            edx: result.edx,
+        }
+    }
+}
+
+impl From<CpuidRegisters> for CpuidResult {
+    fn from(registers: CpuidRegisters) -> Self {
+        Self {
+            eax: registers.eax,
+            ebx: registers.ebx,
+            ecx: registers.ecx,
+            edx: registers.edx,
+        }
+    }
+}
+
+/// One leaf of a serialized dump
+#[derive(Serialize, Deserialize)]
+struct DumpEntry {
+    leaf: u32,
+    sub_leaf: u32,
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    edx: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(from = "Vec<DumpEntry>", into = "Vec<DumpEntry>")]
+pub struct CpuidDump {
+    leaves: BTreeMap<LeafAddr, CpuidRegisters>,
+}
+
+impl CpuidDump {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Read every leaf `source` advertises in its basic, hypervisor and extended ranges
+    ///
+    /// Sub-leaf 0 is kept even when empty so the dump answers for the whole range, further
+    /// sub-leaves are enumerated with the strategy built in for the leaf, see `sub_leaf`.
+    pub fn capture<DB: CpuidDB + ?Sized>(source: &DB) -> Self {
+        let mut ranges = Vec::new();
+        for func in [CpuidFunction::Basic, CpuidFunction::Extended] {
+            if let Some(last) = source.max_leaf(func) {
+                ranges.push((func.start_eax(), last));
+            }
+        }
+        ranges.extend(hypervisor_ranges(|leaf| source.get_cpuid(leaf, 0)));
+
+        let mut dump = Self::new();
+        for (first, last) in ranges {
+            let func = match first {
+                0 => CpuidFunction::Basic,
+                0x80000000 => CpuidFunction::Extended,
+                _ => CpuidFunction::Hypervisor,
+            };
+            for leaf in (first..=last).take_while(|leaf| func.is_valid_leaf(*leaf)) {
+                for (sub_leaf, result) in SubLeaves::for_leaf(leaf).read(source, leaf) {
+                    dump.insert(LeafAddr { leaf, sub_leaf }, result);
+                }
+            }
+        }
+        dump
+    }
+
+    pub fn insert(&mut self, addr: LeafAddr, result: CpuidResult) {
+        self.leaves.insert(addr, result.into());
+    }
+
+    pub fn get(&self, addr: &LeafAddr) -> Option<CpuidRegisters> {
+        self.leaves.get(addr).copied()
+    }
+
+    /// The leaves in order of leaf and sub-leaf
+    pub fn iter(&self) -> btree_map::Iter<'_, LeafAddr, CpuidRegisters> {
+        self.leaves.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+}
+
+impl CpuidDB for CpuidDump {
+    fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        self.get(&LeafAddr { leaf, sub_leaf }).map(Into::into)
+    }
+}
+
+impl From<Vec<DumpEntry>> for CpuidDump {
+    fn from(entries: Vec<DumpEntry>) -> Self {
+        Self {
+            leaves: entries
+                .into_iter()
+                .map(|entry| {
+                    (
+                        LeafAddr {
+                            leaf: entry.leaf,
+                            sub_leaf: entry.sub_leaf,
+                        },
+                        CpuidRegisters {
+                            eax: entry.eax,
+                            ebx: entry.ebx,
+                            ecx: entry.ecx,
+                            edx: entry.edx,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<CpuidDump> for Vec<DumpEntry> {
+    fn from(dump: CpuidDump) -> Self {
+        dump.leaves
+            .into_iter()
+            .map(|(addr, registers)| DumpEntry {
+                leaf: addr.leaf,
+                sub_leaf: addr.sub_leaf,
+                eax: registers.eax,
+                ebx: registers.ebx,
+                ecx: registers.ecx,
+                edx: registers.edx,
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::snapshot::{CpuidEntry, SnapshotBuilder};
+
+    #[test]
+    fn capture_round_trip() {
+        let source = SnapshotBuilder::new()
+            .leaf(0, [7, 1, 2, 3])
+            .leaf(7, [1, 1, 2, 3])
+            .sub_leaf(7, 1, [4, 1, 2, 3])
+            .leaf(0x80000000, [0x80000001, 1, 2, 3])
+            .db();
+        let dump = CpuidDump::capture(&source);
+        // Leaves 0 to 7 and the two extended leaves, with the second sub-leaf of leaf 7
+        assert_eq!(dump.len(), 11);
+        assert_eq!(dump.get_cpuid(7, 1).map(|result| result.eax), Some(4));
+        assert_eq!(dump.get_cpuid(3, 0).map(|result| result.ebx), Some(0));
+        assert_eq!(dump.get_cpuid(3, 1), None);
+        assert_eq!(dump.get_cpuid(8, 0), None);
+
+        let text = serde_yaml::to_string(&dump).unwrap();
+        let loaded: CpuidDump = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(loaded, dump);
+        let entries: Vec<CpuidEntry> = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(entries[8], source.snapshot().cpuid[2]);
+    }
+}
@@ -0,0 +1,95 @@
+//! The error returned by the library
+//!
+//! Each subsystem describes its failures with its own error enum, and the public functions of
+//! every subsystem return [`Error`] wrapping it, so a failure can be matched on by where it came
+//! from. The sources reading from the system fail with [`Error::Io`], or [`Error::Unsupported`]
+//! on platforms where they do not exist.
+
+#[cfg(feature = "core")]
+use super::facts::FACTS_VERSION;
+use super::CpuidError;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error(transparent)]
+    Cpuid(#[from] CpuidError),
+    #[cfg(feature = "std")]
+    #[error(transparent)]
+    Msr(#[from] super::msr::Error),
+    #[cfg(feature = "std")]
+    #[error(transparent)]
+    Snapshot(#[from] super::snapshot::Error),
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[error(transparent)]
+    Kvm(#[from] super::kvm::Error),
+    #[cfg(all(unix, feature = "qmp"))]
+    #[error(transparent)]
+    Qmp(#[from] super::qmp::Error),
+    #[cfg(feature = "history")]
+    #[error(transparent)]
+    History(#[from] super::history::Error),
+    #[cfg(feature = "core")]
+    #[error(transparent)]
+    Expr(#[from] super::expr::Error),
+    #[cfg(feature = "std")]
+    #[error("IOError: {0}")]
+    Io(#[from] std::io::Error),
+    /// A config definition could not be parsed
+    #[cfg(feature = "std")]
+    #[error("Unable to parse config: {0}")]
+    Config(#[from] serde_yaml::Error),
+    /// A config included by another could not be read
+    #[cfg(feature = "std")]
+    #[error("Unable to read included config {0}: {1}")]
+    ConfigInclude(alloc::string::String, alloc::boxed::Box<Error>),
+    /// A config includes itself, directly or through the configs it includes
+    #[cfg(feature = "std")]
+    #[error("Config {0} includes itself")]
+    ConfigCycle(alloc::string::String),
+    /// Configs being merged describe the same leaves or MSRs differently
+    #[cfg(feature = "std")]
+    #[error("Configs conflict on: {}", .0.join(", "))]
+    ConfigConflict(alloc::vec::Vec<alloc::string::String>),
+    /// A config was written for a newer version of the config format than this build reads
+    #[cfg(feature = "std")]
+    #[error("Config version {0} is newer than the supported version")]
+    ConfigVersion(u32),
+    /// No config defines the profile asked for
+    #[cfg(feature = "std")]
+    #[error("No config defines the profile {0}")]
+    ConfigProfile(alloc::string::String),
+    /// A JSON config definition could not be parsed
+    #[cfg(feature = "std")]
+    #[error("Unable to parse JSON config: {0}")]
+    ConfigJson(serde_json::Error),
+    /// A TOML config definition could not be parsed
+    #[cfg(feature = "toml")]
+    #[error("Unable to parse TOML config: {0}")]
+    ConfigToml(#[from] toml::de::Error),
+    /// The config built into the crate could not be decoded
+    #[cfg(feature = "std")]
+    #[error("Unable to decode the built-in config: {0}")]
+    BuiltinConfig(#[from] rmp_serde::decode::Error),
+    /// A fact path pattern is not a valid glob
+    #[cfg(feature = "std")]
+    #[error("Invalid pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
+    /// A fact can not be placed in a `FactTree`, its path runs through the value of another fact
+    /// or other facts are nested under it
+    #[error("Fact {0} overlaps the path of another fact")]
+    FactPath(alloc::string::String),
+    /// A facts file is neither a list of facts, a tree of them nor a versioned facts file
+    #[cfg(feature = "std")]
+    #[error("Unable to parse facts: {0}")]
+    FactsParse(serde_yaml::Error),
+    /// A facts file was written in a newer format than this build reads
+    #[cfg(feature = "core")]
+    #[error("Facts file version {0} is not supported (newest supported is {FACTS_VERSION})")]
+    FactsVersion(u32),
+    /// The source does not exist on this platform
+    #[error("{0}")]
+    Unsupported(&'static str),
+}
+
+pub type Result<V, E = Error> = core::result::Result<V, E>;
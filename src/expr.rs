@@ -0,0 +1,299 @@
+//! Integer expressions over the fields of a leaf or register, for facts derived in the config
+//!
+//! A bit field often only means something after some arithmetic, such as a size encoded as its
+//! number of ways, sets and line size each less one. Rather than a leaf type for every such case
+//! the config can derive facts from the fields of the same leaf or register:
+//!
+//! ```yaml
+//! derived:
+//! - {name: size_bytes, expr: "(Ways + 1) * (Partitions + 1) * (`Line Size` + 1) * (Sets + 1)"}
+//! ```
+//!
+//! Expressions work on unsigned 64 bit integers with `+ - * / % << >> & | ^`, parentheses and
+//! decimal or `0x` hex literals, with the precedence they have in Rust. Fields are named as in the
+//! config, in backticks when the name is not a plain identifier, and flags count as 0 or 1. A
+//! derived fact is left out when a field it uses is missing or the arithmetic overflows.
+
+use super::bitfield::find_field;
+use super::facts::{Fact, FactValue};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Unexpected {0:?} at {1} in expression")]
+    Unexpected(String, usize),
+    #[error("Expression ends early")]
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl Op {
+    /// The operators binding at each level, loosest first
+    const LEVELS: &'static [&'static [(&'static str, Op)]] = &[
+        &[("|", Op::Or)],
+        &[("^", Op::Xor)],
+        &[("&", Op::And)],
+        &[("<<", Op::Shl), (">>", Op::Shr)],
+        &[("+", Op::Add), ("-", Op::Sub)],
+        &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+    ];
+
+    fn apply(self, left: u64, right: u64) -> Option<u64> {
+        match self {
+            Self::Or => Some(left | right),
+            Self::Xor => Some(left ^ right),
+            Self::And => Some(left & right),
+            Self::Shl => left.checked_shl(u32::try_from(right).ok()?),
+            Self::Shr => left.checked_shr(u32::try_from(right).ok()?),
+            Self::Add => left.checked_add(right),
+            Self::Sub => left.checked_sub(right),
+            Self::Mul => left.checked_mul(right),
+            Self::Div => left.checked_div(right),
+            Self::Rem => left.checked_rem(right),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(u64),
+    Field(String),
+    Binary(Box<Node>, Op, Box<Node>),
+}
+
+impl Node {
+    fn eval(&self, lookup: &dyn Fn(&str) -> Option<u64>) -> Option<u64> {
+        match self {
+            Self::Number(value) => Some(*value),
+            Self::Field(name) => lookup(name),
+            Self::Binary(left, op, right) => op.apply(left.eval(lookup)?, right.eval(lookup)?),
+        }
+    }
+}
+
+/// A parsed expression, kept along with its text so the config serializes as it was written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expr {
+    text: String,
+    root: Node,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn rest(&mut self) -> &'a str {
+        self.skip_space();
+        &self.text[self.pos..]
+    }
+
+    fn unexpected(&mut self) -> Error {
+        match self.rest().chars().next() {
+            Some(next) => Error::Unexpected(next.to_string(), self.pos),
+            None => Error::Incomplete,
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, Error> {
+        let ops = match Op::LEVELS.get(level) {
+            Some(ops) => ops,
+            None => return self.primary(),
+        };
+        let mut node = self.binary(level + 1)?;
+        'operators: loop {
+            let rest = self.rest();
+            for (token, op) in ops.iter() {
+                if rest.starts_with(token) {
+                    self.pos += token.len();
+                    let right = self.binary(level + 1)?;
+                    node = Node::Binary(Box::new(node), *op, Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(node);
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, Error> {
+        let rest = self.rest();
+        let start = self.pos;
+        if rest.starts_with('(') {
+            self.pos += 1;
+            let node = self.binary(0)?;
+            if !self.rest().starts_with(')') {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+            return Ok(node);
+        }
+        if let Some(quoted) = rest.strip_prefix('`') {
+            let end = quoted.find('`').ok_or(Error::Incomplete)?;
+            self.pos += end + 2;
+            return Ok(Node::Field(quoted[..end].to_string()));
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        self.pos += len;
+        match word.chars().next() {
+            None => Err(self.unexpected()),
+            Some('0'..='9') => {
+                let number = match word.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                number
+                    .map(Node::Number)
+                    .map_err(|_| Error::Unexpected(word.to_string(), start))
+            }
+            Some(_) => Ok(Node::Field(word.to_string())),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut parser = Parser { text, pos: 0 };
+        let root = parser.binary(0)?;
+        if !parser.rest().is_empty() {
+            return Err(parser.unexpected());
+        }
+        Ok(Self {
+            text: text.to_string(),
+            root,
+        })
+    }
+
+    /// The value of the expression with the fields given by `lookup`, `None` when a field is
+    /// missing or the arithmetic overflows or divides by zero
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<u64>) -> Option<u64> {
+        self.root.eval(lookup)
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = Error;
+    fn try_from(text: String) -> Result<Self, Error> {
+        Self::parse(&text)
+    }
+}
+
+impl From<Expr> for String {
+    fn from(expr: Expr) -> Self {
+        expr.text
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Written as its text in configs
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for Expr {
+    fn schema_name() -> String {
+        "Expr".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// A fact computed from the other fields of a leaf or register
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Derived {
+    pub name: String,
+    pub expr: Expr,
+}
+
+/// The derived facts whose fields are all among `facts`, the facts of one leaf or register, each
+/// can also use the ones derived before it
+pub fn derive_facts(derived: &[Derived], facts: &[Fact]) -> Vec<Fact> {
+    let mut ret: Vec<Fact> = Vec::new();
+    for derived in derived {
+        let lookup = |name: &str| {
+            let fact = find_field(facts, name).or_else(|| find_field(&ret, name))?;
+            match fact.value {
+                FactValue::Bool(set) => Some(u64::from(set)),
+                FactValue::U64(value) => Some(value),
+                FactValue::String(_) => None,
+            }
+        };
+        if let Some(value) = derived.expr.eval(&lookup) {
+            ret.push(Fact::new(derived.name.clone(), value.into()));
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(text: &str) -> Option<u64> {
+        Expr::parse(text).unwrap().eval(&|name| match name {
+            "Ways" => Some(11),
+            "Line Size" => Some(63),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn evaluate() {
+        assert_eq!(eval("1 + 2 * 3"), Some(7));
+        assert_eq!(eval("(1 + 2) * 3"), Some(9));
+        assert_eq!(eval("10 - 4 - 3"), Some(3));
+        assert_eq!(eval("1 << 4 | 0x3 & 1"), Some(17));
+        assert_eq!(eval("(Ways + 1) * (`Line Size` + 1)"), Some(768));
+        assert_eq!(eval("Sets + 1"), None);
+        assert_eq!(eval("1 / 0"), None);
+        assert_eq!(eval("0 - 1"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Expr::parse("1 +"), Err(Error::Incomplete));
+        assert_eq!(Expr::parse("(1 + 2"), Err(Error::Incomplete));
+        assert_eq!(
+            Expr::parse("1 + 2)"),
+            Err(Error::Unexpected(")".to_string(), 5))
+        );
+        assert_eq!(
+            Expr::parse("0xfg"),
+            Err(Error::Unexpected("0xfg".to_string(), 0))
+        );
+        assert_eq!(Expr::parse("`Line Size").unwrap_err(), Error::Incomplete);
+        assert_eq!(Expr::parse(" Ways * 2 ").unwrap().to_string(), " Ways * 2 ");
+    }
+}
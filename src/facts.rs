@@ -1,19 +1,121 @@
 //! Provide a means to work with and diff sets of facts
 //!
 
+use super::bitfield::Docs;
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
 use serde::{Deserialize, Serialize};
-use std::cmp::Eq;
+#[cfg(feature = "std")]
 use std::collections::{HashMap, HashSet};
-use std::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
 use std::hash::Hash;
+#[cfg(feature = "std")]
 use std::rc::Rc;
 
+/// The value of a fact, serialized as a plain boolean, number or string
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum FactValue {
+    Bool(bool),
+    U64(u64),
+    String(String),
+}
+
+impl FactValue {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::U64(value) => Some(*value),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FactValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
+        match self {
+            Self::Bool(value) => value.fmt(f),
+            Self::U64(value) => value.fmt(f),
+            Self::String(value) => value.fmt(f),
+        }
+    }
+}
+
+impl From<bool> for FactValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<u32> for FactValue {
+    fn from(value: u32) -> Self {
+        Self::U64(value.into())
+    }
+}
+
+impl From<u64> for FactValue {
+    fn from(value: u64) -> Self {
+        Self::U64(value)
+    }
+}
+
+impl From<String> for FactValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<&str> for FactValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.into())
+    }
+}
+
+// For consumers still working with the values of the serde formats
+#[cfg(feature = "std")]
+impl From<FactValue> for serde_yaml::Value {
+    fn from(value: FactValue) -> Self {
+        match value {
+            FactValue::Bool(value) => value.into(),
+            FactValue::U64(value) => value.into(),
+            FactValue::String(value) => value.into(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<FactValue> for serde_json::Value {
+    fn from(value: FactValue) -> Self {
+        match value {
+            FactValue::Bool(value) => value.into(),
+            FactValue::U64(value) => value.into(),
+            FactValue::String(value) => value.into(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
-pub struct GenericFact<T> {
+pub struct GenericFact<T = FactValue> {
     pub name: String,
     pub value: T,
 }
 
+/// A fact as collected by this crate
+pub type Fact = GenericFact<FactValue>;
+
 impl<T> GenericFact<T> {
     pub fn new(name: String, value: T) -> Self {
         Self { name, value }
@@ -35,7 +137,7 @@ impl<T> GenericFact<T> {
 }
 
 impl<T: Display> Display for GenericFact<T> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
         write!(f, "{} = {}", self.name, self.value)
     }
 }
@@ -58,20 +160,235 @@ impl<T> From<(&str, T)> for GenericFact<T> {
     }
 }
 
+/// Facts nested by the segments of their paths
+///
+/// Serializes as nested maps, so `cpuid/Feature Information/ecx/AVX: true` becomes
+/// `{cpuid: {Feature Information: {ecx: {AVX: true}}}}`, the shape Ansible and other consumers of
+/// structured facts expect. `flatten` turns a tree back into facts for diffing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum FactTree {
+    Value(FactValue),
+    Node(BTreeMap<String, FactTree>),
+}
+
+impl Default for FactTree {
+    fn default() -> Self {
+        Self::Node(BTreeMap::new())
+    }
+}
+
+impl FactTree {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add `fact` at its path
+    ///
+    /// Fails when a segment of the path already holds a value, or when facts are already nested
+    /// below the path.
+    pub fn insert(&mut self, fact: Fact) -> crate::Result<()> {
+        let mut node = self;
+        let mut segments = fact.name.split('/').peekable();
+        while let Some(segment) = segments.next() {
+            let children = match node {
+                Self::Node(children) => children,
+                Self::Value(_) => return Err(crate::Error::FactPath(fact.name)),
+            };
+            if segments.peek().is_none() {
+                return match children.entry(segment.to_string()) {
+                    btree_map::Entry::Vacant(entry) => {
+                        entry.insert(Self::Value(fact.value));
+                        Ok(())
+                    }
+                    btree_map::Entry::Occupied(_) => Err(crate::Error::FactPath(fact.name)),
+                };
+            }
+            node = children.entry(segment.to_string()).or_default();
+        }
+        Ok(())
+    }
+
+    /// The subtree or value at `path`
+    pub fn get(&self, path: &str) -> Option<&Self> {
+        path.split('/').try_fold(self, |node, segment| match node {
+            Self::Node(children) => children.get(segment),
+            Self::Value(_) => None,
+        })
+    }
+
+    /// The facts of the tree with their paths joined by `/`, ordered by name
+    pub fn flatten(&self) -> Vec<Fact> {
+        let mut facts = Vec::new();
+        self.flatten_into(&mut String::new(), &mut facts);
+        facts
+    }
+
+    fn flatten_into(&self, path: &mut String, facts: &mut Vec<Fact>) {
+        match self {
+            Self::Value(value) => facts.push(Fact::new(path.clone(), value.clone())),
+            Self::Node(children) => {
+                for (segment, child) in children {
+                    let len = path.len();
+                    if len > 0 {
+                        path.push('/');
+                    }
+                    path.push_str(segment);
+                    child.flatten_into(path, facts);
+                    path.truncate(len);
+                }
+            }
+        }
+    }
+}
+
+impl core::convert::TryFrom<Vec<Fact>> for FactTree {
+    type Error = crate::Error;
+    fn try_from(facts: Vec<Fact>) -> crate::Result<Self> {
+        let mut tree = Self::new();
+        for fact in facts {
+            tree.insert(fact)?;
+        }
+        Ok(tree)
+    }
+}
+
+/// Version of the facts file format written by this build
+pub const FACTS_VERSION: u32 = 1;
+
+/// Facts as written to a file, along with the format version and the program that wrote them
+///
+/// ```yaml
+/// version: 1
+/// generator: cpuinfo 0.2.0
+/// facts:
+///   - name: cpuid/Start/type
+///     value: GenuineIntel
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FactsFile {
+    pub version: u32,
+    /// The program and version that collected the facts, empty when not known
+    #[serde(default)]
+    pub generator: String,
+    pub facts: Vec<Fact>,
+    /// The description and reference of the fields behind the facts, by their path in the
+    /// config. Only written by `facts --verbose`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub docs: BTreeMap<String, Docs>,
+}
+
+impl FactsFile {
+    pub fn new(facts: Vec<Fact>) -> Self {
+        Self {
+            version: FACTS_VERSION,
+            generator: concat!("cpuinfo ", env!("CARGO_PKG_VERSION")).to_string(),
+            facts,
+            docs: BTreeMap::new(),
+        }
+    }
+
+    /// Parse YAML or JSON facts
+    ///
+    /// Besides the versioned layout this takes the bare list of facts written before the format
+    /// had a version, and the nested trees of `facts -o tree` and `-o ansible`. Both load as
+    /// version 0 with an unknown generator.
+    #[cfg(feature = "std")]
+    pub fn parse(text: &str) -> crate::Result<Self> {
+        let parse = crate::Error::FactsParse;
+        let value: serde_yaml::Value = serde_yaml::from_str(text).map_err(parse)?;
+        let is_file = matches!(value.as_mapping(), Some(map)
+            if map.contains_key(&"version".into()) && map.contains_key(&"facts".into()));
+        let file = if is_file {
+            serde_yaml::from_value(value).map_err(parse)?
+        } else {
+            let facts = if value.is_sequence() {
+                serde_yaml::from_value(value).map_err(parse)?
+            } else {
+                serde_yaml::from_value::<FactTree>(value)
+                    .map_err(parse)?
+                    .flatten()
+            };
+            Self {
+                version: 0,
+                generator: String::new(),
+                facts,
+                docs: BTreeMap::new(),
+            }
+        };
+        if file.version > FACTS_VERSION {
+            Err(crate::Error::FactsVersion(file.version))
+        } else {
+            Ok(file)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+/// Selects facts by matching their path against glob patterns
+///
+/// `*` and `?` do not match across a `/`, use `**` to match any number of path segments.
+#[derive(Debug, Clone, Default)]
+pub struct PathMatcher {
+    patterns: Vec<glob::Pattern>,
+    case_sensitive: bool,
+}
+
+#[cfg(feature = "std")]
+impl PathMatcher {
+    pub fn new<I, S>(patterns: I) -> crate::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Ok(Self {
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| glob::Pattern::new(pattern.as_ref()))
+                .collect::<Result<_, _>>()?,
+            case_sensitive: true,
+        })
+    }
+
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// True if any of the patterns match `name`
+    pub fn matches(&self, name: &str) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive: self.case_sensitive,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches_with(name, options))
+    }
+}
+
 pub trait Facter<T> {
     fn collect_facts(&self) -> Vec<T>;
 }
 
-pub struct FactSet<T> {
+#[cfg(feature = "std")]
+pub struct FactSet<T = FactValue> {
     backing: HashMap<String, Rc<GenericFact<T>>>,
     name_set: HashSet<String>,
 }
 
+#[cfg(feature = "std")]
 pub struct NameIteration<'s, T, I: 's + Iterator> {
     iter: I,
     backing: &'s HashMap<String, Rc<GenericFact<T>>>,
 }
 
+#[cfg(feature = "std")]
 impl<'s, T, I: Iterator<Item = &'s String> + 's> Iterator for NameIteration<'s, T, I> {
     type Item = &'s GenericFact<T>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -80,12 +397,14 @@ impl<'s, T, I: Iterator<Item = &'s String> + 's> Iterator for NameIteration<'s,
     }
 }
 
+#[cfg(feature = "std")]
 pub struct ChangedIterator<'s, T, I: 's + Iterator> {
     iter: I,
     backing_from: &'s HashMap<String, Rc<GenericFact<T>>>,
     backing_to: &'s HashMap<String, Rc<GenericFact<T>>>,
 }
 
+#[cfg(feature = "std")]
 impl<'s, T: PartialEq, I: Iterator<Item = &'s String> + 's> Iterator for ChangedIterator<'s, T, I> {
     type Item = (&'s GenericFact<T>, &'s GenericFact<T>);
     fn next(&mut self) -> Option<Self::Item> {
@@ -108,12 +427,13 @@ impl<'s, T: PartialEq, I: Iterator<Item = &'s String> + 's> Iterator for Changed
     }
 }
 
+#[cfg(feature = "std")]
 impl<T: PartialEq + Eq + Hash> FactSet<T> {
     /// Facts that are in to but not in self
     pub fn added_facts<'to>(
         &'to self,
         to: &'to Self,
-    ) -> NameIteration<'to, T, impl Iterator<Item = &String>> {
+    ) -> NameIteration<'to, T, impl Iterator<Item = &'to String>> {
         let name_iter = to.name_set.difference(&self.name_set);
         NameIteration {
             iter: name_iter,
@@ -125,7 +445,7 @@ impl<T: PartialEq + Eq + Hash> FactSet<T> {
     pub fn removed_facts<'to>(
         &'to self,
         to: &'to Self,
-    ) -> NameIteration<'to, T, impl Iterator<Item = &String>> {
+    ) -> NameIteration<'to, T, impl Iterator<Item = &'to String>> {
         let name_iter = self.name_set.difference(&to.name_set);
         NameIteration {
             iter: name_iter,
@@ -137,7 +457,7 @@ impl<T: PartialEq + Eq + Hash> FactSet<T> {
     pub fn changed_facts<'to>(
         &'to self,
         to: &'to Self,
-    ) -> ChangedIterator<'to, T, impl Iterator<Item = &String>> {
+    ) -> ChangedIterator<'to, T, impl Iterator<Item = &'to String>> {
         let name_iter = self.backing.keys();
         ChangedIterator {
             iter: name_iter,
@@ -147,6 +467,166 @@ impl<T: PartialEq + Eq + Hash> FactSet<T> {
     }
 }
 
+#[cfg(feature = "std")]
+impl<T: PartialEq> FactSet<T> {
+    /// Facts whose value is not the same in every one of `sets`, keyed by name
+    ///
+    /// Each entry holds the value from each set in order, `None` where a set lacks the fact.
+    pub fn differences(sets: &[Self]) -> BTreeMap<&String, Vec<Option<&T>>> {
+        let names: HashSet<&String> = sets.iter().flat_map(|set| set.name_set.iter()).collect();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let values: Vec<Option<&T>> = sets
+                    .iter()
+                    .map(|set| set.backing.get(name).map(|fact| &fact.value))
+                    .collect();
+                if values.windows(2).all(|pair| pair[0] == pair[1]) {
+                    None
+                } else {
+                    Some((name, values))
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> FactSet<T> {
+    /// All facts in the set, ordered by name
+    pub fn facts(&self) -> Vec<&GenericFact<T>> {
+        let mut facts: Vec<_> = self.backing.values().map(|v| v.as_ref()).collect();
+        facts.sort_by(|a, b| a.name.cmp(&b.name));
+        facts
+    }
+
+    pub fn get(&self, name: &str) -> Option<&GenericFact<T>> {
+        self.backing.get(name).map(|v| v.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.backing.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backing.is_empty()
+    }
+
+    /// Facts found in both self and other, with their values combined by `merge`
+    ///
+    /// A fact is left out of the result when `merge` returns `None`.
+    pub fn intersection_with<F>(&self, other: &Self, merge: F) -> Self
+    where
+        F: Fn(&T, &T) -> Option<T>,
+    {
+        let backing: HashMap<String, Rc<GenericFact<T>>> = self
+            .name_set
+            .intersection(&other.name_set)
+            .filter_map(|name| {
+                let value = merge(&self.backing[name].value, &other.backing[name].value)?;
+                Some((name.clone(), Rc::new(GenericFact::new(name.clone(), value))))
+            })
+            .collect();
+        let name_set = backing.keys().cloned().collect();
+        Self { backing, name_set }
+    }
+
+    /// Facts found in either self or other, those in both with their values combined by `merge`
+    ///
+    /// A fact found in both is left out of the result when `merge` returns `None`.
+    pub fn union_with<F>(&self, other: &Self, merge: F) -> Self
+    where
+        F: Fn(&T, &T) -> Option<T>,
+    {
+        let mut backing = self.backing.clone();
+        for (name, fact) in &other.backing {
+            match self.backing.get(name) {
+                Some(ours) => match merge(&ours.value, &fact.value) {
+                    Some(value) => {
+                        backing
+                            .insert(name.clone(), Rc::new(GenericFact::new(name.clone(), value)));
+                    }
+                    None => {
+                        backing.remove(name);
+                    }
+                },
+                None => {
+                    backing.insert(name.clone(), fact.clone());
+                }
+            }
+        }
+        let name_set = backing.keys().cloned().collect();
+        Self { backing, name_set }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: PartialEq> FactSet<T> {
+    /// Facts of self that other lacks or holds another value of
+    pub fn difference(&self, other: &Self) -> Self {
+        let backing: HashMap<String, Rc<GenericFact<T>>> = self
+            .backing
+            .iter()
+            .filter(|(name, fact)| {
+                other
+                    .backing
+                    .get(*name)
+                    .is_none_or(|theirs| theirs.value != fact.value)
+            })
+            .map(|(name, fact)| (name.clone(), fact.clone()))
+            .collect();
+        let name_set = backing.keys().cloned().collect();
+        Self { backing, name_set }
+    }
+}
+
+/// How the values of a fact found in two sets are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    /// The value both can satisfy: flags set only when set in both and the smaller number
+    Least,
+    /// The value either can satisfy: flags set when set in either and the larger number
+    Greatest,
+    /// The value of the first set
+    First,
+}
+
+impl Merge {
+    /// The combined value, `None` when the values can not be combined, such as strings that
+    /// differ under `Least` and `Greatest`
+    pub fn apply(self, a: &FactValue, b: &FactValue) -> Option<FactValue> {
+        match (self, a, b) {
+            (Self::First, a, _) => Some(a.clone()),
+            (Self::Least, FactValue::Bool(a), FactValue::Bool(b)) => {
+                Some(FactValue::Bool(*a && *b))
+            }
+            (Self::Least, FactValue::U64(a), FactValue::U64(b)) => Some(FactValue::U64(*a.min(b))),
+            (Self::Greatest, FactValue::Bool(a), FactValue::Bool(b)) => {
+                Some(FactValue::Bool(*a || *b))
+            }
+            (Self::Greatest, FactValue::U64(a), FactValue::U64(b)) => {
+                Some(FactValue::U64(*a.max(b)))
+            }
+            (_, a, b) if a == b => Some(a.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl FactSet<FactValue> {
+    /// Facts found in either set, those in both combined by `merge`
+    pub fn union(&self, other: &Self, merge: Merge) -> Self {
+        self.union_with(other, |a, b| merge.apply(a, b))
+    }
+
+    /// Facts found in both sets, combined by `merge`
+    pub fn intersection(&self, other: &Self, merge: Merge) -> Self {
+        self.intersection_with(other, |a, b| merge.apply(a, b))
+    }
+}
+
+#[cfg(feature = "std")]
 impl<T: PartialEq + Eq + Hash> From<Vec<GenericFact<T>>> for FactSet<T> {
     fn from(f: Vec<GenericFact<T>>) -> Self {
         let backing: HashMap<String, Rc<GenericFact<T>>> = f
@@ -158,9 +638,10 @@ impl<T: PartialEq + Eq + Hash> From<Vec<GenericFact<T>>> for FactSet<T> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod fact_set_tests {
     use super::*;
+    use std::convert::TryFrom;
 
     type FactTest = GenericFact<u16>;
 
@@ -204,6 +685,99 @@ mod fact_set_tests {
         );
     }
     #[test]
+    fn test_path_matcher() {
+        let matcher = PathMatcher::new(["cpuid/*/avx*", "msr/**"]).unwrap();
+        assert!(matcher.matches("cpuid/Model/avx2"));
+        assert!(!matcher.matches("cpuid/Model/ecx/avx2"));
+        assert!(!matcher.matches("cpuid/Model/AVX2"));
+        assert!(matcher.matches("msr/Arch Capabilities/MDS_NO"));
+        assert!(matcher.case_sensitive(false).matches("cpuid/Model/AVX2"));
+    }
+    #[test]
+    fn test_intersection_with() {
+        let a: FactSet<u16> = make_set_a().into();
+        let b: FactSet<u16> = make_set_b().into();
+        let result = a.intersection_with(&b, |a, b| if a == b { Some(*a) } else { None });
+        let expected: Vec<FactTest> = vec![("test/c", 3).into(), ("test/d", 3).into()];
+        assert_eq!(result.facts(), expected.iter().collect::<Vec<_>>());
+    }
+    #[test]
+    fn test_union_with() {
+        let a: FactSet<u16> = make_set_a().into();
+        let b: FactSet<u16> = make_set_b().into();
+        let result = a.union_with(&b, |a, b| if a == b { Some(*a) } else { None });
+        let names: Vec<&str> = result.facts().iter().map(|fact| fact.get_name()).collect();
+        assert_eq!(
+            names,
+            ["test/a", "test/b", "test/c", "test/d", "test/f", "test/g"]
+        );
+    }
+    #[test]
+    fn test_difference() {
+        let a: FactSet<u16> = make_set_a().into();
+        let b: FactSet<u16> = make_set_b().into();
+        let expected: Vec<FactTest> = vec![
+            ("test/a", 0).into(),
+            ("test/b", 1).into(),
+            ("test/e", 3).into(),
+        ];
+        assert_eq!(
+            a.difference(&b).facts(),
+            expected.iter().collect::<Vec<_>>()
+        );
+    }
+    #[test]
+    fn test_merge() {
+        let a: FactSet = vec![
+            Fact::new("flag".to_string(), true.into()),
+            Fact::new("count".to_string(), 4u32.into()),
+            Fact::new("vendor".to_string(), "GenuineIntel".to_string().into()),
+        ]
+        .into();
+        let b: FactSet = vec![
+            Fact::new("flag".to_string(), false.into()),
+            Fact::new("count".to_string(), 8u32.into()),
+            Fact::new("vendor".to_string(), "AuthenticAMD".to_string().into()),
+        ]
+        .into();
+        let values = |set: FactSet| -> Vec<(String, FactValue)> {
+            set.facts()
+                .into_iter()
+                .map(|fact| (fact.name.clone(), fact.value.clone()))
+                .collect()
+        };
+        assert_eq!(
+            values(a.intersection(&b, Merge::Least)),
+            [
+                ("count".to_string(), 4u32.into()),
+                ("flag".to_string(), false.into())
+            ]
+        );
+        assert_eq!(
+            values(a.union(&b, Merge::Greatest)),
+            [
+                ("count".to_string(), 8u32.into()),
+                ("flag".to_string(), true.into())
+            ]
+        );
+        assert_eq!(values(a.intersection(&b, Merge::First)), values(a));
+    }
+    #[test]
+    fn test_differences() {
+        let a: FactSet<u16> = make_set_a().into();
+        let b: FactSet<u16> = make_set_b().into();
+        let c: FactSet<u16> = make_set_a().into();
+        let sets = [a, b, c];
+        let result: BTreeMap<&str, _> = FactSet::differences(&sets)
+            .into_iter()
+            .map(|(name, values)| (name.as_str(), values))
+            .collect();
+        let names: Vec<&str> = result.keys().copied().collect();
+        assert_eq!(names, ["test/a", "test/b", "test/e", "test/f", "test/g"]);
+        assert_eq!(result["test/e"], [Some(&3), Some(&2), Some(&3)]);
+        assert_eq!(result["test/f"], [None, Some(&4), None]);
+    }
+    #[test]
     fn test_changed() {
         let a: FactSet<u16> = make_set_a().into();
         let b: FactSet<u16> = make_set_b().into();
@@ -213,4 +787,67 @@ mod fact_set_tests {
             HashSet::from([(&("test/e", 3).into(), &("test/e", 2).into()),])
         );
     }
+    #[test]
+    fn fact_value_serde() {
+        let facts: Vec<Fact> = vec![
+            ("cpuid/Start/type", FactValue::from("GenuineIntel")).into(),
+            ("cpuid/Start/max_leaves", FactValue::from(0x20u32)).into(),
+            ("cpuid/Feature Information/ecx/AVX", FactValue::from(true)).into(),
+        ];
+        let text = serde_yaml::to_string(&facts).unwrap();
+        assert!(text.contains("value: 32\n"));
+        assert_eq!(serde_yaml::from_str::<Vec<Fact>>(&text).unwrap(), facts);
+        assert_eq!(
+            serde_json::Value::from(facts[2].value.clone()),
+            serde_json::Value::Bool(true)
+        );
+        assert_eq!(facts[0].value.to_string(), "GenuineIntel");
+    }
+    #[test]
+    fn fact_tree() {
+        let facts: Vec<Fact> = vec![
+            ("cpuid/Start/type", FactValue::from("GenuineIntel")).into(),
+            ("cpuid/Start/max_leaves", FactValue::from(0x20u32)).into(),
+            ("msr/IA32_ARCH_CAPABILITIES/RDCL_NO", FactValue::from(true)).into(),
+        ];
+        let tree = FactTree::try_from(facts.clone()).unwrap();
+        assert_eq!(
+            tree.get("cpuid/Start/max_leaves"),
+            Some(&FactTree::Value(0x20u32.into()))
+        );
+        assert!(tree.get("cpuid/Start/type/vendor").is_none());
+
+        let text = serde_yaml::to_string(&tree).unwrap();
+        assert!(text.contains("cpuid:\n  Start:\n    max_leaves: 32\n"));
+        let loaded: FactTree = serde_yaml::from_str(&text).unwrap();
+        let mut sorted = facts;
+        sorted.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(loaded.flatten(), sorted);
+
+        let mut tree = loaded;
+        assert!(tree
+            .insert(("cpuid/Start/type/vendor", FactValue::from(true)).into())
+            .is_err());
+        assert!(tree
+            .insert(("cpuid/Start", FactValue::from(true)).into())
+            .is_err());
+    }
+    #[test]
+    fn facts_file_layouts() {
+        let facts: Vec<Fact> = vec![("cpuid/Start/max_leaves", FactValue::from(0x20u32)).into()];
+        let file = FactsFile::new(facts.clone());
+        let loaded = FactsFile::parse(&serde_json::to_string(&file).unwrap()).unwrap();
+        assert_eq!(loaded, file);
+
+        let bare = FactsFile::parse("- {name: cpuid/Start/max_leaves, value: 32}\n").unwrap();
+        assert_eq!((bare.version, bare.facts), (0, facts.clone()));
+        let tree = FactsFile::parse("cpuid: {Start: {max_leaves: 32}}\n").unwrap();
+        assert_eq!(tree.facts, facts);
+
+        let newer = "version: 2\ngenerator: cpuinfo 9.0.0\nfacts: []\n";
+        assert!(matches!(
+            FactsFile::parse(newer),
+            Err(crate::Error::FactsVersion(2))
+        ));
+    }
 }
@@ -0,0 +1,89 @@
+//! A C interface to the fact collection
+//!
+//! Lets C programs collect the facts of the running cpu without spawning `cpuinfo facts` and
+//! parsing its output. Facts are returned as the JSON list under `facts` in the output of
+//! `cpuinfo facts -o json`, and strings must be released with `cpuinfo_free`. `cbindgen` writes
+//! the matching declarations to `include/cpuinfo.h` when the crate is built with the `ffi`
+//! feature.
+
+use super::facts::Fact;
+use super::Collector;
+use core_affinity::CoreId;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic;
+use std::ptr;
+use std::thread;
+
+/// The cpu both CPUID and the MSRs are read from
+const CPU: usize = 0;
+
+fn facts_json() -> Option<String> {
+    // CPUID answers for the cpu the thread runs on, so the facts are collected on a thread of
+    // their own pinned to the cpu the MSRs are read from, leaving the caller's affinity alone
+    thread::spawn(|| {
+        if !core_affinity::set_for_current(CoreId { id: CPU }) {
+            return None;
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "use_msr"))]
+        let collector = match super::msr::linux::LinuxMsrStore::detect(CPU) {
+            Ok(store) => Collector::new().with_msr(store),
+            // MSRs need msr-safe or root and the msr module, the cpuid facts are still worth having
+            Err(_) => Collector::new(),
+        };
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64", feature = "use_msr")))]
+        let collector = Collector::new();
+
+        let facts: Vec<Fact> = collector.collect().ok()?;
+        serde_json::to_string(&facts).ok()
+    })
+    .join()
+    .ok()?
+}
+
+/// Collect the facts of cpu 0 with the built-in config as a JSON list of
+/// `{"name": ..., "value": ...}` objects
+///
+/// Returns NULL when the facts could not be collected, or cpu 0 could not be run on. The string
+/// is owned by the caller and must be released with `cpuinfo_free`.
+#[no_mangle]
+pub extern "C" fn cpuinfo_collect_facts_json() -> *mut c_char {
+    // Unwinding into C is undefined behaviour
+    match panic::catch_unwind(facts_json) {
+        Ok(Some(json)) => CString::new(json).map_or(ptr::null_mut(), CString::into_raw),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Release a string returned by this library, NULL is ignored
+///
+/// # Safety
+///
+/// `string` must be NULL or a pointer returned by a `cpuinfo_` function that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn cpuinfo_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn collect_and_free() {
+        let json = cpuinfo_collect_facts_json();
+        assert!(!json.is_null());
+        let facts: Vec<Fact> =
+            serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        assert!(facts.iter().all(|fact| fact.name.starts_with("cpuid/")
+            || fact.name.starts_with("msr/")
+            || fact.name.starts_with("hypervisor/")));
+        unsafe {
+            cpuinfo_free(json);
+            cpuinfo_free(ptr::null_mut());
+        }
+    }
+}
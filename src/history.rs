@@ -0,0 +1,266 @@
+//! A record of fact collections over time, kept in a SQLite file
+//!
+//! Each collection is stored whole along with the host it came from, the cpu when it was
+//! collected from one and when it was collected. [`History::changes`] compares each collection
+//! with the one before it from the same host and cpu, giving when each fact changed rather than
+//! having to diff dated fact files pairwise.
+
+use super::facts::{Fact, FactSet, FactValue, PathMatcher};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("History database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// A stored value is not one a fact can hold
+    #[error("Unable to parse stored value: {0}")]
+    Value(#[from] serde_json::Error),
+}
+
+/// A change of one fact between two collections from a host
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Seconds since the unix epoch of the collection the change was seen in
+    pub timestamp: u64,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u64>,
+    pub name: String,
+    /// `None` when the fact was added
+    pub from: Option<FactValue>,
+    /// `None` when the fact was removed
+    pub to: Option<FactValue>,
+}
+
+/// The collections recorded in a SQLite file
+pub struct History {
+    connection: Connection,
+}
+
+impl History {
+    /// Open the history at `path`, creating it when it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        Self::new(Connection::open(path).map_err(Error::Sqlite)?)
+    }
+
+    /// A history held in memory, gone once dropped
+    pub fn in_memory() -> crate::Result<Self> {
+        Self::new(Connection::open_in_memory().map_err(Error::Sqlite)?)
+    }
+
+    fn new(connection: Connection) -> crate::Result<Self> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS collections (
+                     id INTEGER PRIMARY KEY,
+                     host TEXT NOT NULL,
+                     cpu INTEGER,
+                     timestamp INTEGER NOT NULL
+                 );
+                 CREATE INDEX IF NOT EXISTS collections_by_time
+                     ON collections (host, cpu, timestamp);
+                 CREATE TABLE IF NOT EXISTS facts (
+                     collection INTEGER NOT NULL REFERENCES collections (id),
+                     name TEXT NOT NULL,
+                     value TEXT NOT NULL,
+                     PRIMARY KEY (collection, name)
+                 );",
+            )
+            .map_err(Error::Sqlite)?;
+        Ok(Self { connection })
+    }
+
+    /// Store the facts of a collection from `host`, `cpu` when it was collected from one
+    pub fn record(
+        &mut self,
+        host: &str,
+        cpu: Option<u64>,
+        timestamp: u64,
+        facts: &[Fact],
+    ) -> crate::Result<()> {
+        Ok(self.insert(host, cpu, timestamp, facts)?)
+    }
+
+    fn insert(
+        &mut self,
+        host: &str,
+        cpu: Option<u64>,
+        timestamp: u64,
+        facts: &[Fact],
+    ) -> Result<(), Error> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO collections (host, cpu, timestamp) VALUES (?1, ?2, ?3)",
+            params![host, cpu, timestamp],
+        )?;
+        let collection = transaction.last_insert_rowid();
+        {
+            let mut insert = transaction.prepare(
+                "INSERT OR REPLACE INTO facts (collection, name, value) VALUES (?1, ?2, ?3)",
+            )?;
+            for fact in facts {
+                insert.execute(params![
+                    collection,
+                    fact.name,
+                    serde_json::to_string(&fact.value)?
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// The changes seen in collections from `since` on, of the facts `matcher` matches
+    ///
+    /// The first collection from `since` on is compared with the last one before it, so a change
+    /// is reported at the first collection it was seen in. An empty `matcher` matches every fact.
+    pub fn changes(&self, since: u64, matcher: &PathMatcher) -> crate::Result<Vec<Change>> {
+        Ok(self.select_changes(since, matcher)?)
+    }
+
+    fn select_changes(&self, since: u64, matcher: &PathMatcher) -> Result<Vec<Change>, Error> {
+        let mut sources = self.connection.prepare(
+            "SELECT DISTINCT host, cpu FROM collections WHERE timestamp >= ?1 ORDER BY host, cpu",
+        )?;
+        let sources = sources
+            .query_map([since], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, Option<u64>)>, _>>()?;
+
+        let mut changes = Vec::new();
+        for (host, cpu) in sources {
+            let mut previous = self
+                .connection
+                .query_row(
+                    "SELECT id FROM collections WHERE host = ?1 AND cpu IS ?2 AND timestamp < ?3
+                     ORDER BY timestamp DESC, id DESC LIMIT 1",
+                    params![host, cpu, since],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .map(|id| self.facts(id, matcher))
+                .transpose()?;
+            let mut collections = self.connection.prepare(
+                "SELECT id, timestamp FROM collections
+                 WHERE host = ?1 AND cpu IS ?2 AND timestamp >= ?3 ORDER BY timestamp, id",
+            )?;
+            let collections = collections
+                .query_map(params![host, cpu, since], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<Result<Vec<(i64, u64)>, _>>()?;
+            for (id, timestamp) in collections {
+                let current = self.facts(id, matcher)?;
+                if let Some(previous) = &previous {
+                    let change =
+                        |name: &str, from: Option<&FactValue>, to: Option<&FactValue>| Change {
+                            timestamp,
+                            host: host.clone(),
+                            cpu,
+                            name: name.to_string(),
+                            from: from.cloned(),
+                            to: to.cloned(),
+                        };
+                    let mut seen: Vec<Change> = previous
+                        .removed_facts(&current)
+                        .map(|fact| change(&fact.name, Some(&fact.value), None))
+                        .chain(
+                            previous
+                                .added_facts(&current)
+                                .map(|fact| change(&fact.name, None, Some(&fact.value))),
+                        )
+                        .chain(previous.changed_facts(&current).map(|(from, to)| {
+                            change(&from.name, Some(&from.value), Some(&to.value))
+                        }))
+                        .collect();
+                    seen.sort_by(|a, b| a.name.cmp(&b.name));
+                    changes.append(&mut seen);
+                }
+                previous = Some(current);
+            }
+        }
+        changes.sort_by_key(|change| change.timestamp);
+        Ok(changes)
+    }
+
+    fn facts(&self, collection: i64, matcher: &PathMatcher) -> Result<FactSet, Error> {
+        let mut select = self
+            .connection
+            .prepare_cached("SELECT name, value FROM facts WHERE collection = ?1")?;
+        let rows = select
+            .query_map([collection], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+        let mut facts = Vec::new();
+        for (name, value) in rows {
+            if matcher.is_empty() || matcher.matches(&name) {
+                facts.push(Fact::new(name, serde_json::from_str(&value)?));
+            }
+        }
+        Ok(facts.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fact(name: &str, value: FactValue) -> Fact {
+        Fact::new(name.to_string(), value)
+    }
+
+    #[test]
+    fn changes_over_time() {
+        let mut history = History::in_memory().unwrap();
+        let microcode = |revision: u32| fact("cpuid/version/microcode", revision.into());
+        history
+            .record(
+                "host1",
+                Some(0),
+                100,
+                &[microcode(0x2b), fact("a", true.into())],
+            )
+            .unwrap();
+        history
+            .record(
+                "host1",
+                Some(0),
+                200,
+                &[microcode(0x2b), fact("a", true.into())],
+            )
+            .unwrap();
+        history
+            .record("host1", Some(0), 300, &[microcode(0x2c)])
+            .unwrap();
+        history
+            .record("host2", None, 300, &[microcode(0x2b)])
+            .unwrap();
+
+        let all = history.changes(150, &PathMatcher::default()).unwrap();
+        assert_eq!(
+            all,
+            [
+                Change {
+                    timestamp: 300,
+                    host: "host1".to_string(),
+                    cpu: Some(0),
+                    name: "a".to_string(),
+                    from: Some(true.into()),
+                    to: None,
+                },
+                Change {
+                    timestamp: 300,
+                    host: "host1".to_string(),
+                    cpu: Some(0),
+                    name: "cpuid/version/microcode".to_string(),
+                    from: Some(0x2bu32.into()),
+                    to: Some(0x2cu32.into()),
+                },
+            ]
+        );
+
+        let matcher = PathMatcher::new(["cpuid/**"]).unwrap();
+        assert_eq!(history.changes(150, &matcher).unwrap().len(), 1);
+        assert!(history.changes(301, &matcher).unwrap().is_empty());
+    }
+}
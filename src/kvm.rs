@@ -1,9 +1,47 @@
+use crate::bitfield;
+use crate::config::Definition;
+use crate::facts::{self, Fact, FactSet};
+use crate::layout::DisplayLeaf;
 use crate::msr::{self, MSRValue, MsrStore};
 
-use super::CpuidDB;
-use core::arch::x86_64::CpuidResult;
-use kvm_bindings::{kvm_msr_entry, Msrs, KVM_CPUID_FLAG_SIGNIFCANT_INDEX, KVM_MAX_CPUID_ENTRIES};
-use std::error::Error;
+use super::{CpuidDB, CpuidFunction, CpuidResult, FnCpuidDB};
+use kvm_bindings::{
+    kvm_cpuid2, kvm_cpuid_entry2, kvm_enable_cap, kvm_msr_entry, Msrs, KVMIO, KVM_CAP_HYPERV_CPUID,
+    KVM_CAP_SPLIT_IRQCHIP, KVM_CPUID_FLAG_SIGNIFCANT_INDEX, KVM_MAX_CPUID_ENTRIES,
+};
+use std::fs::{self, File};
+use std::io;
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use vmm_sys_util::ioctl::{ioctl_expr, ioctl_with_mut_ptr, _IOC_READ, _IOC_WRITE};
+
+const KVM_GET_CPUID2: c_ulong = ioctl_expr(
+    _IOC_READ | _IOC_WRITE,
+    KVMIO,
+    0x91,
+    std::mem::size_of::<kvm_cpuid2>() as u32,
+);
+const KVM_GET_SUPPORTED_HV_CPUID: c_ulong = ioctl_expr(
+    _IOC_READ | _IOC_WRITE,
+    KVMIO,
+    0xc1,
+    std::mem::size_of::<kvm_cpuid2>() as u32,
+);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("KVM error: {0}")]
+    Ioctl(#[from] kvm_ioctls::Error),
+    /// KVM returned more entries than fit the array passed to it
+    #[error("KVM error: {0}")]
+    Fam(#[from] vmm_sys_util::fam::Error),
+    #[error("Process {pid} has no vcpu {index}")]
+    NoVcpu { pid: u32, index: usize },
+    #[error("IOError: {0}")]
+    IOError(#[from] io::Error),
+    #[error("KVM does not support {0}")]
+    Unsupported(&'static str),
+}
 
 /** Wrap information from kvm
  *
@@ -14,42 +52,280 @@ pub struct KvmInfo {
 }
 
 impl KvmInfo {
-    pub fn new(kvm: &kvm_ioctls::Kvm) -> Result<Self, kvm_ioctls::Error> {
-        let cpuid_info = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+    pub fn new(kvm: &kvm_ioctls::Kvm) -> crate::Result<Self> {
+        let cpuid_info = kvm
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(Error::Ioctl)?;
         Ok(Self { cpuid_info })
     }
+
+    /// The entries as KVM returned them
+    pub fn cpuid(&self) -> &kvm_bindings::fam_wrappers::CpuId {
+        &self.cpuid_info
+    }
+}
+
+/** The cpuid to set on a vcpu for a guest limited to `baseline`, such as the facts written by
+ * `cpuinfo baseline`
+ *
+ * The entries of `supported`, usually KVM_GET_SUPPORTED_CPUID, are kept with every flag of the
+ * config's leaves cleared unless it is set in `baseline`. Flags KVM does not support stay clear,
+ * other fields and the leaves the config does not describe are passed on as they are.
+ */
+pub fn baseline_cpuid(
+    config: &Definition,
+    supported: &kvm_bindings::fam_wrappers::CpuId,
+    baseline: &FactSet,
+) -> kvm_bindings::fam_wrappers::CpuId {
+    let mut cpuid = supported.clone();
+    let leaves = config.cpu_leaves(&FnCpuidDB(|leaf, sub_leaf| {
+        find_cpuid(supported, leaf, sub_leaf)
+    }));
+    for (leaf, desc) in leaves {
+        for location in desc.field_locations() {
+            let bit = match location.field {
+                bitfield::Field::Flag(flag) => flag.bit,
+                _ => continue,
+            };
+            let path = format!("cpuid/{}/{}", desc.name(), location.path);
+            if baseline
+                .get(&path)
+                .is_some_and(|fact| fact.value.as_bool() == Some(true))
+            {
+                continue;
+            }
+            // A flag past the register's width is a config mistake with nothing to clear
+            let mask = match 1u32.checked_shl(u32::from(bit)) {
+                Some(mask) => mask,
+                None => continue,
+            };
+            let entry = cpuid
+                .as_mut_slice()
+                .iter_mut()
+                .find(|entry| entry_matches(entry, leaf, location.sub_leaf));
+            if let Some(entry) = entry {
+                let register = match location.register {
+                    "eax" => &mut entry.eax,
+                    "ebx" => &mut entry.ebx,
+                    "ecx" => &mut entry.ecx,
+                    _ => &mut entry.edx,
+                };
+                *register &= !mask;
+            }
+        }
+    }
+    cpuid
 }
 
 impl CpuidDB for KvmInfo {
     fn get_cpuid(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
-        self.cpuid_info.as_slice().iter().find_map(|entry| {
-            if entry.function == leaf {
-                if (subleaf == 0 && (entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX) == 0)
-                    || (subleaf == entry.index)
-                {
-                    Some(CpuidResult {
-                        eax: entry.eax,
-                        ebx: entry.ebx,
-                        ecx: entry.ecx,
-                        edx: entry.edx,
-                    })
-                } else {
-                    None
+        find_cpuid(&self.cpuid_info, leaf, subleaf)
+    }
+}
+
+/** The cpuid features KVM emulates in software rather than passing through from the host
+ *
+ * These can be offered to a guest even when the host cpu lacks them, at the cost of a VM exit
+ * on each use.
+ */
+pub struct KvmEmulatedInfo {
+    cpuid_info: kvm_bindings::fam_wrappers::CpuId,
+}
+
+impl KvmEmulatedInfo {
+    pub fn new(kvm: &kvm_ioctls::Kvm) -> crate::Result<Self> {
+        let cpuid_info = kvm
+            .get_emulated_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(Error::Ioctl)?;
+        Ok(Self { cpuid_info })
+    }
+}
+
+impl CpuidDB for KvmEmulatedInfo {
+    fn get_cpuid(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        find_cpuid(&self.cpuid_info, leaf, subleaf)
+    }
+}
+
+/** The cpuid a VMM has set on a vcpu of a running VM, see `KVM_GET_CPUID2`
+ *
+ * This is what the guest sees, after the VMM has filtered what KVM supports and applied its cpu
+ * model.
+ */
+pub struct KvmVcpuInfo {
+    cpuid_info: kvm_bindings::fam_wrappers::CpuId,
+}
+
+impl KvmVcpuInfo {
+    /// Read the cpuid of an open vcpu fd
+    pub fn from_vcpu<F: AsRawFd>(vcpu: &F) -> crate::Result<Self> {
+        let mut cpuid_info =
+            kvm_bindings::fam_wrappers::CpuId::new(KVM_MAX_CPUID_ENTRIES).map_err(Error::Fam)?;
+        // SAFETY: the kernel writes at most nent entries, which the CpuId was allocated with
+        let ret =
+            unsafe { ioctl_with_mut_ptr(vcpu, KVM_GET_CPUID2, cpuid_info.as_mut_fam_struct_ptr()) };
+        if ret < 0 {
+            return Err(Error::IOError(io::Error::last_os_error()).into());
+        }
+        Ok(Self { cpuid_info })
+    }
+
+    /// Read the cpuid of vcpu `index` of the VM run by process `pid`, e.g. a QEMU process
+    ///
+    /// The vcpu fd is duplicated from the process with pidfd_getfd(2), this needs Linux 5.6 and
+    /// ptrace access to the process, usually root.
+    pub fn from_pid(pid: u32, index: usize) -> crate::Result<Self> {
+        let wanted = format!("anon_inode:kvm-vcpu:{}", index);
+        let fd_dir = format!("/proc/{}/fd", pid);
+        let target_fd = fs::read_dir(&fd_dir)
+            .map_err(Error::IOError)?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| {
+                fs::read_link(entry.path()).is_ok_and(|link| link.as_os_str() == wanted.as_str())
+            })
+            .and_then(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+            .ok_or(Error::NoVcpu { pid, index })?;
+
+        // SAFETY: plain syscalls, each returned fd is checked and then owned by a File
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if pidfd < 0 {
+            return Err(Error::IOError(io::Error::last_os_error()).into());
+        }
+        let pidfd = unsafe { File::from_raw_fd(pidfd as i32) };
+        let vcpu = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), target_fd, 0) };
+        if vcpu < 0 {
+            return Err(Error::IOError(io::Error::last_os_error()).into());
+        }
+        let vcpu = unsafe { File::from_raw_fd(vcpu as i32) };
+        Self::from_vcpu(&vcpu)
+    }
+}
+
+/// How to set up the scratch VM and vcpu of `KvmVcpuInfo::scratch`
+#[derive(Debug, Clone, Default)]
+pub struct ScratchOptions {
+    /// KVM_CAP_* numbers to enable on the VM before its vcpu is created, with their first argument
+    pub vm_caps: Vec<(u32, u64)>,
+    /// KVM_CAP_* numbers to enable on the vcpu, such as the Hyper-V SynIC, with their first argument
+    pub vcpu_caps: Vec<(u32, u64)>,
+    /// Present the Hyper-V leaves KVM supports at 0x40000000, moving KVM's own a block higher as
+    /// QEMU does with its `hv-*` enlightenments
+    pub hyperv: bool,
+}
+
+fn enable_cap(cap: u32, arg: u64) -> kvm_enable_cap {
+    let mut enable = kvm_enable_cap {
+        cap,
+        ..Default::default()
+    };
+    enable.args[0] = arg;
+    enable
+}
+
+impl KvmVcpuInfo {
+    /// The cpuid a guest of a VMM passing on everything KVM supports would see
+    ///
+    /// This creates a VM with one vcpu and an in-kernel irqchip, enables the capabilities of
+    /// `options` and sets KVM_GET_SUPPORTED_CPUID on the vcpu with KVM_SET_CPUID2, then reads
+    /// back what KVM kept. Unlike `KvmInfo` this includes what the enabled capabilities and the
+    /// vcpu's own state change.
+    pub fn scratch(kvm: &kvm_ioctls::Kvm, options: &ScratchOptions) -> crate::Result<Self> {
+        let vm = kvm.create_vm().map_err(Error::Ioctl)?;
+        for (cap, arg) in &options.vm_caps {
+            vm.enable_cap(&enable_cap(*cap, *arg))
+                .map_err(Error::Ioctl)?;
+        }
+        // A split irqchip leaves the IOAPIC and PIC to the VMM
+        if !options
+            .vm_caps
+            .iter()
+            .any(|(cap, _)| *cap == KVM_CAP_SPLIT_IRQCHIP)
+        {
+            vm.create_irq_chip().map_err(Error::Ioctl)?;
+        }
+        let vcpu = vm.create_vcpu(0).map_err(Error::Ioctl)?;
+        for (cap, arg) in &options.vcpu_caps {
+            vcpu.enable_cap(&enable_cap(*cap, *arg))
+                .map_err(Error::Ioctl)?;
+        }
+
+        let mut cpuid = kvm
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(Error::Ioctl)?;
+        if options.hyperv {
+            if kvm.check_extension_raw(KVM_CAP_HYPERV_CPUID as c_ulong) <= 0 {
+                return Err(Error::Unsupported("the Hyper-V cpuid leaves").into());
+            }
+            let mut hv_cpuid = kvm_bindings::fam_wrappers::CpuId::new(KVM_MAX_CPUID_ENTRIES)
+                .map_err(Error::Fam)?;
+            // SAFETY: the kernel writes at most nent entries, which the CpuId was allocated with
+            let ret = unsafe {
+                ioctl_with_mut_ptr(
+                    &vcpu,
+                    KVM_GET_SUPPORTED_HV_CPUID,
+                    hv_cpuid.as_mut_fam_struct_ptr(),
+                )
+            };
+            if ret < 0 {
+                return Err(Error::IOError(io::Error::last_os_error()).into());
+            }
+            let kvm_base = CpuidFunction::Hypervisor.start_eax();
+            for entry in cpuid.as_mut_slice() {
+                if (kvm_base..kvm_base + 0x100).contains(&entry.function) {
+                    entry.function += 0x100;
+                    if entry.function == kvm_base + 0x100 {
+                        entry.eax += 0x100;
+                    }
                 }
-            } else {
-                None
             }
-        })
+            for entry in hv_cpuid.as_slice() {
+                cpuid.push(*entry).map_err(Error::Fam)?;
+            }
+        }
+        vcpu.set_cpuid2(&cpuid).map_err(Error::Ioctl)?;
+        Self::from_vcpu(&vcpu)
     }
 }
 
+impl CpuidDB for KvmVcpuInfo {
+    fn get_cpuid(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        find_cpuid(&self.cpuid_info, leaf, subleaf)
+    }
+}
+
+fn find_cpuid(
+    cpuid_info: &kvm_bindings::fam_wrappers::CpuId,
+    leaf: u32,
+    subleaf: u32,
+) -> Option<CpuidResult> {
+    cpuid_info.as_slice().iter().find_map(|entry| {
+        if entry_matches(entry, leaf, subleaf) {
+            Some(CpuidResult {
+                eax: entry.eax,
+                ebx: entry.ebx,
+                ecx: entry.ecx,
+                edx: entry.edx,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether `entry` answers `subleaf` of `leaf`, entries that ignore the sub-leaf answer sub-leaf 0
+fn entry_matches(entry: &kvm_cpuid_entry2, leaf: u32, subleaf: u32) -> bool {
+    entry.function == leaf
+        && ((subleaf == 0 && (entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX) == 0)
+            || (subleaf == entry.index))
+}
+
 pub struct KvmMsrInfo {
     msr_info: kvm_bindings::Msrs,
 }
 
 impl KvmMsrInfo {
-    pub fn new(kvm: &kvm_ioctls::Kvm) -> Result<Self, Box<dyn Error>> {
-        let msr_features = kvm.get_msr_feature_index_list()?;
+    pub fn new(kvm: &kvm_ioctls::Kvm) -> crate::Result<Self> {
+        let msr_features = kvm.get_msr_feature_index_list().map_err(Error::Ioctl)?;
         let mut msrs = Msrs::from_entries(
             &msr_features
                 .as_slice()
@@ -59,12 +335,23 @@ impl KvmMsrInfo {
                     ..Default::default()
                 })
                 .collect::<Vec<_>>(),
-        )?;
-        kvm.get_msrs(&mut msrs)?;
+        )
+        .map_err(Error::Fam)?;
+        kvm.get_msrs(&mut msrs).map_err(Error::Ioctl)?;
         Ok(KvmMsrInfo { msr_info: msrs })
     }
 }
 
+impl KvmMsrInfo {
+    /// The address and value of each feature MSR KVM reports
+    pub fn entries(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.msr_info
+            .as_slice()
+            .iter()
+            .map(|entry| (entry.index, entry.data))
+    }
+}
+
 impl MsrStore for KvmMsrInfo {
     fn is_empty(&self) -> bool {
         false
@@ -72,20 +359,177 @@ impl MsrStore for KvmMsrInfo {
     fn get_value<'a>(
         &self,
         desc: &'a crate::msr::MSRDesc,
-    ) -> std::result::Result<crate::msr::MSRValue<'a>, crate::msr::Error> {
+    ) -> crate::Result<crate::msr::MSRValue<'a>> {
+        find_msr(&self.msr_info, desc)
+    }
+}
+
+fn find_msr<'a>(msrs: &Msrs, desc: &'a msr::MSRDesc) -> crate::Result<MSRValue<'a>> {
+    msrs.as_slice()
+        .iter()
+        .find_map(|entry| {
+            if entry.index == desc.address {
+                Some(MSRValue {
+                    desc,
+                    value: entry.data,
+                })
+            } else {
+                None
+            }
+        })
+        .ok_or_else(|| msr::Error::NotAvailible("/dev/kvm".to_string()).into())
+}
+
+/** The MSRs KVM saves and restores for a vcpu, see `KVM_GET_MSR_INDEX_LIST`
+ *
+ * Unlike the feature MSRs of `KvmMsrInfo` these hold the state of a vcpu. They are read from a
+ * scratch vcpu with the cpuid KVM supports set on it, so they hold the values a new guest
+ * starts with. MSRs that fail to read on it are left out.
+ */
+pub struct KvmStateMsrInfo {
+    msr_info: Msrs,
+}
+
+impl KvmStateMsrInfo {
+    pub fn new(kvm: &kvm_ioctls::Kvm) -> crate::Result<Self> {
+        let indices = kvm.get_msr_index_list().map_err(Error::Ioctl)?;
+        let vm = kvm.create_vm().map_err(Error::Ioctl)?;
+        vm.create_irq_chip().map_err(Error::Ioctl)?;
+        let vcpu = vm.create_vcpu(0).map_err(Error::Ioctl)?;
+        let cpuid = kvm
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(Error::Ioctl)?;
+        vcpu.set_cpuid2(&cpuid).map_err(Error::Ioctl)?;
+
+        // KVM_GET_MSRS stops at the first MSR that fails, so each is read on its own
+        let mut entries = Vec::new();
+        for &index in indices.as_slice() {
+            let mut msrs = Msrs::from_entries(&[kvm_msr_entry {
+                index,
+                ..Default::default()
+            }])
+            .map_err(Error::Fam)?;
+            if vcpu.get_msrs(&mut msrs).map_err(Error::Ioctl)? == 1 {
+                entries.push(msrs.as_slice()[0]);
+            }
+        }
+        let msr_info = Msrs::from_entries(&entries).map_err(Error::Fam)?;
+        Ok(Self { msr_info })
+    }
+
+    /// The address and value of each MSR read from the scratch vcpu
+    pub fn entries(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
         self.msr_info
             .as_slice()
             .iter()
-            .find_map(|entry| {
-                if entry.index == desc.address {
-                    Some(MSRValue {
-                        desc,
-                        value: entry.data,
-                    })
-                } else {
-                    None
-                }
+            .map(|entry| (entry.index, entry.data))
+    }
+}
+
+impl MsrStore for KvmStateMsrInfo {
+    fn is_empty(&self) -> bool {
+        false
+    }
+    fn get_value<'a>(
+        &self,
+        desc: &'a crate::msr::MSRDesc,
+    ) -> crate::Result<crate::msr::MSRValue<'a>> {
+        find_msr(&self.msr_info, desc)
+    }
+}
+
+/// The results of KVM_CHECK_EXTENSION for a set of named capabilities
+///
+/// Most capabilities report 1 when supported, some report a limit such as the maximum vcpus.
+/// 0 means the capability is not supported.
+pub struct KvmCaps {
+    caps: Vec<(String, u32)>,
+}
+
+impl KvmCaps {
+    /// Check each of `caps`, given as a name and its KVM_CAP_* number
+    pub fn new<'a, I>(kvm: &kvm_ioctls::Kvm, caps: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a String, &'a u32)>,
+    {
+        let caps = caps
+            .into_iter()
+            .map(|(name, cap)| {
+                let result = kvm.check_extension_raw(*cap as c_ulong);
+                (name.clone(), result.max(0) as u32)
             })
-            .ok_or_else(|| msr::Error::NotAvailible("/dev/kvm".to_string()))
+            .collect();
+        Self { caps }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.caps
+            .iter()
+            .find(|(cap_name, _)| cap_name == name)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, u32)> {
+        self.caps.iter().map(|(name, value)| (name, *value))
+    }
+}
+
+impl facts::Facter<Fact> for KvmCaps {
+    fn collect_facts(&self) -> Vec<Fact> {
+        self.caps
+            .iter()
+            .map(|(name, value)| {
+                let mut fact = Fact::new(name.clone(), (*value).into());
+                fact.add_path("caps");
+                fact
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mask_to_baseline() {
+        let config: Definition = serde_yaml::from_str(
+            "cpuids:\n  \
+               7:\n    \
+                 name: Features\n    \
+                 data_type:\n      \
+                   type: BitField\n      \
+                   eax: []\n      \
+                   ebx: [{type: Flag, name: AVX2, bit: 5}, {type: Flag, name: AVX512F, bit: 16},\n        \
+                        {type: Flag, name: Wide, bit: 40}]\n      \
+                   ecx: []\n      \
+                   edx: []\n\
+             msrs: []\n",
+        )
+        .unwrap();
+        let entry = |function, index, ebx| kvm_cpuid_entry2 {
+            function,
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            ebx,
+            ..Default::default()
+        };
+        let supported = kvm_bindings::fam_wrappers::CpuId::from_entries(&[
+            entry(7, 0, 1 << 5 | 1 << 16 | 1 << 3),
+            entry(7, 1, 1 << 5),
+        ])
+        .unwrap();
+        let baseline: FactSet = vec![
+            Fact::new("cpuid/Features/ebx/AVX2".to_string(), true.into()),
+            Fact::new("cpuid/Features/ebx/AVX512F".to_string(), false.into()),
+        ]
+        .into();
+        let cpuid = baseline_cpuid(&config, &supported, &baseline);
+        // Bit 3 is not described and sub-leaf 1 is not configured, both pass through
+        assert_eq!(cpuid.as_slice()[0].ebx, 1 << 5 | 1 << 3);
+        assert_eq!(cpuid.as_slice()[1].ebx, 1 << 5);
+
+        let cpuid = baseline_cpuid(&config, &supported, &FactSet::from(Vec::new()));
+        assert_eq!(cpuid.as_slice()[0].ebx, 1 << 3);
     }
 }
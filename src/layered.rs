@@ -0,0 +1,84 @@
+//! Compose several cpuid sources into one
+//!
+//! Each layer is a source with the leaves it may answer. A leaf is looked up in the layers in the
+//! order they were added, the first that covers it and has it answering, so an earlier layer takes
+//! priority. A snapshot pushed over the running cpu replaces the leaves it holds, and a source
+//! limited to some ranges, such as KVM for the hypervisor leaves, falls back to the next layer
+//! for the rest.
+
+use super::{CpuidDB, CpuidResult, CpuidType};
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+struct Layer {
+    source: CpuidType,
+    /// Every leaf when empty
+    leaves: Vec<RangeInclusive<u32>>,
+}
+
+impl Layer {
+    fn covers(&self, leaf: u32) -> bool {
+        self.leaves.is_empty() || self.leaves.iter().any(|range| range.contains(&leaf))
+    }
+}
+
+#[derive(Default)]
+pub struct LayeredCpuidDB {
+    layers: Vec<Layer>,
+}
+
+impl LayeredCpuidDB {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a layer answering any leaf, below the layers already added
+    pub fn push<S: Into<CpuidType>>(&mut self, source: S) {
+        self.push_leaves(source, Vec::new())
+    }
+
+    /// Add a layer only answering the leaves in `leaves`, below the layers already added
+    pub fn push_leaves<S: Into<CpuidType>>(&mut self, source: S, leaves: Vec<RangeInclusive<u32>>) {
+        self.layers.push(Layer {
+            source: source.into(),
+            leaves,
+        })
+    }
+}
+
+impl CpuidDB for LayeredCpuidDB {
+    fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        self.layers
+            .iter()
+            .filter(|layer| layer.covers(leaf))
+            .find_map(|layer| layer.source.get_cpuid(leaf, sub_leaf))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::snapshot::{FileCpuidDB, SnapshotBuilder};
+
+    fn make_db(cpuid: &[(u32, u32)]) -> FileCpuidDB {
+        cpuid
+            .iter()
+            .fold(SnapshotBuilder::new(), |builder, (leaf, eax)| {
+                builder.leaf(*leaf, [*eax, 0, 0, 0])
+            })
+            .db()
+    }
+
+    #[test]
+    fn layer_priority() {
+        let mut db = LayeredCpuidDB::new();
+        db.push_leaves(make_db(&[(0, 7), (7, 0xaa)]), vec![7..=7]);
+        db.push(make_db(&[(0, 1), (1, 0x11), (7, 0x77)]));
+        let eax = |leaf| db.get_cpuid(leaf, 0).map(|result| result.eax);
+        assert_eq!(eax(7), Some(0xaa));
+        // Leaf 0 is outside the range of the first layer
+        assert_eq!(eax(0), Some(1));
+        assert_eq!(eax(1), Some(0x11));
+        assert_eq!(eax(0x80000000), None);
+    }
+}
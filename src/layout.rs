@@ -1,34 +1,55 @@
 //! Provide funcationality to parse and display different cpuid leaf types
 
-use super::facts::{self, GenericFact};
+use super::descriptors;
+use super::expr;
+use super::facts::{self, Fact, FactValue};
+use super::sub_leaf::{Register, SubLeaves};
+use super::topology::TopologyLevel;
 use super::{
     bitfield::{self, Facter},
-    CpuidDB,
+    crystal_tsc_frequency, is_empty_leaf, leaf_signature, register_text, CpuidDB, CpuidResult,
+    FREQUENCY_LEAF,
 };
-use core::arch::x86_64::CpuidResult;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt;
 use enum_dispatch::enum_dispatch;
 use serde::{Deserialize, Serialize};
-use std::fmt;
-use std::string;
-use std::vec::Vec;
 
 #[enum_dispatch]
 pub trait DisplayLeaf {
-    fn scan_sub_leaves<CPUIDFunc: CpuidDB>(&self, leaf: u32, cpuid: &CPUIDFunc)
-        -> Vec<CpuidResult>;
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult>;
     fn display_leaf(
         &self,
         leaf: &[CpuidResult],
         f: &mut fmt::Formatter<'_>,
     ) -> Result<(), fmt::Error>;
-    fn get_facts<T: From<String> + From<u32> + From<bool>>(
-        &self,
-        leaves: &[CpuidResult],
-    ) -> Vec<GenericFact<T>>;
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact>;
+    /// The bit fields described for this leaf, leaves that are not bit fields have none
+    fn field_locations(&self) -> Vec<FieldLocation<'_>> {
+        Vec::new()
+    }
+}
+
+/// Where a bit field is read from within a leaf
+#[derive(Debug, Clone)]
+pub struct FieldLocation<'a> {
+    pub sub_leaf: u32,
+    pub register: &'static str,
+    pub field: &'a bitfield::Field,
+    /// The path of the field's fact below the leaf, such as `ebx/HLE`
+    pub path: String,
 }
 
 ///The first leaf found requires special processing
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StartLeaf {}
 
 impl StartLeaf {
@@ -40,16 +61,12 @@ impl StartLeaf {
             edx,
         } = leaf;
 
-        let bytes = vec![*ebx, *edx, *ecx]
-            .into_iter()
-            .flat_map(|val| Vec::from(val.to_le_bytes()).into_iter())
-            .collect::<Vec<u8>>();
-        ToString::to_string(&string::String::from_utf8_lossy(&bytes))
+        register_text(&[*ebx, *edx, *ecx])
     }
 }
 
 impl DisplayLeaf for StartLeaf {
-    fn scan_sub_leaves<CPUIDFunc: CpuidDB>(
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
         &self,
         leaf: u32,
         cpuid: &CPUIDFunc,
@@ -76,10 +93,7 @@ impl DisplayLeaf for StartLeaf {
         write!(f, "'{}' max leaf:{}", text, max_leaf)
     }
 
-    fn get_facts<T>(&self, leaves: &[CpuidResult]) -> Vec<GenericFact<T>>
-    where
-        T: From<u32> + From<String>,
-    {
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
         let CpuidResult {
             eax: max_leaf,
             ebx: _,
@@ -89,30 +103,61 @@ impl DisplayLeaf for StartLeaf {
         let text = self.get_text(&leaves[0]);
 
         vec![
-            GenericFact::new("max_leaves".into(), max_leaf.into()),
-            GenericFact::new("type".into(), text.into()),
+            Fact::new("max_leaves".into(), max_leaf.into()),
+            Fact::new("type".into(), text.into()),
+        ]
+    }
+}
+
+/// The first hypervisor leaf, its signature is held in ebx, ecx and edx in that order
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HypervisorStartLeaf {}
+
+impl HypervisorStartLeaf {
+    fn get_text(&self, leaf: &CpuidResult) -> String {
+        leaf_signature(leaf)
+    }
+}
+
+impl DisplayLeaf for HypervisorStartLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        StartLeaf {}.scan_sub_leaves(leaf, cpuid)
+    }
+    fn display_leaf(
+        &self,
+        leaf: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        write!(f, "'{}' max leaf:{}", self.get_text(&leaf[0]), leaf[0].eax)
+    }
+
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        vec![
+            Fact::new("max_leaves".into(), leaves[0].eax.into()),
+            Fact::new("type".into(), self.get_text(&leaves[0]).into()),
         ]
     }
 }
 
 /// A leaf that contains a string encoded in 32-bit registers
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StringLeaf {}
 
 impl StringLeaf {
     pub fn get_text(&self, leaf: &CpuidResult) -> String {
         let CpuidResult { eax, ebx, ecx, edx } = leaf;
-        let text = vec![*eax, *ebx, *ecx, *edx]
-            .into_iter()
-            .flat_map(|val| Vec::from(val.to_le_bytes()).into_iter())
-            .collect::<Vec<u8>>();
-
-        ToString::to_string(&String::from_utf8_lossy(&text))
+        register_text(&[*eax, *ebx, *ecx, *edx])
     }
 }
 
 impl DisplayLeaf for StringLeaf {
-    fn scan_sub_leaves<CPUIDFunc: CpuidDB>(
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
         &self,
         leaf: u32,
         cpuid: &CPUIDFunc,
@@ -131,53 +176,334 @@ impl DisplayLeaf for StringLeaf {
         write!(f, "'{}'", text)
     }
 
-    fn get_facts<T>(&self, leaves: &[CpuidResult]) -> Vec<GenericFact<T>>
-    where
-        T: From<String>,
-    {
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
         let text = self.get_text(&leaves[0]);
-        vec![GenericFact::new("value".into(), text.into())]
+        vec![Fact::new("value".into(), text.into())]
+    }
+}
+
+/// A string spread over `leaves` consecutive leaves, such as the brand string of 0x80000002 to
+/// 0x80000004, joined into the single fact `name` with the padding trimmed
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StringSpanLeaf {
+    leaves: u32,
+    name: String,
+}
+
+impl StringSpanLeaf {
+    pub fn new(leaves: u32, name: String) -> Self {
+        Self { leaves, name }
+    }
+
+    pub fn get_text(&self, leaves: &[CpuidResult]) -> String {
+        let text: String = leaves
+            .iter()
+            .map(|leaf| StringLeaf {}.get_text(leaf))
+            .collect();
+        text.trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string()
+    }
+}
+
+impl DisplayLeaf for StringSpanLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        // A span cut short by the end of the range is left out rather than half read
+        let leaves: Vec<CpuidResult> = (leaf..leaf.saturating_add(self.leaves))
+            .map_while(|leaf| cpuid.get_cpuid(leaf, 0))
+            .collect();
+        if leaves.len() == self.leaves as usize {
+            leaves
+        } else {
+            Vec::new()
+        }
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        write!(f, "'{}'", self.get_text(leaves))
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        vec![Fact::new(self.name.clone(), self.get_text(leaves).into())]
     }
 }
 
 /// A leaf that contains a mix of non 32-bit integers and bit sized flags
+///
+/// The registers describe sub-leaf 0. Leaves with more sub-leaves can describe them in
+/// `sub_leaves`, by number or `*` for any other non-empty one, and have them enumerated with the
+/// leaf's strategy from `sub_leaf`, or `enumerate`. Their facts are placed under `subleaf<N>/`,
+/// while those of sub-leaf 0 stay where they are.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct BitFieldLeaf {
     eax: Vec<bitfield::Field>,
     ebx: Vec<bitfield::Field>,
     ecx: Vec<bitfield::Field>,
     edx: Vec<bitfield::Field>,
+    /// Facts computed from the fields, see `expr`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    derived: Vec<expr::Derived>,
+    /// The fields of the sub-leaves after the first
+    #[serde(default, skip_serializing_if = "SubLeafFields::is_empty")]
+    sub_leaves: SubLeafFields,
+    /// How the sub-leaves are enumerated when `sub_leaves` describes any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enumerate: Option<SubLeaves>,
+}
+
+/// The fields of the sub-leaves of a `BitFieldLeaf` after sub-leaf 0, keyed by sub-leaf or by `*`
+/// for the non-empty ones without a key of their own
+///
+/// Only the registers and derived facts of each are decoded, not sub-leaves of their own.
+#[derive(Debug, Default)]
+pub struct SubLeafFields {
+    by_sub_leaf: BTreeMap<u32, BitFieldLeaf>,
+    other: Option<alloc::boxed::Box<BitFieldLeaf>>,
+}
+
+/// The key of `SubLeafFields::other`
+const ANY_SUB_LEAF: &str = "*";
+
+impl SubLeafFields {
+    fn is_empty(&self) -> bool {
+        self.by_sub_leaf.is_empty() && self.other.is_none()
+    }
+
+    /// The fields of `sub_leaf`, read as `result`
+    fn get(&self, sub_leaf: u32, result: &CpuidResult) -> Option<&BitFieldLeaf> {
+        match self.by_sub_leaf.get(&sub_leaf) {
+            Some(fields) => Some(fields),
+            None if is_empty_leaf(result) => None,
+            None => self.other.as_deref(),
+        }
+    }
+
+    fn merge(&mut self, other: SubLeafFields) {
+        for (sub_leaf, fields) in other.by_sub_leaf {
+            match self.by_sub_leaf.get_mut(&sub_leaf) {
+                Some(existing) => existing.merge(fields),
+                None => {
+                    self.by_sub_leaf.insert(sub_leaf, fields);
+                }
+            }
+        }
+        match (&mut self.other, other.other) {
+            (Some(existing), Some(fields)) => existing.merge(*fields),
+            (existing, fields) => *existing = fields.or(existing.take()),
+        }
+    }
+}
+
+impl Serialize for SubLeafFields {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        for (sub_leaf, fields) in &self.by_sub_leaf {
+            map.serialize_entry(sub_leaf, fields)?;
+        }
+        if let Some(fields) = &self.other {
+            map.serialize_entry(ANY_SUB_LEAF, fields)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for SubLeafFields {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = SubLeafFields;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map keyed by sub-leaf numbers or *")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut ret = SubLeafFields::default();
+                while let Some((key, fields)) =
+                    map.next_entry::<bitfield::NumberKey, BitFieldLeaf>()?
+                {
+                    match key {
+                        bitfield::NumberKey::Text(text) if text == ANY_SUB_LEAF => {
+                            ret.other = Some(alloc::boxed::Box::new(fields))
+                        }
+                        key => match key.parse().map_err(serde::de::Error::custom)? {
+                            0 => {
+                                return Err(serde::de::Error::custom(
+                                    "sub-leaf 0 is described by the leaf's own registers",
+                                ))
+                            }
+                            sub_leaf => {
+                                ret.by_sub_leaf.insert(sub_leaf, fields);
+                            }
+                        },
+                    }
+                }
+                Ok(ret)
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+/// Written as a map of `BitFieldLeaf` keyed by sub-leaf or `*`
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for SubLeafFields {
+    fn schema_name() -> String {
+        "SubLeafFields".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        <BTreeMap<String, BitFieldLeaf>>::json_schema(gen)
+    }
 }
 
 impl BitFieldLeaf {
+    /// Merge the fields and derived facts of `other` into these by name, see
+    /// `bitfield::merge_by_name`
+    pub fn merge(&mut self, other: BitFieldLeaf) {
+        fn field_name(field: &bitfield::Field) -> &str {
+            field.name()
+        }
+        bitfield::merge_by_name(&mut self.eax, other.eax, field_name);
+        bitfield::merge_by_name(&mut self.ebx, other.ebx, field_name);
+        bitfield::merge_by_name(&mut self.ecx, other.ecx, field_name);
+        bitfield::merge_by_name(&mut self.edx, other.edx, field_name);
+        bitfield::merge_by_name(&mut self.derived, other.derived, |derived| {
+            derived.name.as_str()
+        });
+        self.sub_leaves.merge(other.sub_leaves);
+        if other.enumerate.is_some() {
+            self.enumerate = other.enumerate;
+        }
+    }
+
+    pub fn new(
+        eax: Vec<bitfield::Field>,
+        ebx: Vec<bitfield::Field>,
+        ecx: Vec<bitfield::Field>,
+        edx: Vec<bitfield::Field>,
+    ) -> Self {
+        Self {
+            eax,
+            ebx,
+            ecx,
+            edx,
+            derived: Vec::new(),
+            sub_leaves: SubLeafFields::default(),
+            enumerate: None,
+        }
+    }
+
+    /// A placeholder description with one flag for every bit set in `leaf`
+    pub fn from_set_bits(leaf: &CpuidResult) -> Self {
+        let flags = |reg: u32| {
+            (0..32u8)
+                .filter(|bit| reg & (1 << bit) != 0)
+                .map(|bit| {
+                    bitfield::Field::Flag(bitfield::Flag {
+                        name: format!("bit{}", bit),
+                        bit,
+                        valid_if: None,
+                        docs: Default::default(),
+                    })
+                })
+                .collect()
+        };
+        Self::new(
+            flags(leaf.eax),
+            flags(leaf.ebx),
+            flags(leaf.ecx),
+            flags(leaf.edx),
+        )
+    }
+
     fn single_reg(
         name: &str,
         reg: u128,
         fields: &Vec<bitfield::Field>,
+        facts: &[Fact],
         f: &mut fmt::Formatter<'_>,
     ) -> Result<(), fmt::Error> {
         writeln!(f, " {}: {:#8x}", name, reg)?;
         for field in fields {
-            writeln!(
-                f,
-                "  {}",
-                bitfield::BoundField::from_register_and_field(reg, field)
-            )?
+            bitfield::write_field(reg, field, facts, f)?;
         }
         Ok(())
     }
+
+    /// The facts of the valid fields, and those derived from them
+    fn derive(&self, leaf: &CpuidResult) -> (Vec<Fact>, Vec<Fact>) {
+        let (fields, mut facts) = self.decode(leaf);
+        bitfield::retain_valid(&fields, &mut facts);
+        let derived = expr::derive_facts(&self.derived, &facts);
+        (facts, derived)
+    }
+
+    /// The fields of every register along with their facts, whether their conditions hold or not
+    fn decode(&self, leaf: &CpuidResult) -> (Vec<&bitfield::Field>, Vec<Fact>) {
+        let CpuidResult { eax, ebx, ecx, edx } = *leaf;
+        [
+            ("eax", eax, &self.eax),
+            ("ebx", ebx, &self.ebx),
+            ("ecx", ecx, &self.ecx),
+            ("edx", edx, &self.edx),
+        ]
+        .iter()
+        .flat_map(|i| i.2.iter().map(move |j| (i.0, i.1.into(), j)))
+        .map(|q| {
+            let mut fact = bitfield::BoundField::from_register_and_field(q.1, q.2).collect_fact();
+            fact.add_path(q.0);
+            (q.2, fact)
+        })
+        .unzip()
+    }
+
+    /// The sub-leaves of `leaves` after the first that are decoded, along with their number and
+    /// fields
+    fn decoded_sub_leaves<'s>(
+        &'s self,
+        leaves: &'s [CpuidResult],
+    ) -> impl Iterator<Item = (u32, &'s BitFieldLeaf, &'s CpuidResult)> {
+        leaves
+            .iter()
+            .zip(0u32..)
+            .skip(1)
+            .filter_map(move |(result, sub_leaf)| {
+                Some((sub_leaf, self.sub_leaves.get(sub_leaf, result)?, result))
+            })
+    }
 }
 
 impl DisplayLeaf for BitFieldLeaf {
-    fn scan_sub_leaves<CPUIDFunc: CpuidDB>(
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
         &self,
         leaf: u32,
         cpuid: &CPUIDFunc,
     ) -> Vec<CpuidResult> {
-        match cpuid.get_cpuid(leaf, 0) {
-            Some(cpuid) => vec![cpuid],
-            None => vec![],
+        if self.sub_leaves.is_empty() {
+            return match cpuid.get_cpuid(leaf, 0) {
+                Some(cpuid) => vec![cpuid],
+                None => vec![],
+            };
         }
+        let strategy = match &self.enumerate {
+            Some(strategy) => strategy.clone(),
+            None => SubLeaves::for_leaf(leaf),
+        };
+        read_by_index(&strategy, leaf, cpuid)
     }
     fn display_leaf(
         &self,
@@ -185,61 +511,98 @@ impl DisplayLeaf for BitFieldLeaf {
         f: &mut fmt::Formatter<'_>,
     ) -> Result<(), fmt::Error> {
         let CpuidResult { eax, ebx, ecx, edx } = leaf[0];
+        let (_, facts) = self.decode(&leaf[0]);
         writeln!(f)?;
 
-        Self::single_reg("eax", eax.into(), &self.eax, f)?;
-        Self::single_reg("ebx", ebx.into(), &self.ebx, f)?;
-        Self::single_reg("ecx", ecx.into(), &self.ecx, f)?;
-        Self::single_reg("edx", edx.into(), &self.edx, f)?;
+        Self::single_reg("eax", eax.into(), &self.eax, &facts, f)?;
+        Self::single_reg("ebx", ebx.into(), &self.ebx, &facts, f)?;
+        Self::single_reg("ecx", ecx.into(), &self.ecx, &facts, f)?;
+        Self::single_reg("edx", edx.into(), &self.edx, &facts, f)?;
+        if !self.derived.is_empty() {
+            writeln!(f, " derived:")?;
+            for fact in self.derive(&leaf[0]).1 {
+                writeln!(f, "  {} = {:>10}", fact.name, fact.value)?;
+            }
+        }
+        for (sub_leaf, fields, result) in self.decoded_sub_leaves(leaf) {
+            write!(f, " sub-leaf {}:", sub_leaf)?;
+            fields.display_leaf(&[*result], f)?;
+        }
         Ok(())
     }
-    fn get_facts<T>(&self, leaves: &[CpuidResult]) -> Vec<GenericFact<T>>
-    where
-        T: From<bool> + From<u32>,
-    {
-        let CpuidResult { eax, ebx, ecx, edx } = leaves[0];
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        let (mut facts, mut derived) = self.derive(&leaves[0]);
+        facts.append(&mut derived);
+        for (sub_leaf, fields, result) in self.decoded_sub_leaves(leaves) {
+            let (sub_leaf_facts, derived) = fields.derive(result);
+            facts.extend(sub_leaf_facts.into_iter().chain(derived).map(|mut fact| {
+                fact.add_path(&format!("subleaf{}", sub_leaf));
+                fact
+            }));
+        }
+        facts
+    }
+    /// The fields of sub-leaf 0 and the numbered sub-leaves, those for any other sub-leaf have no
+    /// fixed location
+    fn field_locations(&self) -> Vec<FieldLocation<'_>> {
+        let sub_leaf_locations =
+            self.sub_leaves
+                .by_sub_leaf
+                .iter()
+                .flat_map(|(sub_leaf, fields)| {
+                    fields
+                        .field_locations()
+                        .into_iter()
+                        .map(move |location| FieldLocation {
+                            sub_leaf: *sub_leaf,
+                            path: format!("subleaf{}/{}", sub_leaf, location.path),
+                            ..location
+                        })
+                });
         [
-            ("eax", eax, &self.eax),
-            ("ebx", ebx, &self.ebx),
-            ("ecx", ecx, &self.ecx),
-            ("edx", edx, &self.edx),
+            ("eax", &self.eax),
+            ("ebx", &self.ebx),
+            ("ecx", &self.ecx),
+            ("edx", &self.edx),
         ]
         .iter()
-        .flat_map(|i| i.2.iter().map(move |j| (i.0, i.1.into(), j)))
-        .map(|q| {
-            let mut fact = bitfield::BoundField::from_register_and_field(q.1, q.2).collect_fact();
-            fact.add_path(q.0);
-            fact
+        .flat_map(|(register, fields)| {
+            fields.iter().map(move |field| FieldLocation {
+                sub_leaf: 0,
+                register,
+                field,
+                path: format!("{}/{}", register, field.fact_name()),
+            })
         })
-        .collect::<Vec<GenericFact<T>>>()
+        .chain(sub_leaf_locations)
+        .collect()
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct BitFieldMultiLeaf {
     leaves: Vec<BitFieldLeaf>,
 }
 
+impl BitFieldMultiLeaf {
+    pub fn new(leaves: Vec<BitFieldLeaf>) -> Self {
+        Self { leaves }
+    }
+}
+
 impl DisplayLeaf for BitFieldMultiLeaf {
-    fn scan_sub_leaves<CPUIDFunc: CpuidDB>(
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
         &self,
         leaf: u32,
         cpuid: &CPUIDFunc,
     ) -> Vec<CpuidResult> {
-        match cpuid.get_cpuid(leaf, 0) {
-            Some(cpuid_start_leaf) => {
-                let count = cpuid_start_leaf.eax;
-                let mut ret = vec![cpuid_start_leaf];
-                for leaf_id in 1..=count {
-                    match cpuid.get_cpuid(leaf, leaf_id) {
-                        Some(cpuid_value) => ret.push(cpuid_value),
-                        None => break,
-                    }
-                }
-                ret
-            }
-            None => vec![],
-        }
+        // The sub-leaves are described in order, so they are counted rather than scanned
+        SubLeaves::Count
+            .read(cpuid, leaf)
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect()
     }
     fn display_leaf(
         &self,
@@ -251,30 +614,645 @@ impl DisplayLeaf for BitFieldMultiLeaf {
         }
         Ok(())
     }
-    fn get_facts<T: From<String> + From<u32> + From<bool>>(
-        &self,
-        leaves: &[CpuidResult],
-    ) -> Vec<GenericFact<T>> {
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
         self.leaves
             .iter()
             .zip(leaves)
             .flat_map(|(field, leaf)| field.get_facts(&[*leaf]).into_iter())
             .collect()
     }
+    fn field_locations(&self) -> Vec<FieldLocation<'_>> {
+        self.leaves
+            .iter()
+            .zip(0u32..)
+            .flat_map(|(leaf, sub_leaf)| {
+                leaf.field_locations()
+                    .into_iter()
+                    .map(move |location| FieldLocation {
+                        sub_leaf,
+                        ..location
+                    })
+            })
+            .collect()
+    }
+}
+
+const EMPTY_LEAF: CpuidResult = CpuidResult {
+    eax: 0,
+    ebx: 0,
+    ecx: 0,
+    edx: 0,
+};
+
+/// Read the sub-leaves `strategy` enumerates with each result at the index of its sub-leaf, the
+/// ones it skips over are left empty
+fn read_by_index<CPUIDFunc: CpuidDB + ?Sized>(
+    strategy: &SubLeaves,
+    leaf: u32,
+    cpuid: &CPUIDFunc,
+) -> Vec<CpuidResult> {
+    let mut ret = Vec::new();
+    for (sub_leaf, result) in strategy.read(cpuid, leaf) {
+        ret.resize(sub_leaf as usize, EMPTY_LEAF);
+        ret.push(result);
+    }
+    ret
+}
+
+/// A leaf whose sub-leaves each have their own fields, with facts placed under `subleaf<N>/`
+///
+/// The sub-leaves are enumerated with the strategy given by `enumerate`, or the one built in for
+/// the leaf, see `sub_leaf`. Those without an entry in `sub_leaves` are decoded with `default`
+/// when they are not empty, and left out otherwise.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MultiSubLeaf {
+    #[serde(deserialize_with = "crate::bitfield::deserialize_number_keys")]
+    sub_leaves: BTreeMap<u32, BitFieldLeaf>,
+    #[serde(default)]
+    default: Option<BitFieldLeaf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enumerate: Option<SubLeaves>,
+}
+
+impl MultiSubLeaf {
+    pub fn new(sub_leaves: BTreeMap<u32, BitFieldLeaf>, default: Option<BitFieldLeaf>) -> Self {
+        Self {
+            sub_leaves,
+            default,
+            enumerate: None,
+        }
+    }
+
+    /// Enumerate the sub-leaves with `strategy` rather than the one built in for the leaf
+    pub fn with_enumerate(mut self, strategy: SubLeaves) -> Self {
+        self.enumerate = Some(strategy);
+        self
+    }
+
+    /// The sub-leaves of `leaves` that are decoded, along with their number and fields
+    fn decoded<'s>(
+        &'s self,
+        leaves: &'s [CpuidResult],
+    ) -> impl Iterator<Item = (u32, &'s BitFieldLeaf, &'s CpuidResult)> {
+        leaves
+            .iter()
+            .zip(0u32..)
+            .filter_map(move |(result, sub_leaf)| {
+                let fields = match self.sub_leaves.get(&sub_leaf) {
+                    Some(fields) => fields,
+                    None if is_empty_leaf(result) => return None,
+                    None => self.default.as_ref()?,
+                };
+                Some((sub_leaf, fields, result))
+            })
+    }
+}
+
+impl DisplayLeaf for MultiSubLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        let strategy = match &self.enumerate {
+            Some(strategy) => strategy.clone(),
+            None => SubLeaves::for_leaf(leaf),
+        };
+        read_by_index(&strategy, leaf, cpuid)
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        for (sub_leaf, fields, result) in self.decoded(leaves) {
+            write!(f, "\n sub-leaf {}:", sub_leaf)?;
+            fields.display_leaf(&[*result], f)?;
+        }
+        Ok(())
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        self.decoded(leaves)
+            .flat_map(|(sub_leaf, fields, result)| {
+                let mut facts = fields.get_facts(&[*result]);
+                for fact in &mut facts {
+                    fact.add_path(&format!("subleaf{}", sub_leaf));
+                }
+                facts
+            })
+            .collect()
+    }
+    /// The fields of the listed sub-leaves, the default fields have no fixed location
+    fn field_locations(&self) -> Vec<FieldLocation<'_>> {
+        self.sub_leaves
+            .iter()
+            .flat_map(|(sub_leaf, fields)| {
+                fields
+                    .field_locations()
+                    .into_iter()
+                    .map(move |location| FieldLocation {
+                        sub_leaf: *sub_leaf,
+                        path: format!("subleaf{}/{}", sub_leaf, location.path),
+                        ..location
+                    })
+            })
+            .collect()
+    }
+}
+
+/// The deterministic cache parameters of leaf 0x4, or 0x8000001D on AMD, one cache per sub-leaf
+///
+/// Each cache's facts are placed under its level and type, e.g. `L1 Data/size`, with the size in
+/// bytes computed from the ways, partitions, line size and sets.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CacheParamsLeaf {}
+
+/// One cache described by a sub-leaf of the cache parameter leaves
+struct CacheParams {
+    kind: &'static str,
+    level: u32,
+    line_size: u32,
+    partitions: u32,
+    ways: u32,
+    sets: u32,
+    /// The logical processor IDs the cache is shared between, rounded up to a power of two
+    shared_by: u32,
+    fully_associative: bool,
+    inclusive: bool,
+}
+
+impl CacheParams {
+    /// `None` for the null cache type that ends the list
+    fn decode(result: &CpuidResult) -> Option<Self> {
+        let kind = match result.eax & 0x1f {
+            0 => return None,
+            1 => "Data",
+            2 => "Instruction",
+            3 => "Unified",
+            _ => "Unknown",
+        };
+        Some(Self {
+            kind,
+            level: (result.eax >> 5) & 0x7,
+            line_size: (result.ebx & 0xfff) + 1,
+            partitions: ((result.ebx >> 12) & 0x3ff) + 1,
+            ways: (result.ebx >> 22) + 1,
+            sets: result.ecx.wrapping_add(1),
+            shared_by: ((result.eax >> 14) & 0xfff) + 1,
+            fully_associative: result.eax & (1 << 9) != 0,
+            inclusive: result.edx & (1 << 1) != 0,
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("L{} {}", self.level, self.kind)
+    }
+
+    /// The size in bytes
+    fn size(&self) -> u64 {
+        [self.ways, self.partitions, self.line_size, self.sets]
+            .iter()
+            .map(|factor| u64::from(*factor))
+            .product()
+    }
+}
+
+impl DisplayLeaf for CacheParamsLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        let cache_type = SubLeaves::UntilZero {
+            register: Register::Eax,
+            bounds: 0..5,
+        };
+        cache_type
+            .read(cpuid, leaf)
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect()
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        for cache in leaves.iter().filter_map(CacheParams::decode) {
+            write!(
+                f,
+                "\n {}: {} KiB, {} ways, {} sets, {} byte lines, shared by {}",
+                cache.name(),
+                cache.size() >> 10,
+                cache.ways,
+                cache.sets,
+                cache.line_size,
+                cache.shared_by
+            )?;
+        }
+        Ok(())
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        let mut ret = Vec::new();
+        for cache in leaves.iter().filter_map(CacheParams::decode) {
+            let name = cache.name();
+            let mut push = |field: &str, value: FactValue| {
+                ret.push(Fact::new(format!("{}/{}", name, field), value))
+            };
+            push("type", cache.kind.into());
+            push("level", cache.level.into());
+            push("line_size", cache.line_size.into());
+            push("partitions", cache.partitions.into());
+            push("ways", cache.ways.into());
+            push("sets", cache.sets.into());
+            push("shared_by", cache.shared_by.into());
+            push("fully_associative", cache.fully_associative.into());
+            push("inclusive", cache.inclusive.into());
+            push("size", cache.size().into());
+        }
+        ret
+    }
+}
+
+/// The one byte cache and TLB descriptors of leaf 0x2, one fact per descriptor named by its code
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DescriptorsLeaf {}
+
+impl DescriptorsLeaf {
+    fn described(leaf: &CpuidResult) -> impl Iterator<Item = (String, &'static str)> {
+        descriptors::descriptors(leaf).into_iter().map(|byte| {
+            (
+                format!("{:#04x}", byte),
+                descriptors::describe(byte).unwrap_or("Unknown descriptor"),
+            )
+        })
+    }
+}
+
+impl DisplayLeaf for DescriptorsLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        match cpuid.get_cpuid(leaf, 0) {
+            Some(cpuid) => vec![cpuid],
+            None => vec![],
+        }
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        for (code, description) in Self::described(&leaves[0]) {
+            write!(f, "\n {}: {}", code, description)?;
+        }
+        Ok(())
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        Self::described(&leaves[0])
+            .map(|(code, description)| Fact::new(code, description.into()))
+            .collect()
+    }
+}
+
+/// The levels of the extended topology leaves 0xB and 0x1F, one set of facts per level named by
+/// its type
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TopologyLeaf {}
+
+impl DisplayLeaf for TopologyLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        let level_type = SubLeaves::UntilZero {
+            register: Register::Ecx,
+            bounds: 8..16,
+        };
+        level_type
+            .read(cpuid, leaf)
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect()
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        for level in leaves.iter().map_while(TopologyLevel::from_result) {
+            write!(
+                f,
+                "\n {}: {} logical processors, shift {}",
+                level.level_type, level.logical_processors, level.shift
+            )?;
+        }
+        Ok(())
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        let mut ret = Vec::new();
+        for (index, level) in leaves
+            .iter()
+            .map_while(TopologyLevel::from_result)
+            .enumerate()
+        {
+            let name = level.level_type.to_string();
+            let mut push = |field: &str, value: FactValue| {
+                ret.push(Fact::new(format!("{}/{}", name, field), value))
+            };
+            push("level", (index as u32).into());
+            push("shift", level.shift.into());
+            push("logical_processors", level.logical_processors.into());
+        }
+        ret
+    }
+}
+
+/// The ratio of the TSC to the core crystal clock in leaf 0x15, with the TSC frequency derived
+/// from it as `tsc_hz`
+///
+/// Leaf 0x16 and the signature of leaf 0x1 are read after the leaf itself, they stand in for the
+/// crystal clock when the leaf leaves it at zero, see `crystal_tsc_frequency`.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TscLeaf {}
+
+impl TscLeaf {
+    fn tsc_hz(leaves: &[CpuidResult]) -> Option<u64> {
+        crystal_tsc_frequency(
+            leaves.first()?,
+            leaves.get(1),
+            leaves.get(2).map_or(0, |model| model.eax),
+        )
+    }
+}
+
+impl DisplayLeaf for TscLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        match cpuid.get_cpuid(leaf, 0) {
+            Some(tsc) => vec![
+                tsc,
+                cpuid.get_cpuid(FREQUENCY_LEAF, 0).unwrap_or(EMPTY_LEAF),
+                cpuid.get_cpuid(1, 0).unwrap_or(EMPTY_LEAF),
+            ],
+            None => Vec::new(),
+        }
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        let tsc = leaves[0];
+        write!(f, "{}/{} of a {} Hz crystal", tsc.ebx, tsc.eax, tsc.ecx)?;
+        if let Some(hz) = Self::tsc_hz(leaves) {
+            write!(f, ", TSC at {} Hz", hz)?;
+        }
+        Ok(())
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        let tsc = leaves[0];
+        let mut ret = vec![
+            Fact::new("denominator".into(), tsc.eax.into()),
+            Fact::new("numerator".into(), tsc.ebx.into()),
+            Fact::new("crystal_hz".into(), tsc.ecx.into()),
+        ];
+        if let Some(hz) = Self::tsc_hz(leaves) {
+            ret.push(Fact::new("tsc_hz".into(), hz.into()));
+        }
+        ret
+    }
+}
+
+/// The names of the XSAVE state components, by their bit in XCR0 and IA32_XSS
+const XSAVE_COMPONENTS: &[&str] = &[
+    "x87",
+    "SSE",
+    "AVX",
+    "BNDREGS",
+    "BNDCSR",
+    "Opmask",
+    "ZMM_Hi256",
+    "Hi16_ZMM",
+    "PT",
+    "PKRU",
+    "PASID",
+    "CET_U",
+    "CET_S",
+    "HDC",
+    "UINTR",
+    "LBR",
+    "HWP",
+    "TILECFG",
+    "TILEDATA",
+    "APX",
+];
+
+/// The legacy region and header that start every XSAVE area, holding x87 and SSE state
+const XSAVE_LEGACY_SIZE: u32 = 512 + 64;
+
+/// One state component described by a sub-leaf of leaf 0xD
+struct XsaveComponent {
+    name: String,
+    size: u32,
+    /// Where the component is placed in the standard, uncompacted, format
+    offset: u32,
+    supervisor: bool,
+    /// Aligned to 64 bytes in the compacted format
+    aligned: bool,
+    xfd_faulting: bool,
+}
+
+/// The XSAVE features and state components of leaf 0xD
+///
+/// Besides the sizes the cpu reports, the size of the area holding every supported user
+/// component in the standard format and of every supported component in the compacted format
+/// of XSAVES are derived from the components.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct XsaveLeaf {}
+
+impl XsaveLeaf {
+    fn sub_leaf(leaves: &[CpuidResult], sub_leaf: usize) -> CpuidResult {
+        leaves.get(sub_leaf).copied().unwrap_or(EMPTY_LEAF)
+    }
+
+    /// The user components of EDX:EAX of sub-leaf 0
+    fn user_components(leaves: &[CpuidResult]) -> u64 {
+        let first = Self::sub_leaf(leaves, 0);
+        u64::from(first.edx) << 32 | u64::from(first.eax)
+    }
+
+    /// The supervisor components of EDX:ECX of sub-leaf 1
+    fn supervisor_components(leaves: &[CpuidResult]) -> u64 {
+        let second = Self::sub_leaf(leaves, 1);
+        u64::from(second.edx) << 32 | u64::from(second.ecx)
+    }
+
+    /// The components beyond x87 and SSE, which are part of the legacy region
+    fn components(leaves: &[CpuidResult]) -> impl Iterator<Item = XsaveComponent> + '_ {
+        let supported = Self::user_components(leaves) | Self::supervisor_components(leaves);
+        leaves
+            .iter()
+            .enumerate()
+            .skip(2)
+            .filter(move |(index, _)| *index < 64 && supported & (1 << index) != 0)
+            .map(|(index, result)| XsaveComponent {
+                name: XSAVE_COMPONENTS
+                    .get(index)
+                    .map_or_else(|| format!("component{}", index), |name| name.to_string()),
+                size: result.eax,
+                offset: result.ebx,
+                supervisor: result.ecx & 1 != 0,
+                aligned: result.ecx & (1 << 1) != 0,
+                xfd_faulting: result.ecx & (1 << 2) != 0,
+            })
+    }
+
+    /// The size of the standard format holding every supported user component
+    fn standard_size(leaves: &[CpuidResult]) -> u32 {
+        Self::components(leaves)
+            .filter(|component| !component.supervisor)
+            .map(|component| component.offset.saturating_add(component.size))
+            .fold(XSAVE_LEGACY_SIZE, u32::max)
+    }
+
+    /// The size of the compacted format holding every supported component, which places the
+    /// components one after the other in order of their bits
+    fn compacted_size(leaves: &[CpuidResult]) -> u32 {
+        Self::components(leaves).fold(XSAVE_LEGACY_SIZE, |end, component| {
+            let start = if component.aligned {
+                end.checked_add(63).map_or(u32::MAX, |end| end & !63)
+            } else {
+                end
+            };
+            start.saturating_add(component.size)
+        })
+    }
+}
+
+impl DisplayLeaf for XsaveLeaf {
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Vec<CpuidResult> {
+        read_by_index(&SubLeaves::Xsave, leaf, cpuid)
+    }
+    fn display_leaf(
+        &self,
+        leaves: &[CpuidResult],
+        f: &mut fmt::Formatter<'_>,
+    ) -> Result<(), fmt::Error> {
+        let first = Self::sub_leaf(leaves, 0);
+        writeln!(
+            f,
+            "\n user components: {:#x}, supervisor components: {:#x}",
+            Self::user_components(leaves),
+            Self::supervisor_components(leaves)
+        )?;
+        writeln!(
+            f,
+            " size enabled: {}, standard: {}, compacted: {}",
+            first.ebx,
+            Self::standard_size(leaves),
+            Self::compacted_size(leaves)
+        )?;
+        for component in Self::components(leaves) {
+            write!(
+                f,
+                " {}: {} bytes at {}",
+                component.name, component.size, component.offset
+            )?;
+            for (set, flag) in [
+                (component.supervisor, "supervisor"),
+                (component.aligned, "aligned"),
+                (component.xfd_faulting, "xfd faulting"),
+            ] {
+                if set {
+                    write!(f, ", {}", flag)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
+        let first = Self::sub_leaf(leaves, 0);
+        let second = Self::sub_leaf(leaves, 1);
+        let mut ret = vec![
+            Fact::new(
+                "user_components".into(),
+                Self::user_components(leaves).into(),
+            ),
+            Fact::new(
+                "supervisor_components".into(),
+                Self::supervisor_components(leaves).into(),
+            ),
+            Fact::new("enabled_size".into(), first.ebx.into()),
+            Fact::new("max_size".into(), first.ecx.into()),
+            Fact::new("compacted_enabled_size".into(), second.ebx.into()),
+            Fact::new("standard_size".into(), Self::standard_size(leaves).into()),
+            Fact::new("compacted_size".into(), Self::compacted_size(leaves).into()),
+        ];
+        for (bit, name) in ["XSAVEOPT", "XSAVEC", "XGETBV_ECX1", "XSAVES", "XFD"]
+            .iter()
+            .enumerate()
+        {
+            ret.push(Fact::new(
+                name.to_string(),
+                (second.eax & (1 << bit) != 0).into(),
+            ));
+        }
+        for component in Self::components(leaves) {
+            let mut push = |field: &str, value: FactValue| {
+                ret.push(Fact::new(format!("{}/{}", component.name, field), value))
+            };
+            push("size", component.size.into());
+            push("offset", component.offset.into());
+            push("supervisor", component.supervisor.into());
+            push("aligned", component.aligned.into());
+            push("xfd_faulting", component.xfd_faulting.into());
+        }
+        ret
+    }
 }
 
 /// Enum to aid in serializing and deserializing leaf information
 #[enum_dispatch(DisplayLeaf)]
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type")]
 pub enum LeafType {
     Start(StartLeaf),
+    HypervisorStart(HypervisorStartLeaf),
     String(StringLeaf),
+    StringSpan(StringSpanLeaf),
     BitField(BitFieldLeaf),
+    /// Written `SubLeafList` as of config version 2, the old name is still read
+    #[serde(rename = "SubLeafList", alias = "SubLeafBitField")]
     SubLeafBitField(BitFieldMultiLeaf),
+    MultiSubLeaf(MultiSubLeaf),
+    CacheParams(CacheParamsLeaf),
+    Descriptors(DescriptorsLeaf),
+    Xsave(XsaveLeaf),
+    Topology(TopologyLeaf),
+    Tsc(TscLeaf),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct LeafDesc {
     name: String,
     data_type: LeafType,
@@ -289,11 +1267,32 @@ impl LeafDesc {
         &self.name
     }
 
+    /// Merge the fields of `other` into this leaf, taking its name, when both are bit field
+    /// leaves. This leaf is replaced by `other` otherwise
+    pub fn merge_fields(&mut self, other: LeafDesc) {
+        match (&mut self.data_type, other.data_type) {
+            (LeafType::BitField(leaf), LeafType::BitField(other_leaf)) => {
+                leaf.merge(other_leaf);
+                self.name = other.name;
+            }
+            (_, data_type) => {
+                *self = LeafDesc {
+                    name: other.name,
+                    data_type,
+                }
+            }
+        }
+    }
+
     pub fn data_type(&self) -> &LeafType {
         &self.data_type
     }
 
-    pub fn bind_leaf<CPUIDFunc: CpuidDB>(&self, leaf: u32, cpuid: &CPUIDFunc) -> Option<BoundLeaf> {
+    pub fn bind_leaf<CPUIDFunc: CpuidDB + ?Sized>(
+        &self,
+        leaf: u32,
+        cpuid: &CPUIDFunc,
+    ) -> Option<BoundLeaf<'_>> {
         let sub_leaves = self.scan_sub_leaves(leaf, cpuid);
         if !sub_leaves.is_empty() {
             Some(BoundLeaf {
@@ -307,7 +1306,7 @@ impl LeafDesc {
 }
 
 impl DisplayLeaf for LeafDesc {
-    fn scan_sub_leaves<CPUIDFunc: CpuidDB>(
+    fn scan_sub_leaves<CPUIDFunc: CpuidDB + ?Sized>(
         &self,
         leaf: u32,
         cpuid: &CPUIDFunc,
@@ -322,12 +1321,12 @@ impl DisplayLeaf for LeafDesc {
         write!(f, "{}: ", self.name)?;
         self.data_type.display_leaf(leaf, f)
     }
-    fn get_facts<T>(&self, leaves: &[CpuidResult]) -> Vec<GenericFact<T>>
-    where
-        T: From<u32> + From<String> + From<bool>,
-    {
+    fn get_facts(&self, leaves: &[CpuidResult]) -> Vec<Fact> {
         self.data_type.get_facts(leaves)
     }
+    fn field_locations(&self) -> Vec<FieldLocation<'_>> {
+        self.data_type.field_locations()
+    }
 }
 
 pub struct BoundLeaf<'a> {
@@ -336,7 +1335,7 @@ pub struct BoundLeaf<'a> {
 }
 
 impl<'a> BoundLeaf<'a> {
-    pub fn get_facts<T: From<u32> + From<bool> + From<String>>(&self) -> Vec<GenericFact<T>> {
+    pub fn get_facts(&self) -> Vec<Fact> {
         let mut facts = self.desc.get_facts(&self.sub_leaves);
         facts.iter_mut().for_each(|i| {
             i.add_path(&self.desc.name);
@@ -345,8 +1344,8 @@ impl<'a> BoundLeaf<'a> {
     }
 }
 
-impl<'a, T: From<u32> + From<bool> + From<String>> facts::Facter<GenericFact<T>> for BoundLeaf<'a> {
-    fn collect_facts(&self) -> Vec<GenericFact<T>> {
+impl<'a> facts::Facter<Fact> for BoundLeaf<'a> {
+    fn collect_facts(&self) -> Vec<Fact> {
         self.get_facts()
     }
 }
@@ -356,3 +1355,470 @@ impl<'a> fmt::Display for BoundLeaf<'a> {
         self.desc.display_leaf(&self.sub_leaves, f)
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::FnCpuidDB;
+
+    #[test]
+    fn multi_sub_leaf() {
+        // Sub-leaves 0 and 1, then the state components 2 and 5 set in sub-leaf 0
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            (0xd, 0) => Some(CpuidResult {
+                eax: 0x27,
+                ebx: 0x340,
+                ecx: 0x440,
+                edx: 0,
+            }),
+            (0xd, 1 | 3 | 4) => Some(CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }),
+            (0xd, _) => Some(CpuidResult {
+                eax: 0x40,
+                ebx: sub_leaf * 0x100,
+                ecx: 0,
+                edx: 0,
+            }),
+            _ => None,
+        });
+        let desc: LeafDesc = serde_yaml::from_str(
+            "name: Extended State\n\
+             data_type:\n  \
+               type: MultiSubLeaf\n  \
+               sub_leaves:\n    \
+                 0: {eax: [{type: Flag, name: AVX, bit: 2}], ebx: [], ecx: [], edx: []}\n    \
+                 1: {eax: [{type: Flag, name: XSAVES, bit: 3}], ebx: [], ecx: [], edx: []}\n  \
+               default: {eax: [], ebx: [{type: Int, name: Offset, bounds: {start: 0, end: 32}}], \
+                         ecx: [], edx: []}\n",
+        )
+        .unwrap();
+        let bound = desc.bind_leaf(0xd, &source).unwrap();
+        assert_eq!(bound.sub_leaves.len(), 6);
+        let facts: Vec<(String, FactValue)> = bound
+            .get_facts()
+            .into_iter()
+            .map(|fact| (fact.name, fact.value))
+            .collect();
+        assert_eq!(
+            facts,
+            [
+                ("Extended State/subleaf0/eax/AVX".to_string(), true.into()),
+                (
+                    "Extended State/subleaf1/eax/XSAVES".to_string(),
+                    false.into()
+                ),
+                (
+                    "Extended State/subleaf2/ebx/Offset".to_string(),
+                    0x200u32.into()
+                ),
+                (
+                    "Extended State/subleaf5/ebx/Offset".to_string(),
+                    0x500u32.into()
+                ),
+            ]
+        );
+        let paths: Vec<String> = desc
+            .field_locations()
+            .into_iter()
+            .map(|location| location.path)
+            .collect();
+        assert_eq!(paths, ["subleaf0/eax/AVX", "subleaf1/eax/XSAVES"]);
+    }
+
+    #[test]
+    fn enumerated_sub_leaves() {
+        // A leaf the built-in table does not know, counting its sub-leaves in EAX of sub-leaf 0
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            (0x23, 0) => Some(CpuidResult {
+                eax: 2,
+                ebx: 1,
+                ecx: 0,
+                edx: 0,
+            }),
+            // An empty sub-leaf ends a scan before the last one
+            (0x23, 1 | 2) => Some(CpuidResult {
+                eax: 0,
+                ebx: sub_leaf - 1,
+                ecx: 0,
+                edx: 0,
+            }),
+            _ => None,
+        });
+        let desc = |enumerate: &str| -> LeafDesc {
+            serde_yaml::from_str(&format!(
+                "name: Perfmon\n\
+                 data_type:\n  \
+                   type: MultiSubLeaf\n  \
+                   sub_leaves: {{}}\n  \
+                   default: {{eax: [], ebx: [{{type: Int, name: Counters, bounds: {{start: 0, end: 8}}}}], ecx: [], edx: []}}\n\
+                 {}",
+                enumerate
+            ))
+            .unwrap()
+        };
+        let counters = |desc: LeafDesc| desc.bind_leaf(0x23, &source).unwrap().get_facts().len();
+        assert_eq!(counters(desc("")), 1);
+        assert_eq!(counters(desc("  enumerate: {type: Count}\n")), 2);
+    }
+
+    #[test]
+    fn bit_field_sub_leaves() {
+        // Sub-leaf 0 counts two more sub-leaves, as leaf 0x7 does
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            (0x7, 0..=2) => Some(CpuidResult {
+                eax: if sub_leaf == 0 { 2 } else { 1 << 4 },
+                ebx: 1 << 3,
+                ecx: 0,
+                edx: 0,
+            }),
+            _ => None,
+        });
+        let desc: LeafDesc = serde_yaml::from_str(
+            "name: Flags\n\
+             data_type:\n  \
+               type: BitField\n  \
+               eax: []\n  \
+               ebx: [{type: Flag, name: BMI1, bit: 3}]\n  \
+               ecx: []\n  \
+               edx: []\n  \
+               sub_leaves:\n    \
+                 1: {eax: [{type: Flag, name: AVX VNNI, bit: 4}], ebx: [], ecx: [], edx: []}\n    \
+                 '*': {eax: [], ebx: [{type: Flag, name: Other, bit: 3}], ecx: [], edx: []}\n",
+        )
+        .unwrap();
+        let bound = desc.bind_leaf(0x7, &source).unwrap();
+        assert_eq!(bound.sub_leaves.len(), 3);
+        let names: Vec<String> = bound
+            .get_facts()
+            .into_iter()
+            .map(|fact| fact.name)
+            .collect();
+        // Sub-leaf 0 keeps the paths of a single sub-leaf
+        assert_eq!(
+            names,
+            [
+                "Flags/ebx/BMI1",
+                "Flags/subleaf1/eax/AVX VNNI",
+                "Flags/subleaf2/ebx/Other"
+            ]
+        );
+        let paths: Vec<String> = desc
+            .field_locations()
+            .into_iter()
+            .map(|location| location.path)
+            .collect();
+        assert_eq!(paths, ["ebx/BMI1", "subleaf1/eax/AVX VNNI"]);
+        let json = serde_json::to_string(&desc).unwrap();
+        assert!(json.contains(r#""*":"#));
+        let reread: LeafDesc = serde_json::from_str(&json).unwrap();
+        assert_eq!(reread.field_locations().len(), 2);
+
+        // Sub-leaf 0 has no key of its own
+        assert!(serde_yaml::from_str::<LeafDesc>(
+            "name: Flags\n\
+             data_type: {type: BitField, eax: [], ebx: [], ecx: [], edx: [], \
+                         sub_leaves: {0: {eax: [], ebx: [], ecx: [], edx: []}}}\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn cache_params() {
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            // A 48 KiB L1 data cache and a 30 MiB L3 shared by 32 threads
+            (4, 0) => Some(CpuidResult {
+                eax: 0x121,
+                ebx: 0x2c0003f,
+                ecx: 0x3f,
+                edx: 0,
+            }),
+            (4, 1) => Some(CpuidResult {
+                eax: 0x7c163,
+                ebx: 0x4c0003f,
+                ecx: 0x5fff,
+                edx: 0x6,
+            }),
+            (4, _) => Some(CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }),
+            _ => None,
+        });
+        let desc = LeafDesc::new(
+            "Cache Parameters".to_string(),
+            LeafType::CacheParams(CacheParamsLeaf {}),
+        );
+        let facts = desc.bind_leaf(4, &source).unwrap().get_facts();
+        let value = |name: &str| {
+            facts
+                .iter()
+                .find(|fact| fact.name == format!("Cache Parameters/{}", name))
+                .map(|fact| fact.value.clone())
+        };
+        assert_eq!(value("L1 Data/size"), Some(FactValue::from(48u64 << 10)));
+        assert_eq!(value("L3 Unified/size"), Some(FactValue::from(30u64 << 20)));
+        assert_eq!(value("L3 Unified/shared_by"), Some(FactValue::from(32u32)));
+        assert_eq!(value("L3 Unified/inclusive"), Some(FactValue::from(true)));
+        assert_eq!(facts.len(), 20);
+    }
+
+    #[test]
+    fn xsave() {
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            // x87, SSE, an oddly sized AVX and PKRU with CET_U as a supervisor component
+            (0xd, 0) => Some(CpuidResult {
+                eax: 0x207,
+                ebx: 0xa88,
+                ecx: 0xa88,
+                edx: 0,
+            }),
+            (0xd, 1) => Some(CpuidResult {
+                eax: 0xf,
+                ebx: 0xa98,
+                ecx: 0x800,
+                edx: 0,
+            }),
+            (0xd, 2) => Some(CpuidResult {
+                eax: 0x110,
+                ebx: 0x240,
+                ecx: 0,
+                edx: 0,
+            }),
+            (0xd, 9) => Some(CpuidResult {
+                eax: 0x8,
+                ebx: 0xa80,
+                ecx: 0x2,
+                edx: 0,
+            }),
+            (0xd, 11) => Some(CpuidResult {
+                eax: 0x10,
+                ebx: 0,
+                ecx: 0x1,
+                edx: 0,
+            }),
+            _ => None,
+        });
+        let desc = LeafDesc::new("Extended State".to_string(), LeafType::Xsave(XsaveLeaf {}));
+        let facts = desc.bind_leaf(0xd, &source).unwrap().get_facts();
+        let value = |name: &str| {
+            facts
+                .iter()
+                .find(|fact| fact.name == format!("Extended State/{}", name))
+                .map(|fact| fact.value.clone())
+        };
+        assert_eq!(value("user_components"), Some(FactValue::from(0x207u64)));
+        assert_eq!(
+            value("supervisor_components"),
+            Some(FactValue::from(0x800u64))
+        );
+        assert_eq!(value("XSAVES"), Some(FactValue::from(true)));
+        assert_eq!(value("XFD"), Some(FactValue::from(false)));
+        assert_eq!(value("AVX/offset"), Some(FactValue::from(0x240u32)));
+        assert_eq!(value("PKRU/aligned"), Some(FactValue::from(true)));
+        assert_eq!(value("CET_U/supervisor"), Some(FactValue::from(true)));
+        // PKRU ends the standard format, the supervisor CET_U has no place in it
+        assert_eq!(value("standard_size"), Some(FactValue::from(0xa88u32)));
+        // AVX follows the legacy area, PKRU is aligned to 64 bytes past it and CET_U follows
+        assert_eq!(
+            value("compacted_size"),
+            Some(FactValue::from(896u32 + 8 + 16))
+        );
+        assert_eq!(value("BNDREGS/size"), None);
+
+        // Sizes a broken source reports saturate rather than overflow
+        let result = |eax, ecx| CpuidResult {
+            eax,
+            ebx: 0,
+            ecx,
+            edx: 0,
+        };
+        let leaves = [
+            result(0xf, 0),
+            result(0, 0),
+            result(u32::MAX - 8, 0),
+            result(8, 0x2),
+        ];
+        assert_eq!(XsaveLeaf::compacted_size(&leaves), u32::MAX);
+    }
+
+    #[test]
+    fn topology() {
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            // Two threads per core and 16 logical processors per package
+            (0xb, 0) => Some(CpuidResult {
+                eax: 1,
+                ebx: 2,
+                ecx: 0x100,
+                edx: 0x7,
+            }),
+            (0xb, 1) => Some(CpuidResult {
+                eax: 4,
+                ebx: 16,
+                ecx: 0x201,
+                edx: 0x7,
+            }),
+            (0xb, _) => Some(CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: sub_leaf,
+                edx: 0x7,
+            }),
+            _ => None,
+        });
+        let desc = LeafDesc::new(
+            "Extended Topology".to_string(),
+            LeafType::Topology(TopologyLeaf {}),
+        );
+        let facts = desc.bind_leaf(0xb, &source).unwrap().get_facts();
+        let value = |name: &str| {
+            facts
+                .iter()
+                .find(|fact| fact.name == format!("Extended Topology/{}", name))
+                .map(|fact| fact.value.clone())
+        };
+        assert_eq!(value("SMT/logical_processors"), Some(FactValue::from(2u32)));
+        assert_eq!(value("Core/level"), Some(FactValue::from(1u32)));
+        assert_eq!(value("Core/shift"), Some(FactValue::from(4u32)));
+        assert_eq!(facts.len(), 6);
+    }
+
+    #[test]
+    fn tsc() {
+        let source = FnCpuidDB(|leaf, _| match leaf {
+            // Kaby Lake, which leaves the crystal clock out of leaf 0x15
+            0x1 => Some(CpuidResult {
+                eax: 0x906e9,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }),
+            0x15 => Some(CpuidResult {
+                eax: 2,
+                ebx: 300,
+                ecx: 0,
+                edx: 0,
+            }),
+            _ => None,
+        });
+        let desc = LeafDesc::new("TSC Frequency".to_string(), LeafType::Tsc(TscLeaf {}));
+        let facts = desc.bind_leaf(0x15, &source).unwrap().get_facts();
+        assert_eq!(
+            facts
+                .last()
+                .map(|fact| (fact.name.as_str(), fact.value.clone())),
+            Some(("TSC Frequency/tsc_hz", FactValue::from(3_600_000_000u64)))
+        );
+        assert!(desc.bind_leaf(0x16, &source).is_none());
+    }
+
+    #[test]
+    fn string_span() {
+        let brand = b"       Intel(R) Xeon(R) Gold 6338 CPU @ 2.00GHz\0";
+        let source = FnCpuidDB(|leaf: u32, _| {
+            let start = (leaf.checked_sub(0x80000002)? * 16) as usize;
+            let register = |offset: usize| {
+                let bytes = brand.get(start + offset..start + offset + 4)?;
+                Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            };
+            Some(CpuidResult {
+                eax: register(0)?,
+                ebx: register(4)?,
+                ecx: register(8)?,
+                edx: register(12)?,
+            })
+        });
+        let desc = LeafDesc::new(
+            "Brand String".to_string(),
+            LeafType::StringSpan(StringSpanLeaf::new(3, "brand_string".to_string())),
+        );
+        let facts = desc.bind_leaf(0x80000002, &source).unwrap().get_facts();
+        assert_eq!(facts.len(), 1);
+        assert_eq!(facts[0].name, "Brand String/brand_string");
+        assert_eq!(
+            facts[0].value,
+            FactValue::from("Intel(R) Xeon(R) Gold 6338 CPU @ 2.00GHz")
+        );
+        // Only two leaves of the span remain from 0x80000003
+        assert!(desc.bind_leaf(0x80000003, &source).is_none());
+    }
+
+    #[test]
+    fn conditional_fields() {
+        let desc: LeafDesc = serde_yaml::from_str(
+            "name: Features\n\
+             data_type:\n  \
+               type: BitField\n  \
+               eax: []\n  \
+               ebx:\n  \
+               - {type: Flag, name: AVX512F, bit: 16}\n  \
+               - {type: Flag, name: AVX512VL, bit: 31, valid_if: AVX512F}\n  \
+               ecx:\n  \
+               - {type: Int, name: Kind, bounds: {start: 0, end: 2}, valid_if: {field: Kind, values: [1, 2]}}\n  \
+               edx:\n  \
+               - {type: Flag, name: OS, bit: 0, valid_if: {fact: cpuid/Model/ecx/OSXSAVE}}\n",
+        )
+        .unwrap();
+        let source = FnCpuidDB(|_, _| {
+            Some(CpuidResult {
+                eax: 0,
+                ebx: 1 << 31,
+                ecx: 3,
+                edx: 1,
+            })
+        });
+        let bound = desc.bind_leaf(7, &source).unwrap();
+        let names: Vec<String> = bound
+            .get_facts()
+            .into_iter()
+            .map(|fact| fact.name)
+            .collect();
+        // The fact condition is left to the collector
+        assert_eq!(names, ["Features/ebx/AVX512F", "Features/edx/OS"]);
+        assert!(format!("{}", bound).contains("AVX512VL =       true (not valid)"));
+    }
+
+    #[test]
+    fn derived_fields() {
+        let desc: LeafDesc = serde_yaml::from_str(
+            "name: L2 Cache\n\
+             data_type:\n  \
+               type: BitField\n  \
+               eax: []\n  \
+               ebx: []\n  \
+               ecx:\n  \
+               - {type: Int, name: Line Size, bounds: {start: 0, end: 8}}\n  \
+               - {type: Int, name: Size KB, bounds: {start: 16, end: 32}}\n  \
+               edx: []\n  \
+               derived:\n  \
+               - {name: size_bytes, expr: '`Size KB` * 1024'}\n  \
+               - {name: lines, expr: 'size_bytes / `ecx/Line Size`'}\n  \
+               - {name: missing, expr: 'Ways + 1'}\n",
+        )
+        .unwrap();
+        let source = FnCpuidDB(|_, _| {
+            Some(CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0x0800_0040,
+                edx: 0,
+            })
+        });
+        let bound = desc.bind_leaf(0x80000006, &source).unwrap();
+        let facts = bound.get_facts();
+        assert_eq!(facts.len(), 4);
+        assert_eq!(facts[2].name, "L2 Cache/size_bytes");
+        assert_eq!(facts[2].value, FactValue::from(2u64 << 20));
+        assert_eq!(facts[3].value, FactValue::from(32768u64));
+        assert!(format!("{}", bound).contains("  lines =      32768"));
+
+        let invalid = "name: Bad\ndata_type: {type: BitField, eax: [], ebx: [], ecx: [], edx: [], \
+                       derived: [{name: x, expr: '1 +'}]}\n";
+        assert!(serde_yaml::from_str::<LeafDesc>(invalid).is_err());
+    }
+}
@@ -1,90 +1,349 @@
-use core::arch::x86_64::{CpuidResult, __cpuid_count};
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid_count;
+#[cfg(target_arch = "x86_64")]
+pub use core::arch::x86_64::CpuidResult;
+use core::cell::RefCell;
+use core::convert::TryFrom;
 use enum_dispatch::enum_dispatch;
+use serde::{Deserialize, Serialize};
+
+/// The result of a CPUID leaf, as on x86_64 so snapshots can still be decoded on other machines
+#[cfg(not(target_arch = "x86_64"))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
 
+mod error;
+#[cfg(feature = "std")]
+pub use collector::Collector;
+#[cfg(feature = "core")]
+pub use dump::CpuidDump;
+pub use error::{Error, Result};
+
+// The decoders only need `alloc`, sources reading from the system need `std`
+#[cfg(feature = "std")]
+pub mod acpi;
+#[cfg(feature = "std")]
+pub mod arm;
+#[cfg(feature = "core")]
 pub mod bitfield;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod collector;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "core")]
+pub mod descriptors;
+#[cfg(feature = "core")]
+pub mod dump;
+#[cfg(feature = "core")]
+pub mod expr;
+#[cfg(feature = "core")]
 pub mod facts;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod layered;
+#[cfg(feature = "core")]
 pub mod layout;
+#[cfg(feature = "std")]
+pub mod migrate;
+#[cfg(feature = "std")]
 pub mod msr;
+#[cfg(feature = "std")]
+pub mod proc_cpuinfo;
+#[cfg(all(unix, feature = "qmp"))]
+pub mod qmp;
+#[cfg(feature = "std")]
+pub mod snapshot;
+pub mod sub_leaf;
+#[cfg(feature = "std")]
+pub mod sysctl;
+#[cfg(feature = "std")]
+pub mod sysfs;
+#[cfg(feature = "core")]
+pub mod topology;
+#[cfg(feature = "std")]
+pub mod vuln;
 
-#[cfg(all(target_os = "linux", feature = "kvm"))]
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
 pub mod kvm;
 
 #[enum_dispatch]
 pub trait CpuidDB {
     fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult>;
+
+    /// The last leaf of the basic or extended function, as reported in EAX of its first leaf
+    ///
+    /// The hypervisor leaves come in blocks, see `hypervisor_bases`.
+    fn max_leaf(&self, func: CpuidFunction) -> Option<u32> {
+        self.get_cpuid(func.start_eax(), 0).map(|result| result.eax)
+    }
 }
 
-#[derive(Debug)]
-pub enum CpuidError {
-    NoCPUID,
-    LeafOutOfRange(u32, CpuidFunction),
+impl<DB: CpuidDB + ?Sized> CpuidDB for &DB {
+    fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        (**self).get_cpuid(leaf, sub_leaf)
+    }
+    fn max_leaf(&self, func: CpuidFunction) -> Option<u32> {
+        (**self).max_leaf(func)
+    }
 }
 
-impl std::fmt::Display for CpuidError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            CpuidError::NoCPUID => write!(f, "No CPUID Present on hardware"),
-            CpuidError::LeafOutOfRange(leaf, func) => {
-                write!(f, "Leaf {:#x} not present in function {:?}", leaf, func)
+impl<DB: CpuidDB + ?Sized> CpuidDB for Box<DB> {
+    fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        (**self).get_cpuid(leaf, sub_leaf)
+    }
+    fn max_leaf(&self, func: CpuidFunction) -> Option<u32> {
+        (**self).max_leaf(func)
+    }
+}
+
+/// Hypervisors may publish further blocks of leaves every 0x100 leaves above 0x40000000, Xen
+/// moves its leaves up a block when it also presents the Hyper-V interface
+const HYPERVISOR_BLOCK_SIZE: u32 = 0x100;
+const HYPERVISOR_BLOCKS_END: u32 = 0x40010000;
+
+/// The first and last leaf of each block of hypervisor leaves, `read` returning sub-leaf 0 of a leaf
+///
+/// There are none when the hypervisor present bit, leaf 1 ECX bit 31, is clear.
+pub(crate) fn hypervisor_ranges<F>(read: F) -> Vec<(u32, u32)>
+where
+    F: Fn(u32) -> Option<CpuidResult>,
+{
+    if read(1).is_none_or(|model| model.ecx & (1 << 31) == 0) {
+        return Vec::new();
+    }
+    let first = CpuidFunction::Hypervisor.start_eax();
+    (first..HYPERVISOR_BLOCKS_END)
+        .step_by(HYPERVISOR_BLOCK_SIZE as usize)
+        .filter_map(|base| {
+            let result = read(base)?;
+            if base == first {
+                // Always present under a hypervisor, some report a max leaf of 0
+                Some((base, result.eax.max(base)))
+            } else if (base..base + HYPERVISOR_BLOCK_SIZE).contains(&result.eax) && result.ebx != 0
+            {
+                Some((base, result.eax))
+            } else {
+                None
             }
-        }
+        })
+        .collect()
+}
+
+/// The text held in the bytes of `registers`, in the order given
+pub(crate) fn register_text(registers: &[u32]) -> String {
+    let bytes: Vec<u8> = registers.iter().flat_map(|reg| reg.to_le_bytes()).collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// The signature in the first leaf of a block of hypervisor leaves, e.g. "KVMKVMKVM"
+///
+/// Unlike the vendor string of leaf 0, the registers are in order here.
+pub(crate) fn leaf_signature(leaf: &CpuidResult) -> String {
+    register_text(&[leaf.ebx, leaf.ecx, leaf.edx])
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/// The vendor string of leaf 0, e.g. "GenuineIntel" or "AuthenticAMD"
+pub fn cpu_vendor<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<String> {
+    let leaf = cpuid.get_cpuid(0, 0)?;
+    Some(
+        register_text(&[leaf.ebx, leaf.edx, leaf.ecx])
+            .trim_end_matches('\0')
+            .to_string(),
+    )
+}
+
+/// The family of leaf 1, with the extended family added when the family is 0xf as it is on AMD
+pub fn cpu_family<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<u32> {
+    let signature = cpuid.get_cpuid(1, 0)?.eax;
+    let family = (signature >> 8) & 0xf;
+    Some(match family {
+        0xf => family + ((signature >> 20) & 0xff),
+        _ => family,
+    })
+}
+
+/// The base leaf and signature of each block of hypervisor leaves, e.g. `(0x40000000, "KVMKVMKVM")`
+pub fn hypervisor_bases<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Vec<(u32, String)> {
+    hypervisor_ranges(|leaf| cpuid.get_cpuid(leaf, 0))
+        .into_iter()
+        .filter_map(|(base, _)| Some((base, leaf_signature(&cpuid.get_cpuid(base, 0)?))))
+        .collect()
+}
+
+/// The signature a hypervisor reports in leaf 0x40000000, e.g. "Microsoft Hv" or "KVMKVMKVM"
+pub fn hypervisor_signature<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<String> {
+    hypervisor_bases(cpuid)
+        .into_iter()
+        .find(|(base, _)| *base == CpuidFunction::Hypervisor.start_eax())
+        .map(|(_, signature)| signature)
+}
+
+/// The generic timing leaf defined by VMware, KVM also fills it in when QEMU runs with
+/// `vmware-cpuid-freq`
+const HYPERVISOR_TIMING_LEAF: u32 = 0x40000010;
+
+/// The TSC frequency in Hz a hypervisor advertises in leaf 0x40000010
+///
+/// The leaf holds the frequency in kHz, `None` when it is absent or left at zero.
+pub fn hypervisor_tsc_frequency<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<u64> {
+    let timing = cpuid.get_cpuid(HYPERVISOR_TIMING_LEAF, 0)?;
+    (timing.eax != 0).then(|| u64::from(timing.eax) * 1000)
+}
+
+/// The ratio of the TSC to the core crystal clock
+pub const TSC_LEAF: u32 = 0x15;
+/// The base, maximum and bus frequencies in MHz
+pub const FREQUENCY_LEAF: u32 = 0x16;
+
+/// The crystal clock of the family 6 models whose leaf 0x15 leaves it at zero, as Linux assumes
+const CRYSTAL_HZ_BY_MODEL: &[(u32, u64)] = &[
+    // Skylake and Kaby Lake client
+    (0x4e, 24_000_000),
+    (0x5e, 24_000_000),
+    (0x8e, 24_000_000),
+    (0x9e, 24_000_000),
+    // Goldmont and Goldmont Plus
+    (0x5c, 19_200_000),
+    (0x7a, 19_200_000),
+    // Denverton, which has no leaf 0x16 either
+    (0x5f, 25_000_000),
+];
+
+/// The TSC frequency in Hz from the ratio and crystal clock of leaf 0x15 in `tsc`
+///
+/// Cpus that leave the crystal clock at zero have it looked up by the family 6 model of the
+/// signature in EAX of leaf 0x1, or derived from the base frequency of leaf 0x16 in `frequency`.
+/// `None` when the ratio is not enumerated or the crystal clock can not be found.
+pub fn crystal_tsc_frequency(
+    tsc: &CpuidResult,
+    frequency: Option<&CpuidResult>,
+    signature: u32,
+) -> Option<u64> {
+    // Worked in 128 bits, a crystal clock derived from the base frequency overflows 64 bits when
+    // scaled by a large ratio
+    let (denominator, numerator) = (u128::from(tsc.eax), u128::from(tsc.ebx));
+    if denominator == 0 || numerator == 0 {
+        return None;
     }
+    let family = (signature >> 8) & 0xf;
+    let model = (signature >> 12) & 0xf0 | (signature >> 4) & 0xf;
+    let crystal_hz = match tsc.ecx {
+        0 => CRYSTAL_HZ_BY_MODEL
+            .iter()
+            .find(|(known, _)| family == 6 && *known == model)
+            .map(|(_, hz)| u128::from(*hz))
+            .or_else(|| {
+                let base_mhz = u128::from(frequency?.eax & 0xffff);
+                (base_mhz != 0).then(|| base_mhz * 1_000_000 * denominator / numerator)
+            })?,
+        hz => u128::from(hz),
+    };
+    u64::try_from(crystal_hz * numerator / denominator).ok()
+}
+
+/// The TSC frequency in Hz the cpu enumerates in leaf 0x15, see `crystal_tsc_frequency`
+pub fn tsc_frequency<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<u64> {
+    crystal_tsc_frequency(
+        &cpuid.get_cpuid(TSC_LEAF, 0)?,
+        cpuid.get_cpuid(FREQUENCY_LEAF, 0).as_ref(),
+        cpuid.get_cpuid(1, 0).map_or(0, |model| model.eax),
+    )
 }
 
-impl std::error::Error for CpuidError {}
+#[derive(Debug, thiserror::Error)]
+pub enum CpuidError {
+    #[error("No CPUID Present on hardware")]
+    NoCPUID,
+    #[error("Leaf {0:#x} not present in function {1:?}")]
+    LeafOutOfRange(u32, CpuidFunction),
+}
 
+// `__cpuid_count` is only marked safe on newer toolchains
+#[cfg(target_arch = "x86_64")]
+#[allow(unused_unsafe)]
 pub fn cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult {
     unsafe { __cpuid_count(leaf, sub_leaf) }
 }
 
+/// Run CPUID on the running cpu, `None` on architectures without the instruction
+fn host_cpuid(leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+    #[cfg(target_arch = "x86_64")]
+    return Some(cpuid(leaf, sub_leaf));
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = (leaf, sub_leaf);
+        None
+    }
+}
+
+/// Answers from the CPUID instruction of the running cpu, there are no leaves on other
+/// architectures
+///
+/// CPUID serializes the cpu, so each leaf is only run once and answered from a cache after that.
+/// Leaves that can change while running, such as the OSXSAVE flag or the APIC id of a thread that
+/// moved, are seen as they were when first read until [`RunningCpuidDB::refresh`].
 pub struct RunningCpuidDB {
-    basic_max: u32,
-    hypervisor_max: Option<u32>,
-    extended_max: u32,
+    basic_max: Option<u32>,
+    hypervisor_ranges: Vec<(u32, u32)>,
+    extended_max: Option<u32>,
+    cache: RefCell<BTreeMap<(u32, u32), CpuidResult>>,
 }
 
 impl RunningCpuidDB {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Forget the cached leaves and read the ranges again, so later reads run CPUID
+    pub fn refresh(&mut self) {
+        *self = Self::new();
+    }
+
+    fn cached_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        if let Some(result) = self.cache.borrow().get(&(leaf, sub_leaf)) {
+            return Some(*result);
+        }
+        let result = host_cpuid(leaf, sub_leaf)?;
+        self.cache.borrow_mut().insert((leaf, sub_leaf), result);
+        Some(result)
+    }
 }
 
 impl Default for RunningCpuidDB {
     fn default() -> Self {
-        let CpuidResult {
-            eax: basic_max,
-            ebx: _,
-            ecx: _,
-            edx: _,
-        } = cpuid(0, 0);
-
-        // This leaf has a hypervisor feature flag in ECX bit 31 and is also the same in the
-        // extended leaf, letting us detect the presence of those sets
-        let model_leaf = cpuid(1, 0);
-
-        let hypervisor_max = if model_leaf.ecx & (1u32 << 31) != 0 {
-            let CpuidResult {
-                eax: max,
-                ebx: _,
-                ecx: _,
-                edx: _,
-            } = cpuid(CpuidFunction::Hypervisor.start_eax(), 0);
-            Some(max)
-        } else {
-            None
-        };
+        let basic_max = host_cpuid(0, 0).map(|result| result.eax);
+
+        // Leaf 1 has a hypervisor feature flag in ECX bit 31, letting us detect the presence of
+        // the hypervisor leaves
+        let hypervisor_ranges = hypervisor_ranges(|leaf| host_cpuid(leaf, 0));
 
-        let CpuidResult {
-            eax: extended_max,
-            ebx: _,
-            ecx: _,
-            edx: _,
-        } = cpuid(CpuidFunction::Extended.start_eax(), 0);
+        let extended_max =
+            host_cpuid(CpuidFunction::Extended.start_eax(), 0).map(|result| result.eax);
 
         Self {
             basic_max,
-            hypervisor_max,
+            hypervisor_ranges,
             extended_max,
+            cache: RefCell::new(BTreeMap::new()),
         }
     }
 }
@@ -92,25 +351,68 @@ impl Default for RunningCpuidDB {
 impl CpuidDB for RunningCpuidDB {
     fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
         if match leaf {
-            0..=0x3FFFFFFF => leaf <= self.basic_max,
+            0..=0x3FFFFFFF => self.basic_max.is_some_and(|max| leaf <= max),
             0x40000000..=0x4fffffff => self
-                .hypervisor_max
-                .map_or(false, |max| leaf - 0x40000000 <= max),
-            0x80000000..=0x8fffffff => leaf - 0x80000000 <= self.extended_max,
+                .hypervisor_ranges
+                .iter()
+                .any(|(base, max)| (*base..=*max).contains(&leaf)),
+            0x80000000..=0x8fffffff => self
+                .extended_max
+                .is_some_and(|max| leaf - 0x80000000 <= max),
             _ => false,
         } {
-            Some(cpuid(leaf, sub_leaf))
+            self.cached_cpuid(leaf, sub_leaf)
         } else {
             None
         }
     }
+    fn max_leaf(&self, func: CpuidFunction) -> Option<u32> {
+        match func {
+            CpuidFunction::Basic => self.basic_max,
+            CpuidFunction::Hypervisor => self.hypervisor_ranges.first().map(|(_, max)| *max),
+            CpuidFunction::Extended => self.extended_max,
+        }
+    }
+}
+
+/// Answers from a function supplied by the embedding program, such as the host of a WebAssembly
+/// module reading leaves from a dump, where there is no CPUID instruction to run
+pub struct FnCpuidDB<F>(pub F);
+
+impl<F> CpuidDB for FnCpuidDB<F>
+where
+    F: Fn(u32, u32) -> Option<CpuidResult>,
+{
+    fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        (self.0)(leaf, sub_leaf)
+    }
+}
+
+impl<F> From<FnCpuidDB<F>> for CpuidType
+where
+    F: Fn(u32, u32) -> Option<CpuidResult> + 'static,
+{
+    fn from(source: FnCpuidDB<F>) -> Self {
+        Self::Boxed(Box::new(source))
+    }
 }
 
 #[enum_dispatch(CpuidDB)]
 pub enum CpuidType {
     Func(RunningCpuidDB),
-    #[cfg(all(target_os = "linux", feature = "kvm"))]
+    #[cfg(feature = "std")]
+    File(snapshot::FileCpuidDB),
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
     KvmInfo(kvm::KvmInfo),
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    KvmEmulated(kvm::KvmEmulatedInfo),
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    KvmVcpu(kvm::KvmVcpuInfo),
+    Layered(layered::LayeredCpuidDB),
+    #[cfg(feature = "core")]
+    Dump(dump::CpuidDump),
+    /// Any other source, such as one supplied by a user of the library
+    Boxed(Box<dyn CpuidDB>),
 }
 
 impl CpuidType {
@@ -119,7 +421,7 @@ impl CpuidType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CpuidFunction {
     Basic,
     Hypervisor,
@@ -143,46 +445,78 @@ impl CpuidFunction {
     }
 }
 
-#[derive(Debug, Hash, Clone)]
+#[derive(Serialize, Deserialize, Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LeafAddr {
     pub leaf: u32,
     pub sub_leaf: u32,
 }
 
+/// Walks the non-empty leaves and sub-leaves of one function of a cpuid source
+///
+/// The sub-leaves of each leaf are enumerated with its `sub_leaf::SubLeaves` strategy.
 #[derive(Debug, Clone)]
-pub struct CpuidIterator {
+pub struct CpuidIterator<DB> {
+    source: DB,
     leaf: u32,
     sub_leaf: u32,
     last: u32,
-    last_sub_leaf: Option<CpuidResult>,
+    /// Further blocks of hypervisor leaves to visit, last first
+    next_blocks: Vec<(u32, u32)>,
+    sub_leaves: BTreeMap<u32, sub_leaf::SubLeaves>,
+    /// The sub-leaves of the current leaf still to be returned, last first
+    pending: Vec<(LeafAddr, CpuidResult)>,
 }
 
-impl CpuidIterator {
-    pub fn new(func: CpuidFunction) -> Result<CpuidIterator, CpuidError> {
-        CpuidIterator::at_leaf(func.start_eax(), func)
+impl<DB: CpuidDB> CpuidIterator<DB> {
+    /// Iterate over `func` of `source`, pass a reference to keep using the source
+    pub fn new(source: DB, func: CpuidFunction) -> Result<Self> {
+        let leaf = func.start_eax();
+        Self::at_leaf(source, leaf, func)
     }
-    pub fn at_leaf(leaf: u32, func: CpuidFunction) -> Result<CpuidIterator, CpuidError> {
-        CpuidIterator::at_sub_leaf(leaf, 0, func)
+    pub fn at_leaf(source: DB, leaf: u32, func: CpuidFunction) -> Result<Self> {
+        Self::at_sub_leaf(source, leaf, 0, func)
     }
 
-    pub fn at_sub_leaf(
-        leaf: u32,
-        sub_leaf: u32,
-        func: CpuidFunction,
-    ) -> Result<CpuidIterator, CpuidError> {
-        let range_info_function = func.start_eax();
-
-        if func.is_valid_leaf(leaf) {
-            Ok(CpuidIterator {
+    pub fn at_sub_leaf(source: DB, leaf: u32, sub_leaf: u32, func: CpuidFunction) -> Result<Self> {
+        if !func.is_valid_leaf(leaf) {
+            Err(CpuidError::LeafOutOfRange(leaf, func).into())
+        } else if let CpuidFunction::Hypervisor = func {
+            let mut next_blocks: Vec<(u32, u32)> =
+                hypervisor_ranges(|leaf| source.get_cpuid(leaf, 0))
+                    .into_iter()
+                    .filter(|(_, max)| *max >= leaf)
+                    .rev()
+                    .collect();
+            let (base, last) = next_blocks.pop().unwrap_or((leaf, 0));
+            Ok(Self {
+                source,
+                leaf: leaf.max(base),
+                sub_leaf: if leaf >= base { sub_leaf } else { 0 },
+                last,
+                next_blocks,
+                sub_leaves: BTreeMap::new(),
+                pending: Vec::new(),
+            })
+        } else {
+            let last = source.max_leaf(func).ok_or(CpuidError::NoCPUID)?;
+            Ok(Self {
+                source,
                 leaf,
                 sub_leaf,
-                last: cpuid(range_info_function, 0).eax,
-                last_sub_leaf: None,
+                last,
+                next_blocks: Vec::new(),
+                sub_leaves: BTreeMap::new(),
+                pending: Vec::new(),
             })
-        } else {
-            Err(CpuidError::LeafOutOfRange(leaf, func))
         }
     }
+
+    /// Enumerate the sub-leaves of the leaves in `sub_leaves` with the given strategies rather
+    /// than those built in, e.g. the `sub_leaves` of the config
+    pub fn with_sub_leaves(mut self, sub_leaves: BTreeMap<u32, sub_leaf::SubLeaves>) -> Self {
+        self.sub_leaves = sub_leaves;
+        self
+    }
 }
 
 fn is_empty_leaf(result: &CpuidResult) -> bool {
@@ -191,29 +525,238 @@ fn is_empty_leaf(result: &CpuidResult) -> bool {
     *eax == 0 && *ebx == 0 && ((*ecx == 0 && *edx == 0) || (*ecx != 0 && *ecx & 0xFFFFFF00 == 0))
 }
 
-impl Iterator for CpuidIterator {
+impl<DB: CpuidDB> Iterator for CpuidIterator<DB> {
     type Item = (LeafAddr, CpuidResult);
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if self.leaf > self.last {
-                break None;
+            if let Some(next) = self.pending.pop() {
+                break Some(next);
             }
-            let current = cpuid(self.leaf, self.sub_leaf);
-            if is_empty_leaf(&current) || self.last_sub_leaf.take() == Some(current) {
-                self.leaf += 1;
+            if self.leaf > self.last {
+                let (base, last) = self.next_blocks.pop()?;
+                self.leaf = base;
                 self.sub_leaf = 0;
-            } else {
-                let sub_leaf = self.sub_leaf;
-                self.sub_leaf += 1;
-                self.last_sub_leaf.replace(current);
-                break Some((
-                    LeafAddr {
-                        leaf: self.leaf,
-                        sub_leaf,
-                    },
-                    current,
-                ));
+                self.last = last;
+                continue;
+            }
+            let strategy = sub_leaf::SubLeaves::lookup(&self.sub_leaves, self.leaf);
+            let sub_leaves = strategy.read(&self.source, self.leaf);
+            // Sources such as KVM leave out the leaves they do not support
+            if sub_leaves
+                .first()
+                .is_some_and(|(_, first)| !is_empty_leaf(first))
+            {
+                let (leaf, start) = (self.leaf, self.sub_leaf);
+                self.pending = sub_leaves
+                    .into_iter()
+                    .filter(|(sub_leaf, _)| *sub_leaf >= start)
+                    .map(|(sub_leaf, result)| (LeafAddr { leaf, sub_leaf }, result))
+                    .rev()
+                    .collect();
             }
+            self.leaf += 1;
+            self.sub_leaf = 0;
         }
     }
 }
+
+/// Every non-empty leaf of `source`, in the basic, hypervisor and extended functions
+///
+/// `sub_leaves` overrides the built-in sub-leaf strategies of some leaves, see `sub_leaf`.
+pub fn cpuid_leaves<'a, DB: CpuidDB + ?Sized>(
+    source: &'a DB,
+    sub_leaves: &'a BTreeMap<u32, sub_leaf::SubLeaves>,
+) -> impl Iterator<Item = (LeafAddr, CpuidResult)> + 'a {
+    IntoIterator::into_iter([
+        CpuidFunction::Basic,
+        CpuidFunction::Hypervisor,
+        CpuidFunction::Extended,
+    ])
+    .filter_map(move |func| CpuidIterator::new(source, func).ok())
+    .flat_map(move |leaves| leaves.with_sub_leaves(sub_leaves.clone()))
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use snapshot::SnapshotBuilder;
+
+    /// A cpu with the hypervisor bit of leaf 1 set to `hypervisor_bit` and Hyper-V leaves
+    fn make_snapshot(hypervisor_bit: u32) -> SnapshotBuilder {
+        SnapshotBuilder::new()
+            .leaf(1, [0, 0, hypervisor_bit << 31, 0])
+            // "Microsoft Hv"
+            .leaf(0x40000000, [0x40000006, 0x7263694d, 0x666f736f, 0x76482074])
+    }
+
+    #[test]
+    fn detect_hypervisor() {
+        assert_eq!(
+            hypervisor_signature(&make_snapshot(1).db()).as_deref(),
+            Some("Microsoft Hv")
+        );
+        assert_eq!(hypervisor_signature(&make_snapshot(0).db()), None);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn running_cpuid_cache() {
+        let mut db = RunningCpuidDB::new();
+        let vendor = db.get_cpuid(0, 0);
+        assert_eq!(vendor, Some(cpuid(0, 0)));
+        assert!(db.cache.borrow().contains_key(&(0, 0)));
+        assert_eq!(db.get_cpuid(0, 0), vendor);
+
+        db.refresh();
+        assert!(db.cache.borrow().is_empty());
+        assert_eq!(db.get_cpuid(0, 0), vendor);
+    }
+
+    #[test]
+    fn detect_shifted_hypervisor() {
+        let db = make_snapshot(1)
+            // "XenVMMXenVMM"
+            .leaf(0x40000100, [0x40000105, 0x566e6558, 0x65584d4d, 0x4d4d566e])
+            .leaf(0x40000101, [0x0004000a, 0, 0, 0])
+            // Not a block of leaves, the max leaf is outside it
+            .leaf(0x40000200, [0x40000006, 0x566e6558, 0x65584d4d, 0x4d4d566e])
+            .db();
+        assert_eq!(
+            hypervisor_bases(&db),
+            [
+                (0x40000000, "Microsoft Hv".to_string()),
+                (0x40000100, "XenVMMXenVMM".to_string()),
+            ]
+        );
+        assert_eq!(
+            db.get_cpuid(0x40000101, 0).map(|leaf| leaf.eax),
+            Some(0x0004000a)
+        );
+        assert!(db.get_cpuid(0x40000105, 0).is_some());
+        assert!(db.get_cpuid(0x40000106, 0).is_none());
+        assert!(db.get_cpuid(0x40000201, 0).is_none());
+    }
+
+    #[test]
+    fn tsc_frequency() {
+        assert_eq!(hypervisor_tsc_frequency(&make_snapshot(1).db()), None);
+
+        let db = SnapshotBuilder::new()
+            .leaf(1, [0, 0, 1 << 31, 0])
+            // "VMwareVMware"
+            .leaf(0x40000000, [0x40000010, 0x61774d56, 0x4d566572, 0x65726177])
+            .leaf(0x40000010, [2_400_000, 1_000_000, 0, 0])
+            .db();
+        assert_eq!(hypervisor_signature(&db).as_deref(), Some("VMwareVMware"));
+        assert_eq!(hypervisor_tsc_frequency(&db), Some(2_400_000_000));
+    }
+
+    #[test]
+    fn crystal_frequency() {
+        let result = |eax, ebx, ecx| CpuidResult {
+            eax,
+            ebx,
+            ecx,
+            edx: 0,
+        };
+        // A 25 MHz crystal at 2/168, as on Ice Lake servers
+        let tsc = result(2, 168, 25_000_000);
+        assert_eq!(
+            crystal_tsc_frequency(&tsc, None, 0x606a6),
+            Some(2_100_000_000)
+        );
+        // Kaby Lake leaves the crystal out, its model gives 24 MHz
+        let tsc = result(2, 300, 0);
+        assert_eq!(
+            crystal_tsc_frequency(&tsc, None, 0x906e9),
+            Some(3_600_000_000)
+        );
+        // Otherwise it follows from the base frequency
+        let frequency = result(3000, 4000, 100);
+        assert_eq!(
+            crystal_tsc_frequency(&tsc, Some(&frequency), 0x50657),
+            Some(3_000_000_000)
+        );
+        assert_eq!(crystal_tsc_frequency(&tsc, None, 0x50657), None);
+        let frequency = result(0xffff, 0xffff, 100);
+        assert_eq!(
+            crystal_tsc_frequency(&result(u32::MAX, 1, 0), Some(&frequency), 0x50657),
+            Some(65_535_000_000)
+        );
+        assert_eq!(crystal_tsc_frequency(&result(0, 0, 0), None, 0x906e9), None);
+
+        assert_eq!(super::tsc_frequency(&make_snapshot(0).db()), None);
+        let db = make_snapshot(0)
+            .leaf(TSC_LEAF, [2, 168, 25_000_000, 0])
+            .db();
+        assert_eq!(super::tsc_frequency(&db), Some(2_100_000_000));
+    }
+
+    #[test]
+    fn dyn_sources() {
+        let boxed: Box<dyn CpuidDB> = Box::new(make_snapshot(1).db());
+        assert_eq!(
+            hypervisor_signature(boxed.as_ref()).as_deref(),
+            Some("Microsoft Hv")
+        );
+        let desc = layout::LeafDesc::new(
+            "Hypervisor".to_string(),
+            layout::LeafType::HypervisorStart(layout::HypervisorStartLeaf {}),
+        );
+        assert!(desc.bind_leaf(0x40000000, boxed.as_ref()).is_some());
+        assert!(desc.bind_leaf(0x40000000, &boxed).is_some());
+
+        let source: CpuidType = boxed.into();
+        assert_eq!(source.get_cpuid(0x40000000, 0).unwrap().eax, 0x40000006);
+    }
+
+    #[test]
+    fn iterate_source() {
+        let db = make_snapshot(1)
+            .leaf(0, [4, 0, 0, 0])
+            .leaf(4, [0x121, 0x1c0003f, 0x3f, 0])
+            .sub_leaf(4, 1, [0x122, 0x1c0003f, 0x3f, 0])
+            .leaf(0x80000000, [0x80000001, 0, 0, 0])
+            .db();
+        let leaves: Vec<(u32, u32)> = cpuid_leaves(&db, &BTreeMap::new())
+            .map(|(addr, _)| (addr.leaf, addr.sub_leaf))
+            .collect();
+        // Leaves 2, 3 and 0x80000001 read back as zeros and are skipped
+        assert_eq!(
+            leaves,
+            [
+                (0, 0),
+                (1, 0),
+                (4, 0),
+                (4, 1),
+                (0x40000000, 0),
+                (0x80000000, 0)
+            ]
+        );
+        let from_leaf: Vec<u32> = CpuidIterator::at_leaf(&db, 4, CpuidFunction::Basic)
+            .unwrap()
+            .map(|(addr, _)| addr.sub_leaf)
+            .collect();
+        assert_eq!(from_leaf, [0, 1]);
+    }
+
+    #[test]
+    fn fn_source() {
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            // "AuthenticAMD"
+            (0, 0) => Some(CpuidResult {
+                eax: 0,
+                ebx: 0x68747541,
+                ecx: 0x444d4163,
+                edx: 0x69746e65,
+            }),
+            _ => None,
+        });
+        let facts = Collector::new().with_cpuid(source).collect().unwrap();
+        let vendor = facts
+            .iter()
+            .find(|fact| fact.name == "cpuid/Start/type")
+            .unwrap();
+        assert_eq!(vendor.value, facts::FactValue::from("AuthenticAMD"));
+    }
+}
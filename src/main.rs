@@ -4,20 +4,22 @@
 
 use clap::{self, Args, Parser, Subcommand, ValueEnum};
 use core_affinity::CoreId;
-use cpuinfo::facts::{FactSet, Facter, GenericFact};
-use cpuinfo::layout::LeafDesc;
+use cpuinfo::config::{Definition, MergeStrategy, Severity, SeverityRule, ToleranceRule};
+use cpuinfo::facts::Facter;
+use cpuinfo::facts::{self, Fact, FactSet, FactValue};
+use cpuinfo::layout::{BitFieldLeaf, DisplayLeaf, LeafDesc, LeafType, MultiSubLeaf};
 use cpuinfo::msr::MsrStore;
 use cpuinfo::*;
 use enum_dispatch::enum_dispatch;
 use msr::MSRDesc;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-
-type YAMLFact = GenericFact<serde_yaml::Value>;
-type YAMLFactSet = FactSet<serde_yaml::Value>;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[enum_dispatch()]
 trait Command {
@@ -33,6 +35,27 @@ enum CommandOpts {
     Disp(Disp),
     Facts(Facts),
     Diff(Diff),
+    Snapshot(Snapshot),
+    GenerateConfig(GenerateConfig),
+    #[cfg(feature = "schema")]
+    Schema(Schema),
+    Watch(Watch),
+    Daemon(Daemon),
+    #[cfg(feature = "http")]
+    Serve(Serve),
+    Explain(Explain),
+    Query(Query),
+    Topology(Topology),
+    Baseline(Baseline),
+    Export(Export),
+    Msr(Msr),
+    Vuln(Vuln),
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    KvmCompare(KvmCompare),
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    Kvm(Kvm),
+    #[cfg(feature = "history")]
+    History(History),
 }
 
 #[derive(Clone, Args)]
@@ -41,35 +64,129 @@ struct Disp {
     cpu: usize,
     #[arg(short, long)]
     raw: bool,
+    /// Layout of the raw leaves
+    #[arg(long, value_enum, default_value = "cpuinfo", requires = "raw")]
+    format: RawFormat,
     #[arg(long)]
     skip_cpu: bool,
-    #[cfg(all(target_os = "linux", feature = "kvm"))]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
     #[arg(long)]
     skip_kvm: bool,
+    /// With --raw, dump the cpuid KVM supports instead of the running cpu's
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long, requires = "raw", conflicts_with = "from_file")]
+    use_kvm: bool,
     #[cfg(feature = "use_msr")]
     #[arg(long)]
     skip_msr: bool,
+    /// Display a snapshot saved with the snapshot command instead of the running cpu
+    #[arg(long)]
+    from_file: Option<PathBuf>,
+    /// Show the description and reference of each documented field and MSR
+    #[arg(long, conflicts_with = "raw")]
+    describe: bool,
+}
+
+/// `value` in its alternate form when `describe` is set, which adds the docs of its fields
+fn described<T: fmt::Display>(value: &T, describe: bool) -> String {
+    if describe {
+        format!("{:#}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RawFormat {
+    /// `(leaf,sub-leaf) eax ebx ecx edx`, as read back by --from-file
+    Cpuinfo,
+    /// The layout of `cpuid -r` from the cpuid tool
+    CpuidR,
+}
+
+impl RawFormat {
+    /// Print `entries` under `header`, the name of the cpu they were read from
+    fn print<I: IntoIterator<Item = snapshot::CpuidEntry>>(self, header: &str, entries: I) {
+        if self == RawFormat::CpuidR {
+            println!("{}", header);
+        }
+        for entry in entries {
+            match self {
+                RawFormat::Cpuinfo => println!(
+                    "({:#010x},{:#010x}) {:#010x} {:#010x} {:#010x} {:#010x}",
+                    entry.leaf, entry.sub_leaf, entry.eax, entry.ebx, entry.ecx, entry.edx
+                ),
+                RawFormat::CpuidR => println!(
+                    "   {:#010x} {:#04x}: eax={:#010x} ebx={:#010x} ecx={:#010x} edx={:#010x}",
+                    entry.leaf, entry.sub_leaf, entry.eax, entry.ebx, entry.ecx, entry.edx
+                ),
+            }
+        }
+    }
+}
+
+impl Disp {
+    fn display_snapshot(
+        &self,
+        config: &Definition,
+        file_db: &snapshot::FileCpuidDB,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot = file_db.snapshot();
+        if self.raw {
+            display_raw(self.format, config, "CPU:", file_db);
+            return Ok(());
+        }
+        if !self.skip_cpu {
+            println!("CPUID:");
+            for (leaf, desc) in config.cpuids_for(file_db) {
+                if let Some(bound) = desc.bind_leaf(leaf, file_db) {
+                    println!("{:#010x}: {}", leaf, described(&bound, self.describe));
+                }
+            }
+        }
+        if !snapshot.is_empty() {
+            println!("MSRS:");
+            for msr in config.msrs_for(file_db) {
+                match snapshot.get_value(msr) {
+                    Ok(value) => println!("{}", described(&value, self.describe)),
+                    Err(err) => println!("{} Error : {}", msr, err),
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Command for Disp {
     fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(path) = &self.from_file {
+            return self.display_snapshot(config, &snapshot::FileCpuidDB::from_file(path)?);
+        }
         if !core_affinity::set_for_current(CoreId { id: self.cpu }) {
             panic!("Unable to pin to core {}", self.cpu);
         }
+        let header = format!("CPU {}:", self.cpu);
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+        if self.raw && self.use_kvm {
+            let kvm_info = kvm::KvmInfo::new(&kvm_ioctls::Kvm::new()?)?;
+            display_raw(self.format, config, &header, &kvm_info);
+            return Ok(());
+        }
         if self.raw {
-            display_raw()
+            display_raw(self.format, config, &header, &RunningCpuidDB::new());
+            Ok(())
         } else {
             if !self.skip_cpu {
                 println!("CPUID:");
                 let cpuid_db = cpuinfo::RunningCpuidDB::new();
-                for (leaf, desc) in &config.cpuids {
-                    if let Some(bound) = desc.bind_leaf(*leaf, &cpuid_db) {
-                        println!("{:#010x}: {}", leaf, bound);
+                for (leaf, desc) in config.cpuids_for(&cpuid_db) {
+                    if let Some(bound) = desc.bind_leaf(leaf, &cpuid_db) {
+                        println!("{:#010x}: {}", leaf, described(&bound, self.describe));
                     }
                 }
             }
 
-            #[cfg(all(target_os = "linux", feature = "kvm"))]
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
             if !self.skip_kvm {
                 use cpuinfo::kvm::KvmInfo;
                 use kvm_ioctls::Kvm;
@@ -77,35 +194,67 @@ impl Command for Disp {
                 if let Err(e) = {
                     let kvm = Kvm::new()?;
                     let kvm_info = KvmInfo::new(&kvm)?;
-                    for (leaf, desc) in &config.cpuids {
-                        if let Some(bound) = desc.bind_leaf(*leaf, &kvm_info) {
-                            println!("{:#010x}: {}", leaf, bound);
+                    for (leaf, desc) in config.cpuids_for(&kvm_info) {
+                        if let Some(bound) = desc.bind_leaf(leaf, &kvm_info) {
+                            println!("{:#010x}: {}", leaf, described(&bound, self.describe));
                         }
                     }
-                    Ok::<(), kvm_ioctls::Error>(())
+                    Ok::<(), cpuinfo::Error>(())
                 } {
                     println!("Error Processing KVM-CPUID: {}", e);
                 }
+                println!("KVM-EMULATED-CPUID:");
+                if let Err(e) = {
+                    let kvm = Kvm::new()?;
+                    let emulated = kvm::KvmEmulatedInfo::new(&kvm)?;
+                    for (leaf, desc) in config.cpuids_for(&emulated) {
+                        if let Some(bound) = desc.bind_leaf(leaf, &emulated) {
+                            println!("{:#010x}: {}", leaf, described(&bound, self.describe));
+                        }
+                    }
+                    Ok::<(), cpuinfo::Error>(())
+                } {
+                    println!("Error Processing KVM-EMULATED-CPUID: {}", e);
+                }
+                println!("KVM-CAPS:");
+                match Kvm::new() {
+                    Ok(kvm) => {
+                        for (name, value) in kvm::KvmCaps::new(&kvm, &config.kvm_caps).iter() {
+                            println!("  {} = {}", name, value);
+                        }
+                    }
+                    Err(e) => println!("Error Processing KVM-CAPS: {}", e),
+                }
             }
 
-            #[cfg(feature = "use_msr")]
+            #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+            {
+                println!("ID-REGISTERS:");
+                match arm::IdRegisters::read(self.cpu) {
+                    Ok(registers) => {
+                        for value in registers.bind(&config.id_registers) {
+                            print!("{}", described(&value, self.describe));
+                        }
+                    }
+                    Err(e) => println!("Error Processing ID-REGISTERS: {}", e),
+                }
+            }
+
+            #[cfg(all(target_arch = "x86_64", feature = "use_msr"))]
             if !self.skip_msr {
-                #[cfg(target_os = "linux")]
-                {
-                    match msr::linux::LinuxMsrStore::new(self.cpu) {
-                        Ok(linux_store) => {
-                            println!("MSRS:");
-                            for msr in &config.msrs {
-                                match linux_store.get_value(msr) {
-                                    Ok(value) => println!("{}", value),
-                                    Err(err) => println!("{} Error : {}", msr, err),
-                                }
+                match open_msr_store(self.cpu) {
+                    Ok(store) => {
+                        println!("MSRS:");
+                        for msr in config.msrs_for(&RunningCpuidDB::new()) {
+                            match store.get_value(msr) {
+                                Ok(value) => println!("{}", described(&value, self.describe)),
+                                Err(err) => println!("{} Error : {}", msr, err),
                             }
                         }
-                        Err(e) => println!("Error checking all msrs: {}", e),
                     }
+                    Err(e) => println!("Error checking all msrs: {}", e),
                 }
-                #[cfg(all(target_os = "linux", feature = "kvm"))]
+                #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
                 if !self.skip_kvm {
                     use cpuinfo::kvm::KvmMsrInfo;
                     use kvm_ioctls::Kvm;
@@ -113,9 +262,9 @@ impl Command for Disp {
                     if let Err(e) = {
                         let kvm = Kvm::new()?;
                         let kvm_msr = KvmMsrInfo::new(&kvm)?;
-                        for msr in &config.msrs {
+                        for msr in config.msrs_for(&RunningCpuidDB::new()) {
                             match kvm_msr.get_value(msr) {
-                                Ok(value) => println!("{}", value),
+                                Ok(value) => println!("{}", described(&value, self.describe)),
                                 Err(err) => println!("{} Error : {}", msr, err),
                             }
                         }
@@ -134,260 +283,3200 @@ impl Command for Disp {
 enum FactsOutput {
     Yaml,
     Json,
+    /// YAML nested by path segment
+    Tree,
+    /// A QEMU -cpu argument built from the config's qemu_features mapping
+    QemuCpu,
+    /// A libvirt <cpu> element, feature names come from the qemu_features mapping
+    Libvirt,
+    /// JSON nested by path segment, for use as an ansible local fact
+    Ansible,
+    /// node-feature-discovery labels built from the config's nfd_labels mapping
+    Nfd,
+    /// One line of the short names of the flags that are set, from the config's flag_names
+    /// mapping, like the flags of /proc/cpuinfo
+    Flags,
 }
 
 #[derive(Clone, Args)]
-struct Facts {
+struct FactsFormat {
+    #[arg(short, long, value_enum, default_value = "yaml")]
+    out_type: FactsOutput,
+    /// The base cpu model for qemu-cpu and libvirt output
+    #[arg(long, default_value = "host")]
+    cpu_model: String,
+    /// Add the description and reference of each documented field to yaml and json output
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+/// Namespace given to nfd labels that are not already qualified with one
+const NFD_LABEL_NAMESPACE: &str = "feature.node.kubernetes.io/";
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+impl FactsFormat {
+    /// The QEMU feature names of each mapped flag along with whether it is present
+    fn qemu_features<'a>(config: &'a Definition, facts: &[&Fact]) -> Vec<(&'a String, bool)> {
+        let mut seen = std::collections::HashSet::new();
+        facts
+            .iter()
+            .filter_map(
+                |fact| match (config.qemu_features.get(&fact.name), &fact.value) {
+                    (Some(feature), FactValue::Bool(present)) if seen.insert(feature) => {
+                        Some((feature, *present))
+                    }
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+
+    fn file(&self, config: &Definition, facts: &[&Fact]) -> facts::FactsFile {
+        let mut file = facts::FactsFile::new(facts.iter().map(|fact| (*fact).clone()).collect());
+        if self.verbose {
+            file.docs = config.docs_for(facts);
+        }
+        file
+    }
+
+    fn tree(facts: &[&Fact]) -> cpuinfo::Result<facts::FactTree> {
+        let facts: Vec<Fact> = facts.iter().map(|fact| (*fact).clone()).collect();
+        facts::FactTree::try_from(facts)
+    }
+
+    fn render(
+        &self,
+        config: &Definition,
+        facts: &[&Fact],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(match self.out_type {
+            FactsOutput::Yaml => serde_yaml::to_string(&self.file(config, facts))?,
+            FactsOutput::Json => serde_json::to_string(&self.file(config, facts))?,
+            FactsOutput::Tree => serde_yaml::to_string(&Self::tree(facts)?)?,
+            FactsOutput::QemuCpu => {
+                let mut ret = self.cpu_model.clone();
+                for (feature, present) in Self::qemu_features(config, facts) {
+                    ret.push_str(if present { ",+" } else { ",-" });
+                    ret.push_str(feature);
+                }
+                ret
+            }
+            FactsOutput::Ansible => serde_json::to_string_pretty(&Self::tree(facts)?)?,
+            FactsOutput::Libvirt => {
+                let mut ret = if self.cpu_model == "host" {
+                    "<cpu mode='host-model' check='partial'>\n".to_string()
+                } else {
+                    format!(
+                        "<cpu mode='custom' match='exact' check='partial'>\n  <model fallback='forbid'>{}</model>\n",
+                        xml_escape(&self.cpu_model)
+                    )
+                };
+                for (feature, present) in Self::qemu_features(config, facts) {
+                    ret.push_str(&format!(
+                        "  <feature policy='{}' name='{}'/>\n",
+                        if present { "require" } else { "disable" },
+                        xml_escape(feature)
+                    ));
+                }
+                ret.push_str("</cpu>");
+                ret
+            }
+            FactsOutput::Nfd => {
+                let mut labels = BTreeMap::new();
+                for fact in facts {
+                    let label = match config.nfd_labels.get(&fact.name) {
+                        Some(label) if label.contains('/') => label.clone(),
+                        Some(label) => format!("{}{}", NFD_LABEL_NAMESPACE, label),
+                        None => continue,
+                    };
+                    // Missing features are left unlabeled, as nfd does for its own cpuid source
+                    let value = match &fact.value {
+                        FactValue::Bool(false) => continue,
+                        other => other.to_string(),
+                    };
+                    labels.insert(label, value);
+                }
+                labels
+                    .iter()
+                    .map(|(label, value)| format!("{}={}", label, value))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            FactsOutput::Flags => {
+                let mut names: Vec<&str> = Vec::new();
+                for fact in facts {
+                    if let (Some(name), FactValue::Bool(true)) =
+                        (config.flag_names.get(&fact.name), &fact.value)
+                    {
+                        if !names.contains(&name.as_str()) {
+                            names.push(name);
+                        }
+                    }
+                }
+                names.join(" ")
+            }
+        })
+    }
+}
+
+// Selects where facts are collected from
+#[derive(Clone, Args, Debug)]
+struct FactSource {
     #[arg(short, long, default_value = "0")]
     cpu: usize,
-    #[cfg(all(target_os = "linux", feature = "kvm"))]
+    /// Collect from every online cpu, prefixing each fact with cpu<N>/
+    #[arg(long, conflicts_with_all = ["cpu", "from_file"])]
+    all_cpus: bool,
+    /// Collect what KVM gives guests instead of the running cpu: the cpuid it supports and its
+    /// feature MSRs on x86_64, the ID registers of a scratch vcpu on aarch64
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64"),
+        feature = "kvm"
+    ))]
     #[arg(short, long)]
     use_kvm: bool,
-    #[arg(short, long, value_enum, default_value = "yaml")]
-    out_type: FactsOutput,
+    /// With --use-kvm, collect the cpuid KVM emulates in software instead of what it supports
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long, requires = "use_kvm")]
+    kvm_emulated: bool,
+    /// With --use-kvm, collect the cpuid a scratch vcpu keeps once the cpuid KVM supports is set
+    /// on it, as a guest would see it
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long, requires = "use_kvm", conflicts_with = "kvm_emulated")]
+    kvm_scratch: bool,
+    /// A capability to enable on the scratch VM, named as in the config's kvm_caps or numbered,
+    /// with an optional argument such as x86_disable_exits=8
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long = "kvm-vm-cap", requires = "kvm_scratch")]
+    kvm_vm_caps: Vec<String>,
+    /// A capability to enable on the scratch vcpu, such as hyperv_synic
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long = "kvm-vcpu-cap", requires = "kvm_scratch")]
+    kvm_vcpu_caps: Vec<String>,
+    /// Present the Hyper-V leaves KVM supports on the scratch vcpu, KVM's own move to 0x40000100
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long, requires = "kvm_scratch")]
+    kvm_hyperv: bool,
+    /// Collect the cpuid a running VM's vcpu sees, from the VMM process with this pid (e.g.
+    /// QEMU). --cpu selects the vcpu
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+    #[arg(long, conflicts_with_all = ["use_kvm", "all_cpus", "from_file"])]
+    vm_pid: Option<u32>,
+    /// Collect facts from a snapshot saved with the snapshot command instead of the running cpu
+    #[arg(long)]
+    from_file: Option<PathBuf>,
+    /// Answer cpuid leaves from this snapshot over the selected source, e.g. to see the facts
+    /// of the running cpu with a leaf patched
+    #[arg(long, conflicts_with_all = ["from_file", "remote"])]
+    overlay: Option<PathBuf>,
+    /// Only take these leaves from --overlay instead of all it holds, as a leaf or an inclusive
+    /// range such as 0x40000000-0x400000ff
+    #[arg(long = "overlay-leaves", requires = "overlay", value_parser = parse_leaf_range)]
+    overlay_leaves: Vec<RangeInclusive<u32>>,
+    /// Leave MSR facts out of the collection
+    #[arg(long)]
+    skip_msr: bool,
+    /// Also give the whole value of each MSR as msr/<name>/raw
+    #[arg(long, conflicts_with = "skip_msr")]
+    msr_raw: bool,
+    /// Also give the cpu each MSR was read on as msr/<name>/cpu
+    #[arg(long, conflicts_with_all = ["skip_msr", "from_file"])]
+    msr_provenance: bool,
+    /// Also collect the kernel's view of the cpu from /proc/cpuinfo under proc/
+    #[cfg(target_os = "linux")]
+    #[arg(long, conflicts_with = "from_file")]
+    proc_cpuinfo: bool,
+    /// Also collect the cache and topology the kernel reports in sysfs under sysfs/
+    #[cfg(target_os = "linux")]
+    #[arg(long, conflicts_with = "from_file")]
+    sysfs: bool,
+    /// Also collect the processors, NUMA domains and caches of the ACPI tables under acpi/
+    #[cfg(target_os = "linux")]
+    #[arg(long, conflicts_with = "from_file")]
+    acpi: bool,
+    /// Also collect the cpu models named by --qemu-model from this QEMU QMP socket
+    #[cfg(all(unix, feature = "qmp"))]
+    #[arg(long)]
+    qmp: Option<PathBuf>,
+    /// A QEMU cpu model to expand, its properties are collected under qemu/<model>/
+    #[cfg(all(unix, feature = "qmp"))]
+    #[arg(long = "qemu-model", requires = "qmp", default_value = "host")]
+    qemu_models: Vec<String>,
+    /// Collect on this host over ssh (e.g. user@host) by running cpuinfo there, the other
+    /// options are passed along and the remote's built-in config is used
+    #[arg(long, conflicts_with = "from_file")]
+    remote: Option<String>,
+    /// The cpuinfo executable to run on the remote host
+    #[arg(long, default_value = "cpuinfo", requires = "remote")]
+    remote_cpuinfo: String,
 }
 
-fn collect_facts(
-    config: &Definition,
-    cpuid_selected: CpuidType,
-    msr_store: Box<dyn MsrStore>,
-) -> Result<Vec<YAMLFact>, Box<dyn std::error::Error>> {
-    let mut ret: Vec<YAMLFact> = config
-        .cpuids
-        .iter()
-        .filter_map(|(leaf, desc)| desc.bind_leaf(*leaf, &cpuid_selected))
-        .flat_map(|bound| bound.get_facts().into_iter())
-        .map(|mut fact| {
-            fact.add_path("cpuid");
-            fact
-        })
-        .collect();
+impl FactSource {
+    fn open(
+        &self,
+        config: &Definition,
+        cpu: usize,
+    ) -> Result<(CpuidType, Box<dyn MsrStore>), Box<dyn std::error::Error>> {
+        let (cpuid, msr_store) = self.open_source(config, cpu)?;
+        let path = match &self.overlay {
+            Some(path) => path,
+            None => return Ok((cpuid, msr_store)),
+        };
+        let snapshot = snapshot::Snapshot::from_file(path)?;
+        let leaves = if self.overlay_leaves.is_empty() {
+            snapshot
+                .cpuid
+                .iter()
+                .map(|entry| entry.leaf..=entry.leaf)
+                .collect()
+        } else {
+            self.overlay_leaves.clone()
+        };
+        let mut layered = layered::LayeredCpuidDB::new();
+        layered.push_leaves(snapshot::FileCpuidDB::new(snapshot), leaves);
+        layered.push(cpuid);
+        Ok((layered.into(), msr_store))
+    }
+
+    fn open_source(
+        &self,
+        config: &Definition,
+        cpu: usize,
+    ) -> Result<(CpuidType, Box<dyn MsrStore>), Box<dyn std::error::Error>> {
+        if let Some(path) = &self.from_file {
+            let snapshot = snapshot::Snapshot::from_file(path)?;
+            return Ok((
+                CpuidType::File(snapshot::FileCpuidDB::new(snapshot.clone())),
+                Box::new(snapshot),
+            ));
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+        if let Some(pid) = self.vm_pid {
+            return Ok((
+                kvm::KvmVcpuInfo::from_pid(pid, cpu)?.into(),
+                Box::new(msr::EmptyMSR {}),
+            ));
+        }
+        if !core_affinity::set_for_current(CoreId { id: cpu }) {
+            return Err(format!("Unable to pin to core {}", cpu).into());
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+        {
+            if self.use_kvm {
+                use cpuinfo::kvm::{KvmEmulatedInfo, KvmInfo};
+                use kvm::KvmMsrInfo;
+                use kvm_ioctls::Kvm;
+                let kvm = Kvm::new()?;
+                let msr_store: Box<dyn MsrStore> = if self.skip_msr {
+                    Box::new(msr::EmptyMSR {})
+                } else {
+                    Box::new(KvmMsrInfo::new(&kvm)?)
+                };
+                let cpuid: CpuidType = if self.kvm_emulated {
+                    KvmEmulatedInfo::new(&kvm)?.into()
+                } else if self.kvm_scratch {
+                    let caps = |names: &[String]| {
+                        names
+                            .iter()
+                            .map(|name| parse_kvm_cap(config, name))
+                            .collect::<Result<Vec<_>, _>>()
+                    };
+                    let options = kvm::ScratchOptions {
+                        vm_caps: caps(&self.kvm_vm_caps)?,
+                        vcpu_caps: caps(&self.kvm_vcpu_caps)?,
+                        hyperv: self.kvm_hyperv,
+                    };
+                    kvm::KvmVcpuInfo::scratch(&kvm, &options)?.into()
+                } else {
+                    KvmInfo::new(&kvm)?.into()
+                };
+                return Ok((cpuid, msr_store));
+            }
+        }
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64", feature = "kvm")))]
+        let _ = config;
+        let msr_store = if self.skip_msr {
+            Box::new(msr::EmptyMSR {})
+        } else {
+            host_msr_store(cpu)
+        };
+        Ok((CpuidType::func(), msr_store))
+    }
+
+    /// The cpuid and MSRs of `cpu` as the source gives them, unlike facts undecoded
+    #[cfg(feature = "http")]
+    fn capture(
+        &self,
+        config: &Definition,
+        cpu: usize,
+    ) -> Result<snapshot::Snapshot, Box<dyn std::error::Error>> {
+        let (cpuid, msr_store) = self.open(config, cpu)?;
+        Ok(snapshot::Snapshot::capture_from(
+            &cpuid,
+            msr_store.as_ref(),
+            config,
+        ))
+    }
 
-    if !msr_store.is_empty() {
-        for msr in &config.msrs {
-            if let Ok(value) = msr_store.get_value(msr) {
-                let mut facts = value.collect_facts();
+    fn collect(&self, config: &Definition) -> Result<Vec<Fact>, Box<dyn std::error::Error>> {
+        if let Some(destination) = &self.remote {
+            return self.collect_remote(config, destination);
+        }
+        #[allow(unused_mut)]
+        let mut ret = self.collect_cpus(config)?;
+        // Capabilities belong to the host's KVM rather than any one cpu
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+        if self.use_kvm && self.from_file.is_none() {
+            let caps = kvm::KvmCaps::new(&kvm_ioctls::Kvm::new()?, &config.kvm_caps);
+            let mut facts: Vec<Fact> = caps.collect_facts();
+            for fact in &mut facts {
+                fact.add_path("kvm");
+            }
+            ret.append(&mut facts);
+        }
+        #[cfg(target_os = "linux")]
+        if self.acpi {
+            let mut facts: Vec<Fact> = acpi::AcpiTables::read()?.collect_facts();
+            for fact in &mut facts {
+                fact.add_path("acpi");
+            }
+            ret.append(&mut facts);
+        }
+        #[cfg(target_os = "macos")]
+        if self.from_file.is_none() {
+            match sysctl::SysctlFacts::collect() {
+                Ok(sysctl) => {
+                    let mut facts: Vec<Fact> = sysctl.collect_facts();
+                    for fact in &mut facts {
+                        fact.add_path("sysctl");
+                    }
+                    ret.append(&mut facts);
+                }
+                Err(e) => eprintln!("Error reading sysctl: {}", e),
+            }
+        }
+        #[cfg(all(unix, feature = "qmp"))]
+        if let Some(socket) = &self.qmp {
+            let mut client = qmp::QmpClient::connect(socket)?;
+            for model in &self.qemu_models {
+                let mut facts: Vec<Fact> = client.cpu_model_expansion(model)?.collect_facts();
                 for fact in &mut facts {
-                    fact.add_path("msr");
+                    fact.add_path("qemu");
                 }
                 ret.append(&mut facts);
             }
         }
+        Ok(ret)
     }
 
-    Ok(ret)
-}
+    /// The arguments that select this source, for passing to another cpuinfo
+    fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.all_cpus {
+            args.push("--all-cpus".to_string());
+        } else {
+            args.extend(["--cpu".to_string(), self.cpu.to_string()]);
+        }
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64"),
+            feature = "kvm"
+        ))]
+        if self.use_kvm {
+            args.push("--use-kvm".to_string());
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+        {
+            if self.kvm_emulated {
+                args.push("--kvm-emulated".to_string());
+            }
+            if self.kvm_scratch {
+                args.push("--kvm-scratch".to_string());
+            }
+            for cap in &self.kvm_vm_caps {
+                args.extend(["--kvm-vm-cap".to_string(), cap.clone()]);
+            }
+            for cap in &self.kvm_vcpu_caps {
+                args.extend(["--kvm-vcpu-cap".to_string(), cap.clone()]);
+            }
+            if self.kvm_hyperv {
+                args.push("--kvm-hyperv".to_string());
+            }
+            if let Some(pid) = self.vm_pid {
+                args.extend(["--vm-pid".to_string(), pid.to_string()]);
+            }
+        }
+        if self.skip_msr {
+            args.push("--skip-msr".to_string());
+        }
+        if self.msr_raw {
+            args.push("--msr-raw".to_string());
+        }
+        if self.msr_provenance {
+            args.push("--msr-provenance".to_string());
+        }
+        #[cfg(target_os = "linux")]
+        if self.proc_cpuinfo {
+            args.push("--proc-cpuinfo".to_string());
+        }
+        #[cfg(target_os = "linux")]
+        if self.sysfs {
+            args.push("--sysfs".to_string());
+        }
+        #[cfg(target_os = "linux")]
+        if self.acpi {
+            args.push("--acpi".to_string());
+        }
+        #[cfg(all(unix, feature = "qmp"))]
+        if let Some(socket) = &self.qmp {
+            args.extend(["--qmp".to_string(), socket.display().to_string()]);
+            for model in &self.qemu_models {
+                args.extend(["--qemu-model".to_string(), model.clone()]);
+            }
+        }
+        args
+    }
 
-impl Command for Facts {
-    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
-        if !core_affinity::set_for_current(CoreId { id: self.cpu }) {
-            panic!("Unable to pin to core {}", self.cpu);
+    /// Collect on `destination` with its own cpuinfo, renaming the facts of an older one by the
+    /// config's aliases
+    fn collect_remote(
+        &self,
+        config: &Definition,
+        destination: &str,
+    ) -> Result<Vec<Fact>, Box<dyn Error>> {
+        // ssh hands the command to the remote shell as one line, so each argument is quoted
+        let mut args = vec![
+            self.remote_cpuinfo.clone(),
+            "facts".into(),
+            "-o".into(),
+            "yaml".into(),
+        ];
+        args.extend(self.to_args());
+        let command: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+        let output = std::process::Command::new("ssh")
+            .args(["-o", "BatchMode=yes", "--", destination])
+            .arg(command.join(" "))
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "Remote collection on {} failed: {}",
+                destination,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
         }
-        let (cpuid_source, msr_source): (_, Box<dyn MsrStore>) = {
-            #[cfg(all(target_os = "linux", feature = "kvm"))]
-            {
-                if self.use_kvm {
-                    use cpuinfo::kvm::KvmInfo;
-                    use kvm::KvmMsrInfo;
-                    use kvm_ioctls::Kvm;
-                    let kvm = Kvm::new()?;
-                    (
-                        KvmInfo::new(&kvm)?.into(),
-                        Box::new(KvmMsrInfo::new(&kvm)?) as Box<dyn MsrStore>,
-                    )
-                } else {
-                    let msr = {
-                        #[cfg(feature = "use_msr")]
-                        {
-                            match msr::linux::LinuxMsrStore::new(self.cpu) {
-                                Ok(store) => Box::new(store) as Box<dyn MsrStore>,
-                                Err(e) => {
-                                    eprintln!("Error accessing MSRs: {}", e);
-                                    Box::new(msr::EmptyMSR {})
-                                }
-                            }
-                        }
-                        #[cfg(not(feature = "use_msr"))]
-                        {
-                            Box::new(msr::EmptyMSR {})
-                        }
-                    };
-                    (CpuidType::func(), msr)
-                }
+        let mut facts = facts::FactsFile::parse(&String::from_utf8_lossy(&output.stdout))?.facts;
+        config.canonicalize(&mut facts);
+        Ok(facts)
+    }
+
+    /// A collector for `cpu` with the MSR options that were asked for
+    fn collector<'a>(&self, config: &'a Definition, cpu: usize) -> Collector<'a> {
+        let mut collector = Collector::new().with_config(config);
+        if self.msr_raw {
+            collector = collector.with_msr_raw();
+        }
+        // KVM's feature MSRs are not read on any one cpu
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+        let on_cpu = !self.use_kvm;
+        #[cfg(not(all(target_os = "linux", target_arch = "x86_64", feature = "kvm")))]
+        let on_cpu = true;
+        if self.msr_provenance && on_cpu {
+            collector = collector.with_msr_cpu(cpu);
+        }
+        collector
+    }
+
+    fn collect_cpus(&self, config: &Definition) -> Result<Vec<Fact>, Box<dyn std::error::Error>> {
+        let proc = self.proc_cpuinfo()?;
+        let proc = proc.as_ref();
+        if !self.all_cpus {
+            let (cpuid_source, msr_source) = self.open(config, self.cpu)?;
+            let mut facts = self
+                .collector(config, self.cpu)
+                .with_cpuid(cpuid_source)
+                .with_msr(msr_source)
+                .collect()?;
+            facts.append(&mut self.supplementary_facts(config, self.cpu, proc)?);
+            return Ok(facts);
+        }
+        let per_cpu = on_each_cpu(|id| {
+            let (cpuid_source, msr_source) = self.open(config, id).map_err(|e| e.to_string())?;
+            let mut facts = self
+                .collector(config, id)
+                .with_cpuid(cpuid_source)
+                .with_msr(msr_source)
+                .collect()
+                .map_err(|e| e.to_string())?;
+            facts.append(
+                &mut self
+                    .supplementary_facts(config, id, proc)
+                    .map_err(|e| e.to_string())?,
+            );
+            Ok::<_, String>(facts)
+        })?;
+        let mut ret = Vec::new();
+        for (id, facts) in per_cpu {
+            let mut facts = facts?;
+            for fact in &mut facts {
+                fact.add_path(&format!("cpu{}", id));
             }
-            #[cfg(all(target_os = "linux", not(feature = "kvm"), feature = "use_msr"))]
-            {
-                (
-                    CpuidType::func(),
-                    Box::new(msr::linux::LinuxMsrStore::new()?) as Box<dyn MsrStore>,
-                )
+            ret.append(&mut facts);
+        }
+        Ok(ret)
+    }
+
+    /// /proc/cpuinfo when its facts were asked for, read once for every cpu
+    fn proc_cpuinfo(&self) -> Result<Option<proc_cpuinfo::ProcCpuinfo>, Box<dyn Error>> {
+        #[cfg(target_os = "linux")]
+        if self.proc_cpuinfo {
+            return Ok(Some(proc_cpuinfo::ProcCpuinfo::read()?));
+        }
+        Ok(None)
+    }
+
+    /// Facts of `cpu` from the sources beside CPUID and MSRs that were asked for, `proc` being
+    /// /proc/cpuinfo when its facts were
+    #[cfg_attr(
+        not(all(target_os = "linux", target_arch = "aarch64")),
+        allow(unused_variables)
+    )]
+    fn supplementary_facts(
+        &self,
+        config: &Definition,
+        cpu: usize,
+        proc: Option<&proc_cpuinfo::ProcCpuinfo>,
+    ) -> Result<Vec<Fact>, Box<dyn Error>> {
+        #[allow(unused_mut)]
+        let mut ret = Vec::new();
+        if let Some(proc) = proc {
+            let processor = proc
+                .processor(cpu)
+                .ok_or_else(|| format!("cpu {} is not listed in /proc/cpuinfo", cpu))?;
+            let mut facts: Vec<Fact> = processor.collect_facts();
+            for fact in &mut facts {
+                fact.add_path("proc");
             }
-            #[cfg(any(
-                not(target_os = "linux"),
-                all(not(feature = "kvm"), not(feature = "use_msr"))
-            ))]
-            {
-                (
-                    CpuidType::func(),
-                    Box::new(msr::EmptyMSR {}) as Box<dyn MsrStore>,
-                )
+            ret.append(&mut facts);
+        }
+        #[cfg(target_os = "linux")]
+        if self.sysfs {
+            let mut facts: Vec<Fact> = sysfs::CpuSysfs::read(cpu)?.collect_facts();
+            for fact in &mut facts {
+                fact.add_path("sysfs");
             }
-        };
-        let facts = collect_facts(config, cpuid_source, msr_source)?;
-        println!(
-            "{}",
-            match self.out_type {
-                FactsOutput::Yaml => serde_yaml::to_string(&facts)?,
-                FactsOutput::Json => serde_json::to_string(&facts)?,
+            ret.append(&mut facts);
+        }
+        // Arm cpus have no CPUID, their ID registers take its place
+        #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+        if self.from_file.is_none() {
+            #[cfg(feature = "kvm")]
+            let registers = if self.use_kvm {
+                arm::IdRegisters::from_kvm(&kvm_ioctls::Kvm::new()?)?
+            } else {
+                arm::IdRegisters::read(cpu)?
+            };
+            #[cfg(not(feature = "kvm"))]
+            let registers = arm::IdRegisters::read(cpu)?;
+            for value in registers.bind(&config.id_registers) {
+                let mut facts: Vec<Fact> = value.collect_facts();
+                for fact in &mut facts {
+                    fact.add_path("idreg");
+                }
+                ret.append(&mut facts);
             }
-        );
-        Ok(())
+        }
+        Ok(ret)
     }
 }
 
-fn read_facts_from_file(fname: &str) -> Result<Vec<YAMLFact>, Box<dyn Error>> {
-    let file = std::fs::File::open(fname)?;
-    Ok(serde_yaml::from_reader(file)?)
+#[derive(Clone, Args)]
+struct Facts {
+    #[command(flatten)]
+    source: FactSource,
+    #[command(flatten)]
+    format: FactsFormat,
+    /// Also record the collection in this history file, see the history command
+    #[cfg(feature = "history")]
+    #[arg(long)]
+    record: Option<PathBuf>,
 }
 
-#[derive(Serialize, Debug)]
-struct DiffOutput {
-    added: Vec<YAMLFact>,
-    removed: Vec<YAMLFact>,
-    changed: Vec<(YAMLFact, YAMLFact)>,
+/// Run `read` for every online cpu at once, each on its own thread pinned to the cpu, giving the
+/// results in the order of the cpus
+///
+/// Pinning a short lived thread rather than this one also keeps the next listing of cores from
+/// being limited to the last core visited.
+fn on_each_cpu<T, F>(read: F) -> Result<Vec<(usize, T)>, Box<dyn std::error::Error>>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    let cores = core_affinity::get_core_ids().ok_or("Unable to list online cpus")?;
+    let read = &read;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = cores
+            .into_iter()
+            .map(|core| {
+                let worker = scope.spawn(move || {
+                    // A cpu taken offline since the listing is an error rather than a panic
+                    if core_affinity::set_for_current(core) {
+                        Some(read(core.id))
+                    } else {
+                        None
+                    }
+                });
+                (core.id, worker)
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|(id, worker)| {
+                let result = worker.join().expect("cpu worker thread panicked");
+                Ok((
+                    id,
+                    result.ok_or_else(|| format!("Unable to pin to core {}", id))?,
+                ))
+            })
+            .collect()
+    })
 }
 
-impl DiffOutput {
-    pub fn is_empty(&self) -> bool {
-        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+/// `arg` as a POSIX shell reads it back, in single quotes unless it only has characters the
+/// shell takes literally
+fn shell_quote(arg: &str) -> String {
+    let literal = |c: char| c.is_ascii_alphanumeric() || "_-+=.,:/@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(literal) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
     }
 }
 
-#[derive(Debug)]
-struct DiffFoundError {
-    inner: DiffOutput,
+/// Open the MSR store for `cpu` on the running machine, through msr-safe when it is loaded and the
+/// msr driver otherwise, falling back to an empty store
+fn host_msr_store(cpu: usize) -> Box<dyn MsrStore> {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "use_msr"))]
+    {
+        match msr::linux::LinuxMsrStore::detect(cpu) {
+            Ok(store) => Box::new(store),
+            Err(e) => {
+                eprintln!("Error accessing MSRs: {}", e);
+                Box::new(msr::EmptyMSR {})
+            }
+        }
+    }
+    #[cfg(all(windows, target_arch = "x86_64", feature = "winring0"))]
+    {
+        match msr::windows::WinRing0MsrStore::new(cpu) {
+            Ok(store) => Box::new(store),
+            Err(e) => {
+                eprintln!("Error accessing MSRs: {}", e);
+                Box::new(msr::EmptyMSR {})
+            }
+        }
+    }
+    #[cfg(all(
+        not(target_os = "linux"),
+        not(all(windows, feature = "winring0")),
+        target_arch = "x86_64",
+        feature = "use_msr"
+    ))]
+    {
+        let _ = cpu;
+        eprintln!("Error accessing MSRs: not supported on this platform");
+        Box::new(msr::EmptyMSR {})
+    }
+    // Other architectures have no MSRs to read
+    #[cfg(not(all(target_arch = "x86_64", feature = "use_msr")))]
+    {
+        let _ = cpu;
+        Box::new(msr::EmptyMSR {})
+    }
 }
 
-impl DiffFoundError {
-    pub fn new(inner: DiffOutput) -> Self {
-        Self { inner }
+/// Like `host_msr_store`, but failing when the MSRs can not be read
+fn open_msr_store(cpu: usize) -> Result<Box<dyn MsrStore>, Box<dyn std::error::Error>> {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "use_msr"))]
+    {
+        Ok(Box::new(msr::linux::LinuxMsrStore::detect(cpu)?))
+    }
+    #[cfg(all(windows, target_arch = "x86_64", feature = "winring0"))]
+    {
+        Ok(Box::new(msr::windows::WinRing0MsrStore::new(cpu)?))
+    }
+    #[cfg(not(any(
+        all(target_os = "linux", target_arch = "x86_64", feature = "use_msr"),
+        all(windows, target_arch = "x86_64", feature = "winring0")
+    )))]
+    {
+        let _ = cpu;
+        Err("MSR access is not supported by this build".into())
     }
 }
 
-impl fmt::Display for DiffFoundError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let as_yaml = serde_yaml::to_string(&self.inner).map_err(|_| std::fmt::Error {})?;
-        write!(f, "{}", as_yaml)
+/// Like `open_msr_store`, opening the MSRs for writing as well
+#[cfg(feature = "msr_write")]
+fn open_writable_msr_store(
+    cpu: usize,
+) -> Result<Box<dyn msr::MsrStoreMut>, Box<dyn std::error::Error>> {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    {
+        Ok(Box::new(msr::linux::LinuxMsrStore::detect_writable(cpu)?))
+    }
+    #[cfg(all(windows, target_arch = "x86_64", feature = "winring0"))]
+    {
+        Ok(Box::new(msr::windows::WinRing0MsrStore::new(cpu)?))
+    }
+    #[cfg(not(any(
+        all(target_os = "linux", target_arch = "x86_64"),
+        all(windows, target_arch = "x86_64", feature = "winring0")
+    )))]
+    {
+        let _ = cpu;
+        Err("MSR writes are not supported on this platform".into())
     }
 }
 
-impl std::error::Error for DiffFoundError {}
+impl Command for Facts {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let facts = self.source.collect(config)?;
+        #[cfg(feature = "history")]
+        if let Some(path) = &self.record {
+            let host = match &self.source.remote {
+                Some(destination) => destination.clone(),
+                None => hostname(),
+            };
+            let cpu = (!self.source.all_cpus).then_some(self.source.cpu as u64);
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            history::History::open(path)?.record(&host, cpu, timestamp, &facts)?;
+        }
+        println!(
+            "{}",
+            self.format
+                .render(config, &facts.iter().collect::<Vec<_>>())?
+        );
+        Ok(())
+    }
+}
 
 #[derive(Clone, Args)]
-struct Diff {
-    from_file_name: String,
-    to_file_name: String,
+struct Snapshot {
+    #[arg(short, long, default_value = "0")]
+    cpu: usize,
+    #[cfg(feature = "use_msr")]
+    #[arg(long)]
+    skip_msr: bool,
+    /// File to write the snapshot to, stdout if not given
     #[arg(short, long)]
-    verbose: bool,
+    output: Option<PathBuf>,
 }
 
-impl Command for Diff {
-    fn run(&self, _config: &Definition) -> Result<(), Box<dyn Error>> {
-        let from: YAMLFactSet = read_facts_from_file(&self.from_file_name)?.into();
-        let to: YAMLFactSet = read_facts_from_file(&self.to_file_name)?.into();
-
-        let output = DiffOutput {
-            added: from.added_facts(&to).map(Clone::clone).collect(),
-            removed: from.removed_facts(&to).map(Clone::clone).collect(),
-            changed: from
-                .changed_facts(&to)
-                .map(|v| (v.0.clone(), v.1.clone()))
-                .collect(),
-        };
-
-        if output.is_empty() {
-            if self.verbose {
-                println!("{}", serde_yaml::to_string(&output)?);
+impl Command for Snapshot {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        if !core_affinity::set_for_current(CoreId { id: self.cpu }) {
+            panic!("Unable to pin to core {}", self.cpu);
+        }
+        let msr_store = {
+            #[cfg(feature = "use_msr")]
+            {
+                if self.skip_msr {
+                    Box::new(msr::EmptyMSR {})
+                } else {
+                    host_msr_store(self.cpu)
+                }
             }
-            Ok(())
-        } else {
-            println!("{}", serde_yaml::to_string(&output)?);
-            Err(DiffFoundError::new(output).into())
+            #[cfg(not(feature = "use_msr"))]
+            {
+                host_msr_store(self.cpu)
+            }
+        };
+        let snapshot = snapshot::Snapshot::capture(msr_store.as_ref(), config);
+        match &self.output {
+            Some(path) => serde_yaml::to_writer(std::fs::File::create(path)?, &snapshot)?,
+            None => println!("{}", serde_yaml::to_string(&snapshot)?),
         }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Definition {
-    pub cpuids: BTreeMap<u32, LeafDesc>,
-    pub msrs: Vec<MSRDesc>,
+/// Parse a number given either in decimal or as hex with a leading 0x
+fn parse_number(text: &str) -> Result<u32, String> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|e| format!("Invalid number '{}': {}", text, e))
 }
 
-impl Definition {
-    pub fn union(&mut self, b: Definition) {
-        let Definition {
-            mut cpuids,
-            mut msrs,
-        } = b;
-        self.cpuids.append(&mut cpuids);
-        self.msrs.append(&mut msrs);
+#[cfg(feature = "msr_write")]
+fn parse_u64(text: &str) -> Result<u64, String> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
     }
+    .map_err(|e| format!("Invalid number '{}': {}", text, e))
 }
 
-fn find_read_config() -> Result<Definition, Box<dyn std::error::Error>> {
-    let file = include_str!("config.yaml");
-    Ok(serde_yaml::from_str(file)?)
+/// Parse a KVM capability to enable, named as in the config's `kvm_caps` or numbered, with an
+/// optional argument such as `x86_disable_exits=8`
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+fn parse_kvm_cap(config: &Definition, text: &str) -> Result<(u32, u64), String> {
+    let (name, arg) = match text.split_once('=') {
+        Some((name, arg)) => (name, parse_number(arg)?.into()),
+        None => (text, 0),
+    };
+    let cap = match config.kvm_caps.get(name) {
+        Some(cap) => *cap,
+        None => parse_number(name).map_err(|_| format!("Unknown KVM capability '{}'", name))?,
+    };
+    Ok((cap, arg))
 }
 
-fn display_raw() -> Result<(), Box<dyn std::error::Error>> {
-    let iter = CpuidIterator::new(CpuidFunction::Basic)
-        .expect("problems with cpuid iterator")
-        .chain(
-            CpuidIterator::new(CpuidFunction::Hypervisor)
-                .expect("problems with hyperfisor cpuid iterator"),
-        )
-        .chain(
-            CpuidIterator::new(CpuidFunction::Extended)
-                .expect("problems with extended cpuid iterator"),
-        );
-    for (LeafAddr { leaf, sub_leaf }, result) in iter {
-        println!(
-            "({:#010x},{:#010x}) {:#010x} {:#010x} {:#010x} {:#010x}",
-            leaf, sub_leaf, result.eax, result.ebx, result.ecx, result.edx
-        );
+/// Parse a leaf or an inclusive range of leaves such as `0x40000000-0x400000ff`
+fn parse_leaf_range(text: &str) -> Result<RangeInclusive<u32>, String> {
+    match text.split_once('-') {
+        Some((first, last)) => Ok(parse_number(first)?..=parse_number(last)?),
+        None => parse_number(text).map(|leaf| leaf..=leaf),
+    }
+}
+
+/// Parse a duration such as `30s`, `5m`, `2h` or `1d`, a bare number is taken as seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = text.split_at(
+        text.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len()),
+    );
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", text))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Unknown duration unit '{}'", unit)),
+    };
+    Ok(Duration::from_secs(number * scale))
+}
+
+#[derive(Clone, Args)]
+struct Watch {
+    #[command(flatten)]
+    source: FactSource,
+    /// Time between collections, e.g. 30s, 5m or 1h
+    #[arg(short, long, default_value = "30s", value_parser = parse_duration)]
+    interval: Duration,
+    /// Stop after this many re-collections instead of running forever
+    #[arg(long)]
+    count: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct WatchEvent {
+    /// Seconds since the unix epoch when the change was seen
+    timestamp: u64,
+    #[serde(flatten)]
+    changes: DiffOutput,
+}
+
+impl Command for Watch {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let mut current: FactSet = self.source.collect(config)?.into();
+        let mut collections = 0;
+        while self.count.is_none_or(|count| collections < count) {
+            std::thread::sleep(self.interval);
+            collections += 1;
+
+            let next: FactSet = self.source.collect(config)?.into();
+            let changes = DiffOutput::new(&current, &next);
+            if !changes.is_empty() {
+                let event = WatchEvent {
+                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                    changes,
+                };
+                println!("{}", serde_yaml::to_string(&event)?);
+            }
+            current = next;
+        }
+        Ok(())
+    }
+}
+
+/// Collect facts once and keep them current for repeated callers
+///
+/// The facts are written to the cache file and, with `--socket`, sent as YAML to each connection.
+/// They are collected again when the microcode revision or the kernel's boot id changes, and a
+/// cache file left from an earlier run in the same state, with the same source options and
+/// config, is reused without collecting.
+#[derive(Clone, Args)]
+struct Daemon {
+    #[command(flatten)]
+    source: FactSource,
+    /// File to keep the facts in, a facts file with the state they were collected in added
+    #[arg(long)]
+    cache: PathBuf,
+    /// Unix socket to serve the facts on
+    #[cfg(unix)]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// Time between checks of the microcode revision and boot id
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    interval: Duration,
+}
+
+impl Daemon {
+    /// The source options and config facts are collected with, a cache of facts collected with
+    /// others is not current
+    fn options(&self, config: &Definition) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(format!(
+            "{:?}\n{}",
+            self.source,
+            serde_json::to_string(config)?
+        ))
+    }
+
+    /// The cache for `state`, from the file when it is current and collected otherwise
+    fn refresh(
+        &self,
+        config: &Definition,
+        state: cache::MachineState,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let cached = match cache::CachedFacts::read(&self.cache) {
+            Some(cached) if cached.is_current(&state) => cached,
+            _ => {
+                let cached = cache::CachedFacts::new(state, self.source.collect(config)?);
+                cached.write(&self.cache)?;
+                cached
+            }
+        };
+        Ok(serde_yaml::to_string(&cached)?)
+    }
+
+    /// Listen on `socket`, replacing a socket left by an earlier run
+    #[cfg(unix)]
+    fn bind(socket: &Path) -> Result<std::os::unix::net::UnixListener, Box<dyn std::error::Error>> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // A stale socket would make the bind fail, anything else at the path is not ours to remove
+        if let Ok(metadata) = std::fs::symlink_metadata(socket) {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", socket.display()).into());
+            }
+            std::fs::remove_file(socket)?;
+        }
+        std::os::unix::net::UnixListener::bind(socket)
+            .map_err(|e| format!("Unable to listen on {}: {}", socket.display(), e).into())
+    }
+
+    /// Send the current facts to each connection on `listener`, each on a thread of its own
+    #[cfg(unix)]
+    fn serve(listener: std::os::unix::net::UnixListener, current: &std::sync::RwLock<String>) {
+        use std::io::Write;
+
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Error accepting a connection: {}", e);
+                    continue;
+                }
+            };
+            let text = current.read().expect("cache lock poisoned").clone();
+            std::thread::spawn(move || {
+                if let Err(e) = stream.write_all(text.as_bytes()) {
+                    eprintln!("Error serving facts: {}", e);
+                }
+            });
+        }
+    }
+}
+
+impl Command for Daemon {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let options = self.options(config)?;
+        #[cfg(unix)]
+        let listener = self.socket.as_deref().map(Self::bind).transpose()?;
+        let mut state = cache::MachineState::current().with_options(&options);
+        let current = std::sync::RwLock::new(self.refresh(config, state.clone())?);
+        std::thread::scope(|scope| {
+            #[cfg(not(unix))]
+            let _ = scope;
+            #[cfg(unix)]
+            if let Some(listener) = listener {
+                let current = &current;
+                scope.spawn(move || Self::serve(listener, current));
+            }
+            loop {
+                std::thread::sleep(self.interval);
+                let next = cache::MachineState::current().with_options(&options);
+                if next == state {
+                    continue;
+                }
+                eprintln!("Machine state changed, collecting facts");
+                // On failure the old facts are served and collection is tried again next time
+                match self.refresh(config, next.clone()) {
+                    Ok(text) => {
+                        *current.write().expect("cache lock poisoned") = text;
+                        state = next;
+                    }
+                    Err(e) => eprintln!("Error collecting facts: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// The name of the running machine, as recorded in the history
+#[cfg(feature = "history")]
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            std::process::Command::new("hostname")
+                .output()
+                .ok()
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Show when facts changed in a history recorded with `facts --record`
+///
+/// Each collection is compared with the one before it from the same host and cpu, so a change is
+/// reported at the first collection it was seen in.
+#[cfg(feature = "history")]
+#[derive(Clone, Args)]
+struct History {
+    /// The history file written by facts --record
+    database: PathBuf,
+    /// How far back to look, e.g. 12h or 30d
+    #[arg(long, value_parser = parse_duration, default_value = "30d")]
+    since: Duration,
+    /// Only show the facts matching this glob, may be given more than once
+    #[arg(long)]
+    fact: Vec<String>,
+    /// Only show the changes on this host
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(short, long, value_enum, default_value = "text")]
+    out_type: ReportOutput,
+}
+
+#[cfg(feature = "history")]
+impl Command for History {
+    fn run(&self, _config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let since = now.saturating_sub(self.since).as_secs();
+        let matcher = facts::PathMatcher::new(&self.fact)?;
+        let mut changes = history::History::open(&self.database)?.changes(since, &matcher)?;
+        if let Some(host) = &self.host {
+            changes.retain(|change| &change.host == host);
+        }
+
+        match self.out_type {
+            ReportOutput::Text => {
+                let show = |value: &Option<FactValue>| match value {
+                    Some(value) => value.to_string(),
+                    None => "-".to_string(),
+                };
+                for change in &changes {
+                    let source = match change.cpu {
+                        Some(cpu) => format!("{}/cpu{}", change.host, cpu),
+                        None => change.host.clone(),
+                    };
+                    println!(
+                        "{} {} {}: {} -> {}",
+                        change.timestamp,
+                        source,
+                        change.name,
+                        show(&change.from),
+                        show(&change.to)
+                    );
+                }
+            }
+            ReportOutput::Yaml => println!("{}", serde_yaml::to_string(&changes)?),
+            ReportOutput::Json => println!("{}", serde_json::to_string(&changes)?),
+        }
+        Ok(())
+    }
+}
+
+/// Serve facts over HTTP as JSON
+///
+/// `GET /facts` and `GET /raw` collect from the running machine, `POST /diff` compares a fact file
+/// sent as the request body against live facts. The query parameters `cpu`, `all_cpus`, `kvm`,
+/// `kvm_emulated`, `kvm_scratch`, `kvm_hyperv`, `msr`, `msr_raw` and `msr_provenance` select the
+/// source, e.g. `/facts?cpu=3&msr=false`. `/raw` gives a snapshot of the same source, one per
+/// `cpu<N>` with `all_cpus`.
+#[cfg(feature = "http")]
+#[derive(Clone, Args)]
+struct Serve {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:9100")]
+    listen: String,
+}
+
+#[cfg(feature = "http")]
+impl Serve {
+    fn source_from_query(query: &str) -> Result<FactSource, String> {
+        let mut source = FactSource {
+            cpu: 0,
+            all_cpus: false,
+            #[cfg(all(
+                target_os = "linux",
+                any(target_arch = "x86_64", target_arch = "aarch64"),
+                feature = "kvm"
+            ))]
+            use_kvm: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+            kvm_emulated: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+            kvm_scratch: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+            kvm_vm_caps: Vec::new(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+            kvm_vcpu_caps: Vec::new(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+            kvm_hyperv: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+            vm_pid: None,
+            from_file: None,
+            overlay: None,
+            overlay_leaves: Vec::new(),
+            skip_msr: false,
+            msr_raw: false,
+            msr_provenance: false,
+            #[cfg(target_os = "linux")]
+            proc_cpuinfo: false,
+            #[cfg(target_os = "linux")]
+            sysfs: false,
+            #[cfg(target_os = "linux")]
+            acpi: false,
+            #[cfg(all(unix, feature = "qmp"))]
+            qmp: None,
+            #[cfg(all(unix, feature = "qmp"))]
+            qemu_models: Vec::new(),
+            remote: None,
+            remote_cpuinfo: "cpuinfo".to_string(),
+        };
+        let flag = |value: &str| match value {
+            "" | "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => Err(format!("Invalid boolean '{}'", value)),
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "cpu" => {
+                    source.cpu = value
+                        .parse()
+                        .map_err(|_| format!("Invalid cpu '{}'", value))?;
+                    let online = core_affinity::get_core_ids().unwrap_or_default();
+                    if !online.iter().any(|core| core.id == source.cpu) {
+                        return Err(format!("No online cpu {}", source.cpu));
+                    }
+                }
+                "all_cpus" => source.all_cpus = flag(value)?,
+                #[cfg(all(
+                    target_os = "linux",
+                    any(target_arch = "x86_64", target_arch = "aarch64"),
+                    feature = "kvm"
+                ))]
+                "kvm" => source.use_kvm = flag(value)?,
+                #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+                "kvm_emulated" => {
+                    source.kvm_emulated = flag(value)?;
+                    source.use_kvm |= source.kvm_emulated;
+                }
+                #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+                "kvm_scratch" => {
+                    source.kvm_scratch = flag(value)?;
+                    source.use_kvm |= source.kvm_scratch;
+                }
+                #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+                "kvm_hyperv" => {
+                    source.kvm_hyperv = flag(value)?;
+                    source.kvm_scratch |= source.kvm_hyperv;
+                    source.use_kvm |= source.kvm_hyperv;
+                }
+                "msr" => source.skip_msr = !flag(value)?,
+                "msr_raw" => source.msr_raw = flag(value)?,
+                "msr_provenance" => source.msr_provenance = flag(value)?,
+                _ => return Err(format!("Unknown parameter '{}'", key)),
+            }
+        }
+        Ok(source)
+    }
+
+    fn respond(
+        config: &Definition,
+        request: &mut tiny_http::Request,
+    ) -> Result<String, (u16, String)> {
+        use tiny_http::Method;
+
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let source = Self::source_from_query(query).map_err(|e| (400, e))?;
+        let internal = |e: Box<dyn Error>| (500, e.to_string());
+
+        let body = match (request.method(), path) {
+            (Method::Get, "/facts") => {
+                serde_json::to_string(&source.collect(config).map_err(internal)?)
+            }
+            (Method::Get, "/raw") if source.all_cpus => {
+                let per_cpu =
+                    on_each_cpu(|id| source.capture(config, id).map_err(|e| e.to_string()))
+                        .map_err(internal)?;
+                let snapshots = per_cpu
+                    .into_iter()
+                    .map(|(id, snapshot)| Ok((format!("cpu{}", id), snapshot?)))
+                    .collect::<Result<BTreeMap<_, _>, String>>()
+                    .map_err(|e| (500, e))?;
+                serde_json::to_string(&snapshots)
+            }
+            (Method::Get, "/raw") => {
+                serde_json::to_string(&source.capture(config, source.cpu).map_err(internal)?)
+            }
+            (Method::Post, "/diff") => {
+                let mut baseline = String::new();
+                request
+                    .as_reader()
+                    .read_to_string(&mut baseline)
+                    .map_err(|e| (400, e.to_string()))?;
+                let mut from = facts::FactsFile::parse(&baseline)
+                    .map_err(|e| (400, e.to_string()))?
+                    .facts;
+                config.canonicalize(&mut from);
+                let from: FactSet = from.into();
+                let to: FactSet = source.collect(config).map_err(internal)?.into();
+                serde_json::to_string(&DiffOutput::new(&from, &to))
+            }
+            (_, "/facts") | (_, "/raw") | (_, "/diff") => {
+                return Err((405, "Method not allowed".to_string()))
+            }
+            _ => return Err((404, format!("No such endpoint '{}'", path))),
+        };
+        body.map_err(|e| (500, e.to_string()))
+    }
+}
+
+#[cfg(feature = "http")]
+impl Command for Serve {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        use tiny_http::{Header, Response, Server};
+
+        let server = Server::http(&self.listen).map_err(|e| e.to_string())?;
+        let json =
+            Header::from_bytes("Content-Type", "application/json").expect("static header is valid");
+        // Each request gets its own thread as collection pins the calling thread to a cpu
+        std::thread::scope(|scope| {
+            for mut request in server.incoming_requests() {
+                let json = json.clone();
+                scope.spawn(move || {
+                    let response = match Self::respond(config, &mut request) {
+                        Ok(body) => Response::from_string(body),
+                        Err((code, message)) => Response::from_string(
+                            serde_json::json!({ "error": message }).to_string(),
+                        )
+                        .with_status_code(code),
+                    };
+                    if let Err(e) = request.respond(response.with_header(json)) {
+                        eprintln!("Error sending response: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+#[derive(Clone, Args)]
+struct Explain {
+    /// Fact path of the field to explain, e.g. "cpuid/Model/ecx/AESNI"
+    #[arg(required_unless_present = "leaf")]
+    path: Option<String>,
+    /// Explain the fields of this leaf instead of looking up a path
+    #[arg(long, value_parser = parse_number, conflicts_with = "path")]
+    leaf: Option<u32>,
+    /// Only explain the fields covering a bit, given as register:bit e.g. ebx:2
+    #[arg(long, requires = "leaf")]
+    bit: Option<String>,
+    #[command(flatten)]
+    source: FactSource,
+}
+
+/// A field definition from the config along with where it is read from
+struct FieldExplanation<'a> {
+    path: String,
+    location: String,
+    register: Option<&'static str>,
+    field: &'a bitfield::Field,
+}
+
+impl Explain {
+    fn explanations(config: &Definition) -> Vec<(Option<u32>, FieldExplanation<'_>)> {
+        let hypervisor_leaves = config
+            .vendor_cpuids
+            .values()
+            .chain(config.hypervisor_cpuids.values())
+            .flatten();
+        let cpuid_fields =
+            config
+                .cpuids
+                .iter()
+                .chain(hypervisor_leaves)
+                .flat_map(|(leaf, desc)| {
+                    desc.field_locations().into_iter().map(move |location| {
+                        (
+                            Some(*leaf),
+                            FieldExplanation {
+                                path: format!("cpuid/{}/{}", desc.name(), location.path),
+                                location: format!(
+                                    "leaf {:#010x} ({}) sub-leaf {}",
+                                    leaf,
+                                    desc.name(),
+                                    location.sub_leaf
+                                ),
+                                register: Some(location.register),
+                                field: location.field,
+                            },
+                        )
+                    })
+                });
+        let msr_fields = config.msrs.iter().flat_map(|msr| {
+            msr.fields.iter().map(move |field| {
+                (
+                    None,
+                    FieldExplanation {
+                        path: format!("msr/{}/{}", msr.name, field.fact_name()),
+                        location: format!("msr {:#010x} ({})", msr.address, msr.name),
+                        register: None,
+                        field,
+                    },
+                )
+            })
+        });
+        cpuid_fields.chain(msr_fields).collect()
+    }
+}
+
+impl Command for Explain {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let bit = match &self.bit {
+            Some(bit) => {
+                let (register, bit) = bit
+                    .split_once(':')
+                    .ok_or("--bit must be given as register:bit")?;
+                let bit: u8 = bit
+                    .parse()
+                    .map_err(|e| format!("Invalid bit '{}': {}", bit, e))?;
+                Some((register.to_lowercase(), bit))
+            }
+            None => None,
+        };
+        let matching: Vec<_> = Self::explanations(config)
+            .into_iter()
+            .filter(|(leaf, explanation)| match (&self.path, self.leaf) {
+                (Some(path), _) => explanation.path.eq_ignore_ascii_case(path),
+                (None, Some(want)) => {
+                    *leaf == Some(want)
+                        && bit.as_ref().is_none_or(|(register, bit)| {
+                            explanation.register == Some(register.as_str())
+                                && explanation
+                                    .field
+                                    .bits()
+                                    .iter()
+                                    .any(|range| range.contains(bit))
+                        })
+                }
+                (None, None) => false,
+            })
+            .map(|(_, explanation)| explanation)
+            .collect();
+        if matching.is_empty() {
+            return Err("No field in the config matches".into());
+        }
+
+        let facts: BTreeMap<String, FactValue> = self
+            .source
+            .collect(config)?
+            .into_iter()
+            .map(|fact| (fact.name, fact.value))
+            .collect();
+        for explanation in matching {
+            let value = match facts.get(&explanation.path) {
+                Some(value) => value.to_string(),
+                None => "<unavailable>".to_string(),
+            };
+            let bits = explanation
+                .field
+                .bits()
+                .iter()
+                .map(|range| match range.len() {
+                    1 => format!("{}", range.start),
+                    _ => format!("{}-{}", range.start, range.end - 1),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("{} = {}", explanation.path, value);
+            println!("  location: {}", explanation.location);
+            if let Some(register) = explanation.register {
+                println!("  register: {}", register);
+            }
+            println!("  type: {}", explanation.field.type_name());
+            println!("  bits: {}", bits);
+            if let Some(condition) = explanation.field.valid_if() {
+                println!("  valid if: {}", condition);
+            }
+            let docs = explanation.field.docs();
+            if let Some(description) = &docs.description {
+                println!("  description: {}", description);
+            }
+            if let Some(reference) = &docs.reference {
+                println!("  reference: {}", reference);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Args)]
+struct Query {
+    /// Glob patterns over fact paths, e.g. 'cpuid/*/ecx/avx*'. `**` matches across '/'
+    #[arg(required = true)]
+    patterns: Vec<String>,
+    /// Match patterns without regard to case
+    #[arg(short, long)]
+    ignore_case: bool,
+    /// Print only the value of each matching fact, one per line
+    #[arg(long)]
+    value_only: bool,
+    #[command(flatten)]
+    format: FactsFormat,
+    #[command(flatten)]
+    source: FactSource,
+}
+
+impl Command for Query {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let matcher = facts::PathMatcher::new(&self.patterns)?.case_sensitive(!self.ignore_case);
+        let facts: Vec<Fact> = self
+            .source
+            .collect(config)?
+            .into_iter()
+            .filter(|fact| matcher.matches(fact.get_name()))
+            .collect();
+
+        if self.value_only {
+            for fact in &facts {
+                println!("{}", fact.value);
+            }
+        } else {
+            println!(
+                "{}",
+                self.format
+                    .render(config, &facts.iter().collect::<Vec<_>>())?
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, ValueEnum)]
+enum ReportOutput {
+    Text,
+    Yaml,
+    Json,
+}
+
+#[derive(Clone, Args)]
+struct Topology {
+    #[arg(short, long, value_enum, default_value = "text")]
+    out_type: ReportOutput,
+}
+
+#[derive(Serialize)]
+struct TopologyReport {
+    cpus: BTreeMap<usize, topology::CpuTopology>,
+    summary: topology::TopologySummary,
+}
+
+impl Command for Topology {
+    fn run(&self, _config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cpus = BTreeMap::new();
+        // The ids are only visible from the cpu itself
+        for (id, cpu) in on_each_cpu(|_| topology::CpuTopology::from_cpuid(&RunningCpuidDB::new()))?
+        {
+            cpus.insert(id, cpu.ok_or("No topology information reported by cpuid")?);
+        }
+        let report = TopologyReport {
+            summary: topology::TopologySummary::new(cpus.values()),
+            cpus,
+        };
+
+        match self.out_type {
+            ReportOutput::Text => {
+                println!(
+                    "{:>5} {:>10} {:>8} {:>5} {:>5} {:>7}",
+                    "cpu", "apic id", "package", "die", "core", "thread"
+                );
+                for (id, cpu) in &report.cpus {
+                    let location = &cpu.location;
+                    println!(
+                        "{:>5} {:>#10x} {:>8} {:>5} {:>5} {:>7}",
+                        id,
+                        cpu.apic_id,
+                        location.package,
+                        location.die,
+                        location.core,
+                        location.thread
+                    );
+                }
+                println!("{}", report.summary);
+            }
+            ReportOutput::Yaml => println!("{}", serde_yaml::to_string(&report)?),
+            ReportOutput::Json => println!("{}", serde_json::to_string(&report)?),
+        }
+        Ok(())
+    }
+}
+
+/// Report speculative execution vulnerabilities and the mitigations the cpu enumerates
+///
+/// Reading the arch capabilities MSR needs root, without it most classes are reported unknown.
+#[derive(Clone, Args)]
+struct Vuln {
+    #[arg(short, long, value_enum, default_value = "text")]
+    out_type: ReportOutput,
+    #[command(flatten)]
+    source: FactSource,
+}
+
+impl Command for Vuln {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        if self.source.all_cpus {
+            return Err("vuln assesses a single cpu, select it with --cpu".into());
+        }
+        let facts: FactSet = self.source.collect(config)?.into();
+        let assessments = vuln::assess(&facts);
+
+        match self.out_type {
+            ReportOutput::Text => {
+                for assessment in &assessments {
+                    println!(
+                        "{:<34} {:<22} {}",
+                        assessment.class,
+                        assessment.status.to_string(),
+                        assessment.reason
+                    );
+                    println!("  {}", assessment.cves.join(" "));
+                }
+            }
+            ReportOutput::Yaml => println!("{}", serde_yaml::to_string(&assessments)?),
+            ReportOutput::Json => println!("{}", serde_json::to_string(&assessments)?),
+        }
+        Ok(())
+    }
+}
+
+/// Compare the cpuid features of the host with those KVM supports and emulates
+///
+/// Features the host has that KVM neither supports nor emulates can't be given to a guest, these
+/// are marked as hidden.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Clone, Args)]
+struct KvmCompare {
+    #[arg(short, long, default_value = "0")]
+    cpu: usize,
+    #[arg(short, long, value_enum, default_value = "text")]
+    out_type: ReportOutput,
+    /// Only list the features that are hidden from guests
+    #[arg(long)]
+    hidden_only: bool,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Serialize)]
+struct KvmFeature {
+    feature: String,
+    host: Option<bool>,
+    supported: Option<bool>,
+    emulated: Option<bool>,
+    /// The host has the feature but KVM won't expose it
+    hidden: bool,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+impl Command for KvmCompare {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        use cpuinfo::kvm::{KvmEmulatedInfo, KvmInfo};
+        use kvm_ioctls::Kvm;
+
+        if !core_affinity::set_for_current(CoreId { id: self.cpu }) {
+            panic!("Unable to pin to core {}", self.cpu);
+        }
+        let kvm = Kvm::new()?;
+        let sources: [CpuidType; 3] = [
+            CpuidType::func(),
+            KvmInfo::new(&kvm)?.into(),
+            KvmEmulatedInfo::new(&kvm)?.into(),
+        ];
+        let mut names: Vec<String> = Vec::new();
+        let mut sets: Vec<FactSet> = Vec::new();
+        for source in sources {
+            let facts: Vec<Fact> = Collector::new()
+                .with_config(config)
+                .with_cpuid(source)
+                .collect()?
+                .into_iter()
+                .filter(|fact| fact.value.as_bool().is_some())
+                .collect();
+            for fact in &facts {
+                if !names.iter().any(|name| name == fact.get_name()) {
+                    names.push(fact.get_name().to_string());
+                }
+            }
+            sets.push(facts.into());
+        }
+
+        let flag = |set: &FactSet, name: &str| set.get(name).and_then(|fact| fact.value.as_bool());
+        let features: Vec<KvmFeature> = names
+            .into_iter()
+            .map(|name| {
+                let host = flag(&sets[0], &name);
+                let supported = flag(&sets[1], &name);
+                let emulated = flag(&sets[2], &name);
+                KvmFeature {
+                    hidden: host == Some(true) && supported != Some(true) && emulated != Some(true),
+                    feature: name,
+                    host,
+                    supported,
+                    emulated,
+                }
+            })
+            .filter(|feature| feature.hidden || !self.hidden_only)
+            .collect();
+
+        match self.out_type {
+            ReportOutput::Text => {
+                let cell = |value: Option<bool>| match value {
+                    Some(true) => "yes",
+                    Some(false) => "no",
+                    None => "-",
+                };
+                println!(
+                    "{:<64} {:>5} {:>9} {:>8}",
+                    "FEATURE", "HOST", "SUPPORTED", "EMULATED"
+                );
+                for feature in &features {
+                    println!(
+                        "{:<64} {:>5} {:>9} {:>8}{}",
+                        feature.feature,
+                        cell(feature.host),
+                        cell(feature.supported),
+                        cell(feature.emulated),
+                        if feature.hidden { "  hidden" } else { "" }
+                    );
+                }
+            }
+            ReportOutput::Yaml => println!("{}", serde_yaml::to_string(&features)?),
+            ReportOutput::Json => println!("{}", serde_json::to_string(&features)?),
+        }
+        Ok(())
+    }
+}
+
+/// Work with the cpuid KVM gives guests
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Clone, Args)]
+struct Kvm {
+    #[command(subcommand)]
+    action: KvmAction,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Clone, Subcommand)]
+enum KvmAction {
+    /// Print the cpuid to set on a vcpu for a guest limited to a baseline, the cpuid KVM
+    /// supports with every configured flag the baseline lacks cleared
+    EmitCpuid(KvmEmitCpuid),
+    /// List the MSRs KVM exposes to guests, its feature MSRs and the state MSRs of a scratch
+    /// vcpu, with the host's value of each
+    Msrs(KvmMsrs),
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Clone, Args)]
+struct KvmEmitCpuid {
+    /// Fact file of the baseline, such as one written by the baseline command
+    baseline: String,
+    #[arg(short, long, value_enum, default_value = "yaml")]
+    out_type: KvmCpuidOutput,
+    /// File to write the cpuid to, stdout if not given
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Clone, Copy, ValueEnum)]
+enum KvmCpuidOutput {
+    Yaml,
+    Json,
+    /// A `struct kvm_cpuid2` as passed to KVM_SET_CPUID2, in the host's byte order
+    Binary,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Clone, Args)]
+struct KvmMsrs {
+    /// The cpu to read the host's MSRs on
+    #[arg(long, default_value = "0")]
+    cpu: usize,
+    #[arg(short, long, value_enum, default_value = "text")]
+    out_type: ReportOutput,
+}
+
+/// An MSR KVM exposes, `kind` is `feature` for those of `KVM_GET_MSR_FEATURE_INDEX_LIST` and
+/// `state` for those of `KVM_GET_MSR_INDEX_LIST`
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Serialize)]
+struct KvmMsrEntry {
+    address: u32,
+    name: String,
+    kind: &'static str,
+    kvm: u64,
+    host: Option<u64>,
+}
+
+/// An entry of `struct kvm_cpuid2`
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+#[derive(Serialize)]
+struct KvmCpuidEntry {
+    function: u32,
+    index: u32,
+    flags: u32,
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    edx: u32,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+impl Command for Kvm {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.action {
+            KvmAction::EmitCpuid(emit) => emit.run(config),
+            KvmAction::Msrs(msrs) => msrs.run(config),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+impl Command for KvmEmitCpuid {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let baseline: FactSet = read_facts_from_file(config, &self.baseline)?.into();
+        let supported = kvm::KvmInfo::new(&kvm_ioctls::Kvm::new()?)?;
+        let cpuid = kvm::baseline_cpuid(config, supported.cpuid(), &baseline);
+        let entries: Vec<KvmCpuidEntry> = cpuid
+            .as_slice()
+            .iter()
+            .map(|entry| KvmCpuidEntry {
+                function: entry.function,
+                index: entry.index,
+                flags: entry.flags,
+                eax: entry.eax,
+                ebx: entry.ebx,
+                ecx: entry.ecx,
+                edx: entry.edx,
+            })
+            .collect();
+        let bytes = match self.out_type {
+            KvmCpuidOutput::Yaml => serde_yaml::to_string(&entries)?.into_bytes(),
+            KvmCpuidOutput::Json => (serde_json::to_string(&entries)? + "\n").into_bytes(),
+            KvmCpuidOutput::Binary => {
+                // nent and padding, then each entry with its three words of padding
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(&(entries.len() as u32).to_ne_bytes());
+                bytes.extend_from_slice(&0u32.to_ne_bytes());
+                for entry in &entries {
+                    let words = [
+                        entry.function,
+                        entry.index,
+                        entry.flags,
+                        entry.eax,
+                        entry.ebx,
+                        entry.ecx,
+                        entry.edx,
+                        0,
+                        0,
+                        0,
+                    ];
+                    for word in words {
+                        bytes.extend_from_slice(&word.to_ne_bytes());
+                    }
+                }
+                bytes
+            }
+        };
+        match &self.output {
+            Some(path) => std::fs::write(path, bytes)?,
+            None => std::io::Write::write_all(&mut std::io::stdout(), &bytes)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "kvm"))]
+impl Command for KvmMsrs {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let kvm = kvm_ioctls::Kvm::new()?;
+        let features = kvm::KvmMsrInfo::new(&kvm)?;
+        let state = kvm::KvmStateMsrInfo::new(&kvm)?;
+        // The host's values are best effort, the msr module may not be loaded
+        let host = open_msr_store(self.cpu).ok();
+        let entries: Vec<KvmMsrEntry> = features
+            .entries()
+            .map(|entry| ("feature", entry))
+            .chain(state.entries().map(|entry| ("state", entry)))
+            .map(|(kind, (address, kvm))| {
+                let desc = describe_msr(config, address);
+                let host = host
+                    .as_ref()
+                    .and_then(|store| store.get_value(&desc).ok())
+                    .map(|value| value.value);
+                KvmMsrEntry {
+                    address,
+                    name: desc.name,
+                    kind,
+                    kvm,
+                    host,
+                }
+            })
+            .collect();
+        match self.out_type {
+            ReportOutput::Text => {
+                for entry in &entries {
+                    let host = entry
+                        .host
+                        .map_or_else(|| "-".to_string(), |value| format!("{:#018x}", value));
+                    println!(
+                        "{:<7} {:#010x} {:<32} kvm={:#018x} host={}",
+                        entry.kind, entry.address, entry.name, entry.kvm, host
+                    );
+                }
+            }
+            ReportOutput::Yaml => println!("{}", serde_yaml::to_string(&entries)?),
+            ReportOutput::Json => println!("{}", serde_json::to_string(&entries)?),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Args)]
+struct Export {
+    /// Export in the prometheus text exposition format, currently the only format
+    #[arg(long, required = true)]
+    prometheus: bool,
+    /// Write to this file for the node_exporter textfile collector instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Serve metrics on this address (e.g. :9101), collecting again on every scrape
+    #[cfg(feature = "http")]
+    #[arg(long, conflicts_with = "output")]
+    listen: Option<String>,
+    #[command(flatten)]
+    source: FactSource,
+}
+
+impl Export {
+    fn prometheus_text(facts: &[Fact]) -> String {
+        let label = |name: &str| {
+            name.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        };
+        let mut features = String::new();
+        let mut values = String::new();
+        for fact in facts {
+            match &fact.value {
+                FactValue::Bool(present) => features.push_str(&format!(
+                    "cpuinfo_feature{{path=\"{}\"}} {}\n",
+                    label(&fact.name),
+                    *present as u8
+                )),
+                FactValue::U64(value) => values.push_str(&format!(
+                    "cpuinfo_value{{path=\"{}\"}} {}\n",
+                    label(&fact.name),
+                    value
+                )),
+                _ => (),
+            }
+        }
+        format!(
+            "# HELP cpuinfo_feature CPU feature flag, 1 when present\n\
+             # TYPE cpuinfo_feature gauge\n\
+             {}\
+             # HELP cpuinfo_value Numeric CPU fact\n\
+             # TYPE cpuinfo_value gauge\n\
+             {}",
+            features, values
+        )
+    }
+
+    #[cfg(feature = "http")]
+    fn listen(&self, config: &Definition, address: &str) -> Result<(), Box<dyn std::error::Error>> {
+        use tiny_http::{Header, Response, Server};
+
+        let address = match address.strip_prefix(':') {
+            Some(port) => format!("0.0.0.0:{}", port),
+            None => address.to_string(),
+        };
+        let server = Server::http(&address).map_err(|e| e.to_string())?;
+        let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+            .expect("static header is valid");
+        for request in server.incoming_requests() {
+            // Collect on a fresh thread, collection pins the calling thread to a cpu
+            let collected = std::thread::scope(|scope| {
+                scope
+                    .spawn(|| self.source.collect(config).map_err(|e| e.to_string()))
+                    .join()
+                    .expect("fact collection thread panicked")
+            });
+            let response = match collected {
+                Ok(facts) => Response::from_string(Self::prometheus_text(&facts))
+                    .with_header(content_type.clone()),
+                Err(e) => Response::from_string(e).with_status_code(500),
+            };
+            if let Err(e) = request.respond(response) {
+                eprintln!("Error sending response: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Command for Export {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        #[cfg(feature = "http")]
+        if let Some(address) = &self.listen {
+            return self.listen(config, address);
+        }
+        let text = Self::prometheus_text(&self.source.collect(config)?);
+        match &self.output {
+            Some(path) => {
+                // The textfile collector may read at any time, so replace the file atomically
+                let mut temp = path.clone().into_os_string();
+                temp.push(".tmp");
+                std::fs::write(&temp, text)?;
+                std::fs::rename(&temp, path)?;
+            }
+            None => print!("{}", text),
+        }
+        Ok(())
+    }
+}
+
+/// Work with MSRs directly by address
+#[derive(Clone, Args)]
+struct Msr {
+    #[command(subcommand)]
+    action: MsrAction,
+}
+
+#[derive(Clone, Subcommand)]
+enum MsrAction {
+    /// Read MSRs, decoding them with the config's fields where the address is described
+    Read(MsrRead),
+    /// Change the bits of an MSR that the config's msr_writes allows, then read it back
+    #[cfg(feature = "msr_write")]
+    Write(MsrWrite),
+}
+
+#[derive(Clone, Args)]
+struct MsrRead {
+    /// MSR addresses, in hex with a 0x prefix or decimal
+    #[arg(required = true, value_parser = parse_number)]
+    addresses: Vec<u32>,
+    #[arg(short, long, default_value = "0")]
+    cpu: usize,
+}
+
+impl Command for Msr {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.action {
+            MsrAction::Read(read) => read.run(config),
+            #[cfg(feature = "msr_write")]
+            MsrAction::Write(write) => write.run(config),
+        }
+    }
+}
+
+/// The config's description of the MSR at `address`, or one without fields
+fn describe_msr(config: &Definition, address: u32) -> MSRDesc {
+    config
+        .msrs
+        .iter()
+        .find(|desc| desc.address == address)
+        .cloned()
+        .unwrap_or_else(|| MSRDesc {
+            name: "Unknown".to_string(),
+            address,
+            fields: Vec::new(),
+            derived: Vec::new(),
+            docs: Default::default(),
+            applies_to: None,
+            requires: None,
+        })
+}
+
+impl Command for MsrRead {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let store = open_msr_store(self.cpu)?;
+        let descs: Vec<MSRDesc> = self
+            .addresses
+            .iter()
+            .map(|address| describe_msr(config, *address))
+            .collect();
+        // An MSR that can not be read is reported and the others read regardless
+        let mut failed = 0;
+        for desc in &descs {
+            match store.get_value(desc) {
+                Ok(value) => print!("{}", value),
+                Err(e) => {
+                    eprintln!("Error reading MSR {:#x}: {}", desc.address, e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(format!("Unable to read {} of {} MSRs", failed, descs.len()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "msr_write")]
+#[derive(Clone, Args)]
+struct MsrWrite {
+    /// MSR address, in hex with a 0x prefix or decimal
+    #[arg(value_parser = parse_number)]
+    address: u32,
+    /// The value to write, in hex with a 0x prefix or decimal
+    #[arg(value_parser = parse_u64)]
+    value: u64,
+    /// Only write these bits of the value, keeping the others as they read
+    #[arg(long, value_parser = parse_u64)]
+    mask: Option<u64>,
+    #[arg(short, long, default_value = "0")]
+    cpu: usize,
+    /// Required, a wrong write can hang or damage the machine
+    #[arg(long = "i-know-what-i-am-doing", required = true)]
+    _confirmed: bool,
+}
+
+#[cfg(feature = "msr_write")]
+impl Command for MsrWrite {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let store = open_writable_msr_store(self.cpu)?;
+        let desc = describe_msr(config, self.address);
+        let old = store.get_value(&desc)?.value;
+        let new = match self.mask {
+            Some(mask) => (old & !mask) | (self.value & mask),
+            None => self.value,
+        };
+        msr::check_write(&config.msr_writes, self.address, old, new)?;
+        store.set_value(&desc, new)?;
+        println!("{:#x} -> {:#x}", old, new);
+        print!("{}", store.get_value(&desc)?);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Args)]
+struct GenerateConfig {
+    /// A file written by the snapshot command or the output of disp --raw
+    dump_file: PathBuf,
+    /// File to write the generated config to, stdout if not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl Command for GenerateConfig {
+    fn run(&self, _config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot = snapshot::Snapshot::from_file(&self.dump_file)?;
+
+        let mut by_leaf: BTreeMap<u32, Vec<&snapshot::CpuidEntry>> = BTreeMap::new();
+        for entry in &snapshot.cpuid {
+            by_leaf.entry(entry.leaf).or_default().push(entry);
+        }
+        let cpuids = by_leaf
+            .into_iter()
+            .map(|(leaf, mut entries)| {
+                entries.sort_by_key(|entry| entry.sub_leaf);
+                let data_type = match &entries[..] {
+                    [entry] if entry.sub_leaf == 0 => {
+                        LeafType::BitField(BitFieldLeaf::from_set_bits(&entry.result()))
+                    }
+                    // Key the fields by the sub-leaves the dump holds, which may skip some
+                    _ => LeafType::MultiSubLeaf(
+                        MultiSubLeaf::new(
+                            entries
+                                .iter()
+                                .map(|entry| {
+                                    (entry.sub_leaf, BitFieldLeaf::from_set_bits(&entry.result()))
+                                })
+                                .collect(),
+                            None,
+                        )
+                        .with_enumerate(sub_leaf::SubLeaves::Listed {
+                            sub_leaves: entries.iter().map(|entry| entry.sub_leaf).collect(),
+                        }),
+                    ),
+                };
+                (
+                    leaf,
+                    LeafDesc::new(format!("Leaf {:#010x}", leaf), data_type),
+                )
+            })
+            .collect();
+        let msrs = snapshot
+            .msrs
+            .iter()
+            .map(|entry| MSRDesc {
+                name: entry.name.clone(),
+                address: entry.address,
+                fields: (0..64u8)
+                    .filter(|bit| entry.value & (1 << bit) != 0)
+                    .map(|bit| {
+                        bitfield::Field::Flag(bitfield::Flag {
+                            name: format!("bit{}", bit),
+                            bit,
+                            valid_if: None,
+                            docs: Default::default(),
+                        })
+                    })
+                    .collect(),
+                derived: Vec::new(),
+                docs: Default::default(),
+                applies_to: None,
+                requires: None,
+            })
+            .collect();
+
+        let definition = Definition {
+            version: Some(migrate::CURRENT_VERSION),
+            include: Vec::new(),
+            cpuids,
+            msrs,
+            msr_writes: Vec::new(),
+            qemu_features: BTreeMap::new(),
+            nfd_labels: BTreeMap::new(),
+            flag_names: BTreeMap::new(),
+            severities: Vec::new(),
+            tolerances: Vec::new(),
+            aliases: BTreeMap::new(),
+            kvm_caps: BTreeMap::new(),
+            vendor_cpuids: BTreeMap::new(),
+            hypervisor_cpuids: BTreeMap::new(),
+            id_registers: Vec::new(),
+            sub_leaves: BTreeMap::new(),
+            profiles: BTreeMap::new(),
+            warnings: Vec::new(),
+        };
+        match &self.output {
+            Some(path) => serde_yaml::to_writer(std::fs::File::create(path)?, &definition)?,
+            None => println!("{}", serde_yaml::to_string(&definition)?),
+        }
+        Ok(())
     }
-    Ok(())
 }
 
+#[cfg(feature = "schema")]
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SchemaRoot {
+    /// A whole config file
+    Config,
+    /// An entry of `cpuids`
+    Leaf,
+    /// A field of a leaf, MSR or ID register
+    Field,
+    /// An entry of `msrs`
+    Msr,
+}
+
+/// Print a JSON Schema of the config format, to validate configs with in editors and CI
+#[cfg(feature = "schema")]
+#[derive(Clone, Args)]
+struct Schema {
+    /// The part of the config the schema describes
+    #[arg(value_enum, default_value = "config")]
+    root: SchemaRoot,
+    /// File to write the schema to, stdout if not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[cfg(feature = "schema")]
+impl Command for Schema {
+    fn run(&self, _config: &Definition) -> Result<(), Box<dyn std::error::Error>> {
+        let schema = match self.root {
+            SchemaRoot::Config => schemars::schema_for!(Definition),
+            SchemaRoot::Leaf => schemars::schema_for!(LeafDesc),
+            SchemaRoot::Field => schemars::schema_for!(bitfield::Field),
+            SchemaRoot::Msr => schemars::schema_for!(MSRDesc),
+        };
+        match &self.output {
+            Some(path) => serde_json::to_writer_pretty(std::fs::File::create(path)?, &schema)?,
+            None => println!("{}", serde_json::to_string_pretty(&schema)?),
+        }
+        Ok(())
+    }
+}
+
+/// Read the facts of a file in any of the layouts `facts::FactsFile::parse` takes, renamed by the
+/// config's aliases
+fn read_facts_from_file(config: &Definition, fname: &str) -> Result<Vec<Fact>, Box<dyn Error>> {
+    let mut facts = facts::FactsFile::parse(&std::fs::read_to_string(fname)?)?.facts;
+    config.canonicalize(&mut facts);
+    Ok(facts)
+}
+
+/// The facts every host has: the flags set on all of them and the smallest of each number
+#[derive(Clone, Args)]
+struct Baseline {
+    /// Fact files collected from each host
+    #[arg(required = true, num_args = 2..)]
+    file_names: Vec<String>,
+    #[command(flatten)]
+    format: FactsFormat,
+}
+
+impl Command for Baseline {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn Error>> {
+        let mut sets = self
+            .file_names
+            .iter()
+            .map(|name| read_facts_from_file(config, name).map(FactSet::from));
+        let first = sets.next().expect("clap requires at least two files")?;
+        // A flag clear on any host is left out rather than given as false
+        let least = |a: &FactValue, b: &FactValue| {
+            facts::Merge::Least
+                .apply(a, b)
+                .filter(|value| *value != FactValue::Bool(false))
+        };
+        let baseline = sets.try_fold(first, |baseline, set| {
+            Ok::<_, Box<dyn Error>>(baseline.intersection_with(&set?, least))
+        })?;
+
+        println!("{}", self.format.render(config, &baseline.facts())?);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+struct DiffOutput {
+    added: Vec<Fact>,
+    removed: Vec<Fact>,
+    changed: Vec<(Fact, Fact)>,
+}
+
+impl DiffOutput {
+    pub fn new(from: &FactSet, to: &FactSet) -> Self {
+        Self {
+            added: from.added_facts(to).map(Clone::clone).collect(),
+            removed: from.removed_facts(to).map(Clone::clone).collect(),
+            changed: from
+                .changed_facts(to)
+                .map(|v| (v.0.clone(), v.1.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Only the differences that take something away from `to`: removed facts, flags that were
+    /// set and are now clear, numbers that decreased and strings that changed
+    pub fn losses(self) -> Self {
+        let lost = |from: &FactValue, to: &FactValue| match (from, to) {
+            (FactValue::Bool(from), FactValue::Bool(to)) => *from && !*to,
+            (FactValue::U64(from), FactValue::U64(to)) => to < from,
+            _ => true,
+        };
+        Self {
+            added: Vec::new(),
+            removed: self.removed,
+            changed: self
+                .changed
+                .into_iter()
+                .filter(|(from, to)| lost(&from.value, &to.value))
+                .collect(),
+        }
+    }
+
+    /// Split the differences by the severity `policy` gives each fact, dropping ignored ones
+    pub fn classify(self, policy: &SeverityPolicy) -> BTreeMap<Severity, DiffOutput> {
+        let mut classified: BTreeMap<Severity, DiffOutput> = BTreeMap::new();
+        let kept = |name: &str| Some(policy.severity(name)).filter(|s| *s != Severity::Ignore);
+        for fact in self.added {
+            if let Some(severity) = kept(&fact.name) {
+                classified.entry(severity).or_default().added.push(fact);
+            }
+        }
+        for fact in self.removed {
+            if let Some(severity) = kept(&fact.name) {
+                classified.entry(severity).or_default().removed.push(fact);
+            }
+        }
+        for (from, to) in self.changed {
+            if let Some(severity) = kept(&from.name) {
+                classified
+                    .entry(severity)
+                    .or_default()
+                    .changed
+                    .push((from, to));
+            }
+        }
+        classified
+    }
+}
+
+/// The differences of a two way diff by severity, with the changes an allowlist expects apart
+#[derive(Serialize, Debug)]
+struct ClassifiedDiff {
+    #[serde(flatten)]
+    severities: BTreeMap<Severity, DiffOutput>,
+    /// Changes the allowlist expects, which never fail the diff
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    expected: Vec<(Fact, Fact)>,
+}
+
+/// A change of value an allowlist expects
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Transition {
+    Direction(Direction),
+    /// From one value to another, leaving either out allows any value
+    Values {
+        #[serde(default)]
+        from: Option<FactValue>,
+        #[serde(default)]
+        to: Option<FactValue>,
+    },
+}
+
+/// Any change of a number in one direction
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Increase,
+    Decrease,
+}
+
+impl Transition {
+    fn allows(&self, from: &FactValue, to: &FactValue) -> bool {
+        match self {
+            Self::Direction(direction) => match (from.as_u64(), to.as_u64()) {
+                (Some(from), Some(to)) => match direction {
+                    Direction::Increase => to > from,
+                    Direction::Decrease => to < from,
+                },
+                _ => false,
+            },
+            Self::Values { from: a, to: b } => {
+                a.as_ref().is_none_or(|a| a == from) && b.as_ref().is_none_or(|b| b == to)
+            }
+        }
+    }
+}
+
+/// The transitions of allowlist files, by the fact path globs they apply to
+#[derive(Default)]
+struct Expectations {
+    rules: Vec<(facts::PathMatcher, Transition)>,
+}
+
+impl Expectations {
+    /// Read a YAML map from fact path globs to transitions, e.g.
+    /// `cpuid/version/microcode: {from: 0x2b, to: 0x2c}` or `msr/IA32_BIOS_SIGN_ID/*: increase`
+    pub fn read(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let transitions: BTreeMap<String, Transition> =
+            serde_yaml::from_slice(&std::fs::read(path)?)?;
+        for (pattern, transition) in transitions {
+            self.rules
+                .push((facts::PathMatcher::new([pattern])?, transition));
+        }
+        Ok(())
+    }
+
+    pub fn expects(&self, from: &Fact, to: &Fact) -> bool {
+        self.rules.iter().any(|(matcher, transition)| {
+            matcher.matches(&from.name) && transition.allows(&from.value, &to.value)
+        })
+    }
+}
+
+/// The tolerance rules of a config and the command line, ready for matching
+struct Tolerances {
+    rules: Vec<(facts::PathMatcher, ToleranceRule)>,
+}
+
+impl Tolerances {
+    pub fn new(rules: &[ToleranceRule]) -> Result<Self, cpuinfo::Error> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                facts::PathMatcher::new([&rule.path]).map(|matcher| (matcher, rule.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// True when the numbers of `from` and `to` are within a tolerance of a rule matching them
+    pub fn tolerates(&self, from: &Fact, to: &Fact) -> bool {
+        match (from.value.as_u64(), to.value.as_u64()) {
+            (Some(a), Some(b)) => self
+                .rules
+                .iter()
+                .any(|(matcher, rule)| matcher.matches(&from.name) && rule.within(a, b)),
+            _ => false,
+        }
+    }
+}
+
+/// Parse a `--tolerance` of `<path glob>=<absolute>` or `<path glob>=<percent>%`
+fn parse_tolerance(text: &str) -> Result<ToleranceRule, String> {
+    let (path, amount) = text
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected <path>=<amount> in '{}'", text))?;
+    let invalid = || format!("Invalid tolerance '{}'", amount);
+    let rule = ToleranceRule {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    Ok(match amount.strip_suffix('%') {
+        Some(percent) => ToleranceRule {
+            percent: Some(percent.parse().map_err(|_| invalid())?),
+            ..rule
+        },
+        None => ToleranceRule {
+            absolute: Some(amount.parse().map_err(|_| invalid())?),
+            ..rule
+        },
+    })
+}
+
+/// The severity rules of a config, ready for matching
+///
+/// The last matching rule wins, so rules from configs added with `-a` override earlier ones.
+/// Facts matching no rule are errors.
+struct SeverityPolicy {
+    rules: Vec<(facts::PathMatcher, Severity)>,
+}
+
+impl SeverityPolicy {
+    pub fn new(rules: &[SeverityRule]) -> Result<Self, cpuinfo::Error> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                facts::PathMatcher::new([&rule.path]).map(|matcher| (matcher, rule.severity))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn severity(&self, name: &str) -> Severity {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.matches(name))
+            .map_or(Severity::Error, |(_, severity)| *severity)
+    }
+}
+
+/// The values a fact takes across the hosts compared by an n-way diff
+#[derive(Serialize, Debug)]
+struct ValueGroup {
+    /// `None` for the hosts that lack the fact
+    value: Option<FactValue>,
+    hosts: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct MultiDiffOutput {
+    hosts: Vec<String>,
+    differences: BTreeMap<Severity, BTreeMap<String, Vec<ValueGroup>>>,
+}
+
+impl MultiDiffOutput {
+    pub fn new(hosts: Vec<String>, sets: &[FactSet], policy: &SeverityPolicy) -> Self {
+        let mut differences: BTreeMap<Severity, BTreeMap<String, Vec<ValueGroup>>> =
+            BTreeMap::new();
+        for (name, values) in FactSet::differences(sets) {
+            let severity = policy.severity(name);
+            if severity == Severity::Ignore {
+                continue;
+            }
+            let mut groups: Vec<ValueGroup> = Vec::new();
+            for (host, value) in hosts.iter().zip(values) {
+                let value = value.cloned();
+                match groups.iter_mut().find(|group| group.value == value) {
+                    Some(group) => group.hosts.push(host.clone()),
+                    None => groups.push(ValueGroup {
+                        value,
+                        hosts: vec![host.clone()],
+                    }),
+                }
+            }
+            differences
+                .entry(severity)
+                .or_default()
+                .insert(name.clone(), groups);
+        }
+        Self { hosts, differences }
+    }
+}
+
+/// Returned by `diff` once differences that fail it have been printed, `main` turns it into
+/// the exit status rather than printing it again
+#[derive(Debug)]
+struct DiffFoundError;
+
+impl fmt::Display for DiffFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Differences found")
+    }
+}
+
+impl std::error::Error for DiffFoundError {}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DiffFormat {
+    Yaml,
+    Json,
+    /// A standalone HTML page for people to review, with color coded tables grouped by the
+    /// leaf or MSR of each fact
+    Html,
+}
+
+/// A diff result that can be laid out as HTML tables
+trait HtmlDiff {
+    /// The `<h2>` sections of the page
+    fn html_sections(&self) -> String;
+}
+
+/// The section of a fact path a table of the HTML report gathers, its source and leaf or MSR
+/// after any `cpu<N>/` prefix, e.g. `cpu0/cpuid/Model`
+fn html_group(name: &str) -> &str {
+    let mut end = 0;
+    let mut kept = 0;
+    for segment in name.split('/') {
+        if kept == 2 {
+            break;
+        }
+        let is_cpu = end == 0
+            && segment.len() > 3
+            && segment.starts_with("cpu")
+            && segment[3..].bytes().all(|b| b.is_ascii_digit());
+        if !is_cpu {
+            kept += 1;
+        }
+        end += segment.len() + 1;
+    }
+    &name[..name.len().min(end.saturating_sub(1))]
+}
+
+/// A row of an HTML report table: the fact's name, the CSS class giving its color and its other
+/// cells
+type HtmlRow<'a> = (&'a str, &'static str, Vec<String>);
+
+/// Tables of `rows`, one per `html_group` of their fact's name, under the heading `title`
+fn html_section(title: &str, columns: &[&str], rows: Vec<HtmlRow>) -> String {
+    let mut groups: BTreeMap<&str, Vec<HtmlRow>> = BTreeMap::new();
+    for row in rows {
+        groups.entry(html_group(row.0)).or_default().push(row);
+    }
+    let mut html = format!("<h2>{}</h2>\n", xml_escape(title));
+    for (group, rows) in groups {
+        html.push_str(&format!(
+            "<h3>{}</h3>\n<table>\n<tr><th>Fact</th>",
+            xml_escape(group)
+        ));
+        for column in columns {
+            html.push_str(&format!("<th>{}</th>", xml_escape(column)));
+        }
+        html.push_str("</tr>\n");
+        for (name, class, cells) in rows {
+            let name = name[group.len()..].trim_start_matches('/');
+            html.push_str(&format!(
+                "<tr class='{}'><td>{}</td>",
+                class,
+                xml_escape(if name.is_empty() { group } else { name })
+            ));
+            for cell in cells {
+                html.push_str(&format!("<td>{}</td>", xml_escape(&cell)));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</table>\n");
+    }
+    html
+}
+
+/// A standalone page of the sections of `output`
+fn html_page<T: HtmlDiff>(title: &str, output: &T) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset='utf-8'>\n<title>{title}</title>\n\
+         <style>\n\
+         body {{ font-family: sans-serif; }}\n\
+         table {{ border-collapse: collapse; margin-bottom: 1em; }}\n\
+         th, td {{ border: 1px solid #ccc; padding: 2px 8px; text-align: left; }}\n\
+         .added {{ background: #e6ffed; }}\n\
+         .removed {{ background: #ffeef0; }}\n\
+         .changed {{ background: #fff5b1; }}\n\
+         .expected {{ background: #eef6ff; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n{sections}</body>\n</html>\n",
+        title = xml_escape(title),
+        sections = output.html_sections(),
+    )
+}
+
+impl HtmlDiff for ClassifiedDiff {
+    fn html_sections(&self) -> String {
+        let mut html = String::new();
+        for (severity, diff) in &self.severities {
+            let mut rows = Vec::new();
+            for fact in &diff.added {
+                rows.push((
+                    fact.get_name(),
+                    "added",
+                    vec!["added".to_string(), String::new(), fact.value.to_string()],
+                ));
+            }
+            for fact in &diff.removed {
+                rows.push((
+                    fact.get_name(),
+                    "removed",
+                    vec!["removed".to_string(), fact.value.to_string(), String::new()],
+                ));
+            }
+            for (from, to) in &diff.changed {
+                rows.push((
+                    from.get_name(),
+                    "changed",
+                    vec![
+                        "changed".to_string(),
+                        from.value.to_string(),
+                        to.value.to_string(),
+                    ],
+                ));
+            }
+            html.push_str(&html_section(
+                &format!("{:?}", severity),
+                &["Change", "From", "To"],
+                rows,
+            ));
+        }
+        if !self.expected.is_empty() {
+            let rows = self
+                .expected
+                .iter()
+                .map(|(from, to)| {
+                    (
+                        from.get_name(),
+                        "expected",
+                        vec![
+                            "expected".to_string(),
+                            from.value.to_string(),
+                            to.value.to_string(),
+                        ],
+                    )
+                })
+                .collect();
+            html.push_str(&html_section("Expected", &["Change", "From", "To"], rows));
+        }
+        if html.is_empty() {
+            html.push_str("<p>No differences</p>\n");
+        }
+        html
+    }
+}
+
+impl HtmlDiff for MultiDiffOutput {
+    fn html_sections(&self) -> String {
+        let columns: Vec<&str> = self.hosts.iter().map(String::as_str).collect();
+        let mut html = String::new();
+        for (severity, differences) in &self.differences {
+            let rows = differences
+                .iter()
+                .map(|(name, groups)| {
+                    let cells = self
+                        .hosts
+                        .iter()
+                        .map(|host| {
+                            groups
+                                .iter()
+                                .find(|group| group.hosts.contains(host))
+                                .and_then(|group| group.value.as_ref())
+                                .map_or_else(|| "-".to_string(), ToString::to_string)
+                        })
+                        .collect();
+                    (name.as_str(), "changed", cells)
+                })
+                .collect();
+            html.push_str(&html_section(&format!("{:?}", severity), &columns, rows));
+        }
+        if html.is_empty() {
+            html.push_str("<p>No differences</p>\n");
+        }
+        html
+    }
+}
+
+/// Compare sets of facts
+///
+/// Exits with 0 when no error level facts differ, 1 when some do and 2 when the diff could not be
+/// run, such as when a file can not be read.
+#[derive(Clone, Args)]
+struct Diff {
+    from_file_name: String,
+    /// Giving more than one file reports which facts differ across all of them
+    #[arg(required_unless_present = "live")]
+    to_file_names: Vec<String>,
+    #[arg(short, long)]
+    verbose: bool,
+    #[arg(short, long, value_enum, default_value = "yaml")]
+    out_type: DiffFormat,
+    /// File to write the results to, stdout if not given
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Compare the facts in from_file_name against facts collected now
+    #[arg(long, conflicts_with = "to_file_names")]
+    live: bool,
+    /// Leave out the facts matching this glob and those under them, e.g. 'msr/IA32_THERM*'
+    #[arg(long)]
+    ignore: Vec<String>,
+    /// A file of globs to leave out as with --ignore, one per line, lines starting with # are
+    /// comments
+    #[arg(long)]
+    ignore_file: Vec<PathBuf>,
+    /// A YAML file of the value changes to expect, by fact path glob, e.g.
+    /// `cpuid/version/microcode: {from: 0x2b, to: 0x2c}` or `increase` for any increase.
+    /// Expected changes are reported apart and never fail a diff of two sets of facts
+    #[arg(long)]
+    allow: Vec<PathBuf>,
+    /// Only report what the second set of facts lacks that the first has, as moving a guest
+    /// from the first host to the second would lose it. Added facts and gained values are left
+    /// out
+    #[arg(long)]
+    migration_check: bool,
+    /// Let the numbers of the facts matching a glob change by up to an amount, or a percentage
+    /// of their former value, without counting as changed, e.g. 'cpuid/frequency/bus_mhz=1' or
+    /// 'msr/*/Reading=5%'
+    #[arg(long, value_parser = parse_tolerance)]
+    tolerance: Vec<ToleranceRule>,
+    #[command(flatten)]
+    source: FactSource,
+}
+
+impl Diff {
+    /// The config's severities, with the facts given to ignore ignored whatever they say
+    fn policy(&self, config: &Definition) -> Result<SeverityPolicy, Box<dyn Error>> {
+        let mut patterns = self.ignore.clone();
+        for path in &self.ignore_file {
+            let text = std::fs::read_to_string(path)?;
+            patterns.extend(
+                text.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        let mut rules = config.severities.clone();
+        for pattern in patterns {
+            for path in [format!("{}/**", pattern), pattern] {
+                rules.push(SeverityRule {
+                    path,
+                    severity: Severity::Ignore,
+                });
+            }
+        }
+        Ok(SeverityPolicy::new(&rules)?)
+    }
+
+    /// Print `output` and fail when it holds error level differences
+    fn report<T: Serialize + HtmlDiff>(
+        &self,
+        output: T,
+        is_empty: bool,
+        failed: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        // A report asked for by file or as a page is written even when there is nothing in it
+        if !is_empty || self.verbose || self.output.is_some() || self.out_type == DiffFormat::Html {
+            let text = match self.out_type {
+                DiffFormat::Yaml => serde_yaml::to_string(&output)? + "\n",
+                DiffFormat::Json => serde_json::to_string(&output)? + "\n",
+                DiffFormat::Html => {
+                    let title = match self.to_file_names.as_slice() {
+                        [] => format!("{} against this host", self.from_file_name),
+                        names => format!("{} against {}", self.from_file_name, names.join(", ")),
+                    };
+                    html_page(&title, &output)
+                }
+            };
+            match &self.output {
+                Some(path) => std::fs::write(path, text)?,
+                None => print!("{}", text),
+            }
+        }
+        if failed {
+            Err(DiffFoundError.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn run_multi(
+        &self,
+        config: &Definition,
+        policy: &SeverityPolicy,
+    ) -> Result<(), Box<dyn Error>> {
+        let hosts: Vec<String> = std::iter::once(&self.from_file_name)
+            .chain(&self.to_file_names)
+            .cloned()
+            .collect();
+        let sets = hosts
+            .iter()
+            .map(|file_name| Ok(read_facts_from_file(config, file_name)?.into()))
+            .collect::<Result<Vec<FactSet>, Box<dyn Error>>>()?;
+
+        let output = MultiDiffOutput::new(hosts, &sets, policy);
+        let is_empty = output.differences.is_empty();
+        let failed = output.differences.contains_key(&Severity::Error);
+        self.report(output, is_empty, failed)
+    }
+}
+
+impl Command for Diff {
+    fn run(&self, config: &Definition) -> Result<(), Box<dyn Error>> {
+        let policy = self.policy(config)?;
+        if self.to_file_names.len() > 1 {
+            if self.migration_check {
+                return Err("--migration-check compares two sets of facts".into());
+            }
+            if !self.allow.is_empty() {
+                return Err("--allow expects changes between two sets of facts".into());
+            }
+            return self.run_multi(config, &policy);
+        }
+        let from: FactSet = read_facts_from_file(config, &self.from_file_name)?.into();
+        let to: FactSet = match self.to_file_names.first() {
+            Some(to_file_name) => read_facts_from_file(config, to_file_name)?,
+            None => self.source.collect(config)?,
+        }
+        .into();
+
+        let mut expectations = Expectations::default();
+        for path in &self.allow {
+            expectations.read(path)?;
+        }
+        let mut rules = config.tolerances.clone();
+        rules.extend(self.tolerance.iter().cloned());
+        let tolerances = Tolerances::new(&rules)?;
+        let mut output = DiffOutput::new(&from, &to);
+        output
+            .changed
+            .retain(|(from, to)| !tolerances.tolerates(from, to));
+        if self.migration_check {
+            output = output.losses();
+        }
+        let (expected, changed) = output.changed.into_iter().partition(|(from, to)| {
+            expectations.expects(from, to) && policy.severity(&from.name) != Severity::Ignore
+        });
+        output.changed = changed;
+        let output = ClassifiedDiff {
+            severities: output.classify(&policy),
+            expected,
+        };
+        let is_empty = output.severities.is_empty() && output.expected.is_empty();
+        let failed = output.severities.contains_key(&Severity::Error);
+        self.report(output, is_empty, failed)
+    }
+}
+
+/// Print every non-empty leaf of `source` under `header`
+fn display_raw<DB: CpuidDB + ?Sized>(
+    format: RawFormat,
+    config: &Definition,
+    header: &str,
+    source: &DB,
+) {
+    format.print(
+        header,
+        cpuid_leaves(source, &config.sub_leaves)
+            .map(|(addr, result)| snapshot::CpuidEntry::new(addr, result)),
+    );
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ConfigMerge {
+    /// Fail naming the leaves and MSRs configs describe differently
+    Error,
+    /// Keep what the earlier config describes
+    First,
+    /// Take what the later config describes
+    Last,
+    /// Merge the fields of leaves, MSRs and ID registers by name, taking later fields
+    Fields,
+}
+
+impl ConfigMerge {
+    fn strategy(self) -> MergeStrategy {
+        match self {
+            Self::Error => MergeStrategy::Error,
+            Self::First => MergeStrategy::First,
+            Self::Last => MergeStrategy::Last,
+            Self::Fields => MergeStrategy::Fields,
+        }
+    }
+}
+
+/// Merge the configs at `paths` over `def` in order, each after the files it includes. A file
+/// is merged once however many configs include it. The entries they override are listed on
+/// stderr when `merge` was chosen rather than left at the default
 fn read_additional_configs<Paths, P>(
     def: &mut Definition,
     paths: Paths,
+    merge: Option<ConfigMerge>,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     Paths: Iterator<Item = P>,
     P: AsRef<Path> + Sized,
 {
+    let strategy = merge.map_or(MergeStrategy::default(), ConfigMerge::strategy);
+    let mut loaded = BTreeSet::new();
     for path in paths {
-        let file = std::fs::read(path)?;
-        let definition = serde_yaml::from_slice(&file)?;
-        def.union(definition);
+        let path = path.as_ref();
+        let files = Definition::read_with_includes(path, &mut loaded)
+            .map_err(|e| format!("Unable to read config {}: {}", path.display(), e))?;
+        for (path, definition) in files {
+            for warning in &definition.warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            let overridden = def
+                .union_with(definition, strategy)
+                .map_err(|e| format!("Unable to merge config {}: {}", path.display(), e))?;
+            if merge.is_some() {
+                for entry in overridden {
+                    eprintln!("{}: {}", path.display(), entry);
+                }
+            }
+        }
     }
     Ok(())
 }
 
+/// The extensions of the config files read from the system config directories
+const CONFIG_EXTENSIONS: &[&str] = &["yaml", "toml", "json"];
+
+/// The configs merged over the built-in one before those given with `--add-config`: first
+/// `/etc/cpuinfo/config.d/*.{yaml,toml,json}`, then `$XDG_CONFIG_HOME/cpuinfo/` likewise
+/// (`~/.config` when unset), each directory in file name order
+fn system_config_paths() -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let user_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    let dirs = std::iter::once(PathBuf::from("/etc/cpuinfo/config.d"))
+        .chain(user_dir.map(|dir| dir.join("cpuinfo")));
+
+    let mut paths = Vec::new();
+    for dir in dirs.filter(|dir| dir.is_dir()) {
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| CONFIG_EXTENSIONS.iter().any(|known| extension == *known))
+            {
+                found.push(path);
+            }
+        }
+        found.sort();
+        paths.append(&mut found);
+    }
+    Ok(paths)
+}
+
 #[derive(Clone, Parser)]
 struct CmdLine {
+    /// Merge another config over the built-in and system ones, may be given more than once
     #[arg(short, long)]
     add_config: Vec<PathBuf>,
+    /// Skip the configs in /etc/cpuinfo/config.d and $XDG_CONFIG_HOME/cpuinfo
+    #[arg(long)]
+    no_system_config: bool,
+    /// How to merge leaves, MSRs and other entries a config describes differently from those
+    /// before it, the entries overridden are listed on stderr. Later configs win when not given
+    #[arg(long, value_enum)]
+    merge: Option<ConfigMerge>,
+    /// Decode only the leaves and MSRs of this profile of the config, e.g. `minimal`
+    #[arg(long)]
+    profile: Option<String>,
     #[command(subcommand)]
     command: CommandOpts,
 }
+/// The built-in config merged with the system configs and those given with `--add-config`,
+/// narrowed to `--profile`
+fn load_config(args: &CmdLine) -> Result<Definition, Box<dyn std::error::Error>> {
+    let mut config = Definition::builtin()?;
+
+    let system_paths = if args.no_system_config {
+        Vec::new()
+    } else {
+        system_config_paths()?
+    };
+    read_additional_configs(
+        &mut config,
+        system_paths.iter().chain(&args.add_config),
+        args.merge,
+    )?;
+    if let Some(profile) = &args.profile {
+        config.select_profile(profile).map_err(|e| e.to_string())?;
+    }
+    Ok(config)
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = CmdLine::parse();
 
-    let mut config = find_read_config()?;
+    // diff exits 1 only when differences are found, any error, loading the configs included,
+    // exits 2 so scripts can tell them apart
+    match load_config(&args).and_then(|config| args.command.run(&config)) {
+        Err(e) if e.is::<DiffFoundError>() => std::process::exit(1),
+        Err(e) if matches!(args.command, CommandOpts::Diff(_)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flag(name: &str, value: bool) -> Fact {
+        Fact::new(name.to_string(), value.into())
+    }
+
+    fn rule(path: &str, severity: Severity) -> SeverityRule {
+        SeverityRule {
+            path: path.to_string(),
+            severity,
+        }
+    }
+
+    fn names(diff: &DiffOutput) -> Vec<&str> {
+        diff.added
+            .iter()
+            .chain(&diff.removed)
+            .chain(diff.changed.iter().map(|(from, _)| from))
+            .map(|fact| fact.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn quote_remote_arguments() {
+        assert_eq!(shell_quote("--cpu"), "--cpu");
+        assert_eq!(shell_quote("/opt/cpuinfo"), "/opt/cpuinfo");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(
+            shell_quote("/opt/my tools/cpuinfo"),
+            "'/opt/my tools/cpuinfo'"
+        );
+        assert_eq!(shell_quote("$(reboot);"), "'$(reboot);'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn classify() {
+        let diff = || DiffOutput {
+            added: vec![
+                flag("cpuid/Model/ecx/AVX", true),
+                flag("msr/Therm/Reading", true),
+            ],
+            removed: vec![flag("msr/mc_banks/bank0/status", true)],
+            changed: vec![(
+                flag("cpuid/Model/ecx/Hypervisor", true),
+                flag("cpuid/Model/ecx/Hypervisor", false),
+            )],
+        };
+
+        // With no rules every difference is an error
+        let classified = diff().classify(&SeverityPolicy::new(&[]).unwrap());
+        assert_eq!(classified.keys().collect::<Vec<_>>(), [&Severity::Error]);
+        assert_eq!(names(&classified[&Severity::Error]).len(), 4);
+        // The built-in config only lowers the facts that come and go, such as logged errors
+        let builtin = SeverityPolicy::new(&Definition::builtin().unwrap().severities).unwrap();
+        let classified = diff().classify(&builtin);
+        assert_eq!(
+            names(&classified[&Severity::Info]),
+            ["msr/mc_banks/bank0/status"]
+        );
+        assert_eq!(names(&classified[&Severity::Error]).len(), 3);
 
-    read_additional_configs(&mut config, args.add_config.iter())?;
+        // The last matching rule wins, and ignored facts are dropped
+        let policy = SeverityPolicy::new(&[
+            rule("cpuid/**", Severity::Info),
+            rule("cpuid/Model/ecx/Hypervisor", Severity::Warn),
+            rule("msr/mc_banks/**", Severity::Info),
+            rule("msr/Therm/**", Severity::Ignore),
+            rule("cpuid/Model/ecx/AVX", Severity::Error),
+        ])
+        .unwrap();
+        let classified = diff().classify(&policy);
+        assert_eq!(
+            names(&classified[&Severity::Error]),
+            ["cpuid/Model/ecx/AVX"]
+        );
+        assert_eq!(
+            names(&classified[&Severity::Warn]),
+            ["cpuid/Model/ecx/Hypervisor"]
+        );
+        assert_eq!(
+            names(&classified[&Severity::Info]),
+            ["msr/mc_banks/bank0/status"]
+        );
+        assert!(!classified.contains_key(&Severity::Ignore));
+    }
 
-    args.command.run(&config)
+    #[cfg(feature = "http")]
+    #[test]
+    fn query_cpu() {
+        let online = core_affinity::get_core_ids().unwrap();
+        let cpu = online.last().unwrap().id;
+        let source = Serve::source_from_query(&format!("cpu={}&msr=0", cpu)).unwrap();
+        assert_eq!(source.cpu, cpu);
+        assert!(source.skip_msr);
+        // A cpu that is not online is a bad request rather than a failed pin in the handler
+        let missing = online.iter().map(|core| core.id).max().unwrap() + 1;
+        assert_eq!(
+            Serve::source_from_query(&format!("cpu={}", missing)).err(),
+            Some(format!("No online cpu {}", missing))
+        );
+        assert!(Serve::source_from_query("cpu=x").is_err());
+    }
 }
@@ -0,0 +1,117 @@
+//! Migrations of configs written for older versions of the config format
+//!
+//! A config gives the version of the format it was written for with `version:`, configs written
+//! before the key was added are version 1. Configs of older versions are read into a generic
+//! document, brought up to [`CURRENT_VERSION`] by each migration after their version in turn and
+//! only then decoded, so team configs keep loading as leaves and fields change shape.
+//!
+//! | Version | Change |
+//! |---------|--------|
+//! | 2       | The `SubLeafBitField` leaf type is renamed `SubLeafList`, the old name is still read |
+
+use serde_yaml::Value;
+
+/// The version of the config format this build reads and writes
+pub const CURRENT_VERSION: u32 = 2;
+
+/// The version of configs without a `version:` key
+pub const UNVERSIONED: u32 = 1;
+
+/// A change to the config format and how a config of the version before it is rewritten
+struct Migration {
+    /// The version the migration brings a config to
+    version: u32,
+    description: &'static str,
+    /// Rewrite a config, telling whether anything in it changed
+    apply: fn(&mut Value) -> bool,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 2,
+    description: "leaf type SubLeafBitField is renamed SubLeafList",
+    apply: rename_sub_leaf_bit_field,
+}];
+
+/// Bring `document`, a config of `version`, up to `CURRENT_VERSION`
+///
+/// Gives the descriptions of the migrations that changed the config, for the caller to warn
+/// about.
+pub fn migrate(document: &mut Value, version: u32) -> Vec<&'static str> {
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+    {
+        if (migration.apply)(document) {
+            applied.push(migration.description);
+        }
+    }
+    if let Value::Mapping(document) = document {
+        document.insert("version".into(), CURRENT_VERSION.into());
+    }
+    applied
+}
+
+/// Call `apply` with every leaf of `cpuids`, `vendor_cpuids` and `hypervisor_cpuids`
+fn for_each_leaf(document: &mut Value, mut apply: impl FnMut(&mut Value)) {
+    if let Some(Value::Mapping(leaves)) = document.get_mut("cpuids") {
+        leaves.iter_mut().for_each(|(_, leaf)| apply(leaf));
+    }
+    for key in ["vendor_cpuids", "hypervisor_cpuids"] {
+        if let Some(Value::Mapping(names)) = document.get_mut(key) {
+            for (_, leaves) in names.iter_mut() {
+                if let Value::Mapping(leaves) = leaves {
+                    leaves.iter_mut().for_each(|(_, leaf)| apply(leaf));
+                }
+            }
+        }
+    }
+}
+
+fn rename_sub_leaf_bit_field(document: &mut Value) -> bool {
+    let mut renamed = false;
+    for_each_leaf(document, |leaf| {
+        if let Some(kind) = leaf
+            .get_mut("data_type")
+            .and_then(|data_type| data_type.get_mut("type"))
+        {
+            if kind == "SubLeafBitField" {
+                *kind = "SubLeafList".into();
+                renamed = true;
+            }
+        }
+    });
+    renamed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sub_leaf_list() {
+        let mut document: Value = serde_yaml::from_str(
+            "cpuids:
+  7: {name: Flags, data_type: {type: SubLeafBitField, leaves: []}}
+  1: {name: Model, data_type: {type: BitField}}
+hypervisor_cpuids:
+  KVMKVMKVM:
+    0x40000001: {name: KVM, data_type: {type: SubLeafBitField, leaves: []}}
+",
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut document, UNVERSIONED).len(), 1);
+        assert_eq!(document["version"], CURRENT_VERSION);
+        assert_eq!(document["cpuids"][7]["data_type"]["type"], "SubLeafList");
+        assert_eq!(document["cpuids"][1]["data_type"]["type"], "BitField");
+        assert_eq!(
+            document["hypervisor_cpuids"]["KVMKVMKVM"][0x40000001]["data_type"]["type"],
+            "SubLeafList"
+        );
+
+        // Nothing applies to a config of the current version, nor is a migration that changed
+        // nothing reported
+        assert!(migrate(&mut document, CURRENT_VERSION).is_empty());
+        assert!(migrate(&mut document, UNVERSIONED).is_empty());
+    }
+}
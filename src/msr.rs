@@ -1,44 +1,77 @@
-//! Provide Read-Only access to Intel MSRs
+//! Provide Read-Only access to Intel MSRs, writes need the `msr_write` feature
 //!
 
 use super::bitfield::{self, Facter};
-use super::facts::{self, GenericFact};
+use super::expr;
+use super::facts::{self, Fact};
+use super::sub_leaf::Register;
+use super::{cpu_family, cpu_vendor, hypervisor_signature, CpuidDB};
 use serde::{Deserialize, Serialize};
 use std::vec::Vec;
-use std::{convert, error, fmt, io};
+use std::{fmt, io};
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("MSR Feature not availible file: {0}")]
     NotAvailible(String),
-    IOError(io::Error),
+    #[error("IOError: {0}")]
+    IOError(#[from] io::Error),
+    #[error("Bits {bits:#x} of MSR {address:#x} are not in the write allowlist")]
+    NotWritable { address: u32, bits: u64 },
 }
 
-pub type Result<V> = std::result::Result<V, Error>;
+pub trait MsrStore {
+    fn is_empty(&self) -> bool;
+    fn get_value<'a>(&self, desc: &'a MSRDesc) -> crate::Result<MSRValue<'a>>;
+}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::NotAvailible(name) => write!(f, "MSR Feature not availible file: {}", name),
-            Error::IOError(e) => write!(f, "IOError: {}", e),
-        }
+impl<M: MsrStore + ?Sized> MsrStore for Box<M> {
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+    fn get_value<'a>(&self, desc: &'a MSRDesc) -> crate::Result<MSRValue<'a>> {
+        (**self).get_value(desc)
     }
 }
 
-impl error::Error for Error {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        None
-    }
+/// A store that can also write MSRs, only built with the `msr_write` feature
+///
+/// Writes change the behaviour of the cpu until it is reset and a wrong one can hang or damage
+/// the machine. Callers check each write against the config's allowlist with `check_write`.
+#[cfg(feature = "msr_write")]
+pub trait MsrStoreMut: MsrStore {
+    fn set_value(&self, desc: &MSRDesc, value: u64) -> crate::Result<()>;
 }
 
-impl convert::From<io::Error> for Error {
-    fn from(e: io::Error) -> Self {
-        Error::IOError(e)
-    }
+/// Bits of an MSR that may be written, listed under `msr_writes` in the config
+///
+/// ```yaml
+/// msr_writes:
+///   - {address: 0x1a4, mask: 0xf}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WritableMSR {
+    pub address: u32,
+    pub mask: u64,
 }
 
-pub trait MsrStore {
-    fn is_empty(&self) -> bool;
-    fn get_value<'a>(&self, desc: &'a MSRDesc) -> std::result::Result<MSRValue<'a>, Error>;
+/// Whether changing MSR `address` from `old` to `new` only changes bits that `allowlist` lets be
+/// written
+pub fn check_write(
+    allowlist: &[WritableMSR],
+    address: u32,
+    old: u64,
+    new: u64,
+) -> Result<(), Error> {
+    let mask = allowlist
+        .iter()
+        .filter(|writable| writable.address == address)
+        .fold(0, |mask, writable| mask | writable.mask);
+    match (old ^ new) & !mask {
+        0 => Ok(()),
+        bits => Err(Error::NotWritable { address, bits }),
+    }
 }
 
 pub struct EmptyMSR {}
@@ -47,8 +80,8 @@ impl MsrStore for EmptyMSR {
     fn is_empty(&self) -> bool {
         true
     }
-    fn get_value<'a>(&self, _desc: &'a MSRDesc) -> std::result::Result<MSRValue<'a>, Error> {
-        Err(Error::NotAvailible("".to_string()))
+    fn get_value<'a>(&self, _desc: &'a MSRDesc) -> crate::Result<MSRValue<'a>> {
+        Err(Error::NotAvailible("".to_string()).into())
     }
 }
 
@@ -58,16 +91,44 @@ pub mod linux {
     use std::fs;
     use std::io;
 
+    /// Reads MSRs through the device files of one cpu
+    ///
+    /// Besides the kernel's msr driver, which needs root, this reads through LLNL's msr-safe
+    /// driver at `/dev/cpu/<cpu>/msr_safe`. Its readers need no root, but MSRs left out of its
+    /// allowlist fail to read and are skipped like any other unavailable MSR.
     pub struct LinuxMsrStore {
         msr_device: fs::File,
     }
 
     impl LinuxMsrStore {
-        pub fn new(cpu: usize) -> Result<LinuxMsrStore> {
-            let file_name = format!("/dev/cpu/{}/msr", cpu);
+        /// Read through the msr driver
+        pub fn new(cpu: usize) -> crate::Result<LinuxMsrStore> {
+            Self::open(format!("/dev/cpu/{}/msr", cpu), false)
+        }
+
+        /// Read through the msr-safe driver
+        pub fn new_safe(cpu: usize) -> crate::Result<LinuxMsrStore> {
+            Self::open(format!("/dev/cpu/{}/msr_safe", cpu), false)
+        }
+
+        /// Read through msr-safe when it is loaded and readable, the msr driver otherwise.
+        /// Fails with the msr driver's error when neither can be opened
+        pub fn detect(cpu: usize) -> crate::Result<LinuxMsrStore> {
+            Self::new_safe(cpu).or_else(|_| Self::new(cpu))
+        }
+
+        /// Like `detect`, opening the device for writing as well
+        #[cfg(feature = "msr_write")]
+        pub fn detect_writable(cpu: usize) -> crate::Result<LinuxMsrStore> {
+            Self::open(format!("/dev/cpu/{}/msr_safe", cpu), true)
+                .or_else(|_| Self::open(format!("/dev/cpu/{}/msr", cpu), true))
+        }
+
+        fn open(file_name: String, write: bool) -> crate::Result<LinuxMsrStore> {
             Ok(LinuxMsrStore {
                 msr_device: fs::OpenOptions::new()
                     .read(true)
+                    .write(write)
                     .open(file_name.clone())
                     .map_err(|e| match e.kind() {
                         io::ErrorKind::NotFound => Error::NotAvailible(file_name),
@@ -82,25 +143,286 @@ pub mod linux {
         fn is_empty(&self) -> bool {
             false
         }
-        fn get_value<'a>(&self, desc: &'a MSRDesc) -> std::result::Result<MSRValue<'a>, Error> {
+        fn get_value<'a>(&self, desc: &'a MSRDesc) -> crate::Result<MSRValue<'a>> {
             use std::os::unix::fs::FileExt;
             let mut msr_bytes = [u8::MIN; 8];
             self.msr_device
-                .read_at(&mut msr_bytes, desc.address.into())?;
+                .read_at(&mut msr_bytes, desc.address.into())
+                .map_err(Error::IOError)?;
             Ok(MSRValue {
                 desc,
                 value: u64::from_le_bytes(msr_bytes),
             })
         }
     }
+
+    #[cfg(feature = "msr_write")]
+    impl MsrStoreMut for LinuxMsrStore {
+        fn set_value(&self, desc: &MSRDesc, value: u64) -> crate::Result<()> {
+            use std::os::unix::fs::FileExt;
+            self.msr_device
+                .write_at(&value.to_le_bytes(), desc.address.into())
+                .map_err(Error::IOError)?;
+            Ok(())
+        }
+    }
+}
+
+/// Reads MSRs through the WinRing0 driver, the signed driver shipped with OpenHardwareMonitor and
+/// similar tools, only built with the `winring0` feature
+///
+/// The driver must already be installed and running as the `WinRing0_1_2_0` service, which needs
+/// an administrator. It reads the MSR on whichever cpu the calling thread runs on, so each read
+/// first pins the thread to the store's cpu.
+#[cfg(all(windows, feature = "winring0"))]
+pub mod windows {
+    use super::*;
+    use std::ffi::c_void;
+    use std::{mem, ptr};
+    use windows_sys::Win32::Foundation::{
+        CloseHandle, GENERIC_READ, GENERIC_WRITE, HANDLE, INVALID_HANDLE_VALUE,
+    };
+    use windows_sys::Win32::Storage::FileSystem::{
+        CreateFileW, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
+    };
+    use windows_sys::Win32::System::Threading::{GetCurrentThread, SetThreadAffinityMask};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    const DEVICE: &str = r"\\.\WinRing0_1_2_0";
+    /// `CTL_CODE(OLS_TYPE, 0x821, METHOD_BUFFERED, FILE_ANY_ACCESS)` of the driver's headers
+    const IOCTL_OLS_READ_MSR: u32 = 0x9c40_2084;
+    /// `CTL_CODE(OLS_TYPE, 0x822, METHOD_BUFFERED, FILE_ANY_ACCESS)`
+    #[cfg(feature = "msr_write")]
+    const IOCTL_OLS_WRITE_MSR: u32 = 0x9c40_2088;
+
+    pub struct WinRing0MsrStore {
+        device: HANDLE,
+        cpu: usize,
+    }
+
+    /// Keeps the calling thread on one cpu, as the driver reads the MSRs of the cpu it is called
+    /// on, and puts it back on the cpus it ran on before when dropped
+    struct Pinned {
+        previous: usize,
+    }
+
+    impl Pinned {
+        fn to(cpu: usize) -> Result<Self, Error> {
+            let mask = (cpu < usize::BITS as usize).then(|| 1 << cpu);
+            // SAFETY: the pseudo handle of the current thread is always valid
+            let previous = mask.map_or(0, |mask| unsafe {
+                SetThreadAffinityMask(GetCurrentThread(), mask)
+            });
+            match previous {
+                0 => Err(Error::NotAvailible(format!("cpu {}", cpu))),
+                previous => Ok(Self { previous }),
+            }
+        }
+    }
+
+    impl Drop for Pinned {
+        fn drop(&mut self) {
+            // SAFETY: as in `to`, and the mask is one the thread already had
+            unsafe { SetThreadAffinityMask(GetCurrentThread(), self.previous) };
+        }
+    }
+
+    impl WinRing0MsrStore {
+        pub fn new(cpu: usize) -> crate::Result<WinRing0MsrStore> {
+            let name: Vec<u16> = DEVICE.encode_utf16().chain(Some(0)).collect();
+            // SAFETY: `name` is a NUL terminated wide string that outlives the call
+            let device = unsafe {
+                CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ | GENERIC_WRITE,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE,
+                    ptr::null(),
+                    OPEN_EXISTING,
+                    FILE_ATTRIBUTE_NORMAL,
+                    0,
+                )
+            };
+            if device == INVALID_HANDLE_VALUE {
+                return Err(Error::NotAvailible(DEVICE.to_string()).into());
+            }
+            Ok(WinRing0MsrStore { device, cpu })
+        }
+
+        fn control(&self, code: u32, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+            let _pinned = Pinned::to(self.cpu)?;
+            let mut returned = 0u32;
+            // SAFETY: the buffers are valid for the lengths passed and the call is synchronous
+            let ok = unsafe {
+                DeviceIoControl(
+                    self.device,
+                    code,
+                    input.as_ptr() as *const c_void,
+                    input.len() as u32,
+                    output.as_mut_ptr() as *mut c_void,
+                    output.len() as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                )
+            };
+            match ok {
+                0 => Err(Error::IOError(io::Error::last_os_error())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Drop for WinRing0MsrStore {
+        fn drop(&mut self) {
+            // SAFETY: the handle was opened by `new` and is not used again
+            unsafe { CloseHandle(self.device) };
+        }
+    }
+
+    impl MsrStore for WinRing0MsrStore {
+        fn is_empty(&self) -> bool {
+            false
+        }
+        fn get_value<'a>(&self, desc: &'a MSRDesc) -> crate::Result<MSRValue<'a>> {
+            // The driver answers EAX then EDX, which is the value in little endian
+            let mut msr_bytes = [u8::MIN; mem::size_of::<u64>()];
+            self.control(
+                IOCTL_OLS_READ_MSR,
+                &desc.address.to_le_bytes(),
+                &mut msr_bytes,
+            )?;
+            Ok(MSRValue {
+                desc,
+                value: u64::from_le_bytes(msr_bytes),
+            })
+        }
+    }
+
+    #[cfg(feature = "msr_write")]
+    impl MsrStoreMut for WinRing0MsrStore {
+        fn set_value(&self, desc: &MSRDesc, value: u64) -> crate::Result<()> {
+            // The driver takes the packed address and value
+            let mut input = desc.address.to_le_bytes().to_vec();
+            input.extend_from_slice(&value.to_le_bytes());
+            self.control(IOCTL_OLS_WRITE_MSR, &input, &mut [])?;
+            Ok(())
+        }
+    }
 }
 
 /// Wraps a general description of an MSR
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct MSRDesc {
     pub name: String,
     pub address: u32,
     pub fields: Vec<bitfield::Field>,
+    /// Facts computed from the fields, see `expr`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derived: Vec<expr::Derived>,
+    #[serde(flatten)]
+    pub docs: bitfield::Docs,
+    /// The cpus the MSR is implemented on, every cpu when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applies_to: Option<CpuMatch>,
+    /// The cpuid bit enumerating the MSR, it is read regardless when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires: Option<Requirement>,
+}
+
+/// The cpuid bit enumerating an MSR, given as the fact of its field or as the bit itself
+///
+/// ```yaml
+/// requires: "cpuid/Structured Extened Flags/subleaf0/edx/Arch_Capabilities"
+/// requires: {leaf: 0x1, register: ecx, bit: 15}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum Requirement {
+    /// The fact of that full name is set or not zero
+    Fact(String),
+    /// `bit` of `register` in `sub_leaf` of `leaf` is set
+    Bit {
+        leaf: u32,
+        #[serde(default)]
+        sub_leaf: u32,
+        register: Register,
+        bit: u8,
+    },
+}
+
+impl Requirement {
+    /// Whether the requirement holds on the cpu `cpuid` describes, whose facts are `facts`. A
+    /// missing leaf or fact does not
+    pub fn holds<DB: CpuidDB + ?Sized>(&self, cpuid: &DB, facts: &[Fact]) -> bool {
+        match self {
+            Self::Fact(fact) => {
+                bitfield::Condition::Fact { fact: fact.clone() }.check_fact(facts) == Some(true)
+            }
+            Self::Bit {
+                leaf,
+                sub_leaf,
+                register,
+                bit,
+            } => cpuid
+                .get_cpuid(*leaf, *sub_leaf)
+                .and_then(|result| register.of(&result).checked_shr(u32::from(*bit)))
+                .is_some_and(|bits| bits & 1 == 1),
+        }
+    }
+}
+
+/// The cpus an MSR is implemented on, reading it on others faults and fails as an IO error
+///
+/// ```yaml
+/// applies_to: {vendor: GenuineIntel, families: [6]}
+/// applies_to: {hypervisor: "Microsoft Hv"}
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CpuMatch {
+    /// The vendor string of leaf 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<String>,
+    /// The families of leaf 1 including the extended family, any family when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub families: Vec<u32>,
+    /// The signature of leaf 0x40000000, for the synthetic MSRs a hypervisor emulates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hypervisor: Option<String>,
+}
+
+impl CpuMatch {
+    /// Whether `cpuid` is one of the cpus, those it can not identify are not
+    pub fn matches<DB: CpuidDB + ?Sized>(&self, cpuid: &DB) -> bool {
+        let vendor = match &self.vendor {
+            Some(vendor) => cpu_vendor(cpuid).as_ref() == Some(vendor),
+            None => true,
+        };
+        let family = self.families.is_empty()
+            || cpu_family(cpuid).is_some_and(|family| self.families.contains(&family));
+        let hypervisor = match &self.hypervisor {
+            Some(hypervisor) => hypervisor_signature(cpuid).as_ref() == Some(hypervisor),
+            None => true,
+        };
+        vendor && family && hypervisor
+    }
+}
+
+impl MSRDesc {
+    /// Whether the MSR is implemented on the cpu `cpuid` describes
+    pub fn applies_to<DB: CpuidDB + ?Sized>(&self, cpuid: &DB) -> bool {
+        self.applies_to
+            .as_ref()
+            .is_none_or(|applies_to| applies_to.matches(cpuid))
+    }
+
+    /// Whether the cpu `cpuid` describes, whose cpuid facts are `facts`, enumerates the MSR
+    pub fn is_enumerated<DB: CpuidDB + ?Sized>(&self, cpuid: &DB, facts: &[Fact]) -> bool {
+        self.requires
+            .as_ref()
+            .is_none_or(|requires| requires.holds(cpuid, facts))
+    }
 }
 
 impl fmt::Display for MSRDesc {
@@ -109,13 +431,135 @@ impl fmt::Display for MSRDesc {
     }
 }
 
+/// A register repeated in every element of an MSR array, at `offset` from the element's address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MSRArrayRegister {
+    pub name: String,
+    #[serde(default)]
+    pub offset: u32,
+    pub fields: Vec<bitfield::Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derived: Vec<expr::Derived>,
+}
+
+/// Describes `count` elements of the same registers, `stride` addresses apart, such as the
+/// machine check banks
+///
+/// ```yaml
+/// - name: mc_banks
+///   address: 0x400
+///   count: 32
+///   stride: 4
+///   registers:
+///     - {name: status, offset: 1, fields: [{type: Flag, name: VAL, bit: 63}]}
+/// ```
+///
+/// Each register of each element is read as its own MSR named `<name>/<index>/<register>`, so
+/// its facts are `msr/mc_banks/3/status/VAL`. Elements the cpu does not implement fail to read
+/// and are skipped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MSRArrayDesc {
+    pub name: String,
+    /// The address of the first element
+    pub address: u32,
+    pub count: u32,
+    pub stride: u32,
+    pub registers: Vec<MSRArrayRegister>,
+    #[serde(flatten)]
+    pub docs: bitfield::Docs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applies_to: Option<CpuMatch>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires: Option<Requirement>,
+}
+
+impl MSRArrayDesc {
+    /// Every register of every element as an MSR of its own, or an error when an address runs
+    /// past the last MSR
+    pub fn expand(&self) -> Result<Vec<MSRDesc>, String> {
+        let mut msrs = Vec::new();
+        for index in 0..self.count {
+            for register in &self.registers {
+                let address = index
+                    .checked_mul(self.stride)
+                    .and_then(|offset| offset.checked_add(self.address))
+                    .and_then(|base| base.checked_add(register.offset))
+                    .ok_or_else(|| {
+                        format!(
+                            "MSR array {} runs past the last address with {} of element {}",
+                            self.name, register.name, index
+                        )
+                    })?;
+                msrs.push(MSRDesc {
+                    name: format!("{}/{}/{}", self.name, index, register.name),
+                    address,
+                    fields: register.fields.clone(),
+                    derived: register.derived.clone(),
+                    docs: self.docs.clone(),
+                    applies_to: self.applies_to.clone(),
+                    requires: self.requires.clone(),
+                });
+            }
+        }
+        Ok(msrs)
+    }
+}
+
+/// An entry of the `msrs` list of the config, either a single MSR or an array of them, only
+/// described for the schema as `deserialize_msrs` tells them apart itself
+#[cfg(feature = "schema")]
+#[derive(schemars::JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum ConfigEntry {
+    Array(MSRArrayDesc),
+    Single(MSRDesc),
+}
+
+/// The schema of the `msrs` list of the config, which may hold arrays as well as single MSRs
+#[cfg(feature = "schema")]
+pub fn msrs_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+    gen.subschema_for::<Vec<ConfigEntry>>()
+}
+
+/// Deserialize the `msrs` list of the config, expanding arrays into their registers
+///
+/// Arrays are the entries with a `count` or `stride`, so a mistake in an entry is reported as
+/// the error in the MSR or array it is rather than as matching neither.
+pub fn deserialize_msrs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<MSRDesc>, D::Error> {
+    use serde::de::Error;
+
+    let entries: Vec<serde_yaml::Value> = Deserialize::deserialize(deserializer)?;
+    let mut msrs = Vec::new();
+    for entry in entries {
+        let name = entry
+            .get("name")
+            .and_then(serde_yaml::Value::as_str)
+            .unwrap_or("without a name")
+            .to_string();
+        let invalid = |e: &dyn fmt::Display| D::Error::custom(format!("MSR {}: {}", name, e));
+        if entry.get("count").is_some() || entry.get("stride").is_some() {
+            let array: MSRArrayDesc = serde_yaml::from_value(entry).map_err(|e| invalid(&e))?;
+            msrs.append(&mut array.expand().map_err(|e| invalid(&e))?);
+        } else {
+            msrs.push(serde_yaml::from_value(entry).map_err(|e| invalid(&e))?);
+        }
+    }
+    Ok(msrs)
+}
+
 pub struct MSRValue<'a> {
     pub desc: &'a MSRDesc,
     pub value: u64,
 }
 
-impl<'a, T: From<u32> + From<bool> + From<String>> facts::Facter<GenericFact<T>> for MSRValue<'a> {
-    fn collect_facts(&self) -> Vec<GenericFact<T>> {
+impl<'a> MSRValue<'a> {
+    /// The facts of every field, whether their conditions hold or not
+    fn decode(&self) -> Vec<Fact> {
         let value = self.value.into();
         self.desc
             .fields
@@ -130,16 +574,109 @@ impl<'a, T: From<u32> + From<bool> + From<String>> facts::Facter<GenericFact<T>>
     }
 }
 
+impl<'a> facts::Facter<Fact> for MSRValue<'a> {
+    fn collect_facts(&self) -> Vec<Fact> {
+        let fields: Vec<&bitfield::Field> = self.desc.fields.iter().collect();
+        let mut facts = self.decode();
+        bitfield::retain_valid(&fields, &mut facts);
+        let mut derived = expr::derive_facts(&self.desc.derived, &facts);
+        for fact in &mut derived {
+            fact.add_path(&self.desc.name);
+        }
+        facts.append(&mut derived);
+        facts
+    }
+}
+
 impl<'a> fmt::Display for MSRValue<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{} = {:#x}", self.desc, self.value)?;
+        if f.alternate() {
+            self.desc.docs.write_lines("    ", f)?;
+        }
+        let facts = self.decode();
         for field in &self.desc.fields {
-            writeln!(
-                f,
-                "  {}",
-                bitfield::BoundField::from_register_and_field(self.value.into(), field)
-            )?
+            bitfield::write_field(self.value.into(), field, &facts, f)?;
+        }
+        let fields: Vec<&bitfield::Field> = self.desc.fields.iter().collect();
+        let mut valid = facts;
+        bitfield::retain_valid(&fields, &mut valid);
+        for fact in expr::derive_facts(&self.desc.derived, &valid) {
+            writeln!(f, "  {} = {:>10}", fact.name, fact.value)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::snapshot::SnapshotBuilder;
+
+    #[test]
+    fn msr_entry_errors() {
+        #[derive(Deserialize, Debug)]
+        struct Msrs(#[serde(deserialize_with = "deserialize_msrs")] Vec<MSRDesc>);
+
+        let error = |yaml: &str| serde_yaml::from_str::<Msrs>(yaml).unwrap_err().to_string();
+        // The addresses of the last elements do not fit in 32 bits
+        let overflow = error(
+            "- {name: banks, address: 0xfffff000, count: 0x1000, stride: 4, \
+                registers: [{name: status, fields: []}]}\n",
+        );
+        assert!(overflow.contains("MSR array banks runs past the last address"));
+        // Mistakes are reported by what is wrong with the MSR or array
+        let single = error("- {name: Perf, address: 0x345}\n");
+        assert!(single.contains("MSR Perf: missing field `fields`"));
+        let array = error("- {name: banks, address: 0x400, count: 4, registers: []}\n");
+        assert!(array.contains("MSR banks: missing field `stride`"));
+
+        let Msrs(msrs) = serde_yaml::from_str(
+            "- {name: banks, address: 0x400, count: 2, stride: 4, \
+                registers: [{name: status, offset: 1, fields: []}]}\n",
+        )
+        .unwrap();
+        let addresses: Vec<u32> = msrs.iter().map(|msr| msr.address).collect();
+        assert_eq!(addresses, [0x401, 0x405]);
+    }
+
+    #[test]
+    fn requirement_bit() {
+        let cpuid = SnapshotBuilder::new().leaf(1, [0, 0, 1 << 15, 0]).db();
+        let bit = |leaf, bit| Requirement::Bit {
+            leaf,
+            sub_leaf: 0,
+            register: Register::Ecx,
+            bit,
+        };
+        assert!(bit(1, 15).holds(&cpuid, &[]));
+        assert!(!bit(1, 14).holds(&cpuid, &[]));
+        assert!(!bit(7, 15).holds(&cpuid, &[]));
+        // A bit past the register's width is never set
+        assert!(!bit(1, 47).holds(&cpuid, &[]));
+    }
+
+    #[test]
+    fn write_allowlist() {
+        let allowlist = [
+            WritableMSR {
+                address: 0x1a4,
+                mask: 0x3,
+            },
+            WritableMSR {
+                address: 0x1a4,
+                mask: 0xc,
+            },
+        ];
+        assert!(check_write(&allowlist, 0x1a4, 0x10, 0x1f).is_ok());
+        assert!(check_write(&allowlist, 0x1a4, 0x10, 0x10).is_ok());
+        assert!(matches!(
+            check_write(&allowlist, 0x1a4, 0x10, 0x0f),
+            Err(Error::NotWritable { bits: 0x10, .. })
+        ));
+        assert!(matches!(
+            check_write(&allowlist, 0x10, 0, 1),
+            Err(Error::NotWritable { address: 0x10, .. })
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+//! Facts from /proc/cpuinfo, the Linux kernel's view of each cpu
+//!
+//! The kernel clears flags for features it disabled or does not trust, and lists the hardware
+//! bugs it applies workarounds for, so these facts can disagree with the raw CPUID. Each
+//! processor block of the file becomes facts named by key, e.g. `model name` or `microcode`, with
+//! the `flags` and `bugs` lists split into one fact per entry, e.g. `flags/avx2`.
+
+use super::facts::{self, Fact};
+
+/// Keys copied as facts, the rest vary between kernels or are derived from these. `cpu MHz` is
+/// the clock when the file was read, which changes with load from one collection to the next
+const FACT_KEYS: &[&str] = &[
+    "vendor_id",
+    "cpu family",
+    "model",
+    "model name",
+    "stepping",
+    "microcode",
+    "cpu MHz",
+];
+
+/// Keys holding a space separated list
+const LIST_KEYS: &[&str] = &["flags", "bugs"];
+
+pub struct ProcCpuinfo {
+    processors: Vec<ProcessorInfo>,
+}
+
+impl ProcCpuinfo {
+    /// Parse the blank line separated `key : value` blocks of /proc/cpuinfo
+    pub fn from_text(text: &str) -> Self {
+        let processors = text
+            .split("\n\n")
+            .map(|block| ProcessorInfo {
+                entries: block
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                    .collect(),
+            })
+            .filter(|processor| !processor.entries.is_empty())
+            .collect();
+        Self { processors }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn read() -> crate::Result<Self> {
+        Ok(Self::from_text(&std::fs::read_to_string("/proc/cpuinfo")?))
+    }
+
+    /// /proc/cpuinfo is only available on Linux
+    #[cfg(not(target_os = "linux"))]
+    pub fn read() -> crate::Result<Self> {
+        Err(crate::Error::Unsupported(
+            "/proc/cpuinfo is only available on Linux",
+        ))
+    }
+
+    /// The block of the cpu numbered `cpu` by the kernel
+    pub fn processor(&self, cpu: usize) -> Option<&ProcessorInfo> {
+        self.processors
+            .iter()
+            .find(|processor| processor.get("processor") == Some(&cpu.to_string()))
+    }
+}
+
+pub struct ProcessorInfo {
+    entries: Vec<(String, String)>,
+}
+
+impl ProcessorInfo {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == key)
+            .map(|(_, value)| value)
+    }
+}
+
+impl facts::Facter<Fact> for ProcessorInfo {
+    fn collect_facts(&self) -> Vec<Fact> {
+        let mut ret = Vec::new();
+        for (key, value) in &self.entries {
+            if LIST_KEYS.contains(&key.as_str()) {
+                for entry in value.split_whitespace() {
+                    let mut fact = Fact::new(entry.to_string(), true.into());
+                    fact.add_path(key);
+                    ret.push(fact);
+                }
+            } else if FACT_KEYS.contains(&key.as_str()) {
+                let value = match value.parse::<u32>() {
+                    Ok(number) => number.into(),
+                    Err(_) => value.clone().into(),
+                };
+                ret.push(Fact::new(key.clone(), value));
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::{FactValue, Facter};
+
+    #[test]
+    fn proc_cpuinfo_facts() {
+        let proc = ProcCpuinfo::from_text(
+            "processor\t: 0\n\
+             vendor_id\t: GenuineIntel\n\
+             model name\t: Intel(R) Xeon(R) Processor\n\
+             flags\t\t: fpu sse2\n\
+             \n\
+             processor\t: 1\n\
+             vendor_id\t: GenuineIntel\n\
+             microcode\t: 0x1\n\
+             cpu MHz\t\t: 2100.000\n\
+             bugs\t\t: spectre_v1\n\
+             bogomips\t: 4200.00\n\n",
+        );
+        assert!(proc.processor(2).is_none());
+        let facts: Vec<Fact> = proc.processor(1).unwrap().collect_facts();
+        let names: Vec<&str> = facts.iter().map(|fact| fact.get_name()).collect();
+        assert_eq!(
+            names,
+            ["vendor_id", "microcode", "cpu MHz", "bugs/spectre_v1"]
+        );
+        let facts: Vec<Fact> = proc.processor(0).unwrap().collect_facts();
+        assert_eq!(facts[2].get_name(), "flags/fpu");
+        assert_eq!(facts[2].value, FactValue::from(true));
+    }
+}
@@ -0,0 +1,185 @@
+//! Query QEMU cpu models over a QMP socket
+//!
+//! `query-cpu-model-expansion` reports every property QEMU would set for a named cpu model, such as
+//! `host` or `EPYC-v4`. Each property becomes a fact named `<model>/<property>`, which is placed
+//! under `qemu/` by the caller. Properties are mostly feature flags using QEMU's names (`pni`,
+//! `avx512f`), with a few numbers and strings such as `family` and `vendor`.
+//!
+//! QEMU needs to be started with a QMP socket, e.g. `-qmp unix:/run/qemu.sock,server,nowait`.
+
+use super::facts::{self, Fact};
+use serde_json::{json, Map, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IOError: {0}")]
+    IOError(#[from] io::Error),
+    #[error("Unable to parse QMP message: {0}")]
+    Parse(#[from] serde_json::Error),
+    /// QEMU answered a command with an error
+    #[error("QMP error: {0}")]
+    Qmp(String),
+    /// The connection closed or QEMU sent something other than a QMP message
+    #[error("QMP protocol error: {0}")]
+    Protocol(String),
+}
+
+/// A QMP session that has left capabilities negotiation mode
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl QmpClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        Self::new(UnixStream::connect(path).map_err(Error::IOError)?)
+    }
+
+    /// Read the greeting and negotiate capabilities over an already connected `stream`
+    pub fn new(stream: UnixStream) -> crate::Result<Self> {
+        let mut client = Self {
+            reader: BufReader::new(stream.try_clone().map_err(Error::IOError)?),
+            writer: stream,
+        };
+        let greeting = client.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(Error::Protocol(format!("unexpected greeting {}", greeting)).into());
+        }
+        client.execute("qmp_capabilities", json!({}))?;
+        Ok(client)
+    }
+
+    fn read_message(&mut self) -> Result<Value, Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::Protocol("connection closed".to_string()));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Run `command` and return its result, skipping any events sent in the meantime
+    pub fn execute(&mut self, command: &str, arguments: Value) -> crate::Result<Value> {
+        let request = json!({"execute": command, "arguments": arguments});
+        writeln!(self.writer, "{}", request).map_err(Error::IOError)?;
+        loop {
+            let mut message = self.read_message()?;
+            if let Some(result) = message.get_mut("return") {
+                return Ok(result.take());
+            }
+            if let Some(error) = message.get("error") {
+                return Err(Error::Qmp(
+                    error
+                        .get("desc")
+                        .and_then(Value::as_str)
+                        .unwrap_or("unknown error")
+                        .to_string(),
+                )
+                .into());
+            }
+            if message.get("event").is_none() {
+                return Err(Error::Protocol(format!("unexpected message {}", message)).into());
+            }
+        }
+    }
+
+    /// Expand `model` to every property QEMU sets for it
+    pub fn cpu_model_expansion(&mut self, model: &str) -> crate::Result<CpuModel> {
+        let result = self.execute(
+            "query-cpu-model-expansion",
+            json!({"type": "full", "model": {"name": model}}),
+        )?;
+        let props = match result.pointer("/model/props") {
+            Some(Value::Object(props)) => props.clone(),
+            _ => Map::new(),
+        };
+        Ok(CpuModel {
+            name: model.to_string(),
+            props,
+        })
+    }
+}
+
+/// The expanded properties of a QEMU cpu model
+pub struct CpuModel {
+    /// The name the model was requested by, `host` rather than what it expanded to
+    pub name: String,
+    pub props: Map<String, Value>,
+}
+
+impl facts::Facter<Fact> for CpuModel {
+    fn collect_facts(&self) -> Vec<Fact> {
+        self.props
+            .iter()
+            .map(|(prop, value)| {
+                let value = match value {
+                    Value::Bool(flag) => (*flag).into(),
+                    Value::Number(number) => match number.as_u64() {
+                        Some(number) => number.into(),
+                        None => number.to_string().into(),
+                    },
+                    Value::String(text) => text.clone().into(),
+                    other => other.to_string().into(),
+                };
+                let mut fact = Fact::new(prop.clone(), value);
+                fact.add_path(&self.name);
+                fact
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::{FactValue, Facter};
+
+    #[test]
+    fn expand_model() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let qemu = std::thread::spawn(move || {
+            let mut reader = BufReader::new(server.try_clone().unwrap());
+            let mut writer = server;
+            let mut request = String::new();
+            writeln!(
+                writer,
+                r#"{{"QMP": {{"version": {{}}, "capabilities": []}}}}"#
+            )
+            .unwrap();
+            reader.read_line(&mut request).unwrap();
+            assert!(request.contains("qmp_capabilities"));
+            writeln!(writer, r#"{{"return": {{}}}}"#).unwrap();
+            request.clear();
+            reader.read_line(&mut request).unwrap();
+            assert!(request.contains("EPYC-v4"));
+            writeln!(writer, r#"{{"event": "RESUME", "timestamp": {{}}}}"#).unwrap();
+            writeln!(
+                writer,
+                r#"{{"return": {{"model": {{"name": "EPYC-v4-base", "props": {{"pni": true, "vmx": false, "family": 23, "vendor": "AuthenticAMD"}}}}}}}}"#
+            )
+            .unwrap();
+        });
+
+        let model = QmpClient::new(client)
+            .unwrap()
+            .cpu_model_expansion("EPYC-v4")
+            .unwrap();
+        qemu.join().unwrap();
+        let facts: Vec<Fact> = model.collect_facts();
+        let facts: Vec<(&str, &FactValue)> = facts
+            .iter()
+            .map(|fact| (fact.get_name(), &fact.value))
+            .collect();
+        assert_eq!(
+            facts,
+            [
+                ("EPYC-v4/family", &FactValue::from(23u32)),
+                ("EPYC-v4/pni", &FactValue::from(true)),
+                ("EPYC-v4/vendor", &FactValue::from("AuthenticAMD")),
+                ("EPYC-v4/vmx", &FactValue::from(false)),
+            ]
+        );
+    }
+}
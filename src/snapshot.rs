@@ -0,0 +1,460 @@
+//! Capture the raw CPUID leaves and MSR values of a cpu so they can be saved and examined later
+//!
+//! Snapshots are stored as YAML with the following layout, all numbers being plain integers:
+//!
+//! ```yaml
+//! version: 1
+//! cpuid:
+//!   - {leaf: 0, sub_leaf: 0, eax: 32, ebx: 1970169159, ecx: 1818588270, edx: 1231384169}
+//! msrs:
+//!   - {name: Arch Capabilities, address: 266, value: 3}
+//! ```
+//!
+//! The text printed by `cpuinfo disp --raw` is also accepted when loading, as is the output of
+//! `cpuid -r` from the cpuid tool. Both give a snapshot without any MSRs. When a `cpuid -r` dump
+//! covers several cpus only the first is loaded.
+//!
+//! Only non-empty leaves are recorded. A leaf that is missing from the file but within the range
+//! advertised by its function's first leaf reads back as all zeros, as it does on hardware.
+
+use super::config::Definition;
+use super::msr::{self, MSRDesc, MSRValue, MsrStore};
+use super::{cpuid_leaves, CpuidDB, CpuidFunction, CpuidResult, LeafAddr, RunningCpuidDB};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::{fs, io};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IOError: {0}")]
+    IOError(#[from] io::Error),
+    #[error("Unable to parse snapshot: {0}")]
+    Parse(#[from] serde_yaml::Error),
+    #[error("Unable to parse raw cpuid dump line {0}")]
+    RawFormat(usize),
+    #[error("Snapshot version {0} is not supported (newest supported is {SNAPSHOT_VERSION})")]
+    UnsupportedVersion(u32),
+}
+
+/// Version of the snapshot format written by this build
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A single raw CPUID result along with the leaf and sub-leaf that produced it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CpuidEntry {
+    pub leaf: u32,
+    pub sub_leaf: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl CpuidEntry {
+    pub fn new(addr: LeafAddr, result: CpuidResult) -> Self {
+        Self {
+            leaf: addr.leaf,
+            sub_leaf: addr.sub_leaf,
+            eax: result.eax,
+            ebx: result.ebx,
+            ecx: result.ecx,
+            edx: result.edx,
+        }
+    }
+
+    /// Parse a line in the format printed by `cpuinfo disp --raw`
+    ///
+    /// `(0x00000007,0x00000000) 0x00000001 0x029c67af 0x00000000 0x00000000`
+    pub fn from_raw_line(line: &str) -> Option<Self> {
+        fn hex(text: &str) -> Option<u32> {
+            u32::from_str_radix(text.trim().trim_start_matches("0x"), 16).ok()
+        }
+        let (addr, regs) = line.trim().strip_prefix('(')?.split_once(')')?;
+        let (leaf, sub_leaf) = addr.split_once(',')?;
+        let regs = regs
+            .split_whitespace()
+            .map(hex)
+            .collect::<Option<Vec<u32>>>()?;
+        match regs[..] {
+            [eax, ebx, ecx, edx] => Some(Self {
+                leaf: hex(leaf)?,
+                sub_leaf: hex(sub_leaf)?,
+                eax,
+                ebx,
+                ecx,
+                edx,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Parse a line in the format printed by `cpuid -r`
+    ///
+    /// `   0x00000007 0x00: eax=0x00000001 ebx=0x029c67af ecx=0x00000000 edx=0x00000000`
+    pub fn from_cpuid_r_line(line: &str) -> Option<Self> {
+        fn hex(text: &str) -> Option<u32> {
+            u32::from_str_radix(text.trim().strip_prefix("0x")?, 16).ok()
+        }
+        let (addr, regs) = line.trim().split_once(':')?;
+        let (leaf, sub_leaf) = addr.trim().split_once(' ')?;
+        let mut values = [None; 4];
+        for reg in regs.split_whitespace() {
+            let (name, value) = reg.split_once('=')?;
+            let index = ["eax", "ebx", "ecx", "edx"]
+                .iter()
+                .position(|wanted| *wanted == name)?;
+            values[index] = Some(hex(value)?);
+        }
+        Some(Self {
+            leaf: hex(leaf)?,
+            sub_leaf: hex(sub_leaf)?,
+            eax: values[0]?,
+            ebx: values[1]?,
+            ecx: values[2]?,
+            edx: values[3]?,
+        })
+    }
+
+    pub fn result(&self) -> CpuidResult {
+        CpuidResult {
+            eax: self.eax,
+            ebx: self.ebx,
+            ecx: self.ecx,
+            edx: self.edx,
+        }
+    }
+}
+
+/// The raw value of a single MSR
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MsrEntry {
+    pub name: String,
+    pub address: u32,
+    pub value: u64,
+}
+
+/// Everything that could be read from a cpu, independent of how it is described in the config
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    pub cpuid: Vec<CpuidEntry>,
+    #[serde(default)]
+    pub msrs: Vec<MsrEntry>,
+}
+
+impl Snapshot {
+    /// Walk every CPUID function on the current cpu and read each of the MSRs of `config` from
+    /// `msr_store`
+    ///
+    /// MSRs that can not be read are left out of the snapshot.
+    pub fn capture(msr_store: &dyn MsrStore, config: &Definition) -> Self {
+        Self::capture_from(&RunningCpuidDB::new(), msr_store, config)
+    }
+
+    /// Walk every CPUID function `source` answers, such as the cpuid KVM supports, and read the
+    /// MSRs of `config` that apply to it from `msr_store`
+    pub fn capture_from<DB: CpuidDB + ?Sized>(
+        source: &DB,
+        msr_store: &dyn MsrStore,
+        config: &Definition,
+    ) -> Self {
+        let cpuid = cpuid_leaves(source, &config.sub_leaves)
+            .map(|(addr, result)| CpuidEntry::new(addr, result))
+            .collect();
+
+        let msrs = if msr_store.is_empty() {
+            Vec::new()
+        } else {
+            config
+                .msrs_for(source)
+                .into_iter()
+                .filter_map(|desc| msr_store.get_value(desc).ok())
+                .map(|value| MsrEntry {
+                    name: value.desc.name.clone(),
+                    address: value.desc.address,
+                    value: value.value,
+                })
+                .collect()
+        };
+
+        Self {
+            version: SNAPSHOT_VERSION,
+            cpuid,
+            msrs,
+        }
+    }
+
+    /// Build a snapshot from the output of `cpuinfo disp --raw`
+    pub fn from_raw_dump(text: &str) -> crate::Result<Self> {
+        let cpuid = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(num, line)| CpuidEntry::from_raw_line(line).ok_or(Error::RawFormat(num + 1)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            version: SNAPSHOT_VERSION,
+            cpuid,
+            msrs: Vec::new(),
+        })
+    }
+
+    /// Build a snapshot from the first cpu in the output of `cpuid -r`
+    pub fn from_cpuid_r_dump(text: &str) -> crate::Result<Self> {
+        let mut cpuid = Vec::new();
+        let mut cpus_seen = 0;
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            // Each cpu starts with a "CPU:" or "CPU <n>:" header
+            if line.starts_with("CPU") {
+                cpus_seen += 1;
+                if cpus_seen > 1 {
+                    break;
+                }
+                continue;
+            }
+            cpuid.push(CpuidEntry::from_cpuid_r_line(line).ok_or(Error::RawFormat(num + 1))?);
+        }
+        Ok(Self {
+            version: SNAPSHOT_VERSION,
+            cpuid,
+            msrs: Vec::new(),
+        })
+    }
+
+    /// Load a snapshot written by `cpuinfo snapshot`, `cpuinfo disp --raw` or `cpuid -r`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let text = fs::read_to_string(path).map_err(Error::IOError)?;
+        let start = text.trim_start();
+        if start.starts_with('(') {
+            return Self::from_raw_dump(&text);
+        }
+        if start.starts_with("CPU") || start.starts_with("0x") {
+            return Self::from_cpuid_r_dump(&text);
+        }
+        let snapshot: Snapshot = serde_yaml::from_str(&text).map_err(Error::Parse)?;
+        if snapshot.version > SNAPSHOT_VERSION {
+            Err(Error::UnsupportedVersion(snapshot.version).into())
+        } else {
+            Ok(snapshot)
+        }
+    }
+
+    fn find_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        self.cpuid
+            .iter()
+            .find(|entry| entry.leaf == leaf && entry.sub_leaf == sub_leaf)
+            .map(CpuidEntry::result)
+    }
+}
+
+impl MsrStore for Snapshot {
+    fn is_empty(&self) -> bool {
+        self.msrs.is_empty()
+    }
+    fn get_value<'a>(&self, desc: &'a MSRDesc) -> crate::Result<MSRValue<'a>> {
+        self.msrs
+            .iter()
+            .find(|entry| entry.address == desc.address)
+            .map(|entry| MSRValue {
+                desc,
+                value: entry.value,
+            })
+            .ok_or_else(|| msr::Error::NotAvailible("snapshot".to_string()).into())
+    }
+}
+
+/// A `CpuidDB` that answers from a saved `Snapshot` rather than the running cpu
+pub struct FileCpuidDB {
+    snapshot: Snapshot,
+    hypervisor_ranges: Vec<(u32, u32)>,
+}
+
+impl FileCpuidDB {
+    pub fn new(snapshot: Snapshot) -> Self {
+        let hypervisor_ranges = super::hypervisor_ranges(|leaf| snapshot.find_cpuid(leaf, 0));
+        Self {
+            snapshot,
+            hypervisor_ranges,
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        Ok(Self::new(Snapshot::from_file(path)?))
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+}
+
+impl CpuidDB for FileCpuidDB {
+    fn get_cpuid(&self, leaf: u32, sub_leaf: u32) -> Option<CpuidResult> {
+        if let Some(result) = self.snapshot.find_cpuid(leaf, sub_leaf) {
+            return Some(result);
+        }
+        let func = match leaf {
+            0..=0x3FFFFFFF => CpuidFunction::Basic,
+            0x40000000..=0x4fffffff => CpuidFunction::Hypervisor,
+            0x80000000..=0x8fffffff => CpuidFunction::Extended,
+            _ => return None,
+        };
+        // Match the range check done by RunningCpuidDB so a snapshot reproduces the live facts
+        let in_range = match func {
+            CpuidFunction::Hypervisor => self
+                .hypervisor_ranges
+                .iter()
+                .any(|(base, max)| (*base..=*max).contains(&leaf)),
+            _ => self
+                .max_leaf(func)
+                .is_some_and(|max| leaf - func.start_eax() <= max),
+        };
+        in_range.then_some(CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        })
+    }
+    fn max_leaf(&self, func: CpuidFunction) -> Option<u32> {
+        self.snapshot
+            .find_cpuid(func.start_eax(), 0)
+            .map(|result| result.eax)
+    }
+}
+
+/// Builds snapshots for tests from the registers of each leaf and the values of each MSR
+#[cfg(test)]
+pub(crate) struct SnapshotBuilder {
+    snapshot: Snapshot,
+}
+
+#[cfg(test)]
+impl SnapshotBuilder {
+    pub fn new() -> Self {
+        Self {
+            snapshot: Snapshot {
+                version: SNAPSHOT_VERSION,
+                cpuid: Vec::new(),
+                msrs: Vec::new(),
+            },
+        }
+    }
+
+    /// Add sub-leaf 0 of `leaf` with `[eax, ebx, ecx, edx]`
+    pub fn leaf(self, leaf: u32, registers: [u32; 4]) -> Self {
+        self.sub_leaf(leaf, 0, registers)
+    }
+
+    pub fn sub_leaf(mut self, leaf: u32, sub_leaf: u32, [eax, ebx, ecx, edx]: [u32; 4]) -> Self {
+        self.snapshot.cpuid.push(CpuidEntry {
+            leaf,
+            sub_leaf,
+            eax,
+            ebx,
+            ecx,
+            edx,
+        });
+        self
+    }
+
+    /// Add leaf 0 with `max_leaf` and the vendor string `vendor`, such as "GenuineIntel"
+    pub fn vendor(self, max_leaf: u32, vendor: &str) -> Self {
+        let bytes = vendor.as_bytes();
+        let word = |i: usize| {
+            bytes[i..i + 4]
+                .iter()
+                .rev()
+                .fold(0, |word, byte| word << 8 | u32::from(*byte))
+        };
+        self.leaf(0, [max_leaf, word(0), word(8), word(4)])
+    }
+
+    pub fn msr(mut self, address: u32, value: u64) -> Self {
+        self.snapshot.msrs.push(MsrEntry {
+            name: String::new(),
+            address,
+            value,
+        });
+        self
+    }
+
+    pub fn build(self) -> Snapshot {
+        self.snapshot
+    }
+
+    pub fn db(self) -> FileCpuidDB {
+        FileCpuidDB::new(self.snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_snapshot() -> Snapshot {
+        SnapshotBuilder::new()
+            .leaf(0, [7, 1, 2, 3])
+            .leaf(7, [1, 1, 2, 3])
+            .sub_leaf(7, 1, [4, 1, 2, 3])
+            .leaf(0x80000000, [0x80000008, 1, 2, 3])
+            .build()
+    }
+
+    #[test]
+    fn file_db_lookup() {
+        let db = FileCpuidDB::new(make_snapshot());
+        assert_eq!(db.get_cpuid(7, 1).map(|r| r.eax), Some(4));
+        assert_eq!(db.get_cpuid(3, 0).map(|r| r.ebx), Some(0));
+        assert_eq!(db.get_cpuid(8, 0), None);
+        assert_eq!(db.get_cpuid(0x40000000, 0), None);
+        assert_eq!(db.get_cpuid(0x80000001, 0).map(|r| r.eax), Some(0));
+    }
+
+    #[test]
+    fn raw_dump_parse() {
+        let snapshot = Snapshot::from_raw_dump(
+            "(0x00000000,0x00000000) 0x00000020 0x756e6547 0x6c65746e 0x49656e69\n\
+             (0x00000007,0x00000001) 0x00000001 0x00000000 0x00000000 0x00000010\n",
+        )
+        .unwrap();
+        assert_eq!(snapshot.cpuid.len(), 2);
+        assert_eq!(snapshot.cpuid[0].ebx, 0x756e6547);
+        assert_eq!((snapshot.cpuid[1].leaf, snapshot.cpuid[1].sub_leaf), (7, 1));
+        assert!(matches!(
+            Snapshot::from_raw_dump("(0x00000000,0x00000000) 0x00000020\n"),
+            Err(crate::Error::Snapshot(Error::RawFormat(1)))
+        ));
+    }
+
+    #[test]
+    fn cpuid_r_parse() {
+        let snapshot = Snapshot::from_cpuid_r_dump(
+            "CPU 0:\n\
+             \x20  0x00000000 0x00: eax=0x00000020 ebx=0x756e6547 ecx=0x6c65746e edx=0x49656e69\n\
+             \x20  0x00000007 0x01: eax=0x00000001 ebx=0x00000000 ecx=0x00000000 edx=0x00000010\n\
+             CPU 1:\n\
+             \x20  0x00000000 0x00: eax=0x00000020 ebx=0x756e6547 ecx=0x6c65746e edx=0x49656e69\n",
+        )
+        .unwrap();
+        assert_eq!(snapshot.cpuid.len(), 2);
+        assert_eq!(snapshot.cpuid[0].ecx, 0x6c65746e);
+        assert_eq!((snapshot.cpuid[1].leaf, snapshot.cpuid[1].sub_leaf), (7, 1));
+        assert_eq!(snapshot.cpuid[1].edx, 0x10);
+        assert!(matches!(
+            Snapshot::from_cpuid_r_dump("CPU:\n   0x00000000 0x00: eax=0x00000020\n"),
+            Err(crate::Error::Snapshot(Error::RawFormat(2)))
+        ));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let snapshot = make_snapshot();
+        let text = serde_yaml::to_string(&snapshot).unwrap();
+        let loaded: Snapshot = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(loaded.cpuid, snapshot.cpuid);
+    }
+}
@@ -0,0 +1,224 @@
+//! How the sub-leaves of a CPUID leaf are enumerated
+//!
+//! Most leaves ignore ECX and have a single sub-leaf, the others each say in their own way how
+//! many there are. Leaf 0x7 gives the last sub-leaf in EAX of sub-leaf 0, leaves 0x4 and
+//! 0xB end on a sub-leaf whose type field is 0, and the sub-leaves of 0xD are the state
+//! components set in the bit maps of sub-leaves 0 and 1. Leaves without a known strategy are
+//! read until a sub-leaf is empty or repeats the one before it.
+//!
+//! The `sub_leaves` table of the config adds to or overrides the strategies built in here.
+
+use super::{is_empty_leaf, CpuidDB, CpuidResult};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops;
+use serde::{Deserialize, Serialize};
+
+/// No leaf has anywhere near this many sub-leaves, it bounds the scan of a misbehaving source
+pub const MAX_SUB_LEAVES: u32 = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Register {
+    pub fn of(&self, result: &CpuidResult) -> u32 {
+        match self {
+            Self::Eax => result.eax,
+            Self::Ebx => result.ebx,
+            Self::Ecx => result.ecx,
+            Self::Edx => result.edx,
+        }
+    }
+}
+
+// Internally tagged enums need serde's `alloc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "core", derive(Serialize, Deserialize), serde(tag = "type"))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SubLeaves {
+    /// Only sub-leaf 0
+    Single,
+    /// Until a sub-leaf is empty or repeats the one before it
+    Scan,
+    /// Sub-leaf 0 holds the number of the last sub-leaf in EAX, as leaf 0x7 does
+    Count,
+    /// Until a sub-leaf has zero in the `bounds` bits of `register`, such as the cache type of
+    /// leaf 0x4 or the level type of leaf 0xB
+    UntilZero {
+        register: Register,
+        bounds: ops::Range<u8>,
+    },
+    /// Sub-leaves 0 and 1, then one for each state component set in EDX:EAX of sub-leaf 0 or
+    /// EDX:ECX of sub-leaf 1, as leaf 0xD does
+    Xsave,
+    /// Sub-leaf 0 and the listed `sub_leaves` the source has, such as those a snapshot held
+    /// when a config was generated from it
+    Listed { sub_leaves: Vec<u32> },
+}
+
+impl SubLeaves {
+    /// The strategy built in for `leaf`
+    pub fn for_leaf(leaf: u32) -> Self {
+        let cache_type = Self::UntilZero {
+            register: Register::Eax,
+            bounds: 0..5,
+        };
+        let level_type = Self::UntilZero {
+            register: Register::Ecx,
+            bounds: 8..16,
+        };
+        match leaf {
+            0x4 | 0x8000001d => cache_type,
+            0x7 | 0x14 | 0x17 | 0x18 | 0x1d | 0x20 | 0x24 => Self::Count,
+            0xb | 0x1f => level_type,
+            0xd => Self::Xsave,
+            _ => Self::Scan,
+        }
+    }
+
+    /// The strategy for `leaf` from `overrides`, or the one built in
+    pub fn lookup(overrides: &BTreeMap<u32, SubLeaves>, leaf: u32) -> Self {
+        overrides
+            .get(&leaf)
+            .cloned()
+            .unwrap_or_else(|| Self::for_leaf(leaf))
+    }
+
+    /// Read the sub-leaves of `leaf` from `source`, sub-leaf 0 is always included when the
+    /// source has the leaf
+    pub fn read<DB: CpuidDB + ?Sized>(&self, source: &DB, leaf: u32) -> Vec<(u32, CpuidResult)> {
+        let first = match source.get_cpuid(leaf, 0) {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+        let mut components = u64::from(first.edx) << 32 | u64::from(first.eax);
+        let mut ret = vec![(0, first)];
+        if let Self::Listed { sub_leaves } = self {
+            let mut listed: Vec<u32> = sub_leaves
+                .iter()
+                .copied()
+                .filter(|sub_leaf| (1..MAX_SUB_LEAVES).contains(sub_leaf))
+                .collect();
+            listed.sort_unstable();
+            listed.dedup();
+            ret.extend(listed.into_iter().filter_map(|sub_leaf| {
+                source
+                    .get_cpuid(leaf, sub_leaf)
+                    .map(|result| (sub_leaf, result))
+            }));
+            return ret;
+        }
+        for sub_leaf in 1..MAX_SUB_LEAVES {
+            match self {
+                Self::Single => break,
+                Self::Count if sub_leaf > first.eax => break,
+                Self::Xsave if sub_leaf > 1 && components & (1 << sub_leaf) == 0 => continue,
+                _ => {}
+            }
+            let result = match source.get_cpuid(leaf, sub_leaf) {
+                Some(result) => result,
+                None => break,
+            };
+            let end = match self {
+                Self::Scan => {
+                    is_empty_leaf(&result) || ret.last().map(|(_, last)| *last) == Some(result)
+                }
+                Self::UntilZero { register, bounds } => {
+                    // Bounds reaching past the register only look at the bits it has
+                    let width = bounds.end.saturating_sub(bounds.start);
+                    let mask = 1u32
+                        .checked_shl(width.into())
+                        .map_or(u32::MAX, |bit| bit - 1);
+                    let bits = register.of(&result).checked_shr(bounds.start.into());
+                    bits.map_or(0, |bits| bits & mask) == 0
+                }
+                _ => false,
+            };
+            if end {
+                break;
+            }
+            if let (Self::Xsave, 1) = (self, sub_leaf) {
+                components |= u64::from(result.edx) << 32 | u64::from(result.ecx);
+            }
+            ret.push((sub_leaf, result));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FnCpuidDB;
+
+    fn result(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuidResult {
+        CpuidResult { eax, ebx, ecx, edx }
+    }
+
+    fn sub_leaves(strategy: SubLeaves, source: &dyn CpuidDB, leaf: u32) -> Vec<u32> {
+        strategy
+            .read(source, leaf)
+            .into_iter()
+            .map(|(sub_leaf, _)| sub_leaf)
+            .collect()
+    }
+
+    #[test]
+    fn strategies() {
+        let source = FnCpuidDB(|leaf, sub_leaf| match (leaf, sub_leaf) {
+            // Two caches, the second repeating the first as far as the scan can tell
+            (0x4, 0 | 1) => Some(result(0x121, 0x1c0003f, 0x3f, 0)),
+            (0x4, _) => Some(result(0, 0, 0, 0)),
+            // Sub-leaf 2 is the last, though sub-leaf 1 is empty
+            (0x7, 0) => Some(result(2, 0x29c67af, 0, 0)),
+            (0x7, 1 | 2) => Some(result(0, 0, 0, 0x10)),
+            (0xb, 0) => Some(result(1, 2, 0x100, 0)),
+            (0xb, 1) => Some(result(4, 8, 0x201, 0)),
+            (0xb, _) => Some(result(0, 0, sub_leaf, 0)),
+            // x87, SSE, AVX and AVX-512 with PT in sub-leaf 1
+            (0xd, 0) => Some(result(0xe7, 0x980, 0xa88, 0)),
+            (0xd, 1) => Some(result(0xf, 0x990, 0x100, 0)),
+            (0xd, _) => Some(result(sub_leaf, 0x100, 0, 0)),
+            _ => None,
+        });
+        assert_eq!(sub_leaves(SubLeaves::for_leaf(0x4), &source, 0x4), [0, 1]);
+        assert_eq!(sub_leaves(SubLeaves::Scan, &source, 0x4), [0]);
+        assert_eq!(
+            sub_leaves(SubLeaves::for_leaf(0x7), &source, 0x7),
+            [0, 1, 2]
+        );
+        assert_eq!(sub_leaves(SubLeaves::for_leaf(0xb), &source, 0xb), [0, 1]);
+        assert_eq!(
+            sub_leaves(SubLeaves::for_leaf(0xd), &source, 0xd),
+            [0, 1, 2, 5, 6, 7, 8]
+        );
+        assert_eq!(sub_leaves(SubLeaves::Single, &source, 0xd), [0]);
+        let listed = SubLeaves::Listed {
+            sub_leaves: vec![9, 3, 0, 3, MAX_SUB_LEAVES],
+        };
+        assert_eq!(sub_leaves(listed, &source, 0xd), [0, 3, 9]);
+        let whole_register = SubLeaves::UntilZero {
+            register: Register::Ecx,
+            bounds: 0..64,
+        };
+        assert_eq!(sub_leaves(whole_register, &source, 0x4), [0, 1]);
+        let past_register = SubLeaves::UntilZero {
+            register: Register::Ecx,
+            bounds: 40..64,
+        };
+        assert_eq!(sub_leaves(past_register, &source, 0x4), [0]);
+        assert!(sub_leaves(SubLeaves::Scan, &source, 0x5).is_empty());
+
+        let overrides = BTreeMap::from([(0x4, SubLeaves::Single)]);
+        assert_eq!(SubLeaves::lookup(&overrides, 0x4), SubLeaves::Single);
+        assert_eq!(SubLeaves::lookup(&overrides, 0xd), SubLeaves::Xsave);
+    }
+}
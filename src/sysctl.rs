@@ -0,0 +1,115 @@
+//! Facts from the `machdep.cpu` sysctl tree of macOS
+//!
+//! macOS gives no access to MSRs, but the kernel publishes the brand string, cache geometry and
+//! its own decoding of the feature leaves under `machdep.cpu`. Each entry becomes a fact named by
+//! its dotted path, e.g. `machdep/cpu/core_count`. Space separated feature lists such as
+//! `machdep.cpu.features` are split into one flag per feature.
+
+use super::facts::{self, Fact};
+
+/// Sysctl entries holding a space separated list of feature names
+const FEATURE_LISTS: &[&str] = &[
+    "machdep.cpu.features",
+    "machdep.cpu.extfeatures",
+    "machdep.cpu.leaf7_features",
+];
+
+pub struct SysctlFacts {
+    entries: Vec<(String, String)>,
+}
+
+impl SysctlFacts {
+    /// Parse the `name: value` lines printed by `sysctl machdep.cpu`
+    pub fn from_text(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .filter(|(name, _)| !name.is_empty())
+            .collect();
+        Self { entries }
+    }
+
+    /// Read the `machdep.cpu` tree of the running machine
+    #[cfg(target_os = "macos")]
+    pub fn collect() -> crate::Result<Self> {
+        let output = std::process::Command::new("sysctl")
+            .arg("machdep.cpu")
+            .output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            )
+            .into());
+        }
+        Ok(Self::from_text(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// The sysctl tree is only available on macOS
+    #[cfg(not(target_os = "macos"))]
+    pub fn collect() -> crate::Result<Self> {
+        Err(crate::Error::Unsupported(
+            "sysctl machdep.cpu is only available on macOS",
+        ))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl facts::Facter<Fact> for SysctlFacts {
+    fn collect_facts(&self) -> Vec<Fact> {
+        let mut ret = Vec::new();
+        for (name, value) in &self.entries {
+            let path = name.replace('.', "/");
+            if FEATURE_LISTS.contains(&name.as_str()) {
+                for feature in value.split_whitespace() {
+                    let mut fact = Fact::new(feature.to_string(), true.into());
+                    fact.add_path(&path);
+                    ret.push(fact);
+                }
+                continue;
+            }
+            let value = match value.parse::<u32>() {
+                Ok(number) => number.into(),
+                Err(_) => value.clone().into(),
+            };
+            ret.push(Fact::new(path, value));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::{FactValue, Facter};
+
+    #[test]
+    fn sysctl_facts() {
+        let sysctl = SysctlFacts::from_text(
+            "machdep.cpu.brand_string: Intel(R) Core(TM) i7-8700B CPU @ 3.20GHz\n\
+             machdep.cpu.core_count: 6\n\
+             machdep.cpu.features: FPU VME SSE3\n",
+        );
+        assert_eq!(sysctl.get("machdep.cpu.core_count"), Some("6"));
+        let facts: Vec<Fact> = sysctl.collect_facts();
+        let names: Vec<&str> = facts.iter().map(|fact| fact.get_name()).collect();
+        assert_eq!(
+            names,
+            [
+                "machdep/cpu/brand_string",
+                "machdep/cpu/core_count",
+                "machdep/cpu/features/FPU",
+                "machdep/cpu/features/VME",
+                "machdep/cpu/features/SSE3",
+            ]
+        );
+        assert_eq!(facts[1].value, FactValue::from(6u32));
+        assert_eq!(facts[4].value, FactValue::from(true));
+    }
+}
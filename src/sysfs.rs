@@ -0,0 +1,156 @@
+//! Facts from the cache and topology directories Linux publishes for each cpu in sysfs
+//!
+//! The kernel fills these from ACPI tables and its own enumeration, a different source than
+//! the CPUID leaves describing the same caches and topology, so diffing the two views can catch
+//! firmware bugs. Caches are named by level and type rather than by their `index<N>` directory,
+//! e.g. `cache/L1 Data/size`, and topology files keep their names, e.g. `topology/core_id`.
+//! Bitmask files are skipped in favour of their `_list` forms.
+
+use super::facts::{self, Fact};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Files that are not facts, or duplicate a `_list` file as a bitmask
+const SKIPPED: &[&str] = &[
+    "uevent",
+    "shared_cpu_map",
+    "cluster_cpus",
+    "core_cpus",
+    "core_siblings",
+    "die_cpus",
+    "package_cpus",
+    "thread_siblings",
+    "book_siblings",
+    "drawer_siblings",
+];
+
+pub struct CpuSysfs {
+    entries: Vec<(String, String)>,
+}
+
+impl CpuSysfs {
+    /// Read the sysfs directory of the cpu numbered `cpu` by the kernel
+    pub fn read(cpu: usize) -> crate::Result<Self> {
+        Self::from_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+    }
+
+    /// Read a cpu directory laid out like `/sys/devices/system/cpu/cpu<N>`
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> crate::Result<Self> {
+        let dir = dir.as_ref();
+        fs::metadata(dir)?;
+        let mut entries = Vec::new();
+        for index in sorted_entries(&dir.join("cache"))? {
+            if !index.starts_with("index") {
+                continue;
+            }
+            let index_dir = dir.join("cache").join(&index);
+            let read = |name: &str| fs::read_to_string(index_dir.join(name));
+            let name = format!("L{} {}", read("level")?.trim(), read("type")?.trim());
+            entries.extend(
+                read_files(&index_dir)?
+                    .into_iter()
+                    .map(|(file, value)| (format!("cache/{}/{}", name, file), value)),
+            );
+        }
+        entries.extend(
+            read_files(&dir.join("topology"))?
+                .into_iter()
+                .map(|(file, value)| (format!("topology/{}", file), value)),
+        );
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The names in `dir` in order, none when it does not exist
+fn sorted_entries(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<Vec<_>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    names.sort();
+    Ok(names)
+}
+
+/// The trimmed contents of the readable files in `dir`
+fn read_files(dir: &Path) -> io::Result<Vec<(String, String)>> {
+    Ok(sorted_entries(dir)?
+        .into_iter()
+        .filter(|name| !SKIPPED.contains(&name.as_str()))
+        .filter_map(|name| {
+            let path = dir.join(&name);
+            if !path.is_file() {
+                return None;
+            }
+            // Some attributes are write only or need privileges, those are left out
+            let value = fs::read_to_string(path).ok()?;
+            Some((name, value.trim().to_string()))
+        })
+        .collect())
+}
+
+impl facts::Facter<Fact> for CpuSysfs {
+    fn collect_facts(&self) -> Vec<Fact> {
+        self.entries
+            .iter()
+            .map(|(name, value)| {
+                let value = match value.parse::<u32>() {
+                    Ok(number) => number.into(),
+                    Err(_) => value.clone().into(),
+                };
+                Fact::new(name.clone(), value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::{FactValue, Facter};
+
+    #[test]
+    fn sysfs_facts() {
+        let dir = std::env::temp_dir().join(format!("cpuinfo-sysfs-{}", std::process::id()));
+        let write = |path: &str, value: &str| {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, value).unwrap();
+        };
+        write("cache/index0/level", "1\n");
+        write("cache/index0/type", "Data\n");
+        write("cache/index0/size", "48K\n");
+        write("cache/index0/shared_cpu_map", "1\n");
+        write("cache/index0/shared_cpu_list", "0\n");
+        write("topology/core_id", "3\n");
+        write("topology/core_cpus", "1\n");
+
+        let sysfs = CpuSysfs::from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sysfs.get("cache/L1 Data/size"), Some("48K"));
+        let facts: Vec<Fact> = sysfs.collect_facts();
+        let names: Vec<&str> = facts.iter().map(|fact| fact.get_name()).collect();
+        assert_eq!(
+            names,
+            [
+                "cache/L1 Data/level",
+                "cache/L1 Data/shared_cpu_list",
+                "cache/L1 Data/size",
+                "cache/L1 Data/type",
+                "topology/core_id",
+            ]
+        );
+        assert_eq!(facts[4].value, FactValue::from(3u32));
+        assert!(CpuSysfs::from_dir(dir).is_err());
+    }
+}
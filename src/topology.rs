@@ -0,0 +1,269 @@
+//! Derive the package, die, core and thread layout of a system from CPUID
+//!
+//! Each logical processor reports its own x2APIC ID along with how many bits of that ID belong to
+//! each topology level (leaf 0x1F, falling back to 0xB). Splitting every cpu's ID with those
+//! shifts and correlating the results gives the layout of the whole system.
+
+use super::{CpuidDB, CpuidResult};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+const V2_TOPOLOGY_LEAF: u32 = 0x1F;
+const TOPOLOGY_LEAF: u32 = 0xB;
+const AMD_TOPOLOGY_LEAF: u32 = 0x8000001E;
+
+/// The level types reported in `ECX[15:8]` of the extended topology leaves
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LevelType {
+    SMT,
+    Core,
+    Module,
+    Tile,
+    Die,
+    Unknown(u32),
+}
+
+impl LevelType {
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => None,
+            1 => Some(Self::SMT),
+            2 => Some(Self::Core),
+            3 => Some(Self::Module),
+            4 => Some(Self::Tile),
+            5 => Some(Self::Die),
+            other => Some(Self::Unknown(other)),
+        }
+    }
+}
+
+impl fmt::Display for LevelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(raw) => write!(f, "Unknown({})", raw),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// One sub-leaf of an extended topology leaf
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopologyLevel {
+    pub level_type: LevelType,
+    /// Bits to shift the x2APIC ID right to get the ID of the next level up
+    pub shift: u32,
+    /// Logical processors at this level
+    pub logical_processors: u32,
+}
+
+impl TopologyLevel {
+    /// Decode a sub-leaf of an extended topology leaf, `None` for the invalid level terminator
+    pub fn from_result(result: &CpuidResult) -> Option<Self> {
+        Some(Self {
+            level_type: LevelType::from_raw((result.ecx >> 8) & 0xFF)?,
+            shift: result.eax & 0x1F,
+            logical_processors: result.ebx & 0xFFFF,
+        })
+    }
+}
+
+/// Where a single logical processor sits in the system
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CpuLocation {
+    pub package: u32,
+    pub die: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
+/// The topology information reported by a single logical processor
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CpuTopology {
+    pub apic_id: u32,
+    pub levels: Vec<TopologyLevel>,
+    pub location: CpuLocation,
+}
+
+fn mask(bits: u32) -> u32 {
+    1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1)
+}
+
+/// Read the levels of an extended topology leaf, stopping at the invalid level terminator
+fn read_levels<DB: CpuidDB + ?Sized>(cpuid: &DB, leaf: u32) -> Option<(u32, Vec<TopologyLevel>)> {
+    let mut levels = Vec::new();
+    let mut apic_id = None;
+    for sub_leaf in 0.. {
+        let result = match cpuid.get_cpuid(leaf, sub_leaf) {
+            Some(result) => result,
+            None => break,
+        };
+        let level = match TopologyLevel::from_result(&result) {
+            Some(level) => level,
+            None => break,
+        };
+        apic_id.get_or_insert(result.edx);
+        levels.push(level);
+    }
+    apic_id.map(|apic_id| (apic_id, levels))
+}
+
+impl CpuTopology {
+    /// Decode the topology as seen by the cpu `cpuid` answers for
+    pub fn from_cpuid<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<Self> {
+        let (apic_id, levels) = read_levels(cpuid, V2_TOPOLOGY_LEAF)
+            .or_else(|| read_levels(cpuid, TOPOLOGY_LEAF))
+            .or_else(|| Self::legacy_levels(cpuid))?;
+
+        let shift_of = |wanted: &[LevelType]| {
+            levels
+                .iter()
+                .filter(|level| wanted.contains(&level.level_type))
+                .map(|level| level.shift)
+                .max()
+        };
+        let smt_shift = shift_of(&[LevelType::SMT]).unwrap_or(0);
+        let core_shift = shift_of(&[LevelType::Core]).unwrap_or(smt_shift);
+        let die_shift = shift_of(&[LevelType::Module, LevelType::Tile, LevelType::Die])
+            .unwrap_or(core_shift)
+            .max(core_shift);
+        let package_shift = levels.iter().map(|level| level.shift).max().unwrap_or(0);
+
+        let die = match cpuid.get_cpuid(AMD_TOPOLOGY_LEAF, 0) {
+            // AMD reports the node (die) ID directly
+            Some(result) if die_shift == core_shift && result.ecx != 0 => result.ecx & 0xFF,
+            _ => (apic_id & mask(die_shift)) >> core_shift,
+        };
+
+        Some(Self {
+            apic_id,
+            location: CpuLocation {
+                package: apic_id.checked_shr(package_shift).unwrap_or(0),
+                die,
+                core: (apic_id & mask(core_shift)) >> smt_shift,
+                thread: apic_id & mask(smt_shift),
+            },
+            levels,
+        })
+    }
+
+    /// Build SMT and core levels from leaf 1 and AMD's 0x8000001E when extended topology is missing
+    fn legacy_levels<DB: CpuidDB + ?Sized>(cpuid: &DB) -> Option<(u32, Vec<TopologyLevel>)> {
+        let model = cpuid.get_cpuid(1, 0)?;
+        let apic_id = model.ebx >> 24;
+        let logical_processors = (model.ebx >> 16) & 0xFF;
+        let threads_per_core = cpuid
+            .get_cpuid(AMD_TOPOLOGY_LEAF, 0)
+            .map_or(1, |result| ((result.ebx >> 8) & 0xFF) + 1);
+        let bits_for = |count: u32| u32::BITS - count.max(1).saturating_sub(1).leading_zeros();
+        Some((
+            apic_id,
+            vec![
+                TopologyLevel {
+                    level_type: LevelType::SMT,
+                    shift: bits_for(threads_per_core),
+                    logical_processors: threads_per_core,
+                },
+                TopologyLevel {
+                    level_type: LevelType::Core,
+                    shift: bits_for(logical_processors),
+                    logical_processors,
+                },
+            ],
+        ))
+    }
+}
+
+/// Counts of each topology level across all the cpus examined
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopologySummary {
+    pub packages: usize,
+    pub dies: usize,
+    pub cores: usize,
+    pub threads: usize,
+}
+
+impl TopologySummary {
+    pub fn new<'a, I: IntoIterator<Item = &'a CpuTopology>>(cpus: I) -> Self {
+        let mut packages = BTreeSet::new();
+        let mut dies = BTreeSet::new();
+        let mut cores = BTreeSet::new();
+        let mut threads = 0;
+        for cpu in cpus {
+            let CpuLocation {
+                package, die, core, ..
+            } = cpu.location;
+            packages.insert(package);
+            dies.insert((package, die));
+            cores.insert((package, die, core));
+            threads += 1;
+        }
+        Self {
+            packages: packages.len(),
+            dies: dies.len(),
+            cores: cores.len(),
+            threads,
+        }
+    }
+}
+
+impl fmt::Display for TopologySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packages: {} dies: {} cores: {} threads: {}",
+            self.packages, self.dies, self.cores, self.threads
+        )
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::snapshot::{FileCpuidDB, SnapshotBuilder};
+
+    fn make_cpu(apic_id: u32) -> FileCpuidDB {
+        SnapshotBuilder::new()
+            .leaf(0, [0xB, 0, 0, 0])
+            .leaf(1, [0, apic_id << 24, 0, 0])
+            // 2 threads per core, 8 cores per package
+            .leaf(0xB, [1, 2, 0x100, apic_id])
+            .sub_leaf(0xB, 1, [4, 16, 0x201, apic_id])
+            .db()
+    }
+
+    #[test]
+    fn decode_location() {
+        // package 1, core 5, thread 1
+        let cpu = CpuTopology::from_cpuid(&make_cpu((1 << 4) | (5 << 1) | 1)).unwrap();
+        assert_eq!(cpu.levels.len(), 2);
+        assert_eq!(
+            cpu.location,
+            CpuLocation {
+                package: 1,
+                die: 0,
+                core: 5,
+                thread: 1
+            }
+        );
+    }
+
+    #[test]
+    fn summarize() {
+        let cpus: Vec<_> = [0, 1, 2, 3, 16, 17]
+            .iter()
+            .map(|id| CpuTopology::from_cpuid(&make_cpu(*id)).unwrap())
+            .collect();
+        assert_eq!(
+            TopologySummary::new(&cpus),
+            TopologySummary {
+                packages: 2,
+                dies: 2,
+                cores: 3,
+                threads: 6
+            }
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Assess speculative execution vulnerabilities from the CPUID and MSR facts of a cpu
+//!
+//! Each built-in rule names the enumeration bits that declare a cpu unaffected by a class of
+//! vulnerability and the bits that advertise a hardware or microcode mitigation for it. The
+//! assessment only reflects what the cpu enumerates. Model specific exceptions and whether the
+//! operating system has actually enabled a mitigation are not taken into account.
+
+use super::facts::FactSet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const VENDOR: &str = "cpuid/Start/type";
+const ARCH_CAPABILITIES_ENUMERATED: &str =
+    "cpuid/Structured Extened Flags/subleaf0/edx/Arch_Capabilities";
+const ARCH_CAPABILITIES: &str = "msr/Arch Capabilities/";
+
+const RDCL_NO: &str = "msr/Arch Capabilities/RDCL_NO";
+const IBRS_ALL: &str = "msr/Arch Capabilities/IBRS_ALL";
+const SSB_NO: &str = "msr/Arch Capabilities/SSB_NO";
+const MDS_NO: &str = "msr/Arch Capabilities/MDS_NO";
+const TSX_CTRL: &str = "msr/Arch Capabilities/TSX_CTRL";
+const TAA_NO: &str = "msr/Arch Capabilities/TAA_NO";
+const SBDR_SSDP_NO: &str = "msr/Arch Capabilities/SBDR_SSDP_NO";
+const FBSDP_NO: &str = "msr/Arch Capabilities/FBSDP_NO";
+const PSDP_NO: &str = "msr/Arch Capabilities/PSDP_NO";
+const FB_CLEAR: &str = "msr/Arch Capabilities/FB_CLEAR";
+const BHI_NO: &str = "msr/Arch Capabilities/BHI_NO";
+const GDS_CTRL: &str = "msr/Arch Capabilities/GDS_CTRL";
+const GDS_NO: &str = "msr/Arch Capabilities/GDS_NO";
+
+const HLE: &str = "cpuid/Structured Extened Flags/subleaf0/ebx/HLE";
+const RTM: &str = "cpuid/Structured Extened Flags/subleaf0/ebx/RTM";
+const MD_CLEAR: &str = "cpuid/Structured Extened Flags/subleaf0/edx/MD_CLEAR";
+const IBRS_IBPB: &str = "cpuid/Structured Extened Flags/subleaf0/edx/IBRS_IBPB";
+const L1D_FLUSH: &str = "cpuid/Structured Extened Flags/subleaf0/edx/L1D_FLUSH";
+const SSBD: &str = "cpuid/Structured Extened Flags/subleaf0/edx/SSBD";
+const BHI_CTRL: &str = "cpuid/Structured Extened Flags/subleaf2/edx/BHI_CTRL";
+
+const AMD_IBRS: &str = "cpuid/Extended Feature Extensions ID/ebx/IBRS";
+const AMD_SSBD: &str = "cpuid/Extended Feature Extensions ID/ebx/SSBD";
+const AMD_VIRT_SSBD: &str = "cpuid/Extended Feature Extensions ID/ebx/VIRT_SSBD";
+const AMD_SSB_NO: &str = "cpuid/Extended Feature Extensions ID/ebx/SSB_NO";
+
+/// Look up the facts a vulnerability assessment is based on
+pub trait FactLookup {
+    /// The value of the flag at `path`, `None` when it was not collected
+    fn flag(&self, path: &str) -> Option<bool>;
+    /// The value of the text fact at `path`, `None` when it was not collected
+    fn text(&self, path: &str) -> Option<&str>;
+}
+
+impl FactLookup for FactSet {
+    fn flag(&self, path: &str) -> Option<bool> {
+        self.get(path).and_then(|fact| fact.value.as_bool())
+    }
+    fn text(&self, path: &str) -> Option<&str> {
+        self.get(path).and_then(|fact| fact.value.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    NotAffected,
+    /// The cpu provides a mitigation, which still needs to be enabled by the OS
+    MitigationAvailable,
+    /// Affected with no hardware mitigation, only software workarounds remain
+    Vulnerable,
+    /// Not enough facts were collected, usually because the MSRs were not readable
+    Unknown,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Status::NotAffected => "not affected",
+                Status::MitigationAvailable => "mitigation available",
+                Status::Vulnerable => "vulnerable",
+                Status::Unknown => "unknown",
+            }
+        )
+    }
+}
+
+/// The outcome for one class of vulnerability
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Assessment {
+    pub class: String,
+    pub cves: Vec<String>,
+    pub status: Status,
+    /// The facts that decided the status
+    pub reason: String,
+}
+
+struct Rule {
+    class: &'static str,
+    cves: &'static [&'static str],
+    /// Only Intel parts are affected
+    intel_only: bool,
+    /// The cpu is unaffected when every flag of any one group has the given value
+    not_affected: &'static [&'static [(&'static str, bool)]],
+    /// Any of these flags being set means a mitigation is available
+    mitigations: &'static [&'static str],
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        class: "Meltdown",
+        cves: &["CVE-2017-5754"],
+        intel_only: true,
+        not_affected: &[&[(RDCL_NO, true)]],
+        mitigations: &[],
+    },
+    Rule {
+        class: "Spectre v2",
+        cves: &["CVE-2017-5715"],
+        intel_only: false,
+        not_affected: &[],
+        mitigations: &[IBRS_ALL, IBRS_IBPB, AMD_IBRS],
+    },
+    Rule {
+        class: "Speculative Store Bypass",
+        cves: &["CVE-2018-3639"],
+        intel_only: false,
+        not_affected: &[&[(SSB_NO, true)], &[(AMD_SSB_NO, true)]],
+        mitigations: &[SSBD, AMD_SSBD, AMD_VIRT_SSBD],
+    },
+    Rule {
+        class: "L1 Terminal Fault",
+        cves: &["CVE-2018-3615", "CVE-2018-3620", "CVE-2018-3646"],
+        intel_only: true,
+        not_affected: &[&[(RDCL_NO, true)]],
+        mitigations: &[L1D_FLUSH],
+    },
+    Rule {
+        class: "Microarchitectural Data Sampling",
+        cves: &[
+            "CVE-2018-12126",
+            "CVE-2018-12127",
+            "CVE-2018-12130",
+            "CVE-2019-11091",
+        ],
+        intel_only: true,
+        not_affected: &[&[(MDS_NO, true)]],
+        mitigations: &[MD_CLEAR],
+    },
+    Rule {
+        class: "TSX Asynchronous Abort",
+        cves: &["CVE-2019-11135"],
+        intel_only: true,
+        not_affected: &[&[(TAA_NO, true)], &[(HLE, false), (RTM, false)]],
+        mitigations: &[MD_CLEAR, TSX_CTRL],
+    },
+    Rule {
+        class: "MMIO Stale Data",
+        cves: &["CVE-2022-21123", "CVE-2022-21125", "CVE-2022-21166"],
+        intel_only: true,
+        not_affected: &[&[(SBDR_SSDP_NO, true), (FBSDP_NO, true), (PSDP_NO, true)]],
+        mitigations: &[FB_CLEAR, MD_CLEAR],
+    },
+    Rule {
+        class: "Branch History Injection",
+        cves: &["CVE-2022-0001", "CVE-2022-0002"],
+        intel_only: true,
+        not_affected: &[&[(BHI_NO, true)]],
+        mitigations: &[BHI_CTRL],
+    },
+    Rule {
+        class: "Gather Data Sampling",
+        cves: &["CVE-2022-40982"],
+        intel_only: true,
+        not_affected: &[&[(GDS_NO, true)]],
+        mitigations: &[GDS_CTRL],
+    },
+];
+
+/// Flags of the arch capabilities MSR read as clear when the cpu does not enumerate the MSR
+fn flag<L: FactLookup + ?Sized>(facts: &L, path: &str) -> Option<bool> {
+    facts.flag(path).or_else(|| {
+        if path.starts_with(ARCH_CAPABILITIES)
+            && facts.flag(ARCH_CAPABILITIES_ENUMERATED) == Some(false)
+        {
+            Some(false)
+        } else {
+            None
+        }
+    })
+}
+
+fn short_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+impl Rule {
+    fn assess<L: FactLookup + ?Sized>(&self, facts: &L) -> Assessment {
+        let (status, reason) = self.status(facts);
+        Assessment {
+            class: self.class.to_string(),
+            cves: self.cves.iter().map(|cve| cve.to_string()).collect(),
+            status,
+            reason,
+        }
+    }
+
+    fn status<L: FactLookup + ?Sized>(&self, facts: &L) -> (Status, String) {
+        let vendor = facts.text(VENDOR);
+        if self.intel_only && vendor.is_some_and(|vendor| vendor != "GenuineIntel") {
+            return (
+                Status::NotAffected,
+                "only Intel cpus are affected".to_string(),
+            );
+        }
+
+        let mut incomplete = false;
+        for group in self.not_affected {
+            let values: Vec<_> = group.iter().map(|(path, _)| flag(facts, path)).collect();
+            if group
+                .iter()
+                .zip(&values)
+                .all(|((_, wanted), value)| *value == Some(*wanted))
+            {
+                let names: Vec<String> = group
+                    .iter()
+                    .map(|(path, wanted)| {
+                        format!("{}{}", if *wanted { "" } else { "no " }, short_name(path))
+                    })
+                    .collect();
+                return (Status::NotAffected, names.join(", "));
+            }
+            incomplete |= values.iter().any(Option::is_none);
+        }
+
+        let mut available: Vec<&str> = self
+            .mitigations
+            .iter()
+            .filter(|path| flag(facts, path) == Some(true))
+            .map(|path| short_name(path))
+            .collect();
+        // Intel and AMD use the same names for some of their bits
+        available.sort_unstable();
+        available.dedup();
+        if !available.is_empty() {
+            (Status::MitigationAvailable, available.join(", "))
+        } else if incomplete {
+            (
+                Status::Unknown,
+                "arch capabilities facts were not collected".to_string(),
+            )
+        } else {
+            (
+                Status::Vulnerable,
+                "no hardware mitigation enumerated".to_string(),
+            )
+        }
+    }
+}
+
+/// Assess every built-in vulnerability class against `facts`
+pub fn assess<L: FactLookup + ?Sized>(facts: &L) -> Vec<Assessment> {
+    RULES.iter().map(|rule| rule.assess(facts)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::facts::{Fact, FactValue};
+
+    fn make_facts(extra: &[(&str, bool)]) -> FactSet {
+        let mut facts: Vec<Fact> = vec![
+            (VENDOR, FactValue::from("GenuineIntel")).into(),
+            (HLE, true.into()).into(),
+            (RTM, true.into()).into(),
+        ];
+        facts.extend(
+            extra
+                .iter()
+                .map(|(path, value)| (*path, (*value).into()).into()),
+        );
+        facts.into()
+    }
+
+    fn status_of(assessments: &[Assessment], class: &str) -> Status {
+        assessments
+            .iter()
+            .find(|assessment| assessment.class == class)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn assess_intel() {
+        let facts = make_facts(&[
+            (ARCH_CAPABILITIES_ENUMERATED, true),
+            (RDCL_NO, true),
+            (MDS_NO, false),
+            (MD_CLEAR, true),
+            (GDS_NO, false),
+            (GDS_CTRL, false),
+        ]);
+        let assessments = assess(&facts);
+        assert_eq!(status_of(&assessments, "Meltdown"), Status::NotAffected);
+        assert_eq!(
+            status_of(&assessments, "Microarchitectural Data Sampling"),
+            Status::MitigationAvailable
+        );
+        assert_eq!(
+            status_of(&assessments, "Gather Data Sampling"),
+            Status::Vulnerable
+        );
+        assert_eq!(
+            status_of(&assessments, "Branch History Injection"),
+            Status::Unknown
+        );
+    }
+
+    #[test]
+    fn assess_without_arch_capabilities() {
+        let facts = make_facts(&[(ARCH_CAPABILITIES_ENUMERATED, false)]);
+        let assessments = assess(&facts);
+        assert_eq!(status_of(&assessments, "Meltdown"), Status::Vulnerable);
+        assert_eq!(status_of(&assessments, "Spectre v2"), Status::Vulnerable);
+    }
+}
@@ -0,0 +1,152 @@
+//! Exit codes and output of the cpuinfo binary, which scripts depend on
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn cpuinfo(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_cpuinfo"))
+        .arg("--no-system-config")
+        .args(args)
+        .output()
+        .unwrap()
+        .status
+        .code()
+}
+
+fn cpuinfo_stdout(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_cpuinfo"))
+        .arg("--no-system-config")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Facts files in a directory of their own, removed when dropped
+struct FactsFiles(PathBuf);
+
+impl FactsFiles {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cpuinfo-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("a.yaml"),
+            "version: 1\nfacts:\n- name: cpuid/Model/ecx/AVX\n  value: true\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("b.yaml"),
+            "version: 1\nfacts:\n- name: cpuid/Model/ecx/AVX\n  value: false\n",
+        )
+        .unwrap();
+        Self(dir)
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.0.join(name).display().to_string()
+    }
+}
+
+impl Drop for FactsFiles {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn diff_exit_codes() {
+    let files = FactsFiles::new("exit-codes");
+    let (a, b) = (files.path("a.yaml"), files.path("b.yaml"));
+    assert_eq!(cpuinfo(&["diff", &a, &a]), Some(0));
+    assert_eq!(cpuinfo(&["diff", &a, &b]), Some(1));
+    // Errors exit 2 rather than the 1 of differences found, loading the configs included
+    assert_eq!(cpuinfo(&["diff", &a, &files.path("missing.yaml")]), Some(2));
+    assert_eq!(
+        cpuinfo(&["-a", &files.path("missing.yaml"), "diff", &a, &b]),
+        Some(2)
+    );
+    assert_eq!(cpuinfo(&["--profile", "missing", "diff", &a, &b]), Some(2));
+}
+
+#[test]
+fn diff_allowlist() {
+    let files = FactsFiles::new("allowlist");
+    let (a, b) = (files.path("a.yaml"), files.path("b.yaml"));
+    let allow = |name: &str, transitions: &str| {
+        let path = files.path(name);
+        std::fs::write(&path, transitions).unwrap();
+        path
+    };
+    // An expected change never fails the diff, one the allowlist does not describe still does
+    let expected = allow(
+        "expected.yaml",
+        "cpuid/Model/ecx/*: {from: true, to: false}\n",
+    );
+    assert_eq!(cpuinfo(&["diff", &a, &b, "--allow", &expected]), Some(0));
+    let any_to = allow("any.yaml", "cpuid/Model/**: {to: false}\n");
+    assert_eq!(cpuinfo(&["diff", &a, &b, "--allow", &any_to]), Some(0));
+    let reverse = allow("reverse.yaml", "cpuid/Model/ecx/AVX: {from: false}\n");
+    assert_eq!(cpuinfo(&["diff", &a, &b, "--allow", &reverse]), Some(1));
+    // A direction only applies to numbers
+    let increase = allow("increase.yaml", "cpuid/Model/ecx/AVX: increase\n");
+    assert_eq!(cpuinfo(&["diff", &a, &b, "--allow", &increase]), Some(1));
+    // Diffs of more than two sets have no changes to expect
+    assert_eq!(
+        cpuinfo(&["diff", &a, &b, &a, "--allow", &expected]),
+        Some(2)
+    );
+}
+
+#[test]
+fn config_includes() {
+    let files = FactsFiles::new("includes");
+    let (a, b) = (files.path("a.yaml"), files.path("b.yaml"));
+    let config = |name: &str, text: &str| {
+        let path = files.path(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    };
+    config("caps.yaml", "kvm_caps: {hlt: 1}\n");
+    config("left.yaml", "include: [caps.yaml]\n");
+    config("right.yaml", "include: [caps.yaml]\n");
+    // A file included twice is merged once, so it does not conflict with itself
+    let diamond = config("diamond.yaml", "include: [left.yaml, right.yaml]\n");
+    assert_eq!(
+        cpuinfo(&["--merge", "error", "-a", &diamond, "diff", &a, &b]),
+        Some(1)
+    );
+    // Includes are merged by the chosen strategy like any other config
+    let conflict = config(
+        "conflict.yaml",
+        "include: [caps.yaml]\nkvm_caps: {hlt: 2}\n",
+    );
+    assert_eq!(cpuinfo(&["-a", &conflict, "diff", &a, &b]), Some(1));
+    assert_eq!(
+        cpuinfo(&["--merge", "error", "-a", &conflict, "diff", &a, &b]),
+        Some(2)
+    );
+}
+
+#[test]
+fn generated_config_sparse_sub_leaves() {
+    let files = FactsFiles::new("generate");
+    // Only components 0 to 2 are set in sub-leaf 0, so sub-leaf 5 is the one after 2
+    let dump = files.path("dump.yaml");
+    std::fs::write(
+        &dump,
+        "version: 1\ncpuid:\n\
+         - {leaf: 0xd, sub_leaf: 0, eax: 0x7, ebx: 0x340, ecx: 0x340, edx: 0}\n\
+         - {leaf: 0xd, sub_leaf: 1, eax: 0x1, ebx: 0, ecx: 0, edx: 0}\n\
+         - {leaf: 0xd, sub_leaf: 2, eax: 0x100, ebx: 0x240, ecx: 0, edx: 0}\n\
+         - {leaf: 0xd, sub_leaf: 5, eax: 0x40, ebx: 0x340, ecx: 0, edx: 0}\n",
+    )
+    .unwrap();
+    let config = files.path("generated.yaml");
+    cpuinfo_stdout(&["generate-config", &dump, "-o", &config]);
+    let facts = cpuinfo_stdout(&["-a", &config, "facts", "--from-file", &dump]);
+    let leaf = "cpuid/Leaf 0x0000000d";
+    assert!(facts.contains(&format!("{}/subleaf2/eax/bit8\n    value: true", leaf)));
+    assert!(facts.contains(&format!("{}/subleaf5/eax/bit6\n    value: true", leaf)));
+    assert!(!facts.contains(&format!("{}/subleaf3/", leaf)));
+}